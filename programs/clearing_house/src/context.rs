@@ -1,6 +1,7 @@
 use anchor_lang::{prelude::*, AnchorDeserialize, AnchorSerialize};
 use anchor_spl::token::{Mint, Token, TokenAccount};
 
+use crate::controller::position::PositionDirection;
 use crate::state::history::curve::{CurveHistory, ExtendedCurveHistory};
 use crate::state::history::deposit::DepositHistory;
 use crate::state::history::funding_rate::FundingRateHistory;
@@ -9,6 +10,7 @@ use crate::state::history::{funding_payment::FundingPaymentHistory, trade::Trade
 use crate::state::market::Markets;
 use crate::state::state::State;
 use crate::state::user::{User, UserPositions};
+use crate::state::user_orders::{OrderTriggerCondition, OrderType, UserOrders};
 
 #[derive(Accounts)]
 #[instruction(
@@ -298,6 +300,64 @@ pub struct WithdrawFromInsuranceVault<'info> {
     pub token_program: Program<'info, Token>,
 }
 
+#[derive(Accounts)]
+pub struct TransferFeesToInsuranceVault<'info> {
+    #[account(
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        mut,
+        constraint = &state.collateral_vault.eq(&collateral_vault.key())
+    )]
+    pub collateral_vault: Box<Account<'info, TokenAccount>>,
+    #[account(
+        constraint = &state.collateral_vault_authority.eq(&collateral_vault_authority.key())
+    )]
+    pub collateral_vault_authority: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = &state.insurance_vault.eq(&insurance_vault.key())
+    )]
+    pub insurance_vault: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct CoverBadDebtFromInsuranceVault<'info> {
+    #[account(
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        mut,
+        constraint = &state.insurance_vault.eq(&insurance_vault.key())
+    )]
+    pub insurance_vault: Box<Account<'info, TokenAccount>>,
+    #[account(
+        constraint = &state.insurance_vault_authority.eq(&insurance_vault_authority.key())
+    )]
+    pub insurance_vault_authority: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = &state.collateral_vault.eq(&collateral_vault.key())
+    )]
+    pub collateral_vault: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
+
 #[derive(Accounts)]
 pub struct WithdrawFromInsuranceVaultToMarket<'info> {
     #[account(
@@ -373,6 +433,24 @@ pub struct OpenPosition<'info> {
     pub oracle: AccountInfo<'info>,
 }
 
+#[derive(Accounts)]
+pub struct OpenPositionWithCollateral<'info> {
+    pub open_position: OpenPosition<'info>,
+    #[account(
+        mut,
+        constraint = &open_position.state.collateral_vault.eq(&collateral_vault.key())
+    )]
+    pub collateral_vault: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub user_collateral_account: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+    #[account(
+        mut,
+        constraint = &open_position.state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
+}
+
 #[derive(Accounts)]
 pub struct ClosePosition<'info> {
     #[account(mut)]
@@ -496,6 +574,68 @@ pub struct SettleFunding<'info> {
     pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
 }
 
+#[derive(Accounts)]
+pub struct UpdateUserAutoDeleverage<'info> {
+    #[account(
+        mut,
+        has_one = authority
+    )]
+    pub user: Box<Account<'info, User>>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AutoDeleverage<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        constraint = &user.positions.eq(&user_positions.key())
+    )]
+    pub user: Box<Account<'info, User>>,
+    #[account(
+        mut,
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_positions: AccountLoader<'info, UserPositions>,
+    #[account(
+        mut,
+        constraint = &state.trade_history.eq(&trade_history.key())
+    )]
+    pub trade_history: AccountLoader<'info, TradeHistory>,
+    #[account(
+        mut,
+        constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
+    )]
+    pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimSettledPnl<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        has_one = authority,
+        constraint = &user.positions.eq(&user_positions.key())
+    )]
+    pub user: Box<Account<'info, User>>,
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_positions: AccountLoader<'info, UserPositions>,
+}
+
 #[derive(Accounts)]
 pub struct UpdateFundingRate<'info> {
     pub state: Box<Account<'info, State>>,
@@ -512,6 +652,16 @@ pub struct UpdateFundingRate<'info> {
     pub funding_rate_history: AccountLoader<'info, FundingRateHistory>,
 }
 
+#[derive(Accounts)]
+pub struct UpdateTwaps<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+}
+
 #[derive(Accounts)]
 pub struct RepegCurve<'info> {
     #[account(
@@ -591,6 +741,70 @@ pub struct AdminUpdateMarket<'info> {
     pub markets: AccountLoader<'info, Markets>,
 }
 
+#[derive(Accounts)]
+pub struct AdminForceClosePosition<'info> {
+    pub admin: Signer<'info>,
+    #[account(
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        constraint = &user.positions.eq(&user_positions.key())
+    )]
+    pub user: Box<Account<'info, User>>,
+    #[account(
+        mut,
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_positions: AccountLoader<'info, UserPositions>,
+    #[account(
+        mut,
+        constraint = &state.trade_history.eq(&trade_history.key())
+    )]
+    pub trade_history: AccountLoader<'info, TradeHistory>,
+    #[account(
+        mut,
+        constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
+    )]
+    pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+}
+
+#[derive(Accounts)]
+pub struct SettleExpiredPosition<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        constraint = &user.positions.eq(&user_positions.key())
+    )]
+    pub user: Box<Account<'info, User>>,
+    #[account(
+        mut,
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_positions: AccountLoader<'info, UserPositions>,
+    #[account(
+        mut,
+        constraint = &state.trade_history.eq(&trade_history.key())
+    )]
+    pub trade_history: AccountLoader<'info, TradeHistory>,
+    #[account(
+        mut,
+        constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
+    )]
+    pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+}
+
 #[derive(Accounts)]
 pub struct UpdateCurveHistory<'info> {
     pub admin: Signer<'info>,
@@ -606,3 +820,153 @@ pub struct UpdateCurveHistory<'info> {
     )]
     pub curve_history: AccountLoader<'info, CurveHistory>,
 }
+
+#[derive(Accounts)]
+pub struct MigrateMarkets<'info> {
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    #[account(zero)]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        constraint = &state.markets.eq(&legacy_markets.key())
+    )]
+    pub legacy_markets: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(user_orders_nonce: u8)]
+pub struct InitializeUserOrders<'info> {
+    #[account(has_one = authority)]
+    pub user: Box<Account<'info, User>>,
+    #[account(
+        init,
+        seeds = [b"user_orders", user.key().as_ref()],
+        bump = user_orders_nonce,
+        payer = authority
+    )]
+    pub user_orders: AccountLoader<'info, UserOrders>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct OrderParams {
+    pub order_type: OrderType,
+    pub direction: PositionDirection,
+    pub base_asset_amount: u128,
+    pub price: u128,
+    pub market_index: u64,
+    pub reduce_only: bool,
+    pub trigger_price: u128,
+    pub trigger_condition: OrderTriggerCondition,
+    pub oracle_price_offset: i128,
+    pub min_fill_base_asset_amount: u128,
+    pub max_keeper_reward: u128,
+}
+
+#[derive(Accounts)]
+pub struct PlaceOrder<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(has_one = authority)]
+    pub user: Box<Account<'info, User>>,
+    pub authority: Signer<'info>,
+    #[account(
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_orders: AccountLoader<'info, UserOrders>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePositionViaLimit<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        has_one = authority,
+        constraint = &user.positions.eq(&user_positions.key())
+    )]
+    pub user: Box<Account<'info, User>>,
+    pub authority: Signer<'info>,
+    #[account(
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        has_one = user
+    )]
+    pub user_positions: AccountLoader<'info, UserPositions>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_orders: AccountLoader<'info, UserOrders>,
+}
+
+#[derive(Accounts)]
+pub struct CancelOrder<'info> {
+    #[account(has_one = authority)]
+    pub user: Box<Account<'info, User>>,
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_orders: AccountLoader<'info, UserOrders>,
+}
+
+#[derive(Accounts)]
+pub struct FillOrder<'info> {
+    pub state: Box<Account<'info, State>>,
+    pub authority: Signer<'info>,
+    // before user so that if a user fills their own order, the user's writes are the ones persisted
+    #[account(
+        mut,
+        has_one = authority
+    )]
+    pub filler: Box<Account<'info, User>>,
+    #[account(
+        mut,
+        constraint = &user.positions.eq(&user_positions.key())
+    )]
+    pub user: Box<Account<'info, User>>,
+    #[account(
+        mut,
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_positions: AccountLoader<'info, UserPositions>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_orders: AccountLoader<'info, UserOrders>,
+    #[account(
+        mut,
+        constraint = &state.trade_history.eq(&trade_history.key())
+    )]
+    pub trade_history: AccountLoader<'info, TradeHistory>,
+    #[account(
+        mut,
+        constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
+    )]
+    pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+    #[account(
+        mut,
+        constraint = &state.funding_rate_history.eq(&funding_rate_history.key())
+    )]
+    pub funding_rate_history: AccountLoader<'info, FundingRateHistory>,
+    pub oracle: AccountInfo<'info>,
+}
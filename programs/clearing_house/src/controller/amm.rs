@@ -1,12 +1,13 @@
 use solana_program::msg;
+use std::cmp::max;
 
 use crate::error::{ClearingHouseResult, ErrorCode};
 use crate::math::amm::calculate_quote_asset_amount_swapped;
-use crate::math::casting::{cast, cast_to_i128};
-use crate::math::constants::PRICE_TO_PEG_PRECISION_RATIO;
+use crate::math::casting::{cast, cast_to_i128, cast_to_u128};
+use crate::math::constants::{AMM_TO_QUOTE_PRECISION_RATIO, MARK_PRICE_PRECISION};
 use crate::math::{amm, bn, quote_asset::*};
 use crate::math_error;
-use crate::state::market::AMM;
+use crate::state::market::{Market, AMM};
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum SwapDirection {
@@ -14,14 +15,60 @@ pub enum SwapDirection {
     Remove,
 }
 
+/// Swaps an exact quote asset amount. Trades sized in quote (increase, reduce) always route here so the
+/// user's quote spend is exact; the base asset amount comes out of the invariant division and so
+/// rounds against the user. With an oracle_price, the swap fails if it leaves the mark further from the
+/// oracle than the amm's max_oracle_divergence_bps.
 pub fn swap_quote_asset(
     amm: &mut AMM,
     quote_asset_amount: u128,
     direction: SwapDirection,
     now: i64,
     precomputed_mark_price: Option<u128>,
+    oracle_price: Option<u128>,
 ) -> ClearingHouseResult<i128> {
     amm::update_mark_twap(amm, now, precomputed_mark_price)?;
+
+    let (base_asset_amount, new_base_asset_reserve, new_quote_asset_reserve) =
+        calculate_quote_asset_swap(amm, quote_asset_amount, direction)?;
+
+    amm.base_asset_reserve = new_base_asset_reserve;
+    amm.quote_asset_reserve = new_quote_asset_reserve;
+    assert_k_invariant(amm)?;
+
+    if let Some(oracle_price) = oracle_price {
+        amm::validate_mark_oracle_divergence(amm, oracle_price)?;
+    }
+
+    Ok(base_asset_amount)
+}
+
+/// Previews swap_quote_asset without touching the amm, returning the base asset amount the swap
+/// would acquire and the mark price it would leave. Both share the same math, so a trade made right
+/// after against the same reserves gets exactly the simulated base asset amount.
+pub fn simulate_swap_quote_asset(
+    amm: &AMM,
+    quote_asset_amount: u128,
+    direction: SwapDirection,
+) -> ClearingHouseResult<(i128, u128)> {
+    let (base_asset_amount, new_base_asset_reserve, new_quote_asset_reserve) =
+        calculate_quote_asset_swap(amm, quote_asset_amount, direction)?;
+
+    let new_mark_price = amm::calculate_price(
+        new_quote_asset_reserve,
+        new_base_asset_reserve,
+        amm.peg_multiplier,
+    )?;
+
+    Ok((base_asset_amount, new_mark_price))
+}
+
+/// Returns the base asset amount a quote asset swap acquires and the reserves it leaves
+fn calculate_quote_asset_swap(
+    amm: &AMM,
+    quote_asset_amount: u128,
+    direction: SwapDirection,
+) -> ClearingHouseResult<(i128, u128, u128)> {
     let quote_asset_reserve_amount =
         asset_to_reserve_amount(quote_asset_amount, amm.peg_multiplier)?;
 
@@ -29,7 +76,6 @@ pub fn swap_quote_asset(
         return Err(ErrorCode::TradeSizeTooSmall);
     }
 
-    let initial_base_asset_reserve = amm.base_asset_reserve;
     let (new_base_asset_reserve, new_quote_asset_reserve) = amm::calculate_swap_output(
         quote_asset_reserve_amount,
         amm.quote_asset_reserve,
@@ -37,21 +83,27 @@ pub fn swap_quote_asset(
         amm.sqrt_k,
     )?;
 
-    amm.base_asset_reserve = new_base_asset_reserve;
-    amm.quote_asset_reserve = new_quote_asset_reserve;
-
-    let base_asset_amount = cast_to_i128(initial_base_asset_reserve)?
+    let base_asset_amount = cast_to_i128(amm.base_asset_reserve)?
         .checked_sub(cast(new_base_asset_reserve)?)
         .ok_or_else(math_error!())?;
 
-    Ok(base_asset_amount)
+    Ok((
+        base_asset_amount,
+        new_base_asset_reserve,
+        new_quote_asset_reserve,
+    ))
 }
 
+/// Swaps an exact base asset amount. Trades sized in base (orders, close, reduce_with_base_asset_amount)
+/// always route here so the position size is exact; the quote asset amount comes out of the invariant
+/// division and so rounds against the user. Sizing the same trade either way can therefore differ by
+/// rounding, but never in the user's favor. oracle_price bounds the swap as in swap_quote_asset.
 pub fn swap_base_asset(
     amm: &mut AMM,
     base_asset_swap_amount: u128,
     direction: SwapDirection,
     now: i64,
+    oracle_price: Option<u128>,
 ) -> ClearingHouseResult<u128> {
     amm::update_mark_twap(amm, now, None)?;
 
@@ -65,6 +117,11 @@ pub fn swap_base_asset(
 
     amm.base_asset_reserve = new_base_asset_reserve;
     amm.quote_asset_reserve = new_quote_asset_reserve;
+    assert_k_invariant(amm)?;
+
+    if let Some(oracle_price) = oracle_price {
+        amm::validate_mark_oracle_divergence(amm, oracle_price)?;
+    }
 
     calculate_quote_asset_amount_swapped(
         initial_quote_asset_reserve,
@@ -74,6 +131,60 @@ pub fn swap_base_asset(
     )
 }
 
+/// Defensive check that the reserves a swap left still multiply out to the invariant. The swap output
+/// reserve is sqrt_k^2 divided by the input reserve, rounded down, so the product can only fall short
+/// of sqrt_k^2, and by less than the input reserve. Anything else is an arithmetic bug.
+fn assert_k_invariant(amm: &AMM) -> ClearingHouseResult {
+    let base_asset_reserve = amm.base_asset_reserve;
+    let quote_asset_reserve = amm.quote_asset_reserve;
+    let sqrt_k = amm.sqrt_k;
+
+    let k = bn::U192::from(sqrt_k)
+        .checked_mul(bn::U192::from(sqrt_k))
+        .ok_or_else(math_error!())?;
+    let reserve_product = bn::U192::from(base_asset_reserve)
+        .checked_mul(bn::U192::from(quote_asset_reserve))
+        .ok_or_else(math_error!())?;
+    let tolerance = bn::U192::from(max(base_asset_reserve, quote_asset_reserve));
+
+    if reserve_product > k || k - reserve_product >= tolerance {
+        msg!(
+            "Reserves {} * {} drifted from sqrt_k {}",
+            base_asset_reserve,
+            quote_asset_reserve,
+            sqrt_k
+        );
+        return Err(ErrorCode::InvalidAMMInvariant);
+    }
+
+    Ok(())
+}
+
+/// Brings the mark and oracle twaps of many markets up to now in one pass, so a crank can amortize the
+/// per-instruction overhead. oracle_prices lines up with markets, and a market without a price (its
+/// oracle is stale or wasn't provided) or that isn't initialized is skipped. Returns the number of
+/// markets updated.
+pub fn update_twaps(
+    markets: &mut [Market],
+    oracle_prices: &[Option<i128>],
+    now: i64,
+) -> ClearingHouseResult<u64> {
+    let mut markets_updated: u64 = 0;
+    for (market, oracle_price) in markets.iter_mut().zip(oracle_prices.iter()) {
+        let oracle_price = match oracle_price {
+            Some(oracle_price) if market.initialized => *oracle_price,
+            _ => continue,
+        };
+
+        amm::update_mark_twap(&mut market.amm, now, None)?;
+        amm::update_oracle_twap(&mut market.amm, oracle_price, now)?;
+
+        markets_updated = markets_updated.checked_add(1).ok_or_else(math_error!())?;
+    }
+
+    Ok(markets_updated)
+}
+
 pub fn move_price(
     amm: &mut AMM,
     base_asset_reserve: u128,
@@ -93,24 +204,187 @@ pub fn move_price(
 
 #[allow(dead_code)]
 pub fn move_to_price(amm: &mut AMM, target_price: u128) -> ClearingHouseResult {
-    let sqrt_k = bn::U256::from(amm.sqrt_k);
-    let k = sqrt_k.checked_mul(sqrt_k).ok_or_else(math_error!())?;
+    let (new_base_asset_amount, new_quote_asset_amount) =
+        amm::reserves_for_mark(amm.sqrt_k, amm.peg_multiplier, target_price)?;
 
-    let new_base_asset_amount_squared = k
-        .checked_mul(bn::U256::from(amm.peg_multiplier))
-        .ok_or_else(math_error!())?
-        .checked_mul(bn::U256::from(PRICE_TO_PEG_PRECISION_RATIO))
-        .ok_or_else(math_error!())?
-        .checked_div(bn::U256::from(target_price))
+    amm.base_asset_reserve = new_base_asset_amount;
+    amm.quote_asset_reserve = new_quote_asset_amount;
+
+    Ok(())
+}
+
+/// Adds a trade to the market's volume accumulators, from which the vwap funding anchor is priced
+pub fn record_trade_volume(
+    market: &mut Market,
+    base_asset_amount: u128,
+    quote_asset_amount: u128,
+) -> ClearingHouseResult {
+    let base_volume: u64 = cast(
+        base_asset_amount
+            .checked_div(AMM_TO_QUOTE_PRECISION_RATIO)
+            .ok_or_else(math_error!())?,
+    )?;
+    let quote_volume: u64 = cast(quote_asset_amount)?;
+
+    market.cumulative_base_volume = market
+        .cumulative_base_volume
+        .checked_add(base_volume)
+        .ok_or_else(math_error!())?;
+    market.cumulative_quote_volume = market
+        .cumulative_quote_volume
+        .checked_add(quote_volume)
         .ok_or_else(math_error!())?;
 
-    let new_base_asset_amount = new_base_asset_amount_squared.integer_sqrt();
-    let new_quote_asset_amount = k
-        .checked_div(new_base_asset_amount)
+    Ok(())
+}
+
+/// Volume weighted average price of the trades since the last funding update, in
+/// MARK_PRICE_PRECISION. Unlike the mark twap, sustained quoting pressure without fills doesn't
+/// move it. None if nothing traded.
+pub fn calculate_vwap(market: &Market) -> ClearingHouseResult<Option<u128>> {
+    if market.cumulative_base_volume == 0 {
+        return Ok(None);
+    }
+
+    let vwap = cast_to_u128(market.cumulative_quote_volume)?
+        .checked_mul(MARK_PRICE_PRECISION)
+        .ok_or_else(math_error!())?
+        .checked_div(cast_to_u128(market.cumulative_base_volume)?)
         .ok_or_else(math_error!())?;
 
-    amm.base_asset_reserve = new_base_asset_amount.try_to_u128()?;
-    amm.quote_asset_reserve = new_quote_asset_amount.try_to_u128()?;
+    Ok(Some(vwap))
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::{AMM_RESERVE_PRECISION, PEG_PRECISION, QUOTE_PRECISION};
+
+    /// An amm priced at 1, with the reserves the ts tests initialize markets with
+    fn amm() -> AMM {
+        let reserve = 5 * 10_u128.pow(18);
+        AMM {
+            base_asset_reserve: reserve,
+            quote_asset_reserve: reserve,
+            sqrt_k: reserve,
+            peg_multiplier: PEG_PRECISION,
+            funding_period: 3600,
+            max_oracle_divergence_bps: 100,
+            ..AMM::default()
+        }
+    }
+
+    #[test]
+    fn swap_pushing_the_mark_too_far_from_the_oracle_is_rejected() {
+        let oracle_price = Some(MARK_PRICE_PRECISION);
+
+        // 100 USDC moves the mark well under 1%
+        let mut small = amm();
+        swap_quote_asset(
+            &mut small,
+            100 * QUOTE_PRECISION,
+            SwapDirection::Add,
+            0,
+            None,
+            oracle_price,
+        )
+        .unwrap();
+
+        // 10,000 USDC moves it about 4%
+        let mut large = amm();
+        let quote_asset_amount = 10_000 * QUOTE_PRECISION;
+        let res = swap_quote_asset(
+            &mut large,
+            quote_asset_amount,
+            SwapDirection::Add,
+            0,
+            None,
+            oracle_price,
+        );
+        assert!(matches!(res, Err(ErrorCode::OracleMarkTooDivergent)));
+
+        let base_asset_amount = 10_000 * AMM_RESERVE_PRECISION;
+        let res = swap_base_asset(
+            &mut amm(),
+            base_asset_amount,
+            SwapDirection::Remove,
+            0,
+            oracle_price,
+        );
+        assert!(matches!(res, Err(ErrorCode::OracleMarkTooDivergent)));
+
+        // without an oracle price, or with the bound disabled, the same swap goes through
+        swap_quote_asset(
+            &mut amm(),
+            quote_asset_amount,
+            SwapDirection::Add,
+            0,
+            None,
+            None,
+        )
+        .unwrap();
+        let mut unbounded = amm();
+        unbounded.max_oracle_divergence_bps = 0;
+        swap_quote_asset(
+            &mut unbounded,
+            quote_asset_amount,
+            SwapDirection::Add,
+            0,
+            None,
+            oracle_price,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn simulated_swap_matches_the_swap() {
+        for direction in [SwapDirection::Add, SwapDirection::Remove] {
+            let mut amm = amm();
+            let quote_asset_amount = 1_234 * QUOTE_PRECISION + 567;
+            let (simulated_base_asset_amount, simulated_mark_price) =
+                simulate_swap_quote_asset(&amm, quote_asset_amount, direction).unwrap();
+
+            let base_asset_amount =
+                swap_quote_asset(&mut amm, quote_asset_amount, direction, 0, None, None).unwrap();
+
+            assert_eq!(base_asset_amount, simulated_base_asset_amount);
+            assert_eq!(amm.mark_price().unwrap(), simulated_mark_price);
+        }
+    }
+
+    #[test]
+    fn swaps_keep_the_k_invariant() {
+        let mut amm = amm();
+        swap_quote_asset(
+            &mut amm,
+            1_234 * QUOTE_PRECISION + 567,
+            SwapDirection::Add,
+            0,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_k_invariant(&amm).unwrap();
+        swap_base_asset(
+            &mut amm,
+            987 * AMM_RESERVE_PRECISION + 654,
+            SwapDirection::Add,
+            0,
+            None,
+        )
+        .unwrap();
+        assert_k_invariant(&amm).unwrap();
+
+        // a reserve that drifted from sqrt_k is caught
+        amm.quote_asset_reserve += amm.quote_asset_reserve / 1000;
+        assert!(matches!(
+            assert_k_invariant(&amm),
+            Err(ErrorCode::InvalidAMMInvariant)
+        ));
+        amm.quote_asset_reserve -= 2 * (amm.quote_asset_reserve / 1001);
+        assert!(matches!(
+            assert_k_invariant(&amm),
+            Err(ErrorCode::InvalidAMMInvariant)
+        ));
+    }
 }
@@ -0,0 +1,65 @@
+use crate::error::*;
+use crate::math::casting::{cast, cast_to_u128};
+use crate::math::margin::get_max_withdrawable;
+use crate::math_error;
+use crate::state::market::Markets;
+use crate::state::user::{User, UserPositions};
+use solana_program::msg;
+
+/// Credits a deposit to the user's collateral, along with the deposit totals that the max deposit
+/// and the high water mark are measured against
+pub fn deposit(user: &mut User, amount: u64) -> ClearingHouseResult {
+    user.collateral = user
+        .collateral
+        .checked_add(cast(amount)?)
+        .ok_or_else(math_error!())?;
+    user.cumulative_deposits = user
+        .cumulative_deposits
+        .checked_add(cast(amount)?)
+        .ok_or_else(math_error!())?;
+    user.high_water_mark = user
+        .high_water_mark
+        .checked_add(cast(amount)?)
+        .ok_or_else(math_error!())?;
+
+    Ok(())
+}
+
+/// Debits a withdrawal from the user's collateral and deposit totals. Fails with
+/// InsufficientCollateral if the account would be left below the initial margin requirement, so
+/// funding should be settled beforehand.
+pub fn withdraw(
+    user: &mut User,
+    amount: u64,
+    user_positions: &UserPositions,
+    markets: &Markets,
+    margin_ratio_initial: u128,
+    secondary_collateral_weight_bps: u64,
+) -> ClearingHouseResult {
+    let max_withdrawable = get_max_withdrawable(
+        user,
+        user_positions,
+        markets,
+        margin_ratio_initial,
+        secondary_collateral_weight_bps,
+    )?;
+    if cast_to_u128(amount)? > max_withdrawable {
+        return Err(ErrorCode::InsufficientCollateral);
+    }
+
+    user.cumulative_deposits = user
+        .cumulative_deposits
+        .checked_sub(cast(amount)?)
+        .ok_or_else(math_error!())?;
+    user.high_water_mark = user
+        .high_water_mark
+        .checked_sub(cast(amount)?)
+        .ok_or_else(math_error!())?
+        .max(0);
+    user.collateral = user
+        .collateral
+        .checked_sub(cast(amount)?)
+        .ok_or_else(math_error!())?;
+
+    Ok(())
+}
@@ -1,30 +1,32 @@
 use std::cell::{Ref, RefMut};
-use std::cmp::{max, min};
 
 use anchor_lang::prelude::*;
 
+use crate::controller;
+use crate::controller::position::PositionDirection;
 use crate::error::*;
 use crate::math::amm;
-use crate::math::casting::{cast, cast_to_i128, cast_to_i64};
 use crate::math::collateral::calculate_updated_collateral;
-use crate::math::constants::{
-    AMM_TO_QUOTE_PRECISION_RATIO_I128, FUNDING_PAYMENT_PRECISION, ONE_HOUR,
+use crate::math::constants::AMM_TO_QUOTE_PRECISION_RATIO_I128;
+use crate::math::funding::{
+    calculate_funding_payment, calculate_funding_rate, calculate_funding_rate_long_short,
+    calculate_unrealized_funding,
 };
-use crate::math::funding::{calculate_funding_payment, calculate_funding_rate_long_short};
 use crate::math::oracle;
 use crate::math_error;
 use crate::state::history::funding_payment::{FundingPaymentHistory, FundingPaymentRecord};
 use crate::state::history::funding_rate::{FundingRateHistory, FundingRateRecord};
 use crate::state::market::AMM;
-use crate::state::market::{Market, Markets};
+use crate::state::market::{FundingPriceAnchor, Market, Markets};
 use crate::state::state::OracleGuardRails;
-use crate::state::user::{User, UserPositions};
+use crate::state::user::{MarketPosition, User, UserPositions};
 use solana_program::clock::UnixTimestamp;
 use solana_program::msg;
 
 /// Funding payments are settled lazily. The amm tracks its cumulative funding rate (for longs and shorts)
 /// and the user's market position tracks how much funding the user been cumulatively paid for that market.
 /// If the two values are not equal, the user owes/is owed funding.
+/// Settling a flat account is a no-op.
 pub fn settle_funding_payment(
     user: &mut User,
     user_positions: &mut RefMut<UserPositions>,
@@ -32,6 +34,10 @@ pub fn settle_funding_payment(
     funding_payment_history: &mut RefMut<FundingPaymentHistory>,
     now: UnixTimestamp,
 ) -> ClearingHouseResult {
+    if !user_positions.has_open_position() {
+        return Ok(());
+    }
+
     let user_key = user_positions.user;
     let mut funding_payment: i128 = 0;
     for market_position in user_positions.positions.iter_mut() {
@@ -71,8 +77,13 @@ pub fn settle_funding_payment(
                 .checked_add(market_funding_rate_payment)
                 .ok_or_else(math_error!())?;
 
-            market_position.last_cumulative_funding_rate = amm_cumulative_funding_rate;
-            market_position.last_funding_rate_ts = amm.last_funding_rate_ts;
+            market_position.add_funding_payment(
+                market_funding_rate_payment
+                    .checked_div(AMM_TO_QUOTE_PRECISION_RATIO_I128)
+                    .ok_or_else(math_error!())?,
+            )?;
+            market_position
+                .apply_funding_snapshot(amm_cumulative_funding_rate, amm.last_funding_rate_ts);
         }
     }
 
@@ -85,6 +96,35 @@ pub fn settle_funding_payment(
     Ok(())
 }
 
+/// Settles the funding a single position owes or is owed, so the position is current before it's
+/// modified. Longs pay and shorts receive when the cumulative funding rate rises. Unlike
+/// settle_funding_payment this doesn't record the payment, so instructions that have the funding
+/// payment history settle through that first and this is a no-op. Returns the payment applied to
+/// collateral.
+pub fn settle_position_funding_payment(
+    user: &mut User,
+    market: &Market,
+    market_position: &mut MarketPosition,
+) -> ClearingHouseResult<i128> {
+    let amm_cumulative_funding_rate = match market_position.direction() {
+        Some(PositionDirection::Long) => market.amm.cumulative_funding_rate_long,
+        Some(PositionDirection::Short) => market.amm.cumulative_funding_rate_short,
+        None => return Ok(0),
+    };
+
+    let funding_payment = calculate_unrealized_funding(market, market_position)?;
+    user.collateral = calculate_updated_collateral(user.collateral, funding_payment)?;
+    market_position.add_funding_payment(funding_payment)?;
+    market_position
+        .apply_funding_snapshot(amm_cumulative_funding_rate, market.amm.last_funding_rate_ts);
+
+    Ok(funding_payment)
+}
+
+/// Entry point for funding updates. Advances the cumulative funding rates through
+/// advance_funding_rate at most once per funding_period, aligned to the period boundary, and only
+/// while funding isn't paused and the oracle passes the guard rails. The oracle twap is updated
+/// with the current oracle price first, so the rate is taken from the twaps rather than a spot read.
 #[allow(clippy::too_many_arguments)]
 pub fn update_funding_rate(
     market_index: u64,
@@ -96,6 +136,8 @@ pub fn update_funding_rate(
     guard_rails: &OracleGuardRails,
     funding_paused: bool,
 ) -> ClearingHouseResult {
+    amm::validate_timestamp_not_before(now, market.amm.last_funding_rate_ts)?;
+
     let time_since_last_update = now
         .checked_sub(market.amm.last_funding_rate_ts)
         .ok_or_else(math_error!())?;
@@ -138,63 +180,242 @@ pub fn update_funding_rate(
     }
 
     if !funding_paused && !block_funding_rate_update && time_since_last_update >= next_update_wait {
-        let oracle_price_twap = amm::update_oracle_price_twap(&mut market.amm, now, oracle_price)?;
-        let mark_price_twap = amm::update_mark_twap(&mut market.amm, now, None)?;
-
-        let one_hour_i64 = cast_to_i64(ONE_HOUR)?;
-        let period_adjustment = (24_i64)
-            .checked_mul(one_hour_i64)
-            .ok_or_else(math_error!())?
-            .checked_div(max(one_hour_i64, market.amm.funding_period))
-            .ok_or_else(math_error!())?;
-        // funding period = 1 hour, window = 1 day
-        // low periodicity => quickly updating/settled funding rates => lower funding rate payment per interval
-        let price_spread = cast_to_i128(mark_price_twap)?
-            .checked_sub(oracle_price_twap)
-            .ok_or_else(math_error!())?;
-
-        // clamp price divergence to 3% for funding rate calculation
-        let max_price_spread = oracle_price_twap
-            .checked_div(33)
-            .ok_or_else(math_error!())?; // 3%
-        let clamped_price_spread = max(-max_price_spread, min(price_spread, max_price_spread));
-
-        let funding_rate = clamped_price_spread
-            .checked_mul(cast(FUNDING_PAYMENT_PRECISION)?)
-            .ok_or_else(math_error!())?
-            .checked_div(cast(period_adjustment)?)
-            .ok_or_else(math_error!())?;
-
-        let (funding_rate_long, funding_rate_short) =
-            calculate_funding_rate_long_short(market, funding_rate)?;
-
-        market.amm.cumulative_funding_rate_long = market
-            .amm
-            .cumulative_funding_rate_long
-            .checked_add(funding_rate_long)
-            .ok_or_else(math_error!())?;
-
-        market.amm.cumulative_funding_rate_short = market
-            .amm
-            .cumulative_funding_rate_short
-            .checked_add(funding_rate_short)
-            .ok_or_else(math_error!())?;
-
-        market.amm.last_funding_rate = funding_rate;
-        market.amm.last_funding_rate_ts = now;
-
-        let record_id = funding_rate_history.next_record_id();
-        funding_rate_history.append(FundingRateRecord {
-            ts: now,
-            record_id,
+        let oracle_price_twap = amm::update_oracle_twap(&mut market.amm, oracle_price, now)?;
+        advance_funding_rate(
             market_index,
-            funding_rate,
-            cumulative_funding_rate_long: market.amm.cumulative_funding_rate_long,
-            cumulative_funding_rate_short: market.amm.cumulative_funding_rate_short,
-            mark_price_twap,
+            market,
             oracle_price_twap,
-        });
+            now,
+            funding_rate_history,
+        )?;
     }
 
     Ok(())
 }
+
+/// Advances the market's cumulative funding rates by one period: computes the period rate from the
+/// mark and oracle twaps, caps the side the clearing house subsidizes and records the snapshot.
+/// Settlement only ever reads the cumulative rates this writes.
+pub fn advance_funding_rate(
+    market_index: u64,
+    market: &mut Market,
+    oracle_price_twap: i128,
+    now: UnixTimestamp,
+    funding_rate_history: &mut RefMut<FundingRateHistory>,
+) -> ClearingHouseResult {
+    let mark_price_twap = amm::update_mark_twap(&mut market.amm, now, None)?;
+
+    let funding_mark_price = match market.funding_price_anchor {
+        FundingPriceAnchor::MarkTwap => mark_price_twap,
+        FundingPriceAnchor::Vwap => {
+            controller::amm::calculate_vwap(market)?.unwrap_or(mark_price_twap)
+        }
+    };
+    market.cumulative_base_volume = 0;
+    market.cumulative_quote_volume = 0;
+
+    let funding_rate = calculate_funding_rate(
+        funding_mark_price,
+        oracle_price_twap,
+        market.amm.funding_period,
+    )?;
+
+    let (funding_rate_long, funding_rate_short) =
+        calculate_funding_rate_long_short(market, funding_rate)?;
+
+    market.amm.cumulative_funding_rate_long = market
+        .amm
+        .cumulative_funding_rate_long
+        .checked_add(funding_rate_long)
+        .ok_or_else(math_error!())?;
+
+    market.amm.cumulative_funding_rate_short = market
+        .amm
+        .cumulative_funding_rate_short
+        .checked_add(funding_rate_short)
+        .ok_or_else(math_error!())?;
+
+    market.amm.last_funding_rate = funding_rate;
+    market.amm.last_funding_rate_ts = now;
+
+    let record_id = funding_rate_history.next_record_id();
+    funding_rate_history.append(FundingRateRecord {
+        ts: now,
+        record_id,
+        market_index,
+        funding_rate,
+        cumulative_funding_rate_long: market.amm.cumulative_funding_rate_long,
+        cumulative_funding_rate_short: market.amm.cumulative_funding_rate_short,
+        mark_price_twap,
+        oracle_price_twap,
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, MARK_PRICE_PRECISION, PEG_PRECISION, QUOTE_PRECISION,
+    };
+    use std::cell::RefCell;
+
+    /// A market priced at 1 with an hourly funding period, whose mark twap sits at the mark
+    fn market() -> Market {
+        let reserve = 5 * 10_u128.pow(18);
+        Market {
+            initialized: true,
+            amm: AMM {
+                base_asset_reserve: reserve,
+                quote_asset_reserve: reserve,
+                sqrt_k: reserve,
+                peg_multiplier: PEG_PRECISION,
+                funding_period: 3600,
+                last_mark_price_twap: MARK_PRICE_PRECISION,
+                ..AMM::default()
+            },
+            ..Market::default()
+        }
+    }
+
+    #[test]
+    fn advance_funding_rate_accumulates_across_periods() {
+        let mut market = market();
+        let funding_rate_history = RefCell::new(FundingRateHistory::default());
+
+        // the mark trades 1% over the oracle for the first period and 1% under it for the second
+        let oracle_price_twap_above = (MARK_PRICE_PRECISION * 99 / 100) as i128;
+        let oracle_price_twap_below = (MARK_PRICE_PRECISION * 101 / 100) as i128;
+        let funding_rate_above =
+            calculate_funding_rate(MARK_PRICE_PRECISION, oracle_price_twap_above, 3600).unwrap();
+        let funding_rate_below =
+            calculate_funding_rate(MARK_PRICE_PRECISION, oracle_price_twap_below, 3600).unwrap();
+        assert!(funding_rate_above > 0);
+        assert!(funding_rate_below < 0);
+
+        advance_funding_rate(
+            0,
+            &mut market,
+            oracle_price_twap_above,
+            3600,
+            &mut funding_rate_history.borrow_mut(),
+        )
+        .unwrap();
+        assert_eq!(
+            { market.amm.cumulative_funding_rate_long },
+            funding_rate_above
+        );
+        assert_eq!(
+            { market.amm.cumulative_funding_rate_short },
+            funding_rate_above
+        );
+        assert_eq!({ market.amm.last_funding_rate }, funding_rate_above);
+        assert_eq!({ market.amm.last_funding_rate_ts }, 3600);
+
+        advance_funding_rate(
+            0,
+            &mut market,
+            oracle_price_twap_below,
+            7200,
+            &mut funding_rate_history.borrow_mut(),
+        )
+        .unwrap();
+        assert_eq!(
+            { market.amm.cumulative_funding_rate_long },
+            funding_rate_above + funding_rate_below
+        );
+        assert_eq!(
+            { market.amm.cumulative_funding_rate_short },
+            funding_rate_above + funding_rate_below
+        );
+        assert_eq!({ market.amm.last_funding_rate }, funding_rate_below);
+        assert_eq!({ market.amm.last_funding_rate_ts }, 7200);
+
+        // one record per period
+        assert_eq!(funding_rate_history.borrow().next_record_id(), 3);
+    }
+
+    #[test]
+    fn vwap_anchor_prices_funding_off_the_fills() {
+        // quotes have been pushed 2% over the oracle, but every fill cleared at the oracle price
+        let mut twap_market = market();
+        twap_market.amm.peg_multiplier = PEG_PRECISION * 102 / 100;
+        twap_market.amm.last_mark_price_twap = MARK_PRICE_PRECISION * 102 / 100;
+        for _ in 0..3 {
+            controller::amm::record_trade_volume(
+                &mut twap_market,
+                10 * AMM_RESERVE_PRECISION,
+                10 * QUOTE_PRECISION,
+            )
+            .unwrap();
+        }
+        let mut vwap_market = twap_market;
+        vwap_market.funding_price_anchor = FundingPriceAnchor::Vwap;
+        assert_eq!(
+            controller::amm::calculate_vwap(&vwap_market).unwrap(),
+            Some(MARK_PRICE_PRECISION)
+        );
+
+        let oracle_price_twap = MARK_PRICE_PRECISION as i128;
+        let funding_rate_history = RefCell::new(FundingRateHistory::default());
+        for market in [&mut twap_market, &mut vwap_market] {
+            advance_funding_rate(
+                0,
+                market,
+                oracle_price_twap,
+                3600,
+                &mut funding_rate_history.borrow_mut(),
+            )
+            .unwrap();
+        }
+
+        // longs pay the quoted premium under the twap anchor and nothing under the vwap one
+        assert_eq!(
+            { twap_market.amm.last_funding_rate },
+            calculate_funding_rate(MARK_PRICE_PRECISION * 102 / 100, oracle_price_twap, 3600)
+                .unwrap()
+        );
+        assert!(twap_market.amm.last_funding_rate > 0);
+        assert_eq!({ vwap_market.amm.last_funding_rate }, 0);
+        // both start the next period's volume afresh
+        assert_eq!(controller::amm::calculate_vwap(&vwap_market).unwrap(), None);
+        assert_eq!(controller::amm::calculate_vwap(&twap_market).unwrap(), None);
+    }
+
+    #[test]
+    fn settling_a_flat_account_is_a_no_op() {
+        let mut markets = Markets::default();
+        markets.markets[0] = market();
+        markets.markets[0].amm.cumulative_funding_rate_long = 1_000;
+        markets.markets[0].amm.cumulative_funding_rate_short = 1_000;
+        let markets = RefCell::new(markets);
+        // a flat slot left behind by a closed position, still on an old cumulative rate
+        let user_positions = RefCell::new(UserPositions::default());
+        user_positions.borrow_mut().positions[0] = MarketPosition {
+            market_index: 0,
+            last_cumulative_funding_rate: 500,
+            ..MarketPosition::default()
+        };
+        let funding_payment_history = RefCell::new(FundingPaymentHistory::default());
+        let mut user = User {
+            collateral: 10 * QUOTE_PRECISION,
+            ..User::default()
+        };
+
+        settle_funding_payment(
+            &mut user,
+            &mut user_positions.borrow_mut(),
+            &markets.borrow(),
+            &mut funding_payment_history.borrow_mut(),
+            3600,
+        )
+        .unwrap();
+
+        assert_eq!(user.collateral, 10 * QUOTE_PRECISION);
+        assert_eq!(funding_payment_history.borrow().next_record_id(), 1);
+        let market_position = user_positions.borrow().positions[0];
+        assert_eq!({ market_position.last_cumulative_funding_rate }, 500);
+        assert_eq!({ market_position.total_funding_payment }, 0);
+    }
+}
@@ -1,5 +1,7 @@
 pub mod amm;
+pub mod collateral;
 pub mod funding;
+pub mod orders;
 pub mod position;
 pub mod repeg;
 pub mod token;
@@ -0,0 +1,426 @@
+use std::cell::RefMut;
+
+use anchor_lang::prelude::*;
+
+use crate::context::OrderParams;
+use crate::controller::position::{
+    increase_with_base_asset_amount, reduce_with_base_asset_amount, PositionDirection,
+};
+use crate::error::*;
+use crate::math::amm;
+use crate::math::constants::MAX_KEEPER_REWARD;
+use crate::math::position::direction_to_close_position;
+use crate::math_error;
+use crate::state::market::Market;
+use crate::state::state::State;
+use crate::state::user::{MarketPosition, User};
+use crate::state::user_orders::{Order, OrderStatus, OrderTriggerCondition, OrderType, UserOrders};
+use solana_program::msg;
+
+pub fn place_order(
+    user_orders: &mut RefMut<UserOrders>,
+    market: &Market,
+    state: &State,
+    params: &OrderParams,
+    now: i64,
+) -> ClearingHouseResult<u64> {
+    validate_order_params(params)?;
+
+    // resting orders placed during a mark spike would be filled as soon as the mark snaps back
+    let is_resting_order = matches!(
+        params.order_type,
+        OrderType::Limit | OrderType::TriggerLimit | OrderType::Oracle
+    );
+    if is_resting_order
+        && amm::is_mark_twap_too_divergent(
+            &market.amm,
+            state.mark_twap_divergence_numerator,
+            state.mark_twap_divergence_denominator,
+        )?
+    {
+        return Err(ErrorCode::MarkTwapDivergence);
+    }
+
+    let new_order_index = user_orders
+        .orders
+        .iter()
+        .position(|order| !order.is_open())
+        .ok_or(ErrorCode::MaxNumberOfOrders)?;
+
+    let order_id = user_orders.next_order_id;
+    user_orders.next_order_id = order_id.checked_add(1).ok_or_else(math_error!())?;
+
+    user_orders.orders[new_order_index] = Order {
+        status: OrderStatus::Open,
+        order_type: params.order_type,
+        ts: now,
+        order_id,
+        market_index: params.market_index,
+        price: params.price,
+        base_asset_amount: params.base_asset_amount,
+        base_asset_amount_filled: 0,
+        quote_asset_amount_filled: 0,
+        fee: 0,
+        direction: params.direction,
+        reduce_only: params.reduce_only,
+        trigger_price: params.trigger_price,
+        trigger_condition: params.trigger_condition,
+        oracle_price_offset: params.oracle_price_offset,
+        min_fill_base_asset_amount: params.min_fill_base_asset_amount,
+        max_keeper_reward: params.max_keeper_reward,
+        padding2: 0,
+    };
+
+    Ok(order_id)
+}
+
+/// Closes the position by resting a reduce only limit order for all of it at limit_price, instead of
+/// swapping against the amm right away. The position is untouched until a keeper fills the order.
+pub fn close_via_limit(
+    user_orders: &mut RefMut<UserOrders>,
+    market: &Market,
+    state: &State,
+    market_position: &MarketPosition,
+    limit_price: u128,
+    now: i64,
+) -> ClearingHouseResult<u64> {
+    if !market_position.is_open_position() {
+        return Err(ErrorCode::UserHasNoPositionInMarket);
+    }
+
+    let params = OrderParams {
+        order_type: OrderType::Limit,
+        direction: direction_to_close_position(market_position.base_asset_amount),
+        base_asset_amount: market_position.base_asset_amount.unsigned_abs(),
+        price: limit_price,
+        market_index: market_position.market_index,
+        reduce_only: true,
+        trigger_price: 0,
+        trigger_condition: OrderTriggerCondition::default(),
+        oracle_price_offset: 0,
+        min_fill_base_asset_amount: 0,
+        max_keeper_reward: 0,
+    };
+
+    place_order(user_orders, market, state, &params, now)
+}
+
+fn validate_order_params(params: &OrderParams) -> ClearingHouseResult {
+    if params.base_asset_amount == 0 {
+        msg!("Order base asset amount must be greater than 0");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    // a market order trades on the spot through open_position, so it has nothing to rest as
+    if params.order_type == OrderType::Market {
+        msg!("Market orders can not be placed, trade through open_position instead");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    let needs_price = matches!(
+        params.order_type,
+        OrderType::Limit | OrderType::TriggerLimit
+    );
+    if needs_price && params.price == 0 {
+        msg!("Limit orders must set a price");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    let needs_trigger_price = matches!(
+        params.order_type,
+        OrderType::TriggerMarket | OrderType::TriggerLimit
+    );
+    if needs_trigger_price && params.trigger_price == 0 {
+        msg!("Trigger orders must set a trigger price");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    if params.max_keeper_reward != 0 {
+        if !needs_trigger_price {
+            msg!("Only trigger orders can set a keeper reward");
+            return Err(ErrorCode::InvalidOrder);
+        }
+
+        if params.max_keeper_reward > MAX_KEEPER_REWARD {
+            msg!(
+                "Order keeper reward can not exceed {} quote asset",
+                MAX_KEEPER_REWARD
+            );
+            return Err(ErrorCode::InvalidOrder);
+        }
+    }
+
+    if params.min_fill_base_asset_amount > params.base_asset_amount {
+        msg!("Order min fill base asset amount can not exceed its base asset amount");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    // oracle orders are priced off the oracle at fill time, so a fixed price would never be used
+    // and an offset on any other order type would be silently ignored
+    if params.order_type == OrderType::Oracle {
+        if params.price != 0 {
+            msg!("Oracle orders can not set a price, only an oracle price offset");
+            return Err(ErrorCode::InvalidOrder);
+        }
+    } else if params.oracle_price_offset != 0 {
+        msg!("Only oracle orders can set an oracle price offset");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    Ok(())
+}
+
+pub fn cancel_order(user_orders: &mut RefMut<UserOrders>, order_id: u64) -> ClearingHouseResult {
+    let order_index = get_open_order_index(user_orders, order_id)?;
+    user_orders.orders[order_index] = Order::default();
+    Ok(())
+}
+
+pub fn get_open_order_index(user_orders: &UserOrders, order_id: u64) -> ClearingHouseResult<usize> {
+    user_orders
+        .orders
+        .iter()
+        .position(|order| order.is_open() && order.order_id == order_id)
+        .ok_or(ErrorCode::OrderDoesNotExist)
+}
+
+/// Trades base_asset_amount of the order against the amm, closing and flipping the position if the
+/// order is larger than it. Returns the quote asset amount traded, the base and quote asset amounts
+/// of it that opened or increased the position, and whether the fill could have increased the
+/// user's risk.
+pub fn execute_order_fill(
+    direction: PositionDirection,
+    base_asset_amount: u128,
+    user: &mut Account<User>,
+    market_index: u64,
+    market: &mut Market,
+    market_position: &mut MarketPosition,
+    now: i64,
+) -> ClearingHouseResult<(u128, u128, u128, bool)> {
+    let increase_position = market_position.base_asset_amount == 0
+        || market_position.base_asset_amount > 0 && direction == PositionDirection::Long
+        || market_position.base_asset_amount < 0 && direction == PositionDirection::Short;
+
+    if increase_position {
+        let quote_asset_amount = increase_with_base_asset_amount(
+            direction,
+            base_asset_amount,
+            user,
+            market_index,
+            market,
+            market_position,
+            now,
+        )?;
+        return Ok((
+            quote_asset_amount,
+            base_asset_amount,
+            quote_asset_amount,
+            true,
+        ));
+    }
+
+    let existing_base_asset_amount = market_position.base_asset_amount.unsigned_abs();
+    if base_asset_amount <= existing_base_asset_amount {
+        let (_, quote_asset_amount, _) = reduce_with_base_asset_amount(
+            base_asset_amount,
+            user,
+            market_index,
+            market,
+            market_position,
+            now,
+        )?;
+        return Ok((quote_asset_amount, 0, 0, false));
+    }
+
+    let (base_asset_amount_closed, quote_asset_amount_closed, _) = reduce_with_base_asset_amount(
+        existing_base_asset_amount,
+        user,
+        market_index,
+        market,
+        market_position,
+        now,
+    )?;
+
+    let base_asset_amount_after_close = base_asset_amount
+        .checked_sub(base_asset_amount_closed)
+        .ok_or_else(math_error!())?;
+
+    let quote_asset_amount_opened = increase_with_base_asset_amount(
+        direction,
+        base_asset_amount_after_close,
+        user,
+        market_index,
+        market,
+        market_position,
+        now,
+    )?;
+
+    // If the new position is smaller than the old position, consider it risk decreasing
+    let potentially_risk_increasing = base_asset_amount_after_close > base_asset_amount_closed;
+
+    let quote_asset_amount = quote_asset_amount_closed
+        .checked_add(quote_asset_amount_opened)
+        .ok_or_else(math_error!())?;
+
+    Ok((
+        quote_asset_amount,
+        base_asset_amount_after_close,
+        quote_asset_amount_opened,
+        potentially_risk_increasing,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::casting::cast_to_i128;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, MARK_PRICE_PRECISION, PEG_PRECISION, QUOTE_PRECISION,
+    };
+    use crate::math::orders::calculate_base_asset_amount_to_fill;
+    use crate::state::market::AMM;
+    use std::cell::RefCell;
+
+    /// A market priced at 1 whose mark twap sits at the mark
+    fn market() -> Market {
+        let reserve = 5 * 10_u128.pow(18);
+        Market {
+            initialized: true,
+            amm: AMM {
+                base_asset_reserve: reserve,
+                quote_asset_reserve: reserve,
+                sqrt_k: reserve,
+                peg_multiplier: PEG_PRECISION,
+                funding_period: 3600,
+                last_mark_price_twap: MARK_PRICE_PRECISION,
+                ..AMM::default()
+            },
+            ..Market::default()
+        }
+    }
+
+    /// The mark/twap divergence initialize sets, 5%
+    fn state() -> State {
+        State {
+            mark_twap_divergence_numerator: 1,
+            mark_twap_divergence_denominator: 20,
+            ..State::default()
+        }
+    }
+
+    /// A limit bid for 10 base asset units at 1
+    fn limit_order_params() -> OrderParams {
+        OrderParams {
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Long,
+            base_asset_amount: 10 * AMM_RESERVE_PRECISION,
+            price: MARK_PRICE_PRECISION,
+            market_index: 0,
+            reduce_only: false,
+            trigger_price: 0,
+            trigger_condition: OrderTriggerCondition::default(),
+            oracle_price_offset: 0,
+            min_fill_base_asset_amount: 0,
+            max_keeper_reward: 0,
+        }
+    }
+
+    #[test]
+    fn resting_orders_wait_for_the_mark_to_converge_to_its_twap() {
+        let user_orders = RefCell::new(UserOrders::default());
+        let state = state();
+        let mut market = market();
+
+        // the mark spiked 10% over its twap
+        market.amm.last_mark_price_twap = MARK_PRICE_PRECISION * 10 / 11;
+        let result = place_order(
+            &mut user_orders.borrow_mut(),
+            &market,
+            &state,
+            &limit_order_params(),
+            0,
+        );
+        assert!(matches!(result, Err(ErrorCode::MarkTwapDivergence)));
+        assert!(!user_orders.borrow().orders[0].is_open());
+
+        // and is back within 5% of it
+        market.amm.last_mark_price_twap = MARK_PRICE_PRECISION * 100 / 104;
+        place_order(
+            &mut user_orders.borrow_mut(),
+            &market,
+            &state,
+            &limit_order_params(),
+            0,
+        )
+        .unwrap();
+        assert!(user_orders.borrow().orders[0].is_open());
+    }
+
+    #[test]
+    fn close_via_limit_rests_an_order_and_leaves_the_position() {
+        let user_orders = RefCell::new(UserOrders::default());
+        let state = state();
+        let market = market();
+        let market_position = MarketPosition {
+            market_index: 0,
+            base_asset_amount: cast_to_i128(10 * AMM_RESERVE_PRECISION).unwrap(),
+            quote_asset_amount: 10 * QUOTE_PRECISION,
+            ..MarketPosition::default()
+        };
+        let limit_price = MARK_PRICE_PRECISION * 99 / 100;
+
+        let order_id = close_via_limit(
+            &mut user_orders.borrow_mut(),
+            &market,
+            &state,
+            &market_position,
+            limit_price,
+            0,
+        )
+        .unwrap();
+
+        let user_orders = user_orders.borrow();
+        let order = &user_orders.orders[0];
+        assert!(order.is_open());
+        assert_eq!({ order.order_id }, order_id);
+        assert!(order.order_type == OrderType::Limit);
+        assert!(order.direction == PositionDirection::Short);
+        assert!(order.reduce_only);
+        assert_eq!({ order.price }, limit_price);
+        assert_eq!({ order.base_asset_amount }, 10 * AMM_RESERVE_PRECISION);
+        assert_eq!({ order.base_asset_amount_filled }, 0);
+
+        assert_eq!(
+            { market_position.base_asset_amount },
+            cast_to_i128(10 * AMM_RESERVE_PRECISION).unwrap()
+        );
+        assert_eq!({ market_position.quote_asset_amount }, 10 * QUOTE_PRECISION);
+
+        // a keeper can fill all of it, the ask being under the mark
+        assert_eq!(
+            calculate_base_asset_amount_to_fill(
+                order,
+                &market.amm,
+                cast_to_i128(MARK_PRICE_PRECISION).unwrap(),
+                market_position.base_asset_amount,
+            )
+            .unwrap(),
+            10 * AMM_RESERVE_PRECISION
+        );
+    }
+
+    #[test]
+    fn close_via_limit_without_a_position_is_rejected() {
+        let user_orders = RefCell::new(UserOrders::default());
+        let result = close_via_limit(
+            &mut user_orders.borrow_mut(),
+            &market(),
+            &state(),
+            &MarketPosition::default(),
+            MARK_PRICE_PRECISION,
+            0,
+        );
+        assert!(matches!(result, Err(ErrorCode::UserHasNoPositionInMarket)));
+        assert!(!user_orders.borrow().orders[0].is_open());
+    }
+}
@@ -1,22 +1,50 @@
+//! Position state transitions. The base, quote and pnl outcomes of a trade depend only on the amm
+//! reserves; `now` only feeds timestamped state such as the mark twap and pnl velocity, and a
+//! position's funding timestamp comes from the amm's last funding update rather than the trade time.
+
 use anchor_lang::prelude::*;
 use borsh::{BorshDeserialize, BorshSerialize};
 
 use crate::controller;
 use crate::controller::amm::SwapDirection;
 use crate::error::*;
-use crate::math::casting::{cast, cast_to_i128};
-use crate::math::collateral::calculate_updated_collateral;
-use crate::math::pnl::calculate_pnl;
+use crate::math::bn::U192;
+use crate::math::casting::{cast, cast_to_i128, cast_to_u128};
+use crate::math::collateral::{
+    calculate_updated_collateral, calculate_updated_collateral_and_bad_debt,
+};
+use crate::math::constants::{AMM_TO_QUOTE_PRECISION_RATIO, BPS_PRECISION, MARK_PRICE_PRECISION};
+use crate::math::fees;
+use crate::math::margin::validate_margin_requirement_nonzero;
+use crate::math::pnl::{
+    calculate_pnl, calculate_pnl_breakdown, calculate_pnl_velocity, PnlBreakdown,
+};
+use crate::math::position::{
+    _calculate_base_asset_value_and_pnl, calculate_entry_price,
+    calculate_oracle_band_quote_asset_amount, direction_to_close_position,
+    swap_direction_to_close_position,
+};
+use crate::math::quote_asset::asset_to_reserve_amount;
 use crate::math_error;
+use crate::state::market::FeeDenomination;
+use crate::state::state::FeeStructure;
+use crate::state::user::UserPositions;
+use crate::wrap_error;
 use crate::{Market, MarketPosition, User};
 use solana_program::msg;
 
+// Borsh encodes the variant by its declaration order, and directions are stored on chain in order
+// params and history records. New variants have to be appended; reordering or inserting one fails to
+// compile here instead of corrupting deployed accounts.
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq)]
 pub enum PositionDirection {
     Long,
     Short,
 }
 
+const _: [(); 0] = [(); PositionDirection::Long as usize];
+const _: [(); 1] = [(); PositionDirection::Short as usize];
+
 impl Default for PositionDirection {
     // UpOnly
     fn default() -> Self {
@@ -24,34 +52,168 @@ impl Default for PositionDirection {
     }
 }
 
+/// Guards against applying a trade to a position slot for a different market, which would corrupt
+/// both markets' accounting
+pub fn validate_position_for_market(
+    market_position: &MarketPosition,
+    market_index: u64,
+) -> ClearingHouseResult {
+    let position_market_index = market_position.market_index;
+    if position_market_index != market_index {
+        msg!(
+            "Position is for market {}, not market {}",
+            position_market_index,
+            market_index
+        );
+        return Err(ErrorCode::PositionMarketMismatch);
+    }
+
+    Ok(())
+}
+
+/// Increases the position by new_quote_asset_notional_amount. The base asset acquired is what
+/// controller::amm::simulate_swap_quote_asset predicts for the same reserves. The position is
+/// credited all of it; on a base-denominated market the caller skims the fee from it, see
+/// skim_base_asset_fee.
+/// Notionals too small to carry any initial margin are rejected with TradeSizeTooSmall, and a trade
+/// acquiring less base asset than min_base_asset_amount, if set, with SlippageTooLarge.
+#[allow(clippy::too_many_arguments)]
 pub fn increase(
     direction: PositionDirection,
     new_quote_asset_notional_amount: u128,
+    user: &mut Account<User>,
+    market_index: u64,
     market: &mut Market,
     market_position: &mut MarketPosition,
     now: i64,
+    margin_ratio_initial: u128,
+    min_base_asset_amount: Option<u128>,
 ) -> ClearingHouseResult<i128> {
+    validate_position_for_market(market_position, market_index)?;
+    validate_market_not_expired(market, now)?;
+    controller::funding::settle_position_funding_payment(user, market, market_position)?;
+
     if new_quote_asset_notional_amount == 0 {
         return Ok(0);
     }
 
+    validate_margin_requirement_nonzero(new_quote_asset_notional_amount, margin_ratio_initial)?;
+
     // Update funding rate if this is a new position
     if market_position.base_asset_amount == 0 {
-        market_position.last_cumulative_funding_rate = match direction {
-            PositionDirection::Long => market.amm.cumulative_funding_rate_long,
-            PositionDirection::Short => market.amm.cumulative_funding_rate_short,
-        };
+        market_position.apply_funding_snapshot(
+            match direction {
+                PositionDirection::Long => market.amm.cumulative_funding_rate_long,
+                PositionDirection::Short => market.amm.cumulative_funding_rate_short,
+            },
+            market.amm.last_funding_rate_ts,
+        );
+        market_position.reset_trading_costs();
 
-        market.open_interest = market
-            .open_interest
-            .checked_add(1)
-            .ok_or_else(math_error!())?;
+        increment_open_interest(market)?;
     }
 
-    market_position.quote_asset_amount = market_position
+    let base_asset_acquired = increase_position_size(
+        direction,
+        new_quote_asset_notional_amount,
+        user,
+        market,
+        market_position,
+        now,
+    )?;
+
+    if let Some(min_base_asset_amount) = min_base_asset_amount {
+        if base_asset_acquired.unsigned_abs() < min_base_asset_amount {
+            msg!(
+                "Base asset acquired {} is below the minimum {}",
+                base_asset_acquired.unsigned_abs(),
+                min_base_asset_amount
+            );
+            return Err(ErrorCode::SlippageTooLarge);
+        }
+    }
+
+    Ok(base_asset_acquired)
+}
+
+/// Returns the index of the user's position in market_index, claiming an available slot for a new
+/// one if they don't have it. Errors with MaxNumberOfPositions only when every slot is taken.
+pub fn get_or_add_position(
+    user_positions: &mut UserPositions,
+    market_index: u64,
+) -> ClearingHouseResult<usize> {
+    // an available slot may still carry the market index of a closed position, or default to
+    // market 0, so only a slot in use counts as the user's position
+    match user_positions.positions.iter().position(|market_position| {
+        market_position.is_for(market_index) && !market_position.is_available()
+    }) {
+        Some(position_index) => Ok(position_index),
+        None => user_positions.add_new_position(market_index),
+    }
+}
+
+/// Returns the index of the user's open position in market_index. Only needs a shared borrow, so
+/// callers can look the position up before they decide to borrow it, or the market, mutably.
+pub fn get_position_index_ref(
+    user_positions: &UserPositions,
+    market_index: u64,
+) -> ClearingHouseResult<usize> {
+    user_positions
+        .positions
+        .iter()
+        .position(|market_position| market_position.is_active_for(market_index))
+        .ok_or(ErrorCode::UserHasNoPositionInMarket)
+}
+
+/// Rejects opening or growing a position in a dated market once it has expired. Positions can still
+/// be reduced and closed, and whatever is left is settled by settle_expired_position.
+pub fn validate_market_not_expired(market: &Market, now: i64) -> ClearingHouseResult {
+    if market.is_expired(now) {
+        let expiry_ts = market.expiry_ts;
+        msg!("Market expired at {}", expiry_ts);
+        return Err(ErrorCode::MarketExpired);
+    }
+
+    Ok(())
+}
+
+/// Counts a newly opened position in the market's open interest, up to the market's ceiling
+fn increment_open_interest(market: &mut Market) -> ClearingHouseResult {
+    let open_interest = market
+        .open_interest
+        .checked_add(1)
+        .ok_or_else(wrap_error!(ErrorCode::OpenInterestOverflow))?;
+
+    if market.max_open_interest != 0 && open_interest > market.max_open_interest {
+        return Err(ErrorCode::MaxOpenInterestExceeded);
+    }
+
+    market.open_interest = open_interest;
+
+    Ok(())
+}
+
+/// The swap and accounting half of increase, for callers that have already snapshotted funding and
+/// counted open interest for the position
+fn increase_position_size(
+    direction: PositionDirection,
+    new_quote_asset_notional_amount: u128,
+    user: &Account<User>,
+    market: &mut Market,
+    market_position: &mut MarketPosition,
+    now: i64,
+) -> ClearingHouseResult<i128> {
+    let position_before = *market_position;
+    let quote_asset_amount = market_position
         .quote_asset_amount
         .checked_add(new_quote_asset_notional_amount)
-        .ok_or_else(math_error!())?;
+        .ok_or(ErrorCode::MaxPositionSizeExceeded)?;
+    if market.max_quote_asset_amount != 0
+        && quote_asset_amount > market.max_quote_asset_amount as u128
+    {
+        return Err(ErrorCode::MaxPositionSizeExceeded);
+    }
+    market_position.quote_asset_amount = quote_asset_amount;
 
     let swap_direction = match direction {
         PositionDirection::Long => SwapDirection::Add,
@@ -64,42 +226,409 @@ pub fn increase(
         swap_direction,
         now,
         None,
+        None,
+    )?;
+    controller::amm::record_trade_volume(
+        market,
+        base_asset_acquired.unsigned_abs(),
+        new_quote_asset_notional_amount,
     )?;
 
     // update the position size on market and user
     market_position.base_asset_amount = market_position
         .base_asset_amount
         .checked_add(base_asset_acquired)
-        .ok_or_else(math_error!())?;
+        .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+    validate_max_position_base_asset_amount(market, market_position)?;
     market.base_asset_amount = market
         .base_asset_amount
         .checked_add(base_asset_acquired)
-        .ok_or_else(math_error!())?;
+        .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
 
-    if market_position.base_asset_amount > 0 {
+    match market_position.direction() {
+        Some(PositionDirection::Long) => {
+            market.base_asset_amount_long = market
+                .base_asset_amount_long
+                .checked_add(base_asset_acquired)
+                .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+        }
+        Some(PositionDirection::Short) => {
+            market.base_asset_amount_short = market
+                .base_asset_amount_short
+                .checked_add(base_asset_acquired)
+                .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+        }
+        // nothing was acquired, so neither side changes
+        None => {}
+    }
+
+    market.update_entry_quote_asset_amount(&position_before, market_position)?;
+    emit_position_changed(
+        user,
+        direction,
+        &position_before,
+        market_position,
+        0,
+        new_quote_asset_notional_amount,
+    )?;
+
+    Ok(base_asset_acquired)
+}
+
+/// Takes a base-denominated market's fee out of the base asset a trade just opened, so the position
+/// holds base_asset_fee less than the amm swapped while the quote spent stays exact. The fee is
+/// skimmed towards zero from the position and the market's totals for its side.
+pub fn skim_base_asset_fee(
+    market: &mut Market,
+    market_position: &mut MarketPosition,
+    base_asset_fee: u128,
+) -> ClearingHouseResult {
+    let base_asset_fee = match market_position.direction() {
+        Some(PositionDirection::Long) => -cast_to_i128(base_asset_fee)?,
+        Some(PositionDirection::Short) => cast_to_i128(base_asset_fee)?,
+        None => return Ok(()),
+    };
+
+    market_position.base_asset_amount = market_position
+        .base_asset_amount
+        .checked_add(base_asset_fee)
+        .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+    market.base_asset_amount = market
+        .base_asset_amount
+        .checked_add(base_asset_fee)
+        .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+    if base_asset_fee < 0 {
         market.base_asset_amount_long = market
             .base_asset_amount_long
-            .checked_add(base_asset_acquired)
-            .ok_or_else(math_error!())?;
+            .checked_add(base_asset_fee)
+            .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
     } else {
         market.base_asset_amount_short = market
             .base_asset_amount_short
-            .checked_add(base_asset_acquired)
+            .checked_add(base_asset_fee)
+            .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+    }
+
+    Ok(())
+}
+
+/// The all-in price of increasing by quote_asset_amount now, in MARK_PRICE_PRECISION, for display on
+/// an order ticket. The amm has no spread of its own, so it's the swap's average fill price, price
+/// impact included, with the fee layered on the way open_position charges it: on top of the quote
+/// spent or off the quote received, or skimmed from the base acquired on a base-denominated market.
+/// Token and referral discounts aren't known until the trade, so the fee is the undiscounted one.
+pub fn get_all_in_fill_price(
+    market: &Market,
+    quote_asset_amount: u128,
+    direction: PositionDirection,
+    fee_structure: &FeeStructure,
+) -> ClearingHouseResult<u128> {
+    let swap_direction = match direction {
+        PositionDirection::Long => SwapDirection::Add,
+        PositionDirection::Short => SwapDirection::Remove,
+    };
+    let (base_asset_amount, _new_mark_price) = controller::amm::simulate_swap_quote_asset(
+        &market.amm,
+        quote_asset_amount,
+        swap_direction,
+    )?;
+    let base_asset_amount = base_asset_amount.unsigned_abs();
+
+    let (quote_asset_amount_all_in, base_asset_amount_all_in) = if market.fee_denomination
+        == FeeDenomination::Base
+    {
+        let (base_asset_fee, _) =
+            fees::calculate_base_asset_fee(base_asset_amount, quote_asset_amount, fee_structure)?;
+        let base_asset_amount_all_in = base_asset_amount
+            .checked_sub(base_asset_fee)
             .ok_or_else(math_error!())?;
+        (quote_asset_amount, base_asset_amount_all_in)
+    } else {
+        let (fee, _, _, _, _) = fees::calculate(quote_asset_amount, fee_structure, None, &None)?;
+        let quote_asset_amount_all_in = match direction {
+            PositionDirection::Long => quote_asset_amount.checked_add(fee),
+            PositionDirection::Short => quote_asset_amount.checked_sub(fee),
+        }
+        .ok_or_else(math_error!())?;
+        (quote_asset_amount_all_in, base_asset_amount)
+    };
+
+    quote_asset_amount_all_in
+        .checked_mul(MARK_PRICE_PRECISION * AMM_TO_QUOTE_PRECISION_RATIO)
+        .ok_or_else(math_error!())?
+        .checked_div(base_asset_amount_all_in)
+        .ok_or_else(math_error!())
+}
+
+/// Caps a single user's position in the market, independently of open interest, so a new market can
+/// be opened without one account taking most of it. A max of 0 is not enforced.
+fn validate_max_position_base_asset_amount(
+    market: &Market,
+    market_position: &MarketPosition,
+) -> ClearingHouseResult {
+    let base_asset_amount = market_position.base_asset_amount.unsigned_abs();
+    let max_position_base_asset_amount = market.max_position_base_asset_amount;
+    if max_position_base_asset_amount != 0 && base_asset_amount > max_position_base_asset_amount {
+        msg!(
+            "Position base asset amount {} is above the market's maximum {}",
+            base_asset_amount,
+            max_position_base_asset_amount
+        );
+        return Err(ErrorCode::MaxPositionSizeExceeded);
     }
 
-    Ok(base_asset_acquired)
+    Ok(())
+}
+
+/// Increases the position by an exact base asset amount. Returns the quote asset amount paid (longs)
+/// or received (shorts) for it.
+pub fn increase_with_base_asset_amount(
+    direction: PositionDirection,
+    base_asset_amount: u128,
+    user: &mut Account<User>,
+    market_index: u64,
+    market: &mut Market,
+    market_position: &mut MarketPosition,
+    now: i64,
+) -> ClearingHouseResult<u128> {
+    validate_position_for_market(market_position, market_index)?;
+    validate_market_not_expired(market, now)?;
+    controller::funding::settle_position_funding_payment(user, market, market_position)?;
+
+    if base_asset_amount == 0 {
+        return Ok(0);
+    }
+
+    let position_before = *market_position;
+
+    // Update funding rate if this is a new position
+    if market_position.base_asset_amount == 0 {
+        market_position.apply_funding_snapshot(
+            match direction {
+                PositionDirection::Long => market.amm.cumulative_funding_rate_long,
+                PositionDirection::Short => market.amm.cumulative_funding_rate_short,
+            },
+            market.amm.last_funding_rate_ts,
+        );
+        market_position.reset_trading_costs();
+
+        increment_open_interest(market)?;
+    }
+
+    let (swap_direction, base_asset_acquired) = match direction {
+        PositionDirection::Long => (SwapDirection::Remove, cast_to_i128(base_asset_amount)?),
+        PositionDirection::Short => (SwapDirection::Add, -cast_to_i128(base_asset_amount)?),
+    };
+
+    let quote_asset_amount = controller::amm::swap_base_asset(
+        &mut market.amm,
+        base_asset_amount,
+        swap_direction,
+        now,
+        None,
+    )?;
+    controller::amm::record_trade_volume(market, base_asset_amount, quote_asset_amount)?;
+
+    let new_quote_asset_amount = market_position
+        .quote_asset_amount
+        .checked_add(quote_asset_amount)
+        .ok_or(ErrorCode::MaxPositionSizeExceeded)?;
+    if market.max_quote_asset_amount != 0
+        && new_quote_asset_amount > market.max_quote_asset_amount as u128
+    {
+        return Err(ErrorCode::MaxPositionSizeExceeded);
+    }
+    market_position.quote_asset_amount = new_quote_asset_amount;
+
+    // update the position size on market and user
+    market_position.base_asset_amount = market_position
+        .base_asset_amount
+        .checked_add(base_asset_acquired)
+        .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+    validate_max_position_base_asset_amount(market, market_position)?;
+    market.base_asset_amount = market
+        .base_asset_amount
+        .checked_add(base_asset_acquired)
+        .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+
+    match market_position.direction() {
+        Some(PositionDirection::Long) => {
+            market.base_asset_amount_long = market
+                .base_asset_amount_long
+                .checked_add(base_asset_acquired)
+                .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+        }
+        Some(PositionDirection::Short) => {
+            market.base_asset_amount_short = market
+                .base_asset_amount_short
+                .checked_add(base_asset_acquired)
+                .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+        }
+        // nothing was acquired, so neither side changes
+        None => {}
+    }
+
+    market.update_entry_quote_asset_amount(&position_before, market_position)?;
+    emit_position_changed(
+        user,
+        direction,
+        &position_before,
+        market_position,
+        0,
+        quote_asset_amount,
+    )?;
+
+    Ok(quote_asset_amount)
+}
+
+/// Emitted on every trade against a position so indexers can rebuild trade history from events.
+/// The deltas are the change in the position: base_asset_amount_delta is negative when a short grows
+/// or a long shrinks, and quote_asset_amount_delta is the change in the entry quote asset amount.
+/// base_asset_value is the quote asset amount the trade swapped.
+#[event]
+pub struct PositionChanged {
+    pub user: Pubkey,
+    pub market_index: u64,
+    pub direction: PositionDirection,
+    pub base_asset_amount_delta: i128,
+    pub quote_asset_amount_delta: i128,
+    pub pnl: i128,
+    pub base_asset_value: u128,
+}
+
+/// Emitted when a position is closed, with the pnl before and after the fees and funding it paid
+#[event]
+pub struct PositionClosed {
+    pub user: Pubkey,
+    pub market_index: u64,
+    pub gross_pnl: i128,
+    pub net_pnl: i128,
+}
+
+/// Emits PositionClosed for a position closed for gross_pnl. The position has to already include the
+/// closing fee.
+pub fn emit_position_closed(
+    user: &Account<User>,
+    market_index: u64,
+    gross_pnl: i128,
+    market_position: &MarketPosition,
+) -> ClearingHouseResult {
+    let PnlBreakdown { gross_pnl, net_pnl } = calculate_pnl_breakdown(gross_pnl, market_position)?;
+    emit!(PositionClosed {
+        user: user.key(),
+        market_index,
+        gross_pnl,
+        net_pnl,
+    });
+    Ok(())
+}
+
+fn emit_position_changed(
+    user: &Account<User>,
+    direction: PositionDirection,
+    position_before: &MarketPosition,
+    position_after: &MarketPosition,
+    pnl: i128,
+    base_asset_value: u128,
+) -> ClearingHouseResult {
+    emit!(PositionChanged {
+        user: user.key(),
+        market_index: position_after.market_index,
+        direction,
+        base_asset_amount_delta: position_after
+            .base_asset_amount
+            .checked_sub(position_before.base_asset_amount)
+            .ok_or_else(math_error!())?,
+        quote_asset_amount_delta: cast_to_i128(position_after.quote_asset_amount)?
+            .checked_sub(cast_to_i128(position_before.quote_asset_amount)?)
+            .ok_or_else(math_error!())?,
+        pnl,
+        base_asset_value,
+    });
+
+    Ok(())
+}
+
+/// The position as it stands after a trade, plus the pnl the trade realized, so event emitters and
+/// indexers get everything the trade event needs from the one call
+#[derive(Clone, Copy, Default)]
+pub struct PositionAfter {
+    pub base_asset_amount: i128,
+    pub quote_asset_amount: u128,
+    pub entry_price: u128,
+    pub realized_pnl: i128,
+}
+
+impl PositionAfter {
+    pub fn new(market_position: &MarketPosition, realized_pnl: i128) -> ClearingHouseResult<Self> {
+        Ok(PositionAfter {
+            base_asset_amount: market_position.base_asset_amount,
+            quote_asset_amount: market_position.quote_asset_amount,
+            entry_price: calculate_entry_price(
+                market_position.quote_asset_amount,
+                market_position.base_asset_amount,
+            )?,
+            realized_pnl,
+        })
+    }
 }
 
+/// Reduces the position by quote_asset_swap_amount. The base asset swapped is what
+/// controller::amm::simulate_swap_quote_asset predicts for the same reserves.
+#[allow(clippy::too_many_arguments)]
 pub fn reduce<'info>(
     direction: PositionDirection,
     quote_asset_swap_amount: u128,
     user: &mut Account<'info, User>,
+    market_index: u64,
     market: &mut Market,
     market_position: &mut MarketPosition,
     now: i64,
     precomputed_mark_price: Option<u128>,
-) -> ClearingHouseResult<i128> {
+) -> ClearingHouseResult<(i128, i128)> {
+    let (base_asset_swapped, position_after) = reduce_with_position_after(
+        direction,
+        quote_asset_swap_amount,
+        user,
+        market_index,
+        market,
+        market_position,
+        now,
+        precomputed_mark_price,
+        None,
+    )?;
+
+    Ok((base_asset_swapped, position_after.realized_pnl))
+}
+
+/// Same as reduce, also returning a snapshot of the position after the reduce. A max_now, if set,
+/// bounds now as in validate_now.
+#[allow(clippy::too_many_arguments)]
+pub fn reduce_with_position_after<'info>(
+    direction: PositionDirection,
+    quote_asset_swap_amount: u128,
+    user: &mut Account<'info, User>,
+    market_index: u64,
+    market: &mut Market,
+    market_position: &mut MarketPosition,
+    now: i64,
+    precomputed_mark_price: Option<u128>,
+    max_now: Option<i64>,
+) -> ClearingHouseResult<(i128, PositionAfter)> {
+    validate_now(now, max_now)?;
+    validate_position_for_market(market_position, market_index)?;
+    controller::funding::settle_position_funding_payment(user, market, market_position)?;
+
+    // flipping goes through reverse_position, a reduce worth more than the position is an error
+    let (base_asset_value, _unrealized_pnl) = _calculate_base_asset_value_and_pnl(
+        market_position.base_asset_amount,
+        market_position.quote_asset_amount,
+        &market.amm,
+    )?;
+    if quote_asset_swap_amount > base_asset_value {
+        return Err(ErrorCode::TriedToReduceBeyondPositionSize);
+    }
+
     let swap_direction = match direction {
         PositionDirection::Long => SwapDirection::Add,
         PositionDirection::Short => SwapDirection::Remove,
@@ -111,126 +640,2068 @@ pub fn reduce<'info>(
         swap_direction,
         now,
         precomputed_mark_price,
+        None,
+    )?;
+    controller::amm::record_trade_volume(
+        market,
+        base_asset_swapped.unsigned_abs(),
+        quote_asset_swap_amount,
     )?;
 
+    let position_before = *market_position;
     let base_asset_amount_before = market_position.base_asset_amount;
+    // a flat position has nothing to reduce
+    let was_long =
+        market_position.direction().ok_or_else(math_error!())? == PositionDirection::Long;
     market_position.base_asset_amount = market_position
         .base_asset_amount
         .checked_add(base_asset_swapped)
-        .ok_or_else(math_error!())?;
+        .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
 
+    // a reduce only closes out one user's position when it brings it to zero
+    let open_interest_decrement: u128 = if market_position.base_asset_amount == 0 {
+        1
+    } else {
+        0
+    };
     market.open_interest = market
         .open_interest
-        .checked_sub(cast(market_position.base_asset_amount == 0)?)
-        .ok_or_else(math_error!())?;
+        .checked_sub(open_interest_decrement)
+        .ok_or_else(wrap_error!(ErrorCode::OpenInterestOverflow))?;
     market.base_asset_amount = market
         .base_asset_amount
         .checked_add(base_asset_swapped)
-        .ok_or_else(math_error!())?;
+        .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
 
-    if market_position.base_asset_amount > 0 {
+    // swap rounding can still overshoot zero on a reduce for the full value. Such a fill closes the
+    // whole position and opens the residual on the other side. The closed part realizes pnl at the
+    // fill price and the residual gets a fresh entry.
+    let crosses_zero = match market_position.direction() {
+        Some(PositionDirection::Long) => !was_long,
+        Some(PositionDirection::Short) => was_long,
+        None => false,
+    };
+
+    if crosses_zero {
+        if was_long {
+            market.base_asset_amount_long = market
+                .base_asset_amount_long
+                .checked_sub(base_asset_amount_before)
+                .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+            market.base_asset_amount_short = market
+                .base_asset_amount_short
+                .checked_add(market_position.base_asset_amount)
+                .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+        } else {
+            market.base_asset_amount_short = market
+                .base_asset_amount_short
+                .checked_sub(base_asset_amount_before)
+                .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+            market.base_asset_amount_long = market
+                .base_asset_amount_long
+                .checked_add(market_position.base_asset_amount)
+                .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+        }
+    } else if was_long {
         market.base_asset_amount_long = market
             .base_asset_amount_long
             .checked_add(base_asset_swapped)
-            .ok_or_else(math_error!())?;
+            .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
     } else {
         market.base_asset_amount_short = market
             .base_asset_amount_short
             .checked_add(base_asset_swapped)
-            .ok_or_else(math_error!())?;
+            .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
     }
 
-    let base_asset_amount_change = base_asset_amount_before
-        .checked_sub(market_position.base_asset_amount)
-        .ok_or_else(math_error!())?
-        .abs();
+    let (base_asset_amount_closed, quote_asset_amount_closed) = if crosses_zero {
+        let quote_asset_amount_closed = quote_asset_swap_amount
+            .checked_mul(base_asset_amount_before.unsigned_abs())
+            .ok_or_else(wrap_error!(ErrorCode::QuoteAccumulationOverflow))?
+            .checked_div(base_asset_swapped.unsigned_abs())
+            .ok_or_else(wrap_error!(ErrorCode::QuoteAccumulationOverflow))?;
+        (
+            base_asset_amount_before.unsigned_abs(),
+            quote_asset_amount_closed,
+        )
+    } else {
+        let base_asset_amount_change = base_asset_amount_before
+            .checked_sub(market_position.base_asset_amount)
+            .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?
+            .unsigned_abs();
+        (base_asset_amount_change, quote_asset_swap_amount)
+    };
 
+    let quote_asset_amount_before = market_position.quote_asset_amount;
     let initial_quote_asset_amount_closed = market_position
         .quote_asset_amount
-        .checked_mul(base_asset_amount_change.unsigned_abs())
-        .ok_or_else(math_error!())?
+        .checked_mul(base_asset_amount_closed)
+        .ok_or_else(wrap_error!(ErrorCode::QuoteAccumulationOverflow))?
         .checked_div(base_asset_amount_before.unsigned_abs())
-        .ok_or_else(math_error!())?;
+        .ok_or_else(wrap_error!(ErrorCode::QuoteAccumulationOverflow))?;
 
     market_position.quote_asset_amount = market_position
         .quote_asset_amount
         .checked_sub(initial_quote_asset_amount_closed)
-        .ok_or_else(math_error!())?;
+        .ok_or_else(wrap_error!(ErrorCode::QuoteAccumulationOverflow))?;
 
-    let pnl = if market_position.base_asset_amount > 0 {
-        cast_to_i128(quote_asset_swap_amount)?
+    // realize pnl against the quote actually swapped in this reduce, so a position reduced over
+    // several fills realizes the blended fill price. The side comes from the position before the
+    // reduce, which still holds if this reduce takes the position to zero.
+    let pnl = if was_long {
+        cast_to_i128(quote_asset_amount_closed)?
             .checked_sub(cast(initial_quote_asset_amount_closed)?)
-            .ok_or_else(math_error!())?
+            .ok_or_else(wrap_error!(ErrorCode::PnlCalculationOverflow))?
     } else {
         cast_to_i128(initial_quote_asset_amount_closed)?
-            .checked_sub(cast(quote_asset_swap_amount)?)
-            .ok_or_else(math_error!())?
+            .checked_sub(cast(quote_asset_amount_closed)?)
+            .ok_or_else(wrap_error!(ErrorCode::PnlCalculationOverflow))?
     };
 
-    user.collateral = calculate_updated_collateral(user.collateral, pnl)?;
+    validate_pnl_direction(
+        pnl,
+        was_long,
+        quote_asset_amount_closed,
+        base_asset_amount_closed,
+        quote_asset_amount_before,
+        base_asset_amount_before.unsigned_abs(),
+    )?;
+
+    if crosses_zero {
+        market_position.quote_asset_amount = quote_asset_swap_amount
+            .checked_sub(quote_asset_amount_closed)
+            .ok_or_else(wrap_error!(ErrorCode::QuoteAccumulationOverflow))?;
+        market_position.apply_funding_snapshot(
+            match direction {
+                PositionDirection::Long => market.amm.cumulative_funding_rate_long,
+                PositionDirection::Short => market.amm.cumulative_funding_rate_short,
+            },
+            market.amm.last_funding_rate_ts,
+        );
+    }
+
+    market.update_entry_quote_asset_amount(&position_before, market_position)?;
+    emit_position_changed(
+        user,
+        direction,
+        &position_before,
+        market_position,
+        pnl,
+        quote_asset_swap_amount,
+    )?;
+    realize_pnl(user, market, market_position, pnl, now)?;
 
-    Ok(base_asset_swapped)
+    Ok((
+        base_asset_swapped,
+        PositionAfter::new(market_position, pnl)?,
+    ))
 }
 
-pub fn close(
+/// Reduces the position by an exact base asset amount. A request for the whole position closes it,
+/// and a request larger than the position is an error rather than a flip.
+/// Returns the base asset amount actually applied, the quote asset amount it was swapped for and the
+/// pnl realized.
+pub fn reduce_with_base_asset_amount(
+    base_asset_amount: u128,
     user: &mut Account<User>,
+    market_index: u64,
     market: &mut Market,
     market_position: &mut MarketPosition,
     now: i64,
-) -> ClearingHouseResult<(u128, i128)> {
-    // If user has no base asset, return early
-    if market_position.base_asset_amount == 0 {
-        return Ok((0, 0));
+) -> ClearingHouseResult<(u128, u128, i128)> {
+    validate_position_for_market(market_position, market_index)?;
+    controller::funding::settle_position_funding_payment(user, market, market_position)?;
+
+    if base_asset_amount == 0 || market_position.base_asset_amount == 0 {
+        return Ok((0, 0, 0));
     }
 
-    let swap_direction = if market_position.base_asset_amount > 0 {
-        SwapDirection::Add
-    } else {
-        SwapDirection::Remove
-    };
+    if base_asset_amount > market_position.base_asset_amount.unsigned_abs() {
+        return Err(ErrorCode::TriedToReduceBeyondPositionSize);
+    }
 
-    let base_asset_value = controller::amm::swap_base_asset(
+    if base_asset_amount == market_position.base_asset_amount.unsigned_abs() {
+        let swap_direction = swap_direction_to_close_position(market_position.base_asset_amount);
+        let quote_asset_amount_before = market_position.quote_asset_amount;
+        let (base_asset_value, base_asset_amount_closed) =
+            close(user, market_index, market, market_position, now)?;
+        // the same pnl close realized
+        let pnl = calculate_pnl(base_asset_value, quote_asset_amount_before, swap_direction)?;
+        return Ok((
+            base_asset_amount_closed.unsigned_abs(),
+            base_asset_value,
+            pnl,
+        ));
+    }
+
+    let swap_direction = swap_direction_to_close_position(market_position.base_asset_amount);
+    let quote_asset_swapped = controller::amm::swap_base_asset(
         &mut market.amm,
-        market_position.base_asset_amount.unsigned_abs(),
+        base_asset_amount,
         swap_direction,
         now,
+        None,
     )?;
-    let pnl = calculate_pnl(
-        base_asset_value,
-        market_position.quote_asset_amount,
-        swap_direction,
-    )?;
-
-    user.collateral = calculate_updated_collateral(user.collateral, pnl)?;
-    market_position.last_cumulative_funding_rate = 0;
-    market_position.last_funding_rate_ts = 0;
-
-    market.open_interest = market
-        .open_interest
-        .checked_sub(1)
-        .ok_or_else(math_error!())?;
+    controller::amm::record_trade_volume(market, base_asset_amount, quote_asset_swapped)?;
 
-    market_position.quote_asset_amount = 0;
+    let base_asset_amount_change = match swap_direction {
+        SwapDirection::Add => -cast_to_i128(base_asset_amount)?,
+        SwapDirection::Remove => cast_to_i128(base_asset_amount)?,
+    };
 
+    // the position stays open on the same side, a reduce this large goes through close above
+    let is_long = market_position.is_long();
+    let position_before = *market_position;
+    let base_asset_amount_before = market_position.base_asset_amount;
+    market_position.base_asset_amount = market_position
+        .base_asset_amount
+        .checked_add(base_asset_amount_change)
+        .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
     market.base_asset_amount = market
         .base_asset_amount
-        .checked_sub(market_position.base_asset_amount)
-        .ok_or_else(math_error!())?;
+        .checked_add(base_asset_amount_change)
+        .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
 
-    if market_position.base_asset_amount > 0 {
+    if is_long {
         market.base_asset_amount_long = market
             .base_asset_amount_long
-            .checked_sub(market_position.base_asset_amount)
-            .ok_or_else(math_error!())?;
+            .checked_add(base_asset_amount_change)
+            .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
     } else {
         market.base_asset_amount_short = market
             .base_asset_amount_short
-            .checked_sub(market_position.base_asset_amount)
-            .ok_or_else(math_error!())?;
+            .checked_add(base_asset_amount_change)
+            .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
     }
 
-    let base_asset_amount = market_position.base_asset_amount;
-    market_position.base_asset_amount = 0;
+    let initial_quote_asset_amount_closed = market_position
+        .quote_asset_amount
+        .checked_mul(base_asset_amount)
+        .ok_or_else(wrap_error!(ErrorCode::QuoteAccumulationOverflow))?
+        .checked_div(base_asset_amount_before.unsigned_abs())
+        .ok_or_else(wrap_error!(ErrorCode::QuoteAccumulationOverflow))?;
 
-    Ok((base_asset_value, base_asset_amount))
+    market_position.quote_asset_amount = market_position
+        .quote_asset_amount
+        .checked_sub(initial_quote_asset_amount_closed)
+        .ok_or_else(wrap_error!(ErrorCode::QuoteAccumulationOverflow))?;
+
+    let pnl = calculate_pnl(
+        quote_asset_swapped,
+        initial_quote_asset_amount_closed,
+        swap_direction,
+    )?;
+
+    market.update_entry_quote_asset_amount(&position_before, market_position)?;
+    emit_position_changed(
+        user,
+        direction_to_close_position(position_before.base_asset_amount),
+        &position_before,
+        market_position,
+        pnl,
+        quote_asset_swapped,
+    )?;
+    realize_pnl(user, market, market_position, pnl, now)?;
+
+    Ok((base_asset_amount, quote_asset_swapped, pnl))
+}
+
+/// Reduce-only counterpart of reduce_with_base_asset_amount. A direction that would grow the position
+/// reduces nothing, and a base_asset_amount larger than the position is capped at it so the position
+/// is closed rather than flipped. Returns the same (base asset amount, quote asset amount, pnl) as
+/// reduce_with_base_asset_amount, for the amount actually reduced.
+pub fn close_or_reduce(
+    direction: PositionDirection,
+    base_asset_amount: u128,
+    user: &mut Account<User>,
+    market_index: u64,
+    market: &mut Market,
+    market_position: &mut MarketPosition,
+    now: i64,
+) -> ClearingHouseResult<(u128, u128, i128)> {
+    validate_position_for_market(market_position, market_index)?;
+
+    if market_position.base_asset_amount == 0
+        || direction != direction_to_close_position(market_position.base_asset_amount)
+    {
+        return Ok((0, 0, 0));
+    }
+
+    let base_asset_amount = base_asset_amount.min(market_position.base_asset_amount.unsigned_abs());
+
+    reduce_with_base_asset_amount(
+        base_asset_amount,
+        user,
+        market_index,
+        market,
+        market_position,
+        now,
+    )
+}
+
+/// Defensive check that realized pnl has the sign the fill price implies against the entry price: a
+/// long only profits filling above entry and a short only profits filling below it. Prices are
+/// compared by cross multiplying, independently of how pnl was rounded.
+fn validate_pnl_direction(
+    pnl: i128,
+    is_long: bool,
+    fill_quote_asset_amount: u128,
+    fill_base_asset_amount: u128,
+    entry_quote_asset_amount: u128,
+    entry_base_asset_amount: u128,
+) -> ClearingHouseResult {
+    if pnl == 0 {
+        return Ok(());
+    }
+
+    let fill_price_scaled = U192::from(fill_quote_asset_amount)
+        .checked_mul(U192::from(entry_base_asset_amount))
+        .ok_or_else(math_error!())?;
+    let entry_price_scaled = U192::from(entry_quote_asset_amount)
+        .checked_mul(U192::from(fill_base_asset_amount))
+        .ok_or_else(math_error!())?;
+
+    let is_profitable_fill = if is_long {
+        fill_price_scaled > entry_price_scaled
+    } else {
+        fill_price_scaled < entry_price_scaled
+    };
+    let is_losing_fill = if is_long {
+        fill_price_scaled < entry_price_scaled
+    } else {
+        fill_price_scaled > entry_price_scaled
+    };
+
+    if (pnl > 0 && !is_profitable_fill) || (pnl < 0 && !is_losing_fill) {
+        msg!(
+            "Realized pnl {} is inconsistent with the fill for a {} position",
+            pnl,
+            if is_long { "long" } else { "short" }
+        );
+        return Err(ErrorCode::InvalidPnlCalculation);
+    }
+
+    Ok(())
+}
+
+/// Books realized pnl to the user's collateral, lifetime realized pnl and pnl velocity, and to the
+/// market's trader pnl totals. A market that settles pnl separately holds gains on the position
+/// instead of collateral, until they're claimed. Losses are netted against held gains and the rest
+/// booked straight away, so they reduce collateral and record any bad debt as they're realized.
+fn realize_pnl(
+    user: &mut Account<User>,
+    market: &mut Market,
+    market_position: &mut MarketPosition,
+    pnl: i128,
+    now: i64,
+) -> ClearingHouseResult {
+    if market.settle_pnl_separately {
+        let settled_pnl = market_position
+            .settled_pnl
+            .checked_add(pnl)
+            .ok_or_else(math_error!())?;
+        if settled_pnl < 0 {
+            book_pnl_to_collateral(user, market, settled_pnl)?;
+            market_position.settled_pnl = 0;
+        } else {
+            market_position.settled_pnl = settled_pnl;
+        }
+    } else {
+        book_pnl_to_collateral(user, market, pnl)?;
+    }
+    record_realized_pnl(user, pnl)?;
+    user.pnl_velocity = calculate_pnl_velocity(user.pnl_velocity, user.pnl_velocity_ts, pnl, now)?;
+    user.pnl_velocity_ts = now;
+    Ok(())
+}
+
+/// Adds pnl to the user's lifetime realized pnl. Pnl counts when it's realized, whether it's booked to
+/// collateral then or held on the position until claimed.
+fn record_realized_pnl(user: &mut Account<User>, pnl: i128) -> ClearingHouseResult {
+    user.total_realized_pnl = user
+        .total_realized_pnl
+        .checked_add(pnl)
+        .ok_or_else(math_error!())?;
+    Ok(())
+}
+
+/// Moves the pnl a position holds from a market that settles pnl separately into the user's
+/// collateral. Returns the amount claimed.
+pub fn claim_settled_pnl(
+    user: &mut Account<User>,
+    market: &mut Market,
+    market_position: &mut MarketPosition,
+) -> ClearingHouseResult<i128> {
+    let settled_pnl = market_position.settled_pnl;
+    if settled_pnl == 0 {
+        return Ok(0);
+    }
+
+    book_pnl_to_collateral(user, market, settled_pnl)?;
+    market_position.settled_pnl = 0;
+
+    Ok(settled_pnl)
+}
+
+/// Books pnl to the user's collateral. A loss larger than the collateral zeroes it and the rest is
+/// bad debt, which the market owes the collateral vault until it's covered from the insurance fund.
+fn book_pnl_to_collateral(
+    user: &mut Account<User>,
+    market: &mut Market,
+    pnl: i128,
+) -> ClearingHouseResult {
+    let collateral_before = user.collateral;
+    let (collateral, bad_debt) = calculate_updated_collateral_and_bad_debt(user.collateral, pnl)?;
+    user.collateral = collateral;
+    market.record_trader_pnl(collateral_before, user.collateral)?;
+
+    if bad_debt > 0 {
+        msg!("Realized loss leaves {} of bad debt", bad_debt);
+        market.total_bad_debt = market
+            .total_bad_debt
+            .checked_add(cast(bad_debt)?)
+            .ok_or_else(math_error!())?;
+    }
+
+    Ok(())
+}
+
+/// Sweeps collateral left below dust_collateral_threshold to the market's fee pool once the user's
+/// last position is closed, so rounding doesn't leave unwithdrawable balances behind. A threshold of 0
+/// leaves the collateral with the user. Returns the amount swept.
+pub fn sweep_dust_collateral(
+    user: &mut User,
+    user_positions: &UserPositions,
+    market: &mut Market,
+    dust_collateral_threshold: u64,
+) -> ClearingHouseResult<u128> {
+    if dust_collateral_threshold == 0
+        || user_positions.has_open_position()
+        || user.collateral >= cast_to_u128(dust_collateral_threshold)?
+    {
+        return Ok(0);
+    }
+
+    let dust = user.collateral;
+    user.collateral = 0;
+    market.amm.total_fee_minus_distributions = market
+        .amm
+        .total_fee_minus_distributions
+        .checked_add(dust)
+        .ok_or_else(math_error!())?;
+
+    if dust > 0 {
+        msg!("Swept {} of dust collateral to the fee pool", dust);
+    }
+
+    Ok(dust)
+}
+
+/// Throttles accounts realizing pnl, gains or losses, faster than the guarded launch allows. A
+/// max_pnl_velocity of 0 is not enforced.
+pub fn validate_pnl_velocity(user: &User, max_pnl_velocity: u64) -> ClearingHouseResult {
+    if max_pnl_velocity != 0 && user.pnl_velocity.unsigned_abs() > max_pnl_velocity {
+        msg!(
+            "Pnl velocity {} exceeds the maximum {}",
+            user.pnl_velocity,
+            max_pnl_velocity
+        );
+        return Err(ErrorCode::PnlVelocityExceeded);
+    }
+
+    Ok(())
+}
+
+/// Bounds the quote asset amount a close swapped for, for integrators that express slippage as a
+/// notional rather than a price. A bound of 0 is not enforced.
+pub fn validate_quote_asset_amount_out(
+    quote_asset_amount: u128,
+    min_quote_asset_amount_out: u128,
+    max_quote_asset_amount_out: u128,
+) -> ClearingHouseResult {
+    if min_quote_asset_amount_out != 0 && quote_asset_amount < min_quote_asset_amount_out {
+        msg!(
+            "Quote asset amount out {} is below the minimum {}",
+            quote_asset_amount,
+            min_quote_asset_amount_out
+        );
+        return Err(ErrorCode::QuoteAssetAmountOutOfBounds);
+    }
+
+    if max_quote_asset_amount_out != 0 && quote_asset_amount > max_quote_asset_amount_out {
+        msg!(
+            "Quote asset amount out {} is above the maximum {}",
+            quote_asset_amount,
+            max_quote_asset_amount_out
+        );
+        return Err(ErrorCode::QuoteAssetAmountOutOfBounds);
+    }
+
+    Ok(())
+}
+
+/// Rejects a now past the caller's max_now, before a close or reduce settles funding or trades on
+/// a clock further along than the caller signed for. A max_now of None is not enforced.
+pub fn validate_now(now: i64, max_now: Option<i64>) -> ClearingHouseResult {
+    if let Some(max_now) = max_now {
+        if now > max_now {
+            msg!("Timestamp {} is past the maximum {}", now, max_now);
+            return Err(ErrorCode::InvalidTimestamp);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn close(
+    user: &mut Account<User>,
+    market_index: u64,
+    market: &mut Market,
+    market_position: &mut MarketPosition,
+    now: i64,
+) -> ClearingHouseResult<(u128, i128)> {
+    let (base_asset_value, base_asset_amount, _pnl) =
+        close_with_pnl(user, market_index, market, market_position, now, None)?;
+
+    Ok((base_asset_value, base_asset_amount))
+}
+
+/// Same as close, also returning the pnl realized. A max_now, if set, bounds now as in validate_now.
+pub fn close_with_pnl(
+    user: &mut Account<User>,
+    market_index: u64,
+    market: &mut Market,
+    market_position: &mut MarketPosition,
+    now: i64,
+    max_now: Option<i64>,
+) -> ClearingHouseResult<(u128, i128, i128)> {
+    validate_now(now, max_now)?;
+    validate_position_for_market(market_position, market_index)?;
+    controller::funding::settle_position_funding_payment(user, market, market_position)?;
+
+    let swap_direction = match market_position.direction() {
+        Some(PositionDirection::Long) => SwapDirection::Add,
+        Some(PositionDirection::Short) => SwapDirection::Remove,
+        // If user has no base asset, return early
+        None => return Ok((0, 0, 0)),
+    };
+    let position_before = *market_position;
+
+    let base_asset_value = controller::amm::swap_base_asset(
+        &mut market.amm,
+        market_position.base_asset_amount.unsigned_abs(),
+        swap_direction,
+        now,
+        None,
+    )?;
+    controller::amm::record_trade_volume(
+        market,
+        market_position.base_asset_amount.unsigned_abs(),
+        base_asset_value,
+    )?;
+    // the only way this fails is a pnl too large to represent
+    let pnl = calculate_pnl(
+        base_asset_value,
+        market_position.quote_asset_amount,
+        swap_direction,
+    )
+    .map_err(|_| ErrorCode::PnlCalculationOverflow)?;
+
+    realize_pnl(user, market, market_position, pnl, now)?;
+    market_position.apply_funding_snapshot(0, 0);
+
+    market.open_interest = market
+        .open_interest
+        .checked_sub(1)
+        .ok_or_else(wrap_error!(ErrorCode::OpenInterestOverflow))?;
+
+    market_position.quote_asset_amount = 0;
+
+    market.base_asset_amount = market
+        .base_asset_amount
+        .checked_sub(market_position.base_asset_amount)
+        .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+
+    if swap_direction == SwapDirection::Add {
+        market.base_asset_amount_long = market
+            .base_asset_amount_long
+            .checked_sub(market_position.base_asset_amount)
+            .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+    } else {
+        market.base_asset_amount_short = market
+            .base_asset_amount_short
+            .checked_sub(market_position.base_asset_amount)
+            .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+    }
+
+    let base_asset_amount = market_position.base_asset_amount;
+    market_position.base_asset_amount = 0;
+    market.update_entry_quote_asset_amount(&position_before, market_position)?;
+    emit_position_changed(
+        user,
+        direction_to_close_position(position_before.base_asset_amount),
+        &position_before,
+        market_position,
+        pnl,
+        base_asset_value,
+    )?;
+
+    Ok((base_asset_value, base_asset_amount, pnl))
+}
+
+/// Closes the position and opens the rest of quote_asset_amount in the opposite direction in one
+/// step. The part of quote_asset_amount worth the current position is accounted as a close and
+/// realizes its pnl; the surplus opens a new position with a fresh funding snapshot. Open interest
+/// only changes if there is no surplus and the position ends flat. A surplus too small to carry any
+/// initial margin is rejected with TradeSizeTooSmall. Returns the base asset amounts closed and
+/// opened.
+#[allow(clippy::too_many_arguments)]
+pub fn reverse_position(
+    direction: PositionDirection,
+    quote_asset_amount: u128,
+    user: &mut Account<User>,
+    market_index: u64,
+    market: &mut Market,
+    market_position: &mut MarketPosition,
+    now: i64,
+    margin_ratio_initial: u128,
+) -> ClearingHouseResult<(u128, u128)> {
+    validate_position_for_market(market_position, market_index)?;
+    // funding accrued on the position being closed is owed before the new snapshot replaces it
+    controller::funding::settle_position_funding_payment(user, market, market_position)?;
+
+    let is_reversal = match market_position.direction() {
+        Some(position_direction) => position_direction != direction,
+        None => false,
+    };
+    if !is_reversal {
+        return Err(ErrorCode::InvalidPositionReversal);
+    }
+
+    let (base_asset_value, _unrealized_pnl) = _calculate_base_asset_value_and_pnl(
+        market_position.base_asset_amount,
+        market_position.quote_asset_amount,
+        &market.amm,
+    )?;
+    let quote_asset_amount_after_close = quote_asset_amount
+        .checked_sub(base_asset_value)
+        .ok_or(ErrorCode::InvalidPositionReversal)?;
+
+    if quote_asset_amount_after_close == 0 {
+        let (_, base_asset_amount_closed) =
+            close(user, market_index, market, market_position, now)?;
+        return Ok((base_asset_amount_closed.unsigned_abs(), 0));
+    }
+
+    // an expired market can still be closed out of, but not re-opened into
+    validate_market_not_expired(market, now)?;
+    validate_margin_requirement_nonzero(quote_asset_amount_after_close, margin_ratio_initial)?;
+
+    let position_before = *market_position;
+    let base_asset_amount_closed = market_position.base_asset_amount;
+    let swap_direction = swap_direction_to_close_position(base_asset_amount_closed);
+    let base_asset_value = controller::amm::swap_base_asset(
+        &mut market.amm,
+        base_asset_amount_closed.unsigned_abs(),
+        swap_direction,
+        now,
+        None,
+    )?;
+    controller::amm::record_trade_volume(
+        market,
+        base_asset_amount_closed.unsigned_abs(),
+        base_asset_value,
+    )?;
+    let pnl = calculate_pnl(
+        base_asset_value,
+        market_position.quote_asset_amount,
+        swap_direction,
+    )?;
+
+    realize_pnl(user, market, market_position, pnl, now)?;
+
+    market.base_asset_amount = market
+        .base_asset_amount
+        .checked_sub(base_asset_amount_closed)
+        .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+    if base_asset_amount_closed > 0 {
+        market.base_asset_amount_long = market
+            .base_asset_amount_long
+            .checked_sub(base_asset_amount_closed)
+            .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+    } else {
+        market.base_asset_amount_short = market
+            .base_asset_amount_short
+            .checked_sub(base_asset_amount_closed)
+            .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+    }
+
+    // the user still holds a position, so open interest stays as is
+    market_position.base_asset_amount = 0;
+    market_position.quote_asset_amount = 0;
+    market.update_entry_quote_asset_amount(&position_before, market_position)?;
+    emit_position_changed(
+        user,
+        direction,
+        &position_before,
+        market_position,
+        pnl,
+        base_asset_value,
+    )?;
+    market_position.apply_funding_snapshot(
+        match direction {
+            PositionDirection::Long => market.amm.cumulative_funding_rate_long,
+            PositionDirection::Short => market.amm.cumulative_funding_rate_short,
+        },
+        market.amm.last_funding_rate_ts,
+    );
+    market_position.reset_trading_costs();
+
+    let base_asset_amount_opened = increase_position_size(
+        direction,
+        quote_asset_amount_after_close,
+        user,
+        market,
+        market_position,
+        now,
+    )?;
+
+    Ok((
+        base_asset_amount_closed.unsigned_abs(),
+        base_asset_amount_opened.unsigned_abs(),
+    ))
+}
+
+/// Realizes the position's unrealized pnl into the user's collateral without closing it. Funding is
+/// settled first, so the collateral the pnl is booked against is current. The entry quote asset
+/// amount is rebased so later pnl is measured from this settlement, and settling twice at the same
+/// price realizes nothing the second time. The base asset amount, open interest and the market's
+/// base asset totals are untouched. Returns the pnl realized.
+pub fn settle_position_pnl(
+    user: &mut Account<User>,
+    market_index: u64,
+    market: &mut Market,
+    market_position: &mut MarketPosition,
+    now: i64,
+) -> ClearingHouseResult<i128> {
+    validate_position_for_market(market_position, market_index)?;
+    controller::funding::settle_position_funding_payment(user, market, market_position)?;
+
+    if market_position.base_asset_amount == 0 {
+        return Ok(0);
+    }
+
+    let position_before = *market_position;
+    let (_base_asset_value, pnl) = _calculate_base_asset_value_and_pnl(
+        market_position.base_asset_amount,
+        market_position.quote_asset_amount,
+        &market.amm,
+    )?;
+
+    // move the realized pnl out of the entry, which leaves the position with no pnl at this price
+    // including the unit a short gives up to rounding
+    let quote_asset_amount = cast_to_i128(market_position.quote_asset_amount)?;
+    market_position.quote_asset_amount = cast_to_u128(
+        if market_position.is_long() {
+            quote_asset_amount.checked_add(pnl)
+        } else {
+            quote_asset_amount.checked_sub(pnl)
+        }
+        .ok_or_else(math_error!())?,
+    )?;
+    market.update_entry_quote_asset_amount(&position_before, market_position)?;
+
+    realize_pnl(user, market, market_position, pnl, now)?;
+
+    Ok(pnl)
+}
+
+/// Closes fraction_bps / 10000 of the position through the same swap and pnl logic as close. A
+/// fraction of 10000 or more, or one that would leave a remainder worth less than the market's
+/// minimum trade size, closes the whole position. Returns the same (base asset value, base asset
+/// amount) as close, for the part closed.
+pub fn close_partial(
+    user: &mut Account<User>,
+    market_index: u64,
+    market: &mut Market,
+    market_position: &mut MarketPosition,
+    fraction_bps: u16,
+    now: i64,
+) -> ClearingHouseResult<(u128, i128)> {
+    validate_position_for_market(market_position, market_index)?;
+
+    if fraction_bps == 0 || market_position.base_asset_amount == 0 {
+        return Ok((0, 0));
+    }
+
+    if u128::from(fraction_bps) >= BPS_PRECISION {
+        return close(user, market_index, market, market_position, now);
+    }
+
+    let base_asset_amount_to_close = market_position
+        .base_asset_amount
+        .unsigned_abs()
+        .checked_mul(u128::from(fraction_bps))
+        .ok_or_else(math_error!())?
+        .checked_div(BPS_PRECISION)
+        .ok_or_else(math_error!())?;
+
+    let base_asset_amount_remaining = market_position
+        .base_asset_amount
+        .unsigned_abs()
+        .checked_sub(base_asset_amount_to_close)
+        .ok_or_else(math_error!())?;
+    let (base_asset_value_remaining, _pnl) = _calculate_base_asset_value_and_pnl(
+        cast_to_i128(base_asset_amount_remaining)?,
+        0,
+        &market.amm,
+    )?;
+    if asset_to_reserve_amount(base_asset_value_remaining, market.amm.peg_multiplier)?
+        < market.amm.minimum_trade_size
+    {
+        return close(user, market_index, market, market_position, now);
+    }
+
+    if base_asset_amount_to_close == 0 {
+        return Ok((0, 0));
+    }
+
+    let is_long = market_position.base_asset_amount > 0;
+    let (base_asset_amount_closed, base_asset_value, _pnl) = reduce_with_base_asset_amount(
+        base_asset_amount_to_close,
+        user,
+        market_index,
+        market,
+        market_position,
+        now,
+    )?;
+
+    let base_asset_amount_closed = cast_to_i128(base_asset_amount_closed)?;
+    Ok((
+        base_asset_value,
+        if is_long {
+            base_asset_amount_closed
+        } else {
+            -base_asset_amount_closed
+        },
+    ))
+}
+
+/// Recovery path for a market whose amm can't be traded against. Settles the position's funding,
+/// then closes it at an admin provided settlement price without touching the amm and books the pnl
+/// against it. Emits the same PositionChanged and PositionClosed events as a close. Returns the same
+/// (base asset value, base asset amount) as close.
+pub fn force_close_position(
+    user: &mut Account<User>,
+    market_index: u64,
+    market: &mut Market,
+    market_position: &mut MarketPosition,
+    settlement_price: u128,
+) -> ClearingHouseResult<(u128, i128)> {
+    validate_position_for_market(market_position, market_index)?;
+    controller::funding::settle_position_funding_payment(user, market, market_position)?;
+
+    if market_position.base_asset_amount == 0 {
+        return Ok((0, 0));
+    }
+    let position_before = *market_position;
+
+    let base_asset_value = market_position
+        .base_asset_amount
+        .unsigned_abs()
+        .checked_mul(settlement_price)
+        .ok_or_else(math_error!())?
+        .checked_div(MARK_PRICE_PRECISION * AMM_TO_QUOTE_PRECISION_RATIO)
+        .ok_or_else(math_error!())?;
+    let pnl = calculate_pnl(
+        base_asset_value,
+        market_position.quote_asset_amount,
+        swap_direction_to_close_position(market_position.base_asset_amount),
+    )?;
+
+    book_pnl_to_collateral(user, market, pnl)?;
+    record_realized_pnl(user, pnl)?;
+    market_position.apply_funding_snapshot(0, 0);
+
+    market.open_interest = market
+        .open_interest
+        .checked_sub(1)
+        .ok_or_else(wrap_error!(ErrorCode::OpenInterestOverflow))?;
+
+    market_position.quote_asset_amount = 0;
+
+    market.base_asset_amount = market
+        .base_asset_amount
+        .checked_sub(market_position.base_asset_amount)
+        .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+
+    if market_position.base_asset_amount > 0 {
+        market.base_asset_amount_long = market
+            .base_asset_amount_long
+            .checked_sub(market_position.base_asset_amount)
+            .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+    } else {
+        market.base_asset_amount_short = market
+            .base_asset_amount_short
+            .checked_sub(market_position.base_asset_amount)
+            .ok_or_else(wrap_error!(ErrorCode::PositionSizeOverflow))?;
+    }
+
+    let base_asset_amount = market_position.base_asset_amount;
+    market_position.base_asset_amount = 0;
+    market.update_entry_quote_asset_amount(&position_before, market_position)?;
+    emit_position_changed(
+        user,
+        direction_to_close_position(position_before.base_asset_amount),
+        &position_before,
+        market_position,
+        pnl,
+        base_asset_value,
+    )?;
+    // there's no closing fee, so the position already holds everything it paid
+    emit_position_closed(user, market_index, pnl, market_position)?;
+
+    Ok((base_asset_value, base_asset_amount))
+}
+
+/// Closes a position in an expired market at the settlement price the admin set for it, through the
+/// same accounting as force_close_position. Returns the same (base asset value, base asset amount)
+/// as close.
+pub fn settle_expired_position(
+    user: &mut Account<User>,
+    market_index: u64,
+    market: &mut Market,
+    market_position: &mut MarketPosition,
+    now: i64,
+) -> ClearingHouseResult<(u128, i128)> {
+    if !market.is_expired(now) {
+        return Err(ErrorCode::MarketNotExpired);
+    }
+
+    let settlement_price = market.settlement_price;
+    if settlement_price == 0 {
+        return Err(ErrorCode::InvalidSettlementPrice);
+    }
+
+    force_close_position(
+        user,
+        market_index,
+        market,
+        market_position,
+        settlement_price,
+    )
+}
+
+/// Reprices a liquidation fill to the oracle band. The difference between the AMM fill and the
+/// banded price is settled between the user and the market's fee pool. Returns the banded quote
+/// asset amount.
+#[allow(clippy::too_many_arguments)]
+pub fn apply_liquidation_oracle_band(
+    user: &mut Account<User>,
+    market: &mut Market,
+    direction_to_close: PositionDirection,
+    base_asset_amount: u128,
+    quote_asset_amount: u128,
+    oracle_price: i128,
+    band_numerator: u64,
+    band_denominator: u64,
+) -> ClearingHouseResult<u128> {
+    let banded_quote_asset_amount = calculate_oracle_band_quote_asset_amount(
+        base_asset_amount,
+        quote_asset_amount,
+        oracle_price,
+        band_numerator,
+        band_denominator,
+    )?;
+
+    if banded_quote_asset_amount == quote_asset_amount {
+        return Ok(quote_asset_amount);
+    }
+
+    // closing a long sells base (user receives quote), closing a short buys base (user pays quote)
+    let quote_asset_amount_difference = cast_to_i128(banded_quote_asset_amount)?
+        .checked_sub(cast(quote_asset_amount)?)
+        .ok_or_else(math_error!())?;
+    let user_adjustment = match direction_to_close {
+        PositionDirection::Short => quote_asset_amount_difference,
+        PositionDirection::Long => -quote_asset_amount_difference,
+    };
+
+    if user_adjustment > 0 {
+        // the fee pool can only hand back what it has collected
+        let user_adjustment = user_adjustment
+            .unsigned_abs()
+            .min(market.amm.total_fee_minus_distributions);
+        market.amm.total_fee_minus_distributions = market
+            .amm
+            .total_fee_minus_distributions
+            .checked_sub(user_adjustment)
+            .ok_or_else(math_error!())?;
+        user.collateral = user
+            .collateral
+            .checked_add(user_adjustment)
+            .ok_or_else(math_error!())?;
+    } else {
+        let collateral_before = user.collateral;
+        user.collateral = calculate_updated_collateral(user.collateral, user_adjustment)?;
+        market.amm.total_fee_minus_distributions = market
+            .amm
+            .total_fee_minus_distributions
+            .checked_add(
+                collateral_before
+                    .checked_sub(user.collateral)
+                    .ok_or_else(math_error!())?,
+            )
+            .ok_or_else(math_error!())?;
+    }
+
+    Ok(banded_quote_asset_amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, MARK_PRICE_PRECISION, PEG_PRECISION, PNL_VELOCITY_WINDOW,
+        QUOTE_PRECISION,
+    };
+    use crate::state::market::AMM;
+    use anchor_lang::Discriminator;
+
+    const MARGIN_RATIO_INITIAL: u128 = 2000;
+
+    /// A market priced at 1, with the reserves the ts tests initialize markets with
+    fn market() -> Market {
+        let reserve = 5 * 10_u128.pow(18);
+        Market {
+            initialized: true,
+            amm: AMM {
+                base_asset_reserve: reserve,
+                quote_asset_reserve: reserve,
+                sqrt_k: reserve,
+                peg_multiplier: PEG_PRECISION,
+                funding_period: 3600,
+                ..AMM::default()
+            },
+            ..Market::default()
+        }
+    }
+
+    /// Serialized user account data, to back an Account<User> in user_account
+    fn user_account_data(collateral: u128) -> Vec<u8> {
+        let mut data = User::discriminator().to_vec();
+        User {
+            collateral,
+            ..User::default()
+        }
+        .serialize(&mut data)
+        .unwrap();
+        data
+    }
+
+    fn user_account<'a>(
+        key: &'a Pubkey,
+        lamports: &'a mut u64,
+        data: &'a mut [u8],
+    ) -> Account<'a, User> {
+        let account_info = AccountInfo::new(key, false, true, lamports, data, &crate::ID, false, 0);
+        Account::try_from(&account_info).unwrap()
+    }
+
+    #[test]
+    fn reduce_to_zero_decrements_open_interest_by_one() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        let mut market_position = MarketPosition::default();
+
+        increase(
+            PositionDirection::Long,
+            10 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            MARGIN_RATIO_INITIAL,
+            None,
+        )
+        .unwrap();
+        assert_eq!({ market.open_interest }, 1);
+
+        let (base_asset_value, _) = _calculate_base_asset_value_and_pnl(
+            market_position.base_asset_amount,
+            market_position.quote_asset_amount,
+            &market.amm,
+        )
+        .unwrap();
+        reduce(
+            PositionDirection::Short,
+            base_asset_value,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!({ market_position.base_asset_amount }, 0);
+        assert_eq!({ market.open_interest }, 0);
+    }
+
+    #[test]
+    fn partial_reduce_leaves_open_interest() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        let mut market_position = MarketPosition::default();
+
+        increase(
+            PositionDirection::Long,
+            10 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            MARGIN_RATIO_INITIAL,
+            None,
+        )
+        .unwrap();
+
+        reduce(
+            PositionDirection::Short,
+            5 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            None,
+        )
+        .unwrap();
+
+        assert!(market_position.base_asset_amount > 0);
+        assert_eq!({ market.open_interest }, 1);
+    }
+
+    /// A long position of 10 base asset units entered for quote_asset_amount, counted in market
+    fn long_position(market: &mut Market, quote_asset_amount: u128) -> MarketPosition {
+        let base_asset_amount = 10 * AMM_RESERVE_PRECISION as i128;
+        market.base_asset_amount += base_asset_amount;
+        market.base_asset_amount_long += base_asset_amount;
+        market.open_interest += 1;
+        MarketPosition {
+            base_asset_amount,
+            quote_asset_amount,
+            ..MarketPosition::default()
+        }
+    }
+
+    #[test]
+    fn increase_surfaces_position_size_overflow() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        let mut market_position = long_position(&mut market, 10 * QUOTE_PRECISION);
+        market_position.base_asset_amount = i128::MAX - 1;
+
+        let result = increase(
+            PositionDirection::Long,
+            10 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            MARGIN_RATIO_INITIAL,
+            None,
+        );
+        assert!(matches!(result, Err(ErrorCode::PositionSizeOverflow)));
+    }
+
+    #[test]
+    fn increase_surfaces_open_interest_overflow() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        market.open_interest = u128::MAX;
+
+        let result = increase(
+            PositionDirection::Long,
+            10 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut MarketPosition::default(),
+            0,
+            MARGIN_RATIO_INITIAL,
+            None,
+        );
+        assert!(matches!(result, Err(ErrorCode::OpenInterestOverflow)));
+    }
+
+    #[test]
+    fn reduce_surfaces_quote_accumulation_overflow() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        // the entry quote scaled by the base asset closed no longer fits
+        let mut market_position = long_position(&mut market, i128::MAX as u128);
+
+        let result = reduce(
+            PositionDirection::Short,
+            5 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            None,
+        );
+        assert!(matches!(result, Err(ErrorCode::QuoteAccumulationOverflow)));
+    }
+
+    #[test]
+    fn reduce_surfaces_open_interest_overflow() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        let mut market_position = MarketPosition::default();
+
+        increase(
+            PositionDirection::Long,
+            10 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            MARGIN_RATIO_INITIAL,
+            None,
+        )
+        .unwrap();
+        market.open_interest = 0;
+
+        let (base_asset_value, _) = _calculate_base_asset_value_and_pnl(
+            market_position.base_asset_amount,
+            market_position.quote_asset_amount,
+            &market.amm,
+        )
+        .unwrap();
+        let result = reduce(
+            PositionDirection::Short,
+            base_asset_value,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            None,
+        );
+        assert!(matches!(result, Err(ErrorCode::OpenInterestOverflow)));
+    }
+
+    #[test]
+    fn close_surfaces_pnl_calculation_overflow() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        let mut market_position = long_position(&mut market, u128::MAX);
+
+        let result = close(&mut user, 0, &mut market, &mut market_position, 0);
+        assert!(matches!(result, Err(ErrorCode::PnlCalculationOverflow)));
+    }
+
+    #[test]
+    fn close_surfaces_open_interest_overflow() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        let mut market_position = long_position(&mut market, 10 * QUOTE_PRECISION);
+        market.open_interest = 0;
+
+        let result = close(&mut user, 0, &mut market, &mut market_position, 0);
+        assert!(matches!(result, Err(ErrorCode::OpenInterestOverflow)));
+    }
+
+    #[test]
+    fn close_surfaces_position_size_overflow() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        let mut market_position = long_position(&mut market, 10 * QUOTE_PRECISION);
+        market.base_asset_amount = i128::MIN;
+
+        let result = close(&mut user, 0, &mut market, &mut market_position, 0);
+        assert!(matches!(result, Err(ErrorCode::PositionSizeOverflow)));
+    }
+
+    #[test]
+    fn trade_outcome_is_independent_of_now() {
+        let trade = |now: i64| {
+            let key = Pubkey::default();
+            let mut lamports = 0;
+            let mut data = user_account_data(100 * QUOTE_PRECISION);
+            let mut user = user_account(&key, &mut lamports, &mut data);
+            let mut market = market();
+            let mut market_position = MarketPosition::default();
+
+            increase(
+                PositionDirection::Long,
+                10 * QUOTE_PRECISION,
+                &mut user,
+                0,
+                &mut market,
+                &mut market_position,
+                now,
+                MARGIN_RATIO_INITIAL,
+                None,
+            )
+            .unwrap();
+            reduce(
+                PositionDirection::Short,
+                4 * QUOTE_PRECISION,
+                &mut user,
+                0,
+                &mut market,
+                &mut market_position,
+                now,
+                None,
+            )
+            .unwrap();
+
+            (
+                market_position.base_asset_amount,
+                market_position.quote_asset_amount,
+                user.collateral,
+                user.total_realized_pnl,
+                market.amm.base_asset_reserve,
+                market.amm.quote_asset_reserve,
+            )
+        };
+
+        assert_eq!(trade(1_000), trade(1_000_000));
+    }
+
+    #[test]
+    fn position_direction_serializes_by_variant_order() {
+        assert_eq!(PositionDirection::Long.try_to_vec().unwrap(), vec![0]);
+        assert_eq!(PositionDirection::Short.try_to_vec().unwrap(), vec![1]);
+
+        for direction in [PositionDirection::Long, PositionDirection::Short] {
+            let bytes = direction.try_to_vec().unwrap();
+            assert!(PositionDirection::try_from_slice(&bytes).unwrap() == direction);
+        }
+    }
+
+    #[test]
+    fn settle_position_pnl_settles_funding_first() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        let mut market_position = MarketPosition::default();
+
+        increase(
+            PositionDirection::Long,
+            10 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            MARGIN_RATIO_INITIAL,
+            None,
+        )
+        .unwrap();
+        market.amm.cumulative_funding_rate_long = MARK_PRICE_PRECISION as i128;
+        market.amm.last_funding_rate_ts = 3600;
+
+        settle_position_pnl(&mut user, 0, &mut market, &mut market_position, 3600).unwrap();
+
+        assert_eq!(
+            { market_position.last_cumulative_funding_rate },
+            MARK_PRICE_PRECISION as i128
+        );
+        assert_eq!({ market_position.last_funding_rate_ts }, 3600);
+        // the long paid funding as the cumulative rate rose
+        assert!({ market_position.total_funding_payment } < 0);
+    }
+
+    #[test]
+    fn increase_up_to_the_max_quote_asset_amount() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        market.max_quote_asset_amount = (20 * QUOTE_PRECISION) as u64;
+        let mut market_position = MarketPosition::default();
+
+        for quote_asset_amount in [15 * QUOTE_PRECISION, 5 * QUOTE_PRECISION] {
+            increase(
+                PositionDirection::Long,
+                quote_asset_amount,
+                &mut user,
+                0,
+                &mut market,
+                &mut market_position,
+                0,
+                MARGIN_RATIO_INITIAL,
+                None,
+            )
+            .unwrap();
+        }
+        assert_eq!({ market_position.quote_asset_amount }, 20 * QUOTE_PRECISION);
+
+        let position_at_cap = market_position;
+        let result = increase(
+            PositionDirection::Long,
+            QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            MARGIN_RATIO_INITIAL,
+            None,
+        );
+        assert!(matches!(result, Err(ErrorCode::MaxPositionSizeExceeded)));
+        assert_eq!({ market_position.base_asset_amount }, {
+            position_at_cap.base_asset_amount
+        });
+        assert_eq!({ market_position.quote_asset_amount }, {
+            position_at_cap.quote_asset_amount
+        });
+    }
+
+    #[test]
+    fn reduce_with_base_asset_amount_returns_the_amount_applied() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        let mut market_position = MarketPosition::default();
+
+        increase(
+            PositionDirection::Long,
+            10 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            MARGIN_RATIO_INITIAL,
+            None,
+        )
+        .unwrap();
+        let base_asset_amount = market_position.base_asset_amount.unsigned_abs();
+
+        let (base_asset_amount_applied, _, _) = reduce_with_base_asset_amount(
+            base_asset_amount / 4,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+        )
+        .unwrap();
+        assert_eq!(base_asset_amount_applied, base_asset_amount / 4);
+
+        // asking for everything that's left closes the position and reports all of it as applied
+        let remaining_base_asset_amount = market_position.base_asset_amount.unsigned_abs();
+        let (base_asset_amount_applied, _, _) = reduce_with_base_asset_amount(
+            remaining_base_asset_amount,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+        )
+        .unwrap();
+        assert_eq!(base_asset_amount_applied, remaining_base_asset_amount);
+        assert_eq!({ market_position.base_asset_amount }, 0);
+        assert_eq!({ market.open_interest }, 0);
+    }
+
+    #[test]
+    fn position_for_another_market_is_rejected_untouched() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        let mut market_position = long_position(&mut market, 10 * QUOTE_PRECISION);
+        market_position.market_index = 1;
+        let position_before = market_position;
+        let base_asset_reserve_before = market.amm.base_asset_reserve;
+
+        let result = increase(
+            PositionDirection::Long,
+            10 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            MARGIN_RATIO_INITIAL,
+            None,
+        );
+        assert!(matches!(result, Err(ErrorCode::PositionMarketMismatch)));
+        let result = reduce(
+            PositionDirection::Short,
+            5 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            None,
+        );
+        assert!(matches!(result, Err(ErrorCode::PositionMarketMismatch)));
+        let result = close(&mut user, 0, &mut market, &mut market_position, 0);
+        assert!(matches!(result, Err(ErrorCode::PositionMarketMismatch)));
+
+        assert_eq!({ market_position.base_asset_amount }, {
+            position_before.base_asset_amount
+        });
+        assert_eq!({ market_position.quote_asset_amount }, {
+            position_before.quote_asset_amount
+        });
+        assert_eq!({ market.amm.base_asset_reserve }, base_asset_reserve_before);
+        assert_eq!(user.collateral, 100 * QUOTE_PRECISION);
+    }
+
+    #[test]
+    fn position_index_ref_finds_only_open_positions() {
+        let mut user_positions = UserPositions::default();
+        // a closed position in market 2 and an open one in market 3
+        user_positions.positions[0].market_index = 2;
+        user_positions.positions[1] = MarketPosition {
+            market_index: 3,
+            base_asset_amount: -1,
+            ..MarketPosition::default()
+        };
+
+        assert_eq!(get_position_index_ref(&user_positions, 3).unwrap(), 1);
+        assert!(matches!(
+            get_position_index_ref(&user_positions, 2),
+            Err(ErrorCode::UserHasNoPositionInMarket)
+        ));
+        // the default slots are for market 0 but aren't open positions in it either
+        assert!(matches!(
+            get_position_index_ref(&user_positions, 0),
+            Err(ErrorCode::UserHasNoPositionInMarket)
+        ));
+    }
+
+    #[test]
+    fn partial_reduces_realize_the_blended_fills() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        let mut market_position = MarketPosition::default();
+
+        increase(
+            PositionDirection::Long,
+            10 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            MARGIN_RATIO_INITIAL,
+            None,
+        )
+        .unwrap();
+        // someone else buys after the open, so each fill below is at a different price
+        controller::amm::swap_quote_asset(
+            &mut market.amm,
+            1_000 * QUOTE_PRECISION,
+            SwapDirection::Add,
+            0,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let fills = [
+            2 * QUOTE_PRECISION,
+            3 * QUOTE_PRECISION,
+            4 * QUOTE_PRECISION,
+        ];
+        let mut realized_pnl = 0;
+        for quote_asset_amount in fills {
+            let (_, pnl) = reduce(
+                PositionDirection::Short,
+                quote_asset_amount,
+                &mut user,
+                0,
+                &mut market,
+                &mut market_position,
+                0,
+                None,
+            )
+            .unwrap();
+            assert!(pnl > 0);
+            realized_pnl += pnl;
+        }
+
+        // the quote the fills paid out less the part of the entry they closed
+        let entry_quote_asset_amount_closed =
+            10 * QUOTE_PRECISION - { market_position.quote_asset_amount };
+        let quote_asset_amount_filled: u128 = fills.iter().sum();
+        assert_eq!(
+            realized_pnl,
+            quote_asset_amount_filled as i128 - entry_quote_asset_amount_closed as i128
+        );
+        assert_eq!(user.total_realized_pnl, realized_pnl);
+        assert_eq!(
+            user.collateral as i128,
+            (100 * QUOTE_PRECISION) as i128 + realized_pnl
+        );
+    }
+
+    #[test]
+    fn position_after_matches_the_reduced_position() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        let mut market_position = MarketPosition::default();
+
+        increase(
+            PositionDirection::Long,
+            10 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            MARGIN_RATIO_INITIAL,
+            None,
+        )
+        .unwrap();
+        controller::amm::swap_quote_asset(
+            &mut market.amm,
+            1_000 * QUOTE_PRECISION,
+            SwapDirection::Add,
+            0,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let collateral_before = user.collateral;
+        let (_base_asset_swapped, position_after) = reduce_with_position_after(
+            PositionDirection::Short,
+            4 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            None,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(position_after.base_asset_amount, {
+            market_position.base_asset_amount
+        });
+        assert_eq!(position_after.quote_asset_amount, {
+            market_position.quote_asset_amount
+        });
+        assert_eq!(
+            position_after.entry_price,
+            calculate_entry_price(
+                market_position.quote_asset_amount,
+                market_position.base_asset_amount
+            )
+            .unwrap()
+        );
+        assert!(position_after.realized_pnl > 0);
+        assert_eq!(
+            user.collateral as i128,
+            collateral_before as i128 + position_after.realized_pnl
+        );
+    }
+
+    #[test]
+    fn close_with_proceeds_outside_the_notional_bounds_is_rejected() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        let mut market_position = MarketPosition::default();
+
+        increase(
+            PositionDirection::Long,
+            10 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            MARGIN_RATIO_INITIAL,
+            None,
+        )
+        .unwrap();
+        let (quote_asset_amount, _base_asset_amount, _pnl) =
+            close_with_pnl(&mut user, 0, &mut market, &mut market_position, 0, None).unwrap();
+
+        // a floor a quantum above the proceeds reverts the close
+        assert!(matches!(
+            validate_quote_asset_amount_out(quote_asset_amount, quote_asset_amount + 1, 0),
+            Err(ErrorCode::QuoteAssetAmountOutOfBounds)
+        ));
+        assert!(matches!(
+            validate_quote_asset_amount_out(quote_asset_amount, 0, quote_asset_amount - 1),
+            Err(ErrorCode::QuoteAssetAmountOutOfBounds)
+        ));
+        assert!(validate_quote_asset_amount_out(
+            quote_asset_amount,
+            quote_asset_amount,
+            quote_asset_amount
+        )
+        .is_ok());
+        assert!(validate_quote_asset_amount_out(quote_asset_amount, 0, 0).is_ok());
+    }
+
+    #[test]
+    fn rapid_realized_pnl_trips_the_pnl_velocity_throttle() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        let mut market_position = MarketPosition::default();
+
+        increase(
+            PositionDirection::Long,
+            10 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            MARGIN_RATIO_INITIAL,
+            None,
+        )
+        .unwrap();
+        controller::amm::swap_quote_asset(
+            &mut market.amm,
+            1_000_000 * QUOTE_PRECISION,
+            SwapDirection::Add,
+            0,
+            None,
+            None,
+        )
+        .unwrap();
+
+        // realizing a profit a second apart, the first take passes a throttle the third trips
+        let mut realized_pnl = vec![];
+        let mut pnl_velocity = vec![];
+        for now in 1..=3 {
+            let (_, pnl) = reduce(
+                PositionDirection::Short,
+                2 * QUOTE_PRECISION,
+                &mut user,
+                0,
+                &mut market,
+                &mut market_position,
+                now,
+                None,
+            )
+            .unwrap();
+            realized_pnl.push(pnl);
+            pnl_velocity.push(user.pnl_velocity);
+        }
+        let max_pnl_velocity = (realized_pnl[0] * 3 / 2) as u64;
+        assert!(realized_pnl[0] > 0);
+        assert!(pnl_velocity[0].unsigned_abs() <= max_pnl_velocity);
+        assert!({ user.pnl_velocity }.unsigned_abs() > max_pnl_velocity);
+        assert!(matches!(
+            validate_pnl_velocity(&user, max_pnl_velocity),
+            Err(ErrorCode::PnlVelocityExceeded)
+        ));
+        // 0 turns the throttle off
+        assert!(validate_pnl_velocity(&user, 0).is_ok());
+
+        // once the window has passed, the earlier takes no longer count
+        let (_, pnl) = reduce(
+            PositionDirection::Short,
+            QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            3 + PNL_VELOCITY_WINDOW as i64,
+            None,
+        )
+        .unwrap();
+        assert_eq!({ user.pnl_velocity }, pnl as i64);
+        assert!(validate_pnl_velocity(&user, max_pnl_velocity).is_ok());
+    }
+
+    #[test]
+    fn realized_pnl_against_the_fill_direction_is_rejected() {
+        // longs and shorts, each realizing a gain and then a loss as the mark moves past entry
+        for direction in [PositionDirection::Long, PositionDirection::Short] {
+            let key = Pubkey::default();
+            let mut lamports = 0;
+            let mut data = user_account_data(100 * QUOTE_PRECISION);
+            let mut user = user_account(&key, &mut lamports, &mut data);
+            let mut market = market();
+            let mut market_position = MarketPosition::default();
+            let (direction_to_close, winning_swap, losing_swap) = match direction {
+                PositionDirection::Long => (
+                    PositionDirection::Short,
+                    SwapDirection::Add,
+                    SwapDirection::Remove,
+                ),
+                PositionDirection::Short => (
+                    PositionDirection::Long,
+                    SwapDirection::Remove,
+                    SwapDirection::Add,
+                ),
+            };
+
+            increase(
+                direction,
+                10 * QUOTE_PRECISION,
+                &mut user,
+                0,
+                &mut market,
+                &mut market_position,
+                0,
+                MARGIN_RATIO_INITIAL,
+                None,
+            )
+            .unwrap();
+
+            // the losing swap undoes the winning one and then moves the mark as far past entry
+            for (swap_direction, quote_asset_amount, is_gain) in [
+                (winning_swap, 100_000 * QUOTE_PRECISION, true),
+                (losing_swap, 200_000 * QUOTE_PRECISION, false),
+            ] {
+                controller::amm::swap_quote_asset(
+                    &mut market.amm,
+                    quote_asset_amount,
+                    swap_direction,
+                    0,
+                    None,
+                    None,
+                )
+                .unwrap();
+                let (_, pnl) = reduce(
+                    direction_to_close,
+                    QUOTE_PRECISION,
+                    &mut user,
+                    0,
+                    &mut market,
+                    &mut market_position,
+                    0,
+                    None,
+                )
+                .unwrap();
+                assert_eq!(pnl > 0, is_gain);
+            }
+        }
+
+        // a long gaining on a fill under its entry, and a short on a fill over it
+        assert!(matches!(
+            validate_pnl_direction(1, true, 9, 10, 10, 10),
+            Err(ErrorCode::InvalidPnlCalculation)
+        ));
+        assert!(matches!(
+            validate_pnl_direction(1, false, 11, 10, 10, 10),
+            Err(ErrorCode::InvalidPnlCalculation)
+        ));
+        // or losing on a fill in their favor
+        assert!(matches!(
+            validate_pnl_direction(-1, true, 11, 10, 10, 10),
+            Err(ErrorCode::InvalidPnlCalculation)
+        ));
+        assert!(validate_pnl_direction(1, true, 11, 10, 10, 10).is_ok());
+        assert!(validate_pnl_direction(0, true, 9, 10, 10, 10).is_ok());
+    }
+
+    #[test]
+    fn funding_snapshot_follows_open_settle_and_close() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        market.amm.cumulative_funding_rate_long = 7;
+        market.amm.cumulative_funding_rate_short = -3;
+        market.amm.last_funding_rate_ts = 100;
+        let mut market_position = MarketPosition::default();
+
+        // opening short snapshots the short side's rate
+        increase(
+            PositionDirection::Short,
+            10 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            MARGIN_RATIO_INITIAL,
+            None,
+        )
+        .unwrap();
+        assert_eq!({ market_position.last_cumulative_funding_rate }, -3);
+        assert_eq!({ market_position.last_funding_rate_ts }, 100);
+
+        // settling catches the snapshot up with the market
+        market.amm.cumulative_funding_rate_long = 12;
+        market.amm.cumulative_funding_rate_short = 2;
+        market.amm.last_funding_rate_ts = 200;
+        controller::funding::settle_position_funding_payment(
+            &mut user,
+            &market,
+            &mut market_position,
+        )
+        .unwrap();
+        assert_eq!({ market_position.last_cumulative_funding_rate }, 2);
+        assert_eq!({ market_position.last_funding_rate_ts }, 200);
+
+        // and closing zeroes it
+        close(&mut user, 0, &mut market, &mut market_position, 0).unwrap();
+        assert_eq!({ market_position.last_cumulative_funding_rate }, 0);
+        assert_eq!({ market_position.last_funding_rate_ts }, 0);
+    }
+
+    #[test]
+    fn reducing_one_more_than_the_position_is_rejected() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = user_account_data(100 * QUOTE_PRECISION);
+        let mut user = user_account(&key, &mut lamports, &mut data);
+        let mut market = market();
+        let mut market_position = MarketPosition::default();
+
+        increase(
+            PositionDirection::Short,
+            10 * QUOTE_PRECISION,
+            &mut user,
+            0,
+            &mut market,
+            &mut market_position,
+            0,
+            MARGIN_RATIO_INITIAL,
+            None,
+        )
+        .unwrap();
+        let position_before = market_position;
+        let (base_asset_value, _unrealized_pnl) =
+            market_position.notional_and_pnl(&market).unwrap();
+
+        assert!(matches!(
+            reduce(
+                PositionDirection::Long,
+                base_asset_value + 1,
+                &mut user,
+                0,
+                &mut market,
+                &mut market_position,
+                0,
+                None,
+            ),
+            Err(ErrorCode::TriedToReduceBeyondPositionSize)
+        ));
+        assert!(matches!(
+            reduce_with_base_asset_amount(
+                market_position.base_asset_amount.unsigned_abs() + 1,
+                &mut user,
+                0,
+                &mut market,
+                &mut market_position,
+                0,
+            ),
+            Err(ErrorCode::TriedToReduceBeyondPositionSize)
+        ));
+
+        // neither flipped the short
+        assert_eq!({ market_position.base_asset_amount }, {
+            position_before.base_asset_amount
+        });
+        assert_eq!({ market_position.quote_asset_amount }, {
+            position_before.quote_asset_amount
+        });
+    }
+
+    #[test]
+    fn all_in_price_is_the_fill_price_plus_the_fee() {
+        let market = market();
+        // 10bps
+        let fee_structure = FeeStructure {
+            fee_numerator: 1,
+            fee_denominator: 1000,
+            ..FeeStructure::default()
+        };
+        let quote_asset_amount = 100 * QUOTE_PRECISION;
+
+        for (direction, swap_direction) in [
+            (PositionDirection::Long, SwapDirection::Add),
+            (PositionDirection::Short, SwapDirection::Remove),
+        ] {
+            let (base_asset_amount, _) = controller::amm::simulate_swap_quote_asset(
+                &market.amm,
+                quote_asset_amount,
+                swap_direction,
+            )
+            .unwrap();
+            let fill_price =
+                quote_asset_amount * MARK_PRICE_PRECISION * AMM_TO_QUOTE_PRECISION_RATIO
+                    / base_asset_amount.unsigned_abs();
+
+            let all_in_price =
+                get_all_in_fill_price(&market, quote_asset_amount, direction, &fee_structure)
+                    .unwrap();
+
+            // longs pay the fee on top of a fill above the mark, shorts receive less below it
+            let mark_price = market.amm.mark_price().unwrap();
+            let expected_all_in_price = match direction {
+                PositionDirection::Long => {
+                    assert!(all_in_price > fill_price && fill_price > mark_price);
+                    fill_price * 1001 / 1000
+                }
+                PositionDirection::Short => {
+                    assert!(all_in_price < fill_price && fill_price < mark_price);
+                    fill_price * 999 / 1000
+                }
+            };
+            assert!(all_in_price.abs_diff(expected_all_in_price) <= 1);
+        }
+    }
 }
@@ -1,4 +1,5 @@
 use crate::error::*;
+use crate::math::oracle::OraclePriceData;
 use crate::math::{amm, repeg};
 
 use crate::math::constants::{
@@ -29,8 +30,11 @@ pub fn repeg(
 
     let adjustment_cost = repeg::adjust_peg_cost(market, new_peg_candidate)?;
 
-    let (oracle_price, _oracle_twap, oracle_conf, _oracle_twac, _oracle_delay) =
-        market.amm.get_oracle_price(price_oracle, clock_slot)?;
+    let OraclePriceData {
+        price: oracle_price,
+        confidence: oracle_conf,
+        ..
+    } = market.amm.get_oracle_price(price_oracle, clock_slot)?;
 
     let oracle_is_valid = amm::is_oracle_valid(
         &market.amm,
@@ -82,6 +82,76 @@ pub enum ErrorCode {
     InvalidFundingProfitability,
     #[msg("Casting Failure")]
     CastingFailure,
+    #[msg("Correlation must be below 10000 bps")]
+    InvalidCorrelation,
+    #[msg("Position size exceeds the market's max position size")]
+    MaxPositionSizeExceeded,
+    #[msg("Max number of orders taken")]
+    MaxNumberOfOrders,
+    #[msg("Order does not exist")]
+    OrderDoesNotExist,
+    #[msg("Invalid order")]
+    InvalidOrder,
+    #[msg("Order's fill condition isn't met")]
+    CouldNotFillOrder,
+    #[msg("Position is for a different market")]
+    PositionMarketMismatch,
+    #[msg("Mark price is too far from the mark twap to place resting orders")]
+    MarkTwapDivergence,
+    #[msg("Position size overflow")]
+    PositionSizeOverflow,
+    #[msg("Quote asset amount accumulation overflow")]
+    QuoteAccumulationOverflow,
+    #[msg("Pnl calculation overflow")]
+    PnlCalculationOverflow,
+    #[msg("Open interest overflow")]
+    OpenInterestOverflow,
+    #[msg("Position would be immediately liquidatable")]
+    PositionWouldBeLiquidatable,
+    #[msg("Quote asset amount out of bounds")]
+    QuoteAssetAmountOutOfBounds,
+    #[msg("Invalid settlement price")]
+    InvalidSettlementPrice,
+    #[msg("Pnl velocity exceeded")]
+    PnlVelocityExceeded,
+    #[msg("Invalid insurance fund fee share")]
+    InvalidInsuranceFundFeeShare,
+    #[msg("Invalid pnl calculation")]
+    InvalidPnlCalculation,
+    #[msg("Fill too small")]
+    FillTooSmall,
+    #[msg("Keeper token not found")]
+    KeeperTokenNotFound,
+    #[msg("Trade does not reverse the position")]
+    InvalidPositionReversal,
+    #[msg("Position index out of range")]
+    InvalidPositionIndex,
+    #[msg("Tried to reduce a position by more than its size")]
+    TriedToReduceBeyondPositionSize,
+    #[msg("Timestamp is outside its valid range")]
+    InvalidTimestamp,
+    #[msg("Market open interest would exceed its maximum")]
+    MaxOpenInterestExceeded,
+    #[msg("Swap would move the mark price too far from the oracle price")]
+    OracleMarkTooDivergent,
+    #[msg("Market has expired")]
+    MarketExpired,
+    #[msg("Market has not expired")]
+    MarketNotExpired,
+    #[msg("Trade would acquire less base asset than the minimum")]
+    SlippageTooLarge,
+    #[msg("AMM reserves drifted from the invariant")]
+    InvalidAMMInvariant,
+    #[msg("User has not opted in to auto deleveraging")]
+    AutoDeleverageNotEnabled,
+    #[msg("Oracle weight must be between 0 and 10000 bps")]
+    InvalidOracleWeight,
+    #[msg("Collateral weight must be between 0 and 10000 bps")]
+    InvalidCollateralWeight,
+    #[msg("Liquidation oracle band must be greater than 0 and below 100%")]
+    InvalidOracleBand,
+    #[msg("Markets account isn't in the legacy layout")]
+    InvalidLegacyMarkets,
 }
 
 #[macro_export]
@@ -4,12 +4,18 @@ use borsh::{BorshDeserialize, BorshSerialize};
 use context::*;
 use controller::position::PositionDirection;
 use error::*;
-use math::{amm, bn, constants::*, fees, margin::*, position::*, withdrawal::*};
+use math::{
+    amm, bn, constants::*, fees, margin::*, oracle::OraclePriceData, position::*, withdrawal::*,
+};
 use state::{
     history::trade::TradeRecord,
-    market::{Market, Markets, OracleSource, AMM},
+    market::{
+        FeeDenomination, FundingPriceAnchor, Market, Markets, OracleObservation, OracleSource, AMM,
+        ORACLE_OBSERVATIONS_SIZE,
+    },
     state::*,
     user::{MarketPosition, User},
+    user_orders::Order,
 };
 
 pub mod context;
@@ -31,6 +37,8 @@ pub mod clearing_house {
     use crate::state::history::curve::ExtendedCurveRecord;
     use crate::state::history::deposit::{DepositDirection, DepositRecord};
     use crate::state::history::liquidation::LiquidationRecord;
+    use crate::state::legacy_market::LegacyMarkets;
+    use anchor_lang::Discriminator;
 
     use super::*;
     use crate::math::casting::{cast, cast_to_i128, cast_to_u128};
@@ -144,11 +152,14 @@ pub mod clearing_house {
                 use_for_liquidations: true,
             },
             extended_curve_history: Pubkey::default(),
-            padding0: 0,
-            padding1: 0,
-            padding2: 0,
-            padding3: 0,
-            padding4: 0,
+            liquidation_oracle_band_numerator: 2,
+            liquidation_oracle_band_denominator: 100,
+            mark_twap_divergence_numerator: 1,
+            mark_twap_divergence_denominator: 20,
+            max_pnl_velocity: 0,
+            keeper_mint: Pubkey::default(),
+            dust_collateral_threshold: 0,
+            secondary_collateral_weight_bps: 0,
             padding5: 0,
         };
 
@@ -227,17 +238,45 @@ pub mod clearing_house {
             .ok_or_else(math_error!())?;
 
         // Verify oracle is readable
-        let (oracle_price, oracle_price_twap, _, _, _) = market
+        let OraclePriceData {
+            price: oracle_price,
+            twap: oracle_price_twap,
+            ..
+        } = market
             .amm
             .get_oracle_price(&ctx.accounts.oracle, clock_slot)
             .unwrap();
 
-        let market = Market {
+        let mut market = Market {
             initialized: true,
             base_asset_amount_long: 0,
             base_asset_amount_short: 0,
             base_asset_amount: 0,
             open_interest: 0,
+            asset_group: 0,
+            correlation_bps: 0,
+            margin_oracle_weight_bps: 0,
+            max_quote_asset_amount: 0,
+            max_open_interest: 0,
+            max_position_base_asset_amount: 0,
+            margin_ratio_initial: 0,
+            fee_denomination: FeeDenomination::Quote,
+            insurance_fund_target: 0,
+            insurance_fund_fee_share_bps: 0,
+            total_fee_to_insurance_fund: 0,
+            total_fee_to_insurance_fund_settled: 0,
+            total_bad_debt: 0,
+            total_bad_debt_settled: 0,
+            funding_price_anchor: FundingPriceAnchor::MarkTwap,
+            cumulative_quote_volume: 0,
+            cumulative_base_volume: 0,
+            total_trader_pnl_paid: 0,
+            total_trader_pnl_collected: 0,
+            quote_asset_amount_long: 0,
+            quote_asset_amount_short: 0,
+            settle_pnl_separately: false,
+            expiry_ts: 0,
+            settlement_price: 0,
             padding0: 0,
             padding1: 0,
             padding2: 0,
@@ -266,12 +305,14 @@ pub mod clearing_house {
                 minimum_trade_size: 10000000,
                 last_oracle_price_twap_ts: now,
                 last_oracle_price: oracle_price,
-                padding1: 0,
-                padding2: 0,
-                padding3: 0,
-                padding4: 0,
+                oracle_observations_head: 0,
+                max_oracle_divergence_bps: 0,
+                oracle_observations: [OracleObservation::default(); ORACLE_OBSERVATIONS_SIZE],
             },
         };
+        market
+            .amm
+            .reset_oracle_observations(oracle_price_twap, now)?;
 
         markets.markets[Markets::index_from_u64(market_index)] = market;
 
@@ -290,14 +331,7 @@ pub mod clearing_house {
         let collateral_before = user.collateral;
         let cumulative_deposits_before = user.cumulative_deposits;
 
-        user.collateral = user
-            .collateral
-            .checked_add(cast(amount)?)
-            .ok_or_else(math_error!())?;
-        user.cumulative_deposits = user
-            .cumulative_deposits
-            .checked_add(cast(amount)?)
-            .ok_or_else(math_error!())?;
+        controller::collateral::deposit(user, amount)?;
 
         let markets = &ctx.accounts.markets.load()?;
         let user_positions = &mut ctx.accounts.user_positions.load_mut()?;
@@ -362,10 +396,6 @@ pub mod clearing_house {
             now,
         )?;
 
-        if cast_to_u128(amount)? > user.collateral {
-            return Err(ErrorCode::InsufficientCollateral.into());
-        }
-
         let (collateral_account_withdrawal, insurance_account_withdrawal) =
             calculate_withdrawal_amounts(
                 amount,
@@ -378,24 +408,15 @@ pub mod clearing_house {
             .checked_add(insurance_account_withdrawal)
             .ok_or_else(math_error!())?;
 
-        user.cumulative_deposits = user
-            .cumulative_deposits
-            .checked_sub(cast(amount_withdraw)?)
-            .ok_or_else(math_error!())?;
-
-        user.collateral = user
-            .collateral
-            .checked_sub(cast(collateral_account_withdrawal)?)
-            .ok_or_else(math_error!())?
-            .checked_sub(cast(insurance_account_withdrawal)?)
-            .ok_or_else(math_error!())?;
-
-        // Verify that the user doesn't enter liquidation territory if they withdraw
-        let (_total_collateral, _unrealized_pnl, _base_asset_value, margin_ratio) =
-            calculate_margin_ratio(user, user_positions, markets)?;
-        if margin_ratio < ctx.accounts.state.margin_ratio_initial {
-            return Err(ErrorCode::InsufficientCollateral.into());
-        }
+        // funding is settled, so the margin check sees up to date collateral
+        controller::collateral::withdraw(
+            user,
+            amount_withdraw,
+            user_positions,
+            markets,
+            ctx.accounts.state.margin_ratio_initial,
+            ctx.accounts.state.secondary_collateral_weight_bps,
+        )?;
 
         controller::token::send(
             &ctx.accounts.token_program,
@@ -446,6 +467,7 @@ pub mod clearing_house {
         market_index: u64,
         limit_price: u128,
         optional_accounts: ManagePositionOptionalAccounts,
+        min_base_asset_amount: u128,
     ) -> ProgramResult {
         let user = &mut ctx.accounts.user;
         let clock = Clock::get()?;
@@ -463,47 +485,11 @@ pub mod clearing_house {
             now,
         )?;
 
-        // Check if the user has an existing position for the market
-        let mut market_position = user_positions
-            .positions
-            .iter_mut()
-            .find(|market_position| market_position.is_for(market_index));
-
-        // If they don't have an existing position, look into the positions account for a spot for space
-        // for a new position
-        if market_position.is_none() {
-            let available_position_index = user_positions
-                .positions
-                .iter()
-                .position(|market_position| !market_position.is_open_position());
-
-            if available_position_index.is_none() {
-                return Err(ErrorCode::MaxNumberOfPositions.into());
-            }
-
-            let new_market_position = MarketPosition {
-                market_index,
-                base_asset_amount: 0,
-                quote_asset_amount: 0,
-                last_cumulative_funding_rate: 0,
-                last_cumulative_repeg_rebate: 0,
-                last_funding_rate_ts: 0,
-                stop_profit_price: 0,
-                stop_profit_amount: 0,
-                stop_loss_price: 0,
-                stop_loss_amount: 0,
-                transfer_to: Pubkey::default(),
-                padding0: 0,
-                padding1: 0,
-            };
-
-            user_positions.positions[available_position_index.unwrap()] = new_market_position;
-
-            market_position =
-                Some(&mut user_positions.positions[available_position_index.unwrap()]);
-        }
-
-        let market_position = market_position.unwrap();
+        // Check if the user has an existing position for the market. If they don't, claim a spot
+        // in the positions account for a new position
+        let position_index =
+            controller::position::get_or_add_position(user_positions, market_index)?;
+        let market_position = &mut user_positions.positions[position_index];
 
         // A trade is risk increasing if it increases the users leverage
         // If a trade is risk increasing and brings the user's margin ratio below initial requirement
@@ -516,10 +502,13 @@ pub mod clearing_house {
         let mark_price_before: u128;
         let oracle_mark_spread_pct_before: i128;
         let is_oracle_valid: bool;
+        let margin_ratio_initial: u128;
         {
             let market = &mut ctx.accounts.markets.load_mut()?.markets
                 [Markets::index_from_u64(market_index)];
             mark_price_before = market.amm.mark_price()?;
+            margin_ratio_initial =
+                market.get_margin_ratio_initial(ctx.accounts.state.margin_ratio_initial);
             let (oracle_price, _, _oracle_mark_spread_pct_before) =
                 amm::calculate_oracle_mark_spread_pct(
                     &market.amm,
@@ -537,12 +526,17 @@ pub mod clearing_house {
             )?;
 
             if is_oracle_valid {
-                amm::update_oracle_price_twap(&mut market.amm, now, oracle_price)?;
+                amm::update_oracle_twap(&mut market.amm, oracle_price, now)?;
             }
         }
 
         let mut quote_asset_amount = quote_asset_amount;
         let base_asset_amount;
+        // the part of the trade charged the quote fee, with its discounts and referral rewards
+        let quote_asset_amount_for_fee;
+        // on a base-denominated market, the fee skimmed from the base asset opened, valued at the
+        // fill price
+        let mut base_asset_fee_value = 0;
         // The trade increases the the user position if
         // 1) the user does not have a position
         // 2) the trade is in the same direction as the user's existing position
@@ -556,11 +550,37 @@ pub mod clearing_house {
             base_asset_amount = controller::position::increase(
                 direction,
                 quote_asset_amount,
+                user,
+                market_index,
                 market,
                 market_position,
                 now,
+                margin_ratio_initial,
+                if min_base_asset_amount == 0 {
+                    None
+                } else {
+                    Some(min_base_asset_amount)
+                },
             )?
             .unsigned_abs();
+
+            quote_asset_amount_for_fee = match market.fee_denomination {
+                FeeDenomination::Quote => quote_asset_amount,
+                FeeDenomination::Base => {
+                    let (base_asset_fee, _base_asset_fee_value) = fees::calculate_base_asset_fee(
+                        base_asset_amount,
+                        quote_asset_amount,
+                        &ctx.accounts.state.fee_structure,
+                    )?;
+                    controller::position::skim_base_asset_fee(
+                        market,
+                        market_position,
+                        base_asset_fee,
+                    )?;
+                    base_asset_fee_value = _base_asset_fee_value;
+                    0
+                }
+            };
         } else {
             let market = &mut ctx.accounts.markets.load_mut()?.markets
                 [Markets::index_from_u64(market_index)];
@@ -577,17 +597,19 @@ pub mod clearing_house {
             // we calculate what the user's position is worth if they closed to determine
             // if they are reducing or closing and reversing their position
             if base_asset_value > quote_asset_amount {
-                base_asset_amount = controller::position::reduce(
+                let (base_asset_amount_reduced, _) = controller::position::reduce(
                     direction,
                     quote_asset_amount,
                     user,
+                    market_index,
                     market,
                     market_position,
                     now,
                     None,
-                )?
-                .unsigned_abs();
+                )?;
+                base_asset_amount = base_asset_amount_reduced.unsigned_abs();
 
+                quote_asset_amount_for_fee = quote_asset_amount;
                 potentially_risk_increasing = false;
             } else {
                 // after closing existing position, how large should trade be in opposite direction
@@ -600,18 +622,36 @@ pub mod clearing_house {
                     potentially_risk_increasing = false;
                 }
 
-                let (_, base_asset_amount_closed) =
-                    controller::position::close(user, market, market_position, now)?;
-                let base_asset_amount_closed = base_asset_amount_closed.unsigned_abs();
-
-                let base_asset_amount_opened = controller::position::increase(
-                    direction,
-                    quote_asset_amount_after_close,
-                    market,
-                    market_position,
-                    now,
-                )?
-                .unsigned_abs();
+                let (base_asset_amount_closed, base_asset_amount_opened) =
+                    controller::position::reverse_position(
+                        direction,
+                        quote_asset_amount,
+                        user,
+                        market_index,
+                        market,
+                        market_position,
+                        now,
+                        margin_ratio_initial,
+                    )?;
+
+                quote_asset_amount_for_fee = match market.fee_denomination {
+                    FeeDenomination::Quote => quote_asset_amount,
+                    FeeDenomination::Base => {
+                        let (base_asset_fee, _base_asset_fee_value) =
+                            fees::calculate_base_asset_fee(
+                                base_asset_amount_opened,
+                                quote_asset_amount_after_close,
+                                &ctx.accounts.state.fee_structure,
+                            )?;
+                        controller::position::skim_base_asset_fee(
+                            market,
+                            market_position,
+                            base_asset_fee,
+                        )?;
+                        base_asset_fee_value = _base_asset_fee_value;
+                        base_asset_value
+                    }
+                };
 
                 base_asset_amount = base_asset_amount_closed
                     .checked_add(base_asset_amount_opened)
@@ -641,16 +681,25 @@ pub mod clearing_house {
 
         // Trade fails if it's risk increasing and it brings the user below the initial margin ratio level
         let (
-            _total_collateral_after,
-            _unrealized_pnl_after,
-            _base_asset_value_after,
-            margin_ratio_after,
-        ) = calculate_margin_ratio(user, user_positions, &ctx.accounts.markets.load()?)?;
-        if margin_ratio_after < ctx.accounts.state.margin_ratio_initial
-            && potentially_risk_increasing
-        {
-            return Err(ErrorCode::InsufficientCollateral.into());
-        }
+            total_collateral_after,
+            initial_margin_requirement_after,
+            maintenance_margin_requirement_after,
+        ) = calculate_total_collateral_and_margin_requirements(
+            user,
+            user_positions,
+            &*ctx.accounts.markets.load()?,
+            margin_ratio_initial,
+            ctx.accounts.state.margin_ratio_maintenance,
+            ctx.accounts.state.secondary_collateral_weight_bps,
+        )?;
+        validate_margin_after_trade(
+            total_collateral_after,
+            initial_margin_requirement_after,
+            maintenance_margin_requirement_after,
+            potentially_risk_increasing,
+        )?;
+
+        controller::position::validate_pnl_velocity(user, ctx.accounts.state.max_pnl_velocity)?;
 
         // Calculate the fee to charge the user
         let (discount_token, referrer) = optional_accounts::get_discount_token_and_referrer(
@@ -662,11 +711,14 @@ pub mod clearing_house {
         )?;
         let (user_fee, fee_to_market, token_discount, referrer_reward, referee_discount) =
             fees::calculate(
-                quote_asset_amount,
+                quote_asset_amount_for_fee,
                 &ctx.accounts.state.fee_structure,
                 discount_token,
                 &referrer,
             )?;
+        let fee_to_market = fee_to_market
+            .checked_add(base_asset_fee_value)
+            .ok_or_else(math_error!())?;
 
         // Increment the clearing house's total fee variables
         {
@@ -682,10 +734,21 @@ pub mod clearing_house {
                 .total_fee_minus_distributions
                 .checked_add(fee_to_market)
                 .ok_or_else(math_error!())?;
+            let fee_to_insurance_fund =
+                fees::calculate_fee_to_insurance_fund(fee_to_market, market)?;
+            market.total_fee_to_insurance_fund = market
+                .total_fee_to_insurance_fund
+                .checked_add(cast(fee_to_insurance_fund)?)
+                .ok_or_else(math_error!())?;
         }
 
-        // Subtract the fee from user's collateral
+        // Subtract the fee from user's collateral. The base-denominated fee was paid from the
+        // position, so it only counts towards what the user paid
         user.collateral = user.collateral.checked_sub(user_fee).or(Some(0)).unwrap();
+        let user_fee = user_fee
+            .checked_add(base_asset_fee_value)
+            .ok_or_else(math_error!())?;
+        user_positions.positions[position_index].add_fee_paid(user_fee)?;
 
         // Increment the user's total fee variables
         user.total_fee_paid = user
@@ -813,6 +876,77 @@ pub mod clearing_house {
         Ok(())
     }
 
+    /// Deposits collateral and opens a position in one instruction, so the market can't move between
+    /// the two. A failed open reverts the deposit along with it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn open_position_with_collateral<'info>(
+        ctx: Context<'_, '_, '_, 'info, OpenPositionWithCollateral<'info>>,
+        deposit_amount: u64,
+        direction: PositionDirection,
+        quote_asset_amount: u128,
+        market_index: u64,
+        limit_price: u128,
+        optional_accounts: ManagePositionOptionalAccounts,
+        min_base_asset_amount: u128,
+    ) -> ProgramResult {
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+
+        if deposit_amount == 0 {
+            return Err(ErrorCode::InsufficientDeposit.into());
+        }
+
+        {
+            let state = &ctx.accounts.open_position.state;
+            let user = &mut ctx.accounts.open_position.user;
+
+            let collateral_before = user.collateral;
+            let cumulative_deposits_before = user.cumulative_deposits;
+
+            controller::collateral::deposit(user, deposit_amount)?;
+
+            controller::token::receive(
+                &ctx.accounts.token_program,
+                &ctx.accounts.user_collateral_account,
+                &ctx.accounts.collateral_vault,
+                &ctx.accounts.open_position.authority,
+                deposit_amount,
+            )?;
+
+            let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+            let record_id = deposit_history.next_record_id();
+            deposit_history.append(DepositRecord {
+                ts: now,
+                record_id,
+                user_authority: user.authority,
+                user: user.to_account_info().key(),
+                direction: DepositDirection::DEPOSIT,
+                collateral_before,
+                cumulative_deposits_before,
+                amount: deposit_amount,
+            });
+
+            if state.max_deposit > 0 && user.cumulative_deposits > cast(state.max_deposit)? {
+                return Err(ErrorCode::UserMaxDeposit.into());
+            }
+        }
+
+        // open_position settles funding before trading, which covers the deposit too
+        open_position(
+            Context::new(
+                ctx.program_id,
+                &mut ctx.accounts.open_position,
+                ctx.remaining_accounts,
+            ),
+            direction,
+            quote_asset_amount,
+            market_index,
+            limit_price,
+            optional_accounts,
+            min_base_asset_amount,
+        )
+    }
+
     #[allow(unused_must_use)]
     #[access_control(
         market_initialized(&ctx.accounts.markets, market_index) &&
@@ -823,12 +957,19 @@ pub mod clearing_house {
         ctx: Context<ClosePosition>,
         market_index: u64,
         optional_accounts: ManagePositionOptionalAccounts,
+        min_quote_asset_amount_out: u128,
+        max_quote_asset_amount_out: u128,
+        max_ts: i64,
     ) -> ProgramResult {
         let user = &mut ctx.accounts.user;
         let clock = Clock::get()?;
         let now = clock.unix_timestamp;
         let clock_slot = clock.slot;
 
+        // Checked up front so a late close fails before funding is settled
+        let max_now = if max_ts == 0 { None } else { Some(max_ts) };
+        controller::position::validate_now(now, max_now)?;
+
         // Settle user's funding payments so that collateral is up to date
         let user_positions = &mut ctx.accounts.user_positions.load_mut()?;
         let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
@@ -841,14 +982,9 @@ pub mod clearing_house {
         )?;
 
         // Try to find user's position for specified market. Return Err if there is none
-        let market_position = user_positions
-            .positions
-            .iter_mut()
-            .find(|market_position| market_position.is_for(market_index));
-        if market_position.is_none() {
-            return Err(ErrorCode::UserHasNoPositionInMarket.into());
-        }
-        let market_position = market_position.unwrap();
+        let position_index =
+            controller::position::get_position_index_ref(user_positions, market_index)?;
+        let market_position = &mut user_positions.positions[position_index];
 
         let market =
             &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
@@ -864,10 +1000,23 @@ pub mod clearing_house {
         )?;
         let direction_to_close =
             math::position::direction_to_close_position(market_position.base_asset_amount);
-        let (quote_asset_amount, base_asset_amount) =
-            controller::position::close(user, market, market_position, now)?;
+        let (quote_asset_amount, base_asset_amount, pnl) = controller::position::close_with_pnl(
+            user,
+            market_index,
+            market,
+            market_position,
+            now,
+            max_now,
+        )?;
         let base_asset_amount = base_asset_amount.unsigned_abs();
 
+        controller::position::validate_quote_asset_amount_out(
+            quote_asset_amount,
+            min_quote_asset_amount_out,
+            max_quote_asset_amount_out,
+        )?;
+        controller::position::validate_pnl_velocity(user, ctx.accounts.state.max_pnl_velocity)?;
+
         // Calculate the fee to charge the user
         let (discount_token, referrer) = optional_accounts::get_discount_token_and_referrer(
             optional_accounts,
@@ -895,9 +1044,16 @@ pub mod clearing_house {
             .total_fee_minus_distributions
             .checked_add(fee_to_market)
             .ok_or_else(math_error!())?;
+        let fee_to_insurance_fund = fees::calculate_fee_to_insurance_fund(fee_to_market, market)?;
+        market.total_fee_to_insurance_fund = market
+            .total_fee_to_insurance_fund
+            .checked_add(cast(fee_to_insurance_fund)?)
+            .ok_or_else(math_error!())?;
 
         // Subtract the fee from user's collateral
         user.collateral = user.collateral.checked_sub(user_fee).or(Some(0)).unwrap();
+        market_position.add_fee_paid(user_fee)?;
+        controller::position::emit_position_closed(user, market_index, pnl, market_position)?;
 
         // Increment the user's total fee variables
         user.total_fee_paid = user
@@ -923,6 +1079,13 @@ pub mod clearing_house {
             referrer.exit(ctx.program_id)?;
         }
 
+        controller::position::sweep_dust_collateral(
+            user,
+            user_positions,
+            market,
+            ctx.accounts.state.dust_collateral_threshold,
+        )?;
+
         // Collect data about market after trade is executed so that it can be stored in trade history
         let mark_price_after = market.amm.mark_price()?;
         let price_oracle = &ctx.accounts.oracle;
@@ -943,7 +1106,7 @@ pub mod clearing_house {
             &ctx.accounts.state.oracle_guard_rails.validity,
         )?;
         if is_oracle_valid {
-            amm::update_oracle_price_twap(&mut market.amm, now, oracle_price_after)?;
+            amm::update_oracle_twap(&mut market.amm, oracle_price_after, now)?;
         }
 
         // Trade fails if the trade is risk increasing and it pushes to mark price too far
@@ -1002,8 +1165,16 @@ pub mod clearing_house {
         Ok(())
     }
 
+    /// Liquidates an account below the partial liquidation margin ratio, failing with
+    /// SufficientCollateral for a healthy one. Below maintenance every position is closed. Between
+    /// the two, a share of the account's notional is closed, starting with the positions contributing
+    /// most to the margin shortfall, so closing one position is often enough. Either way the fee is
+    /// taken from collateral and split between the liquidator and the insurance fund.
+    // one guard per attribute rather than joined with &&, so the body needs no
+    // #[allow(unused_must_use)] and a Result it drops still warns
+    #[access_control(exchange_not_paused(&ctx.accounts.state))]
     #[access_control(
-        exchange_not_paused(&ctx.accounts.state)
+        keeper_allowed(&ctx.accounts.state, &ctx.accounts.authority, ctx.remaining_accounts)
     )]
     pub fn liquidate(ctx: Context<Liquidate>) -> ProgramResult {
         let state = &ctx.accounts.state;
@@ -1027,7 +1198,12 @@ pub mod clearing_house {
         // Verify that the user is in liquidation territory
         let collateral = user.collateral;
         let (total_collateral, unrealized_pnl, base_asset_value, margin_ratio) =
-            calculate_margin_ratio(user, user_positions, &ctx.accounts.markets.load()?)?;
+            calculate_margin_ratio(
+                user,
+                user_positions,
+                &ctx.accounts.markets.load()?,
+                ctx.accounts.state.secondary_collateral_weight_bps,
+            )?;
         if margin_ratio > ctx.accounts.state.margin_ratio_partial {
             msg!("total_collateral {}", total_collateral);
             msg!("unrealized_pnl {}", unrealized_pnl);
@@ -1071,9 +1247,24 @@ pub mod clearing_house {
                     math::position::direction_to_close_position(market_position.base_asset_amount);
 
                 let mark_price_before = market.amm.mark_price()?;
-                let (base_asset_value, base_asset_amount) =
-                    controller::position::close(user, market, market_position, now)?;
+                let (base_asset_value, base_asset_amount) = controller::position::close(
+                    user,
+                    market_position.market_index,
+                    market,
+                    market_position,
+                    now,
+                )?;
                 let base_asset_amount = base_asset_amount.unsigned_abs();
+                let base_asset_value = controller::position::apply_liquidation_oracle_band(
+                    user,
+                    market,
+                    direction_to_close,
+                    base_asset_amount,
+                    base_asset_value,
+                    oracle_price,
+                    state.liquidation_oracle_band_numerator,
+                    state.liquidation_oracle_band_denominator,
+                )?;
                 base_asset_value_closed = base_asset_value_closed
                     .checked_add(base_asset_value)
                     .ok_or_else(math_error!())?;
@@ -1101,11 +1292,22 @@ pub mod clearing_house {
             }
         } else {
             let markets = &mut ctx.accounts.markets.load_mut()?;
-            for market_position in user_positions.positions.iter_mut() {
-                if market_position.base_asset_amount == 0 {
-                    continue;
+
+            // Close the partial share of the account's notional, starting with the positions
+            // contributing most to the margin shortfall
+            let mut base_asset_value_to_close = base_asset_value
+                .checked_mul(state.partial_liquidation_close_percentage_numerator)
+                .ok_or_else(math_error!())?
+                .checked_div(state.partial_liquidation_close_percentage_denominator)
+                .ok_or_else(math_error!())?;
+
+            let ranked_positions = rank_positions_by_adverse_notional(user_positions, markets)?;
+            for (position_index, _adverse_notional) in ranked_positions {
+                if base_asset_value_to_close == 0 {
+                    break;
                 }
 
+                let market_position = &mut user_positions.positions[position_index];
                 let market =
                     &mut markets.markets[Markets::index_from_u64(market_position.market_index)];
 
@@ -1127,31 +1329,55 @@ pub mod clearing_house {
                     return Err(ErrorCode::LiquidationsBlockedByOracle.into());
                 }
 
-                let (base_asset_value, _pnl) =
+                let (position_base_asset_value, _pnl) =
                     calculate_base_asset_value_and_pnl(market_position, &market.amm)?;
 
-                let base_asset_value_to_close = base_asset_value
-                    .checked_mul(state.partial_liquidation_close_percentage_numerator)
-                    .ok_or_else(math_error!())?
-                    .checked_div(state.partial_liquidation_close_percentage_denominator)
-                    .ok_or_else(math_error!())?;
-                base_asset_value_closed = base_asset_value_closed
-                    .checked_add(base_asset_value_to_close)
-                    .ok_or_else(math_error!())?;
-
                 let direction_to_reduce =
                     math::position::direction_to_close_position(market_position.base_asset_amount);
 
-                let base_asset_amount_change = controller::position::reduce(
-                    direction_to_reduce,
-                    base_asset_value_to_close,
-                    user,
-                    market,
-                    market_position,
-                    now,
-                    Some(mark_price_before),
-                )?
-                .unsigned_abs();
+                // Close the position outright if what's left to close covers it, rather than
+                // reducing it to dust
+                let (base_asset_amount_change, quote_asset_amount_closed) =
+                    if position_base_asset_value <= base_asset_value_to_close {
+                        let (base_asset_value, base_asset_amount) = controller::position::close(
+                            user,
+                            market_position.market_index,
+                            market,
+                            market_position,
+                            now,
+                        )?;
+                        (base_asset_amount.unsigned_abs(), base_asset_value)
+                    } else {
+                        let (base_asset_amount_change, _) = controller::position::reduce(
+                            direction_to_reduce,
+                            base_asset_value_to_close,
+                            user,
+                            market_position.market_index,
+                            market,
+                            market_position,
+                            now,
+                            Some(mark_price_before),
+                        )?;
+                        let base_asset_amount_change = base_asset_amount_change.unsigned_abs();
+                        (base_asset_amount_change, base_asset_value_to_close)
+                    };
+                let quote_asset_amount_closed =
+                    controller::position::apply_liquidation_oracle_band(
+                        user,
+                        market,
+                        direction_to_reduce,
+                        base_asset_amount_change,
+                        quote_asset_amount_closed,
+                        oracle_price,
+                        state.liquidation_oracle_band_numerator,
+                        state.liquidation_oracle_band_denominator,
+                    )?;
+
+                base_asset_value_to_close =
+                    base_asset_value_to_close.saturating_sub(quote_asset_amount_closed);
+                base_asset_value_closed = base_asset_value_closed
+                    .checked_add(quote_asset_amount_closed)
+                    .ok_or_else(math_error!())?;
 
                 let mark_price_after = market.amm.mark_price()?;
                 let record_id = trade_history.next_record_id();
@@ -1162,7 +1388,7 @@ pub mod clearing_house {
                     user: *user.to_account_info().key,
                     direction: direction_to_reduce,
                     base_asset_amount: base_asset_amount_change,
-                    quote_asset_amount: base_asset_value_to_close,
+                    quote_asset_amount: quote_asset_amount_closed,
                     mark_price_before,
                     mark_price_after,
                     fee: 0,
@@ -1183,11 +1409,13 @@ pub mod clearing_house {
                 .checked_div(state.full_liquidation_penalty_percentage_denominator)
                 .ok_or_else(math_error!())?
         } else {
+            // total collateral counts secondary collateral, but the fee can only come out of the vault
             total_collateral
                 .checked_mul(state.partial_liquidation_penalty_percentage_numerator)
                 .ok_or_else(math_error!())?
                 .checked_div(state.partial_liquidation_penalty_percentage_denominator)
                 .ok_or_else(math_error!())?
+                .min(user.collateral)
         };
 
         let (withdrawal_amount, _) = calculate_withdrawal_amounts(
@@ -1258,6 +1486,146 @@ pub mod clearing_house {
         Ok(())
     }
 
+    pub fn update_user_auto_deleverage(
+        ctx: Context<UpdateUserAutoDeleverage>,
+        auto_deleverage: bool,
+    ) -> ProgramResult {
+        ctx.accounts.user.auto_deleverage = auto_deleverage;
+        Ok(())
+    }
+
+    /// Grace reduction for accounts that opted in: once an account can be liquidated, anyone may
+    /// reduce its worst position, without a liquidation fee, by enough to bring its margin ratio
+    /// back to the initial margin ratio. The oracle for the position's market is passed in the
+    /// remaining accounts and has to pass the same guard rails as a liquidation, and the fill is
+    /// repriced to the liquidation oracle band so the reduction can't be forced at a pushed mark.
+    #[access_control(
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn auto_deleverage(ctx: Context<AutoDeleverage>) -> ProgramResult {
+        let state = &ctx.accounts.state;
+        let user = &mut ctx.accounts.user;
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        let clock_slot = clock.slot;
+
+        if !user.auto_deleverage {
+            return Err(ErrorCode::AutoDeleverageNotEnabled.into());
+        }
+
+        // Settle user's funding payments so that collateral is up to date
+        let user_positions = &mut ctx.accounts.user_positions.load_mut()?;
+        let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
+        controller::funding::settle_funding_payment(
+            user,
+            user_positions,
+            &ctx.accounts.markets.load()?,
+            funding_payment_history,
+            now,
+        )?;
+
+        // Only an account a liquidator could act on is deleveraged
+        let (total_collateral, _unrealized_pnl, base_asset_value, margin_ratio) =
+            calculate_margin_ratio(
+                user,
+                user_positions,
+                &ctx.accounts.markets.load()?,
+                state.secondary_collateral_weight_bps,
+            )?;
+        if margin_ratio > state.margin_ratio_partial {
+            return Err(ErrorCode::SufficientCollateral.into());
+        }
+
+        let base_asset_value_to_reduce = calculate_base_asset_value_to_deleverage(
+            total_collateral,
+            base_asset_value,
+            state.margin_ratio_initial,
+        )?;
+
+        let markets = &mut ctx.accounts.markets.load_mut()?;
+        let (position_index, _adverse_notional) = get_worst_position(user_positions, markets)?
+            .ok_or(ErrorCode::UserHasNoPositionInMarket)?;
+        let market_position = &mut user_positions.positions[position_index];
+        let market_index = market_position.market_index;
+        let market = &mut markets.markets[Markets::index_from_u64(market_index)];
+
+        let mark_price_before = market.amm.mark_price()?;
+        let oracle_account_info = ctx
+            .remaining_accounts
+            .iter()
+            .find(|account_info| account_info.key.eq(&market.amm.oracle))
+            .ok_or(ErrorCode::OracleNotFound)?;
+        let (deleverage_blocked, oracle_price) = math::oracle::block_operation(
+            &market.amm,
+            oracle_account_info,
+            clock_slot,
+            &state.oracle_guard_rails,
+            Some(mark_price_before),
+        )?;
+        if deleverage_blocked {
+            return Err(ErrorCode::LiquidationsBlockedByOracle.into());
+        }
+
+        let (position_base_asset_value, _pnl) =
+            calculate_base_asset_value_and_pnl(market_position, &market.amm)?;
+        let direction_to_reduce =
+            math::position::direction_to_close_position(market_position.base_asset_amount);
+
+        // Close the position outright if the reduction covers it, rather than leaving dust
+        let (base_asset_amount, quote_asset_amount) =
+            if position_base_asset_value <= base_asset_value_to_reduce {
+                let (base_asset_value, base_asset_amount) =
+                    controller::position::close(user, market_index, market, market_position, now)?;
+                (base_asset_amount.unsigned_abs(), base_asset_value)
+            } else {
+                let (base_asset_amount, _) = controller::position::reduce(
+                    direction_to_reduce,
+                    base_asset_value_to_reduce,
+                    user,
+                    market_index,
+                    market,
+                    market_position,
+                    now,
+                    Some(mark_price_before),
+                )?;
+                (base_asset_amount.unsigned_abs(), base_asset_value_to_reduce)
+            };
+        let quote_asset_amount = controller::position::apply_liquidation_oracle_band(
+            user,
+            market,
+            direction_to_reduce,
+            base_asset_amount,
+            quote_asset_amount,
+            oracle_price,
+            state.liquidation_oracle_band_numerator,
+            state.liquidation_oracle_band_denominator,
+        )?;
+        let mark_price_after = market.amm.mark_price()?;
+
+        let trade_history = &mut ctx.accounts.trade_history.load_mut()?;
+        let record_id = trade_history.next_record_id();
+        trade_history.append(TradeRecord {
+            ts: now,
+            record_id,
+            user_authority: user.authority,
+            user: *user.to_account_info().key,
+            direction: direction_to_reduce,
+            base_asset_amount,
+            quote_asset_amount,
+            mark_price_before,
+            mark_price_after,
+            fee: 0,
+            token_discount: 0,
+            referrer_reward: 0,
+            referee_discount: 0,
+            liquidation: false,
+            market_index,
+            oracle_price,
+        });
+
+        Ok(())
+    }
+
     #[allow(unused_must_use)]
     #[access_control(
         market_initialized(&ctx.accounts.markets, market_index) &&
@@ -1297,12 +1665,25 @@ pub mod clearing_house {
             .checked_div(SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_DENOMINATOR)
             .ok_or_else(math_error!())?
             .checked_sub(market.amm.total_fee_withdrawn)
+            .ok_or_else(math_error!())?
+            // fees routed to the insurance fund but not yet transferred aren't the admin's
+            .checked_sub(cast(
+                market
+                    .total_fee_to_insurance_fund
+                    .checked_sub(market.total_fee_to_insurance_fund_settled)
+                    .ok_or_else(math_error!())?,
+            )?)
             .ok_or_else(math_error!())?;
 
         if cast_to_u128(amount)? > max_withdraw {
             return Err(ErrorCode::AdminWithdrawTooLarge.into());
         }
 
+        // withdrawals can only come out of fees that haven't been distributed back to the market
+        if cast_to_i128(amount)? > fees::calculate_undistributed_fees(&market.amm)? {
+            return Err(ErrorCode::AdminWithdrawTooLarge.into());
+        }
+
         controller::token::send(
             &ctx.accounts.token_program,
             &ctx.accounts.collateral_vault,
@@ -1321,37 +1702,247 @@ pub mod clearing_house {
         Ok(())
     }
 
-    pub fn withdraw_from_insurance_vault(
-        ctx: Context<WithdrawFromInsuranceVault>,
-        amount: u64,
-    ) -> ProgramResult {
-        controller::token::send(
-            &ctx.accounts.token_program,
-            &ctx.accounts.insurance_vault,
-            &ctx.accounts.recipient,
-            &ctx.accounts.insurance_vault_authority,
-            ctx.accounts.state.insurance_vault_nonce,
-            amount,
-        )?;
-        Ok(())
-    }
-
     #[access_control(
         market_initialized(&ctx.accounts.markets, market_index)
     )]
-    pub fn withdraw_from_insurance_vault_to_market(
-        ctx: Context<WithdrawFromInsuranceVaultToMarket>,
+    pub fn transfer_fees_to_insurance_vault(
+        ctx: Context<TransferFeesToInsuranceVault>,
         market_index: u64,
-        amount: u64,
     ) -> ProgramResult {
+        let state = &ctx.accounts.state;
         let markets = &mut ctx.accounts.markets.load_mut()?;
         let market = &mut markets.markets[Markets::index_from_u64(market_index)];
 
-        // The admin can move fees from the insurance fund back to the protocol so that money in
-        // the insurance fund can be used to make market more optimal
-        // 100% goes to user fee pool (symmetric funding, repeg, and k adjustments)
-        market.amm.total_fee_minus_distributions = market
-            .amm
+        let amount = market
+            .total_fee_to_insurance_fund
+            .checked_sub(market.total_fee_to_insurance_fund_settled)
+            .ok_or_else(math_error!())?;
+        if amount == 0 {
+            return Ok(());
+        }
+
+        controller::token::send(
+            &ctx.accounts.token_program,
+            &ctx.accounts.collateral_vault,
+            &ctx.accounts.insurance_vault,
+            &ctx.accounts.collateral_vault_authority,
+            state.collateral_vault_nonce,
+            amount,
+        )?;
+
+        // routed fees come out of the clearing house's share, same as a fee withdrawal
+        market.amm.total_fee_withdrawn = market
+            .amm
+            .total_fee_withdrawn
+            .checked_add(cast(amount)?)
+            .ok_or_else(math_error!())?;
+        market.total_fee_to_insurance_fund_settled = market.total_fee_to_insurance_fund;
+
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn cover_bad_debt_from_insurance_vault(
+        ctx: Context<CoverBadDebtFromInsuranceVault>,
+        market_index: u64,
+    ) -> ProgramResult {
+        let state = &ctx.accounts.state;
+        let markets = &mut ctx.accounts.markets.load_mut()?;
+        let market = &mut markets.markets[Markets::index_from_u64(market_index)];
+
+        let bad_debt = market
+            .total_bad_debt
+            .checked_sub(market.total_bad_debt_settled)
+            .ok_or_else(math_error!())?;
+        // cover what the insurance fund can and leave the rest outstanding
+        let amount = bad_debt.min(ctx.accounts.insurance_vault.amount);
+        if amount == 0 {
+            return Ok(());
+        }
+
+        controller::token::send(
+            &ctx.accounts.token_program,
+            &ctx.accounts.insurance_vault,
+            &ctx.accounts.collateral_vault,
+            &ctx.accounts.insurance_vault_authority,
+            state.insurance_vault_nonce,
+            amount,
+        )?;
+
+        market.total_bad_debt_settled = market
+            .total_bad_debt_settled
+            .checked_add(amount)
+            .ok_or_else(math_error!())?;
+
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn force_close_position(
+        ctx: Context<AdminForceClosePosition>,
+        market_index: u64,
+        settlement_price: u128,
+    ) -> ProgramResult {
+        if settlement_price == 0 {
+            return Err(ErrorCode::InvalidSettlementPrice.into());
+        }
+
+        let user = &mut ctx.accounts.user;
+        let now = Clock::get()?.unix_timestamp;
+
+        // Settle user's funding payments so that collateral is up to date
+        let user_positions = &mut ctx.accounts.user_positions.load_mut()?;
+        let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
+        controller::funding::settle_funding_payment(
+            user,
+            user_positions,
+            &ctx.accounts.markets.load()?,
+            funding_payment_history,
+            now,
+        )?;
+
+        let position_index =
+            controller::position::get_position_index_ref(user_positions, market_index)?;
+        let market_position = &mut user_positions.positions[position_index];
+
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+
+        let direction_to_close =
+            math::position::direction_to_close_position(market_position.base_asset_amount);
+        let (quote_asset_amount, base_asset_amount) = controller::position::force_close_position(
+            user,
+            market_index,
+            market,
+            market_position,
+            settlement_price,
+        )?;
+
+        // The amm isn't touched, so the settlement price stands in for the mark and oracle prices
+        let trade_history_account = &mut ctx.accounts.trade_history.load_mut()?;
+        let record_id = trade_history_account.next_record_id();
+        trade_history_account.append(TradeRecord {
+            ts: now,
+            record_id,
+            user_authority: user.authority,
+            user: *user.to_account_info().key,
+            direction: direction_to_close,
+            base_asset_amount: base_asset_amount.unsigned_abs(),
+            quote_asset_amount,
+            mark_price_before: settlement_price,
+            mark_price_after: settlement_price,
+            fee: 0,
+            token_discount: 0,
+            referrer_reward: 0,
+            referee_discount: 0,
+            liquidation: false,
+            market_index,
+            oracle_price: cast(settlement_price)?,
+        });
+
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn settle_expired_position(
+        ctx: Context<SettleExpiredPosition>,
+        market_index: u64,
+    ) -> ProgramResult {
+        let user = &mut ctx.accounts.user;
+        let now = Clock::get()?.unix_timestamp;
+
+        // Settle user's funding payments so that collateral is up to date
+        let user_positions = &mut ctx.accounts.user_positions.load_mut()?;
+        let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
+        controller::funding::settle_funding_payment(
+            user,
+            user_positions,
+            &ctx.accounts.markets.load()?,
+            funding_payment_history,
+            now,
+        )?;
+
+        let position_index =
+            controller::position::get_position_index_ref(user_positions, market_index)?;
+        let market_position = &mut user_positions.positions[position_index];
+
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+
+        let direction_to_close =
+            math::position::direction_to_close_position(market_position.base_asset_amount);
+        let (quote_asset_amount, base_asset_amount) =
+            controller::position::settle_expired_position(
+                user,
+                market_index,
+                market,
+                market_position,
+                now,
+            )?;
+        let settlement_price = market.settlement_price;
+
+        // The amm isn't touched, so the settlement price stands in for the mark and oracle prices
+        let trade_history_account = &mut ctx.accounts.trade_history.load_mut()?;
+        let record_id = trade_history_account.next_record_id();
+        trade_history_account.append(TradeRecord {
+            ts: now,
+            record_id,
+            user_authority: user.authority,
+            user: *user.to_account_info().key,
+            direction: direction_to_close,
+            base_asset_amount: base_asset_amount.unsigned_abs(),
+            quote_asset_amount,
+            mark_price_before: settlement_price,
+            mark_price_after: settlement_price,
+            fee: 0,
+            token_discount: 0,
+            referrer_reward: 0,
+            referee_discount: 0,
+            liquidation: false,
+            market_index,
+            oracle_price: cast(settlement_price)?,
+        });
+
+        Ok(())
+    }
+
+    pub fn withdraw_from_insurance_vault(
+        ctx: Context<WithdrawFromInsuranceVault>,
+        amount: u64,
+    ) -> ProgramResult {
+        controller::token::send(
+            &ctx.accounts.token_program,
+            &ctx.accounts.insurance_vault,
+            &ctx.accounts.recipient,
+            &ctx.accounts.insurance_vault_authority,
+            ctx.accounts.state.insurance_vault_nonce,
+            amount,
+        )?;
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn withdraw_from_insurance_vault_to_market(
+        ctx: Context<WithdrawFromInsuranceVaultToMarket>,
+        market_index: u64,
+        amount: u64,
+    ) -> ProgramResult {
+        let markets = &mut ctx.accounts.markets.load_mut()?;
+        let market = &mut markets.markets[Markets::index_from_u64(market_index)];
+
+        // The admin can move fees from the insurance fund back to the protocol so that money in
+        // the insurance fund can be used to make market more optimal
+        // 100% goes to user fee pool (symmetric funding, repeg, and k adjustments)
+        market.amm.total_fee_minus_distributions = market
+            .amm
             .total_fee_minus_distributions
             .checked_add(cast(amount)?)
             .ok_or_else(math_error!())?;
@@ -1385,7 +1976,7 @@ pub mod clearing_house {
         let market =
             &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
         let price_oracle = &ctx.accounts.oracle;
-        let (oracle_price, _, _, _, _) = market.amm.get_oracle_price(price_oracle, 0)?;
+        let oracle_price = market.amm.get_oracle_price(price_oracle, 0)?.price;
 
         let peg_multiplier_before = market.amm.peg_multiplier;
         let base_asset_reserve_before = market.amm.base_asset_reserve;
@@ -1452,8 +2043,7 @@ pub mod clearing_house {
         let market =
             &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
         let price_oracle = &ctx.accounts.oracle;
-        let (_, oracle_twap, _oracle_conf, _oracle_twac, _oracle_delay) =
-            market.amm.get_oracle_price(price_oracle, clock_slot)?;
+        let oracle_twap = market.amm.get_oracle_price(price_oracle, clock_slot)?.twap;
 
         let is_oracle_valid = amm::is_oracle_valid(
             &market.amm,
@@ -1476,10 +2066,14 @@ pub mod clearing_house {
             {
                 market.amm.last_oracle_price_twap = cast_to_i128(market.amm.last_mark_price_twap)?;
                 market.amm.last_oracle_price_twap_ts = now;
+                market
+                    .amm
+                    .reset_oracle_observations(market.amm.last_oracle_price_twap, now)?;
             } else if oracle_mark_gap_after.unsigned_abs() <= oracle_mark_gap_before.unsigned_abs()
             {
                 market.amm.last_oracle_price_twap = oracle_twap;
                 market.amm.last_oracle_price_twap_ts = now;
+                market.amm.reset_oracle_observations(oracle_twap, now)?;
             } else {
                 return Err(ErrorCode::OracleMarkSpreadLimit.into());
             }
@@ -1505,8 +2099,7 @@ pub mod clearing_house {
         let market =
             &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
         let price_oracle = &ctx.accounts.oracle;
-        let (_, _, _oracle_conf, _oracle_twac, _oracle_delay) =
-            market.amm.get_oracle_price(price_oracle, clock_slot)?;
+        market.amm.get_oracle_price(price_oracle, clock_slot)?;
 
         let is_oracle_valid = amm::is_oracle_valid(
             &market.amm,
@@ -1518,6 +2111,9 @@ pub mod clearing_house {
         if !is_oracle_valid {
             market.amm.last_oracle_price_twap = cast_to_i128(market.amm.last_mark_price_twap)?;
             market.amm.last_oracle_price_twap_ts = now;
+            market
+                .amm
+                .reset_oracle_observations(market.amm.last_oracle_price_twap, now)?;
         }
 
         Ok(())
@@ -1556,16 +2152,409 @@ pub mod clearing_house {
     pub fn delete_user(ctx: Context<DeleteUser>) -> ProgramResult {
         let user = &ctx.accounts.user;
 
-        // Block the delete if the user still has collateral
-        if user.collateral > 0 {
-            return Err(ErrorCode::CantDeleteUserWithCollateral.into());
-        }
+        // Block the delete if the user still has collateral
+        if user.collateral > 0 {
+            return Err(ErrorCode::CantDeleteUserWithCollateral.into());
+        }
+
+        Ok(())
+    }
+
+    pub fn initialize_user_orders(
+        ctx: Context<InitializeUserOrders>,
+        _user_orders_nonce: u8,
+    ) -> ProgramResult {
+        let user_orders = &mut ctx.accounts.user_orders.load_init()?;
+        user_orders.user = *ctx.accounts.user.to_account_info().key;
+        user_orders.next_order_id = 1;
+        Ok(())
+    }
+
+    #[allow(unused_must_use)]
+    #[access_control(
+        exchange_not_paused(&ctx.accounts.state) &&
+        market_initialized(&ctx.accounts.markets, params.market_index)
+    )]
+    pub fn place_order(ctx: Context<PlaceOrder>, params: OrderParams) -> ProgramResult {
+        let now = Clock::get()?.unix_timestamp;
+        let market =
+            &ctx.accounts.markets.load()?.markets[Markets::index_from_u64(params.market_index)];
+        controller::orders::place_order(
+            &mut ctx.accounts.user_orders.load_mut()?,
+            market,
+            &ctx.accounts.state,
+            &params,
+            now,
+        )?;
+        Ok(())
+    }
+
+    #[allow(unused_must_use)]
+    #[access_control(
+        exchange_not_paused(&ctx.accounts.state) &&
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn close_position_via_limit(
+        ctx: Context<ClosePositionViaLimit>,
+        market_index: u64,
+        limit_price: u128,
+    ) -> ProgramResult {
+        let now = Clock::get()?.unix_timestamp;
+        let user_positions = &ctx.accounts.user_positions.load()?;
+        let position_index =
+            controller::position::get_position_index_ref(user_positions, market_index)?;
+        let market = &ctx.accounts.markets.load()?.markets[Markets::index_from_u64(market_index)];
+        controller::orders::close_via_limit(
+            &mut ctx.accounts.user_orders.load_mut()?,
+            market,
+            &ctx.accounts.state,
+            &user_positions.positions[position_index],
+            limit_price,
+            now,
+        )?;
+        Ok(())
+    }
+
+    pub fn cancel_order(ctx: Context<CancelOrder>, order_id: u64) -> ProgramResult {
+        controller::orders::cancel_order(&mut ctx.accounts.user_orders.load_mut()?, order_id)?;
+        Ok(())
+    }
+
+    #[access_control(
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn fill_order(ctx: Context<FillOrder>, order_id: u64) -> ProgramResult {
+        let user = &mut ctx.accounts.user;
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        let clock_slot = clock.slot;
+
+        // Settle user's funding payments so that collateral is up to date
+        let user_positions = &mut ctx.accounts.user_positions.load_mut()?;
+        let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
+        controller::funding::settle_funding_payment(
+            user,
+            user_positions,
+            &ctx.accounts.markets.load()?,
+            funding_payment_history,
+            now,
+        )?;
+
+        let user_orders = &mut ctx.accounts.user_orders.load_mut()?;
+        let order_index = controller::orders::get_open_order_index(user_orders, order_id)?;
+        let order = user_orders.orders[order_index];
+        let market_index = order.market_index;
+
+        if !ctx.accounts.markets.load()?.markets[Markets::index_from_u64(market_index)]
+            .amm
+            .oracle
+            .eq(ctx.accounts.oracle.key)
+        {
+            return Err(ErrorCode::InvalidOracle.into());
+        }
+
+        // Check if the user has an existing position for the market
+        let position_index =
+            controller::position::get_or_add_position(user_positions, market_index)?;
+
+        let base_asset_amount: u128;
+        let quote_asset_amount: u128;
+        // the part of the fill charged the quote fee
+        let quote_asset_amount_for_fee: u128;
+        // on a base-denominated market, the fee skimmed from the base asset opened, valued at the
+        // fill price
+        let mut base_asset_fee_value = 0;
+        let potentially_risk_increasing: bool;
+        let mark_price_before: u128;
+        let mark_price_after: u128;
+        let oracle_price: i128;
+        let oracle_mark_spread_pct_before: i128;
+        let oracle_mark_spread_pct_after: i128;
+        let is_oracle_valid: bool;
+        let margin_ratio_initial: u128;
+        {
+            let market = &mut ctx.accounts.markets.load_mut()?.markets
+                [Markets::index_from_u64(market_index)];
+            let market_position = &mut user_positions.positions[position_index];
+            margin_ratio_initial =
+                market.get_margin_ratio_initial(ctx.accounts.state.margin_ratio_initial);
+
+            oracle_price = market
+                .amm
+                .get_oracle_price(&ctx.accounts.oracle, clock_slot)?
+                .price;
+            mark_price_before = market.amm.mark_price()?;
+            let (_, _, _oracle_mark_spread_pct_before) = amm::calculate_oracle_mark_spread_pct(
+                &market.amm,
+                &ctx.accounts.oracle,
+                0,
+                clock_slot,
+                Some(mark_price_before),
+            )?;
+            oracle_mark_spread_pct_before = _oracle_mark_spread_pct_before;
+            is_oracle_valid = amm::is_oracle_valid(
+                &market.amm,
+                &ctx.accounts.oracle,
+                clock_slot,
+                &ctx.accounts.state.oracle_guard_rails.validity,
+            )?;
+
+            base_asset_amount = math::orders::calculate_base_asset_amount_to_fill(
+                &order,
+                &market.amm,
+                oracle_price,
+                market_position.base_asset_amount,
+            )?;
+            if base_asset_amount == 0 {
+                return Err(ErrorCode::CouldNotFillOrder.into());
+            }
+            math::orders::validate_fill_size(&order, base_asset_amount)?;
+
+            let (
+                _quote_asset_amount,
+                base_asset_amount_opened,
+                quote_asset_amount_opened,
+                _potentially_risk_increasing,
+            ) = controller::orders::execute_order_fill(
+                order.direction,
+                base_asset_amount,
+                user,
+                market_index,
+                market,
+                market_position,
+                now,
+            )?;
+            quote_asset_amount = _quote_asset_amount;
+            potentially_risk_increasing = _potentially_risk_increasing;
+
+            quote_asset_amount_for_fee = match market.fee_denomination {
+                FeeDenomination::Quote => quote_asset_amount,
+                FeeDenomination::Base => {
+                    let (base_asset_fee, _base_asset_fee_value) = fees::calculate_base_asset_fee(
+                        base_asset_amount_opened,
+                        quote_asset_amount_opened,
+                        &ctx.accounts.state.fee_structure,
+                    )?;
+                    controller::position::skim_base_asset_fee(
+                        market,
+                        market_position,
+                        base_asset_fee,
+                    )?;
+                    base_asset_fee_value = _base_asset_fee_value;
+                    quote_asset_amount
+                        .checked_sub(quote_asset_amount_opened)
+                        .ok_or_else(math_error!())?
+                }
+            };
+            mark_price_after = market.amm.mark_price()?;
+            let (_, _, _oracle_mark_spread_pct_after) = amm::calculate_oracle_mark_spread_pct(
+                &market.amm,
+                &ctx.accounts.oracle,
+                0,
+                clock_slot,
+                Some(mark_price_after),
+            )?;
+            oracle_mark_spread_pct_after = _oracle_mark_spread_pct_after;
+        }
+
+        // Fill fails if it's risk increasing and it brings the user below the initial margin ratio level
+        let (
+            total_collateral_after,
+            initial_margin_requirement_after,
+            maintenance_margin_requirement_after,
+        ) = calculate_total_collateral_and_margin_requirements(
+            user,
+            user_positions,
+            &*ctx.accounts.markets.load()?,
+            margin_ratio_initial,
+            ctx.accounts.state.margin_ratio_maintenance,
+            ctx.accounts.state.secondary_collateral_weight_bps,
+        )?;
+        validate_margin_after_trade(
+            total_collateral_after,
+            initial_margin_requirement_after,
+            maintenance_margin_requirement_after,
+            potentially_risk_increasing,
+        )?;
+
+        controller::position::validate_pnl_velocity(user, ctx.accounts.state.max_pnl_velocity)?;
+
+        // Fill fails if it pushes the mark too far from the oracle, same as open_position
+        let is_oracle_mark_too_divergent_before = amm::is_oracle_mark_too_divergent(
+            oracle_mark_spread_pct_before,
+            &ctx.accounts.state.oracle_guard_rails.price_divergence,
+        )?;
+        let is_oracle_mark_too_divergent_after = amm::is_oracle_mark_too_divergent(
+            oracle_mark_spread_pct_after,
+            &ctx.accounts.state.oracle_guard_rails.price_divergence,
+        )?;
+
+        // if oracle-mark divergence pushed outside limit, block fill
+        if is_oracle_mark_too_divergent_after
+            && !is_oracle_mark_too_divergent_before
+            && is_oracle_valid
+        {
+            return Err(ErrorCode::OracleMarkSpreadLimit.into());
+        }
+
+        // if oracle-mark divergence outside limit and risk-increasing, block fill
+        if is_oracle_mark_too_divergent_after
+            && oracle_mark_spread_pct_after.unsigned_abs()
+                >= oracle_mark_spread_pct_before.unsigned_abs()
+            && is_oracle_valid
+            && potentially_risk_increasing
+        {
+            return Err(ErrorCode::OracleMarkSpreadLimit.into());
+        }
+
+        // Calculate the fee to charge the user
+        let (user_fee, fee_to_market, _token_discount, _referrer_reward, _referee_discount) =
+            fees::calculate(
+                quote_asset_amount_for_fee,
+                &ctx.accounts.state.fee_structure,
+                None,
+                &None,
+            )?;
+        let fee_to_market = fee_to_market
+            .checked_add(base_asset_fee_value)
+            .ok_or_else(math_error!())?;
+
+        // Increment the clearing house's total fee variables
+        {
+            let market = &mut ctx.accounts.markets.load_mut()?.markets
+                [Markets::index_from_u64(market_index)];
+            market.amm.total_fee = market
+                .amm
+                .total_fee
+                .checked_add(fee_to_market)
+                .ok_or_else(math_error!())?;
+            market.amm.total_fee_minus_distributions = market
+                .amm
+                .total_fee_minus_distributions
+                .checked_add(fee_to_market)
+                .ok_or_else(math_error!())?;
+            let fee_to_insurance_fund =
+                fees::calculate_fee_to_insurance_fund(fee_to_market, market)?;
+            market.total_fee_to_insurance_fund = market
+                .total_fee_to_insurance_fund
+                .checked_add(cast(fee_to_insurance_fund)?)
+                .ok_or_else(math_error!())?;
+        }
+
+        // Subtract the fee from user's collateral. The base-denominated fee was paid from the
+        // position, so it only counts towards what the user paid
+        user.collateral = user.collateral.saturating_sub(user_fee);
+        let user_fee = user_fee
+            .checked_add(base_asset_fee_value)
+            .ok_or_else(math_error!())?;
+        user_positions.positions[position_index].add_fee_paid(user_fee)?;
+        user.total_fee_paid = user
+            .total_fee_paid
+            .checked_add(user_fee)
+            .ok_or_else(math_error!())?;
+
+        // Pay the filler the trigger order's keeper reward out of the user's collateral
+        let keeper_reward = math::orders::calculate_keeper_reward(&order, base_asset_amount, now)?
+            .min(user.collateral);
+        if keeper_reward > 0 && ctx.accounts.filler.key() != user.key() {
+            user.collateral = user
+                .collateral
+                .checked_sub(keeper_reward)
+                .ok_or_else(math_error!())?;
+            let filler = &mut ctx.accounts.filler;
+            filler.collateral = filler
+                .collateral
+                .checked_add(keeper_reward)
+                .ok_or_else(math_error!())?;
+        }
+
+        // Update the order, freeing its slot once it is completely filled
+        {
+            let order = &mut user_orders.orders[order_index];
+            order.base_asset_amount_filled = order
+                .base_asset_amount_filled
+                .checked_add(base_asset_amount)
+                .ok_or_else(math_error!())?;
+            order.quote_asset_amount_filled = order
+                .quote_asset_amount_filled
+                .checked_add(quote_asset_amount)
+                .ok_or_else(math_error!())?;
+            order.fee = order.fee.checked_add(user_fee).ok_or_else(math_error!())?;
+
+            if order.get_base_asset_amount_unfilled() == 0 {
+                *order = Order::default();
+            }
+        }
+
+        // Add to the trade history account
+        let trade_history_account = &mut ctx.accounts.trade_history.load_mut()?;
+        let record_id = trade_history_account.next_record_id();
+        trade_history_account.append(TradeRecord {
+            ts: now,
+            record_id,
+            user_authority: user.authority,
+            user: *user.to_account_info().key,
+            direction: order.direction,
+            base_asset_amount,
+            quote_asset_amount,
+            mark_price_before,
+            mark_price_after,
+            fee: user_fee,
+            token_discount: 0,
+            referrer_reward: 0,
+            referee_discount: 0,
+            liquidation: false,
+            market_index,
+            oracle_price,
+        });
+
+        // Try to update the funding rate at the end of every trade
+        {
+            let market = &mut ctx.accounts.markets.load_mut()?.markets
+                [Markets::index_from_u64(market_index)];
+            let price_oracle = &ctx.accounts.oracle;
+            let funding_rate_history = &mut ctx.accounts.funding_rate_history.load_mut()?;
+            controller::funding::update_funding_rate(
+                market_index,
+                market,
+                price_oracle,
+                now,
+                clock_slot,
+                funding_rate_history,
+                &ctx.accounts.state.oracle_guard_rails,
+                ctx.accounts.state.funding_paused,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[allow(unused_must_use)]
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index) &&
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn claim_settled_pnl(ctx: Context<ClaimSettledPnl>, market_index: u64) -> ProgramResult {
+        let user = &mut ctx.accounts.user;
+        let user_positions = &mut ctx.accounts.user_positions.load_mut()?;
+        let market_position = user_positions
+            .positions
+            .iter_mut()
+            .find(|market_position| market_position.is_for(market_index))
+            .ok_or(ErrorCode::UserHasNoPositionInMarket)?;
+
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+
+        controller::position::claim_settled_pnl(user, market, market_position)?;
 
         Ok(())
     }
 
+    #[allow(unused_must_use)]
     #[access_control(
-        exchange_not_paused(&ctx.accounts.state)
+        exchange_not_paused(&ctx.accounts.state) &&
+        remaining_accounts_keeper_allowed(&ctx.accounts.state, ctx.remaining_accounts)
     )]
     pub fn settle_funding_payment(ctx: Context<SettleFunding>) -> ProgramResult {
         let clock = Clock::get()?;
@@ -1612,6 +2601,49 @@ pub mod clearing_house {
         Ok(())
     }
 
+    /// Updates the mark and oracle twaps of every market in market_indexes. The markets' oracles are
+    /// passed as remaining accounts, in the same order. Markets whose oracle is invalid are skipped.
+    #[access_control(
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn update_twaps(ctx: Context<UpdateTwaps>, market_indexes: Vec<u64>) -> ProgramResult {
+        if market_indexes.len() != ctx.remaining_accounts.len() {
+            return Err(ErrorCode::InvalidOracle.into());
+        }
+
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        let clock_slot = clock.slot;
+
+        let markets = &mut ctx.accounts.markets.load_mut()?;
+        let mut oracle_prices: [Option<i128>; 64] = [None; 64];
+        for (market_index, price_oracle) in market_indexes.iter().zip(ctx.remaining_accounts) {
+            let market_index = Markets::index_from_u64(*market_index);
+            let market = &markets.markets[market_index];
+            if !market.initialized {
+                return Err(ErrorCode::MarketIndexNotInitialized.into());
+            }
+            if !market.amm.oracle.eq(price_oracle.key) {
+                return Err(ErrorCode::InvalidOracle.into());
+            }
+
+            let is_oracle_valid = amm::is_oracle_valid(
+                &market.amm,
+                price_oracle,
+                clock_slot,
+                &ctx.accounts.state.oracle_guard_rails.validity,
+            )?;
+            if is_oracle_valid {
+                oracle_prices[market_index] =
+                    Some(market.amm.get_oracle_price(price_oracle, clock_slot)?.price);
+            }
+        }
+
+        controller::amm::update_twaps(&mut markets.markets, &oracle_prices, now)?;
+
+        Ok(())
+    }
+
     #[allow(unused_must_use)]
     #[access_control(
         market_initialized(&ctx.accounts.markets, market_index) &&
@@ -1644,12 +2676,8 @@ pub mod clearing_house {
         let adjustment_cost = math::amm::adjust_k_cost(market, bn::U256::from(sqrt_k))?;
 
         if adjustment_cost > 0 {
-            let max_cost = market
-                .amm
-                .total_fee_minus_distributions
-                .checked_sub(market.amm.total_fee_withdrawn)
-                .ok_or_else(math_error!())?;
-            if adjustment_cost.unsigned_abs() > max_cost {
+            let max_cost = fees::calculate_undistributed_fees(&market.amm)?;
+            if adjustment_cost > max_cost {
                 return Err(ErrorCode::InvalidUpdateK.into());
             } else {
                 market.amm.total_fee_minus_distributions = market
@@ -1692,7 +2720,7 @@ pub mod clearing_house {
         let total_fee = amm.total_fee;
         let total_fee_minus_distributions = amm.total_fee_minus_distributions;
 
-        let (oracle_price, _, _, _, _) = amm.get_oracle_price(&ctx.accounts.oracle, 0)?;
+        let oracle_price = amm.get_oracle_price(&ctx.accounts.oracle, 0)?.price;
 
         let curve_history = &mut ctx.accounts.curve_history.load_mut()?;
         let record_id = curve_history.next_record_id();
@@ -1761,6 +2789,29 @@ pub mod clearing_house {
         Ok(())
     }
 
+    pub fn migrate_markets(ctx: Context<MigrateMarkets>) -> ProgramResult {
+        let legacy_markets_data = ctx.accounts.legacy_markets.try_borrow_data()?;
+        if legacy_markets_data.len() != 8 + std::mem::size_of::<LegacyMarkets>()
+            || legacy_markets_data[..8] != Markets::discriminator()
+        {
+            return Err(ErrorCode::InvalidLegacyMarkets.into());
+        }
+        let legacy_markets: &LegacyMarkets = bytemuck::from_bytes(&legacy_markets_data[8..]);
+        let markets = &mut ctx.accounts.markets.load_init()?;
+
+        for (market, legacy_market) in markets
+            .markets
+            .iter_mut()
+            .zip(legacy_markets.markets.iter())
+        {
+            *market = legacy_market.migrate()?;
+        }
+
+        let state = &mut ctx.accounts.state;
+        state.markets = ctx.accounts.markets.key();
+        Ok(())
+    }
+
     pub fn update_margin_ratio(
         ctx: Context<AdminUpdateState>,
         margin_ratio_initial: u128,
@@ -1815,6 +2866,59 @@ pub mod clearing_house {
         Ok(())
     }
 
+    pub fn update_liquidation_oracle_band(
+        ctx: Context<AdminUpdateState>,
+        numerator: u64,
+        denominator: u64,
+    ) -> ProgramResult {
+        // a band of 0 pins liquidations to the oracle, and one of 100% or more bounds nothing
+        if numerator == 0 || numerator >= denominator {
+            return Err(ErrorCode::InvalidOracleBand.into());
+        }
+
+        ctx.accounts.state.liquidation_oracle_band_numerator = numerator;
+        ctx.accounts.state.liquidation_oracle_band_denominator = denominator;
+        Ok(())
+    }
+
+    pub fn update_mark_twap_divergence(
+        ctx: Context<AdminUpdateState>,
+        numerator: u64,
+        denominator: u64,
+    ) -> ProgramResult {
+        ctx.accounts.state.mark_twap_divergence_numerator = numerator;
+        ctx.accounts.state.mark_twap_divergence_denominator = denominator;
+        Ok(())
+    }
+
+    pub fn update_max_pnl_velocity(
+        ctx: Context<AdminUpdateState>,
+        max_pnl_velocity: u64,
+    ) -> ProgramResult {
+        ctx.accounts.state.max_pnl_velocity = max_pnl_velocity;
+        Ok(())
+    }
+
+    pub fn update_dust_collateral_threshold(
+        ctx: Context<AdminUpdateState>,
+        dust_collateral_threshold: u64,
+    ) -> ProgramResult {
+        ctx.accounts.state.dust_collateral_threshold = dust_collateral_threshold;
+        Ok(())
+    }
+
+    pub fn update_secondary_collateral_weight(
+        ctx: Context<AdminUpdateState>,
+        secondary_collateral_weight_bps: u64,
+    ) -> ProgramResult {
+        if cast_to_u128(secondary_collateral_weight_bps)? > BPS_PRECISION {
+            return Err(ErrorCode::InvalidCollateralWeight.into());
+        }
+
+        ctx.accounts.state.secondary_collateral_weight_bps = secondary_collateral_weight_bps;
+        Ok(())
+    }
+
     pub fn update_partial_liquidation_liquidator_share_denominator(
         ctx: Context<AdminUpdateState>,
         denominator: u64,
@@ -1878,6 +2982,219 @@ pub mod clearing_house {
         Ok(())
     }
 
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_market_insurance_fund_target(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        insurance_fund_target: u64,
+        insurance_fund_fee_share_bps: u64,
+    ) -> ProgramResult {
+        // routed fees come out of the clearing house's share of fees
+        let max_insurance_fund_fee_share_bps = BPS_PRECISION
+            .checked_mul(SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_NUMERATOR)
+            .ok_or_else(math_error!())?
+            .checked_div(SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_DENOMINATOR)
+            .ok_or_else(math_error!())?;
+        if cast_to_u128(insurance_fund_fee_share_bps)? > max_insurance_fund_fee_share_bps {
+            return Err(ErrorCode::InvalidInsuranceFundFeeShare.into());
+        }
+
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.insurance_fund_target = insurance_fund_target;
+        market.insurance_fund_fee_share_bps = insurance_fund_fee_share_bps;
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_market_funding_price_anchor(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        funding_price_anchor: FundingPriceAnchor,
+    ) -> ProgramResult {
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.funding_price_anchor = funding_price_anchor;
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_market_fee_denomination(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        fee_denomination: FeeDenomination,
+    ) -> ProgramResult {
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.fee_denomination = fee_denomination;
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_market_max_quote_asset_amount(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        max_quote_asset_amount: u64,
+    ) -> ProgramResult {
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.max_quote_asset_amount = max_quote_asset_amount;
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_market_max_open_interest(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        max_open_interest: u128,
+    ) -> ProgramResult {
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.max_open_interest = max_open_interest;
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_market_max_position_base_asset_amount(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        max_position_base_asset_amount: u128,
+    ) -> ProgramResult {
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.max_position_base_asset_amount = max_position_base_asset_amount;
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_market_margin_ratio_initial(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        margin_ratio_initial: u128,
+    ) -> ProgramResult {
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.margin_ratio_initial = margin_ratio_initial;
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_market_max_oracle_divergence(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        max_oracle_divergence_bps: u64,
+    ) -> ProgramResult {
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.amm.max_oracle_divergence_bps = max_oracle_divergence_bps;
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_market_settle_pnl_separately(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        settle_pnl_separately: bool,
+    ) -> ProgramResult {
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.settle_pnl_separately = settle_pnl_separately;
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_market_expiry(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        expiry_ts: i64,
+    ) -> ProgramResult {
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.expiry_ts = expiry_ts;
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_market_settlement_price(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        settlement_price: u128,
+    ) -> ProgramResult {
+        if settlement_price == 0 {
+            return Err(ErrorCode::InvalidSettlementPrice.into());
+        }
+
+        let now = Clock::get()?.unix_timestamp;
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        if !market.is_expired(now) {
+            return Err(ErrorCode::MarketNotExpired.into());
+        }
+        market.settlement_price = settlement_price;
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_market_asset_group(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        asset_group: u64,
+        correlation_bps: u64,
+    ) -> ProgramResult {
+        // a full correlation would credit a hedge as if it carried no risk at all
+        if cast_to_u128(correlation_bps)? >= BPS_PRECISION {
+            return Err(ErrorCode::InvalidCorrelation.into());
+        }
+
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.asset_group = asset_group;
+        market.correlation_bps = correlation_bps;
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_market_margin_oracle_weight(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        margin_oracle_weight_bps: u64,
+    ) -> ProgramResult {
+        if cast_to_u128(margin_oracle_weight_bps)? > BPS_PRECISION {
+            return Err(ErrorCode::InvalidOracleWeight.into());
+        }
+
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.margin_oracle_weight_bps = margin_oracle_weight_bps;
+        Ok(())
+    }
+
     pub fn update_admin(ctx: Context<AdminUpdateState>, admin: Pubkey) -> ProgramResult {
         ctx.accounts.state.admin = admin;
         Ok(())
@@ -1891,6 +3208,14 @@ pub mod clearing_house {
         Ok(())
     }
 
+    pub fn update_keeper_mint(
+        ctx: Context<AdminUpdateState>,
+        keeper_mint: Pubkey,
+    ) -> ProgramResult {
+        ctx.accounts.state.keeper_mint = keeper_mint;
+        Ok(())
+    }
+
     pub fn update_discount_mint(
         ctx: Context<AdminUpdateState>,
         discount_mint: Pubkey,
@@ -1955,6 +3280,47 @@ fn exchange_not_paused(state: &Account<State>) -> Result<()> {
     Ok(())
 }
 
+/// During a guarded launch only holders of the keeper mint can liquidate or settle funding, so
+/// callers can't grief by spamming no-op attempts. The keeper's token account is passed among the
+/// remaining accounts.
+fn keeper_allowed(
+    state: &Account<State>,
+    authority: &Signer,
+    remaining_accounts: &[AccountInfo],
+) -> Result<()> {
+    validate_keeper(state, &[authority.key()], remaining_accounts)
+}
+
+/// Settling funding has no signer of its own, so the keeper signs as one of the remaining accounts
+fn remaining_accounts_keeper_allowed(
+    state: &Account<State>,
+    remaining_accounts: &[AccountInfo],
+) -> Result<()> {
+    let signers: Vec<Pubkey> = remaining_accounts
+        .iter()
+        .filter(|account_info| account_info.is_signer)
+        .map(|account_info| *account_info.key)
+        .collect();
+    validate_keeper(state, &signers, remaining_accounts)
+}
+
+fn validate_keeper(
+    state: &Account<State>,
+    keepers: &[Pubkey],
+    remaining_accounts: &[AccountInfo],
+) -> Result<()> {
+    if state.keeper_mint.eq(&Pubkey::default()) {
+        return Ok(());
+    }
+
+    if optional_accounts::get_keeper_token(remaining_accounts, &state.keeper_mint, keepers)
+        .is_none()
+    {
+        return Err(ErrorCode::KeeperTokenNotFound.into());
+    }
+    Ok(())
+}
+
 fn admin_controls_prices(state: &Account<State>) -> Result<()> {
     if !state.admin_controls_prices {
         return Err(ErrorCode::AdminControlsPricesDisabled.into());
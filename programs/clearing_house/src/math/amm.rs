@@ -4,11 +4,15 @@ use anchor_lang::prelude::AccountInfo;
 use solana_program::msg;
 
 use crate::controller::amm::SwapDirection;
+use crate::controller::position::PositionDirection;
 use crate::error::*;
 use crate::math::bn;
 use crate::math::bn::U192;
 use crate::math::casting::{cast, cast_to_i128, cast_to_u128};
-use crate::math::constants::{MARK_PRICE_PRECISION, PRICE_TO_PEG_PRECISION_RATIO};
+use crate::math::constants::{
+    AMM_TO_QUOTE_PRECISION_RATIO, BPS_PRECISION, MARK_PRICE_PRECISION, PRICE_TO_PEG_PRECISION_RATIO,
+};
+use crate::math::oracle::OraclePriceData;
 use crate::math::position::_calculate_base_asset_value_and_pnl;
 use crate::math::quote_asset::{asset_to_reserve_amount, reserve_to_asset_amount};
 use crate::math_error;
@@ -32,6 +36,62 @@ pub fn calculate_price(
         .try_to_u128()
 }
 
+/// The base and quote asset reserves that price the curve at target_mark while holding k fixed
+pub fn reserves_for_mark(
+    sqrt_k: u128,
+    peg_multiplier: u128,
+    target_mark: u128,
+) -> ClearingHouseResult<(u128, u128)> {
+    let sqrt_k = bn::U256::from(sqrt_k);
+    let k = sqrt_k.checked_mul(sqrt_k).ok_or_else(math_error!())?;
+
+    // mark = quote * peg / base and k = base * quote, so base^2 = k * peg / mark
+    let base_asset_reserve_squared = k
+        .checked_mul(bn::U256::from(peg_multiplier))
+        .ok_or_else(math_error!())?
+        .checked_mul(bn::U256::from(PRICE_TO_PEG_PRECISION_RATIO))
+        .ok_or_else(math_error!())?
+        .checked_div(bn::U256::from(target_mark))
+        .ok_or_else(math_error!())?;
+
+    let base_asset_reserve = base_asset_reserve_squared.integer_sqrt();
+    let quote_asset_reserve = k
+        .checked_div(base_asset_reserve)
+        .ok_or_else(math_error!())?;
+
+    Ok((
+        base_asset_reserve.try_to_u128()?,
+        quote_asset_reserve.try_to_u128()?,
+    ))
+}
+
+/// The base asset amount to trade to move the mark price to target_price, and the direction of the
+/// trade that moves it there
+pub fn calculate_base_asset_amount_to_trade_to_price(
+    amm: &AMM,
+    target_price: u128,
+) -> ClearingHouseResult<(u128, PositionDirection)> {
+    let (new_base_asset_reserve, _new_quote_asset_reserve) =
+        reserves_for_mark(amm.sqrt_k, amm.peg_multiplier, target_price)?;
+
+    // longs take base out of the pool, which pushes the mark up
+    if new_base_asset_reserve < amm.base_asset_reserve {
+        Ok((
+            amm.base_asset_reserve
+                .checked_sub(new_base_asset_reserve)
+                .ok_or_else(math_error!())?,
+            PositionDirection::Long,
+        ))
+    } else {
+        Ok((
+            new_base_asset_reserve
+                .checked_sub(amm.base_asset_reserve)
+                .ok_or_else(math_error!())?,
+            PositionDirection::Short,
+        ))
+    }
+}
+
 pub fn calculate_terminal_price(market: &mut Market) -> ClearingHouseResult<u128> {
     let swap_direction = if market.base_asset_amount > 0 {
         SwapDirection::Add
@@ -54,11 +114,156 @@ pub fn calculate_terminal_price(market: &mut Market) -> ClearingHouseResult<u128
     Ok(terminal_price)
 }
 
+/// The AMM takes the other side of net user open interest, so the protocol's residual base asset
+/// exposure is the negative of the market's net user position
+pub fn get_protocol_net_exposure(market: &Market) -> ClearingHouseResult<i128> {
+    market
+        .base_asset_amount
+        .checked_neg()
+        .ok_or_else(math_error!())
+}
+
+/// The protocol's net exposure in quote, valued at what it would cost to unwind the net user
+/// position against the curve. Negative when the protocol is short.
+pub fn get_protocol_net_exposure_notional(market: &Market) -> ClearingHouseResult<i128> {
+    let (net_user_base_asset_value, _pnl) =
+        _calculate_base_asset_value_and_pnl(market.base_asset_amount, 0, &market.amm)?;
+
+    let net_exposure_notional = cast_to_i128(net_user_base_asset_value)?;
+    Ok(if market.base_asset_amount > 0 {
+        -net_exposure_notional
+    } else {
+        net_exposure_notional
+    })
+}
+
+/// The amm's unrealized pnl on its inventory, which is the other side of the net user position: the
+/// net entry quote asset amount users paid in, less what unwinding the net user position against the
+/// curve would pay back out. Moves opposite to traders' aggregate unrealized pnl.
+pub fn get_amm_unrealized_pnl(market: &Market) -> ClearingHouseResult<i128> {
+    let net_entry_quote_asset_amount = cast_to_i128(market.quote_asset_amount_long)?
+        .checked_sub(cast_to_i128(market.quote_asset_amount_short)?)
+        .ok_or_else(math_error!())?;
+
+    let (net_user_base_asset_value, _pnl) =
+        _calculate_base_asset_value_and_pnl(market.base_asset_amount, 0, &market.amm)?;
+    let net_user_base_asset_value = cast_to_i128(net_user_base_asset_value)?;
+
+    if market.base_asset_amount > 0 {
+        net_entry_quote_asset_amount.checked_sub(net_user_base_asset_value)
+    } else {
+        net_entry_quote_asset_amount.checked_add(net_user_base_asset_value)
+    }
+    .ok_or_else(math_error!())
+}
+
+/// One-sided open interest in quote: the larger of the long and short base asset open interest,
+/// valued at the current mark price. Every long is matched by a short or by the amm, so summing both
+/// sides would count the same exposure twice.
+pub fn get_open_interest_notional(market: &Market) -> ClearingHouseResult<u128> {
+    let open_interest = market
+        .base_asset_amount_long
+        .unsigned_abs()
+        .max(market.base_asset_amount_short.unsigned_abs());
+
+    open_interest
+        .checked_mul(market.amm.mark_price()?)
+        .ok_or_else(math_error!())?
+        .checked_div(MARK_PRICE_PRECISION * AMM_TO_QUOTE_PRECISION_RATIO)
+        .ok_or_else(math_error!())
+}
+
+/// The curve's current invariant, base asset reserve times quote asset reserve
+pub fn get_current_k(amm: &AMM) -> ClearingHouseResult<u128> {
+    U192::from(amm.base_asset_reserve)
+        .checked_mul(U192::from(amm.quote_asset_reserve))
+        .ok_or_else(math_error!())?
+        .try_to_u128()
+}
+
+/// How far the current invariant has drifted from the recorded sqrt_k squared, in bps. Swaps round
+/// the reserves so a little drift is expected; a large drift means the reserves are corrupted.
+pub fn get_k_drift_bps(amm: &AMM) -> ClearingHouseResult<i128> {
+    let recorded_k = U192::from(amm.sqrt_k)
+        .checked_mul(U192::from(amm.sqrt_k))
+        .ok_or_else(math_error!())?;
+    let current_k = U192::from(get_current_k(amm)?);
+
+    // k can be within a few orders of magnitude of u128::MAX, so the drift is scaled in U192
+    let k_drift = if current_k >= recorded_k {
+        current_k.checked_sub(recorded_k)
+    } else {
+        recorded_k.checked_sub(current_k)
+    }
+    .ok_or_else(math_error!())?;
+
+    let k_drift_bps = cast_to_i128(
+        k_drift
+            .checked_mul(U192::from(BPS_PRECISION))
+            .ok_or_else(math_error!())?
+            .checked_div(recorded_k)
+            .ok_or_else(math_error!())?
+            .try_to_u128()?,
+    )?;
+
+    Ok(if current_k >= recorded_k {
+        k_drift_bps
+    } else {
+        -k_drift_bps
+    })
+}
+
+/// Rejects a now earlier than a timestamp already stored, which would make the elapsed time that
+/// funding and twap math weights by negative
+pub fn validate_timestamp_not_before(now: i64, last_ts: i64) -> ClearingHouseResult {
+    if now < last_ts {
+        msg!(
+            "Timestamp {} is before last recorded timestamp {}",
+            now,
+            last_ts
+        );
+        return Err(ErrorCode::InvalidTimestamp);
+    }
+
+    Ok(())
+}
+
+/// Bounds how far a swap can move the mark price from the oracle price (in MARK_PRICE_PRECISION),
+/// so thin liquidity can't be used to push the amm around. A max_oracle_divergence_bps of 0 is not
+/// enforced.
+pub fn validate_mark_oracle_divergence(amm: &AMM, oracle_price: u128) -> ClearingHouseResult {
+    if amm.max_oracle_divergence_bps == 0 || oracle_price == 0 {
+        return Ok(());
+    }
+
+    let mark_price = amm.mark_price()?;
+    let divergence_bps = max(mark_price, oracle_price)
+        .checked_sub(min(mark_price, oracle_price))
+        .ok_or_else(math_error!())?
+        .checked_mul(BPS_PRECISION)
+        .ok_or_else(math_error!())?
+        .checked_div(oracle_price)
+        .ok_or_else(math_error!())?;
+
+    if divergence_bps > cast_to_u128(amm.max_oracle_divergence_bps)? {
+        msg!(
+            "Mark price {} is {} bps from oracle price {}",
+            mark_price,
+            divergence_bps,
+            oracle_price
+        );
+        return Err(ErrorCode::OracleMarkTooDivergent);
+    }
+
+    Ok(())
+}
+
 pub fn update_mark_twap(
     amm: &mut AMM,
     now: i64,
     precomputed_mark_price: Option<u128>,
 ) -> ClearingHouseResult<u128> {
+    validate_timestamp_not_before(now, amm.last_mark_price_twap_ts)?;
     let mark_twap = calculate_new_mark_twap(amm, now, precomputed_mark_price)?;
     amm.last_mark_price_twap = mark_twap;
     amm.last_mark_price_twap_ts = now;
@@ -97,11 +302,13 @@ pub fn calculate_new_mark_twap(
     Ok(new_twap)
 }
 
-pub fn update_oracle_price_twap(
+pub fn update_oracle_twap(
     amm: &mut AMM,
-    now: i64,
     oracle_price: i128,
+    now: i64,
 ) -> ClearingHouseResult<i128> {
+    validate_timestamp_not_before(now, amm.last_oracle_price_twap_ts)?;
+
     let new_oracle_price_spread = oracle_price
         .checked_sub(amm.last_oracle_price_twap)
         .ok_or_else(math_error!())?;
@@ -127,7 +334,10 @@ pub fn update_oracle_price_twap(
     // sanity check
     let oracle_price_twap: i128;
     if capped_oracle_update_price > 0 && oracle_price > 0 {
-        oracle_price_twap = calculate_new_oracle_price_twap(amm, now, capped_oracle_update_price)?;
+        let interpolated_oracle_price =
+            calculate_interpolated_oracle_price(amm, capped_oracle_update_price)?;
+        amm.append_oracle_observation(interpolated_oracle_price, now)?;
+        oracle_price_twap = calculate_oracle_observations_twap(amm, now)?;
         amm.last_oracle_price = capped_oracle_update_price;
         amm.last_oracle_price_twap = oracle_price_twap;
         amm.last_oracle_price_twap_ts = now;
@@ -138,23 +348,9 @@ pub fn update_oracle_price_twap(
     Ok(oracle_price_twap)
 }
 
-pub fn calculate_new_oracle_price_twap(
-    amm: &AMM,
-    now: i64,
-    oracle_price: i128,
-) -> ClearingHouseResult<i128> {
-    let since_last = cast_to_i128(max(
-        1,
-        now.checked_sub(amm.last_oracle_price_twap_ts)
-            .ok_or_else(math_error!())?,
-    ))?;
-    let from_start = max(
-        1,
-        cast_to_i128(amm.funding_period)?
-            .checked_sub(since_last)
-            .ok_or_else(math_error!())?,
-    );
-
+/// Nudges the last oracle price up to .1% toward the new oracle price, so a single observation can
+/// only move the twap so far
+fn calculate_interpolated_oracle_price(amm: &AMM, oracle_price: i128) -> ClearingHouseResult<i128> {
     // ensure amm.last_oracle_price is proper
     let capped_last_oracle_price = if amm.last_oracle_price > 0 {
         amm.last_oracle_price
@@ -162,12 +358,11 @@ pub fn calculate_new_oracle_price_twap(
         oracle_price
     };
 
-    // nudge last_oracle_price up to .1% toward oracle price
     let capped_last_oracle_price_10bp = capped_last_oracle_price
         .checked_div(1000)
         .ok_or_else(math_error!())?;
 
-    let interpolated_oracle_price = min(
+    Ok(min(
         capped_last_oracle_price
             .checked_add(capped_last_oracle_price_10bp)
             .ok_or_else(math_error!())?,
@@ -177,16 +372,87 @@ pub fn calculate_new_oracle_price_twap(
                 .ok_or_else(math_error!())?,
             oracle_price,
         ),
-    );
+    ))
+}
 
-    let new_twap = calculate_twap(
-        interpolated_oracle_price,
-        amm.last_oracle_price_twap,
-        since_last,
-        from_start,
-    )?;
+/// Time weights the amm's recorded oracle observations over the last funding period. Each
+/// observation is weighted over the time since the one before it, and the latest observation also
+/// holds from when it was recorded until now. The buffer only holds a few observations, so the part
+/// of the window they don't cover is weighted at the previous twap. If no time has elapsed since
+/// the observations were recorded, the previous twap is kept.
+pub fn calculate_oracle_observations_twap(amm: &AMM, now: i64) -> ClearingHouseResult<i128> {
+    let window = max(1, amm.funding_period);
+    let window_start = now.checked_sub(window).ok_or_else(math_error!())?;
+
+    // the head points at the oldest observation
+    let buffer_size = amm.oracle_observations.len();
+    let head: usize = cast(amm.oracle_observations_head)?;
+    let observations = (0..buffer_size)
+        .map(|i| amm.oracle_observations[(head + i) % buffer_size])
+        .filter(|observation| observation.ts != 0)
+        .collect::<Vec<_>>();
+
+    let mut weighted_price_sum: i128 = 0;
+    let mut total_weight: i128 = 0;
+    for (i, observation) in observations.iter().enumerate() {
+        // the segment before the oldest observation was evicted and is in the previous twap
+        let previous_ts = if i == 0 {
+            observation.ts
+        } else {
+            observations[i - 1].ts
+        };
+        let segment_start = max(previous_ts, window_start);
+        let segment_end = if i + 1 == observations.len() {
+            now
+        } else {
+            observation.ts
+        };
 
-    Ok(new_twap)
+        if segment_end <= segment_start {
+            continue;
+        }
+
+        let weight = cast_to_i128(
+            segment_end
+                .checked_sub(segment_start)
+                .ok_or_else(math_error!())?,
+        )?;
+        weighted_price_sum = weighted_price_sum
+            .checked_add(
+                cast_to_i128(observation.price)?
+                    .checked_mul(weight)
+                    .ok_or_else(math_error!())?,
+            )
+            .ok_or_else(math_error!())?;
+        total_weight = total_weight.checked_add(weight).ok_or_else(math_error!())?;
+    }
+
+    if total_weight == 0 {
+        return Ok(match observations.last() {
+            Some(latest_observation) if amm.last_oracle_price_twap <= 0 => {
+                cast_to_i128(latest_observation.price)?
+            }
+            _ => amm.last_oracle_price_twap,
+        });
+    }
+
+    let observations_twap = weighted_price_sum
+        .checked_div(total_weight)
+        .ok_or_else(math_error!())?;
+
+    let uncovered_weight = cast_to_i128(window)?
+        .checked_sub(total_weight)
+        .ok_or_else(math_error!())?;
+    if uncovered_weight <= 0 || amm.last_oracle_price_twap <= 0 {
+        return Ok(observations_twap);
+    }
+
+    calculate_twap(
+        observations_twap,
+        amm.last_oracle_price_twap,
+        total_weight,
+        uncovered_weight,
+    )
 }
 
 pub fn calculate_twap(
@@ -268,15 +534,20 @@ pub fn calculate_oracle_mark_spread(
     let mark_price: i128;
     let mark_price_1bp: i128;
 
-    let (oracle_price, oracle_twap, _oracle_conf, _oracle_twac, _oracle_delay) =
-        amm.get_oracle_price(price_oracle, clock_slot)?;
+    let OraclePriceData {
+        price: oracle_price,
+        twap: oracle_twap,
+        confidence: oracle_conf,
+        twap_confidence: oracle_twac,
+        ..
+    } = amm.get_oracle_price(price_oracle, clock_slot)?;
 
     let oracle_processed: i128;
 
     if window > 0 {
         mark_price = cast_to_i128(amm.last_mark_price_twap)?;
         mark_price_1bp = mark_price.checked_div(10000).ok_or_else(math_error!())?;
-        let conf_int = cast_to_i128(_oracle_twac)?;
+        let conf_int = cast_to_i128(oracle_twac)?;
 
         oracle_processed = if normalise {
             if mark_price > oracle_twap {
@@ -326,7 +597,7 @@ pub fn calculate_oracle_mark_spread(
         //  (this guarantees more reasonable funding rates in volatile periods)
         oracle_processed = if normalise {
             mark_price_1bp = mark_price.checked_div(10000).ok_or_else(math_error!())?;
-            let conf_int = cast_to_i128(_oracle_conf)?;
+            let conf_int = cast_to_i128(oracle_conf)?;
 
             if mark_price > oracle_price {
                 min(
@@ -406,14 +677,46 @@ pub fn is_oracle_mark_too_divergent(
     Ok(price_spread_pct.unsigned_abs() > max_divergence)
 }
 
+/// Whether the mark has moved further from its twap than numerator / denominator allows. A zero
+/// denominator disables the check.
+pub fn is_mark_twap_too_divergent(
+    amm: &AMM,
+    numerator: u64,
+    denominator: u64,
+) -> ClearingHouseResult<bool> {
+    if denominator == 0 || amm.last_mark_price_twap == 0 {
+        return Ok(false);
+    }
+
+    let mark_price = amm.mark_price()?;
+    let mark_twap_spread = cast_to_i128(mark_price)?
+        .checked_sub(cast_to_i128(amm.last_mark_price_twap)?)
+        .ok_or_else(math_error!())?
+        .unsigned_abs();
+
+    let max_mark_twap_spread = amm
+        .last_mark_price_twap
+        .checked_mul(cast_to_u128(numerator)?)
+        .ok_or_else(math_error!())?
+        .checked_div(cast_to_u128(denominator)?)
+        .ok_or_else(math_error!())?;
+
+    Ok(mark_twap_spread > max_mark_twap_spread)
+}
+
 pub fn is_oracle_valid(
     amm: &AMM,
     price_oracle: &AccountInfo,
     clock_slot: u64,
     valid_oracle_guard_rails: &ValidityGuardRails,
 ) -> ClearingHouseResult<bool> {
-    let (oracle_price, oracle_twap, oracle_conf, oracle_twap_conf, oracle_delay) =
-        amm.get_oracle_price(price_oracle, clock_slot)?;
+    let OraclePriceData {
+        price: oracle_price,
+        twap: oracle_twap,
+        confidence: oracle_conf,
+        twap_confidence: oracle_twap_conf,
+        delay: oracle_delay,
+    } = amm.get_oracle_price(price_oracle, clock_slot)?;
 
     let is_oracle_price_nonpositive = (oracle_twap <= 0) || (oracle_price <= 0);
 
@@ -515,3 +818,306 @@ pub fn should_round_trade(
 
     Ok(quote_asset_reserve_amount < amm.minimum_trade_size)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, MARK_PRICE_PRECISION, PEG_PRECISION, QUOTE_PRECISION,
+    };
+    use crate::state::market::ORACLE_OBSERVATIONS_SIZE;
+    use crate::state::user::MarketPosition;
+
+    fn amm_with_oracle_twap(last_oracle_price_twap: i128, ts: i64) -> AMM {
+        let mut amm = AMM {
+            funding_period: 3600,
+            last_oracle_price_twap,
+            last_oracle_price_twap_ts: ts,
+            ..AMM::default()
+        };
+        amm.reset_oracle_observations(last_oracle_price_twap, ts)
+            .unwrap();
+        amm
+    }
+
+    #[test]
+    fn oracle_twap_weights_observations_by_time() {
+        let mut amm = amm_with_oracle_twap(100, 1000);
+        amm.append_oracle_observation(110, 1600).unwrap();
+        amm.append_oracle_observation(120, 2200).unwrap();
+        amm.append_oracle_observation(130, 2800).unwrap();
+
+        // 600s each at 110, 120 and 130, and the 1800s the observations don't cover at 100
+        let twap = calculate_oracle_observations_twap(&amm, 2800).unwrap();
+        assert_eq!(
+            twap,
+            (110 * 600 + 120 * 600 + 130 * 600 + 100 * 1800) / 3600
+        );
+    }
+
+    #[test]
+    fn oracle_twap_weights_the_latest_observation_up_to_now() {
+        let mut amm = amm_with_oracle_twap(100, 1000);
+        amm.append_oracle_observation(200, 1360).unwrap();
+
+        let twap = calculate_oracle_observations_twap(&amm, 1360).unwrap();
+        assert_eq!(twap, (200 * 360 + 100 * 3240) / 3600);
+
+        // and it keeps holding until the next observation
+        let twap = calculate_oracle_observations_twap(&amm, 1720).unwrap();
+        assert_eq!(twap, (200 * 720 + 100 * 2880) / 3600);
+    }
+
+    #[test]
+    fn oracle_twap_only_weights_the_window() {
+        let mut amm = amm_with_oracle_twap(100, 1000);
+        amm.append_oracle_observation(110, 2000).unwrap();
+        amm.append_oracle_observation(120, 5000).unwrap();
+
+        // only the last 3600s count: 400s at 110 and 3200s at 120
+        let twap = calculate_oracle_observations_twap(&amm, 5000).unwrap();
+        assert_eq!(twap, (110 * 400 + 120 * 3200) / 3600);
+    }
+
+    #[test]
+    fn oracle_twap_evicts_the_oldest_observation() {
+        let mut amm = amm_with_oracle_twap(100, 1000);
+        for i in 1..=ORACLE_OBSERVATIONS_SIZE as i64 {
+            amm.append_oracle_observation(100 + 10 * i128::from(i), 1000 + 100 * i)
+                .unwrap();
+        }
+
+        // the reset observation was evicted, so the first 100s are carried by the previous twap
+        let now = 1000 + 100 * ORACLE_OBSERVATIONS_SIZE as i64;
+        let covered = 100 * (ORACLE_OBSERVATIONS_SIZE as i128 - 1);
+        let weighted_price_sum: i128 = (2..=ORACLE_OBSERVATIONS_SIZE as i128)
+            .map(|i| (100 + 10 * i) * 100)
+            .sum();
+        let observations_twap = weighted_price_sum / covered;
+        let twap = calculate_oracle_observations_twap(&amm, now).unwrap();
+        assert_eq!(
+            twap,
+            (observations_twap * covered + 100 * (3600 - covered)) / 3600
+        );
+    }
+
+    #[test]
+    fn oracle_twap_keeps_previous_twap_when_no_time_elapsed() {
+        let amm = amm_with_oracle_twap(100, 1000);
+        assert_eq!(calculate_oracle_observations_twap(&amm, 1000).unwrap(), 100);
+
+        let mut amm = amm_with_oracle_twap(100, 1000);
+        amm.append_oracle_observation(150, 1600).unwrap();
+        amm.append_oracle_observation(130, 1600).unwrap();
+
+        // an observation at the same timestamp replaces the latest one
+        let twap = calculate_oracle_observations_twap(&amm, 1600).unwrap();
+        assert_eq!(twap, (130 * 600 + 100 * 3000) / 3600);
+    }
+
+    #[test]
+    fn twap_update_before_the_last_update_is_rejected() {
+        let mut amm = amm_with_oracle_twap(100, 1000);
+        let res = update_oracle_twap(&mut amm, 110, 999);
+        assert!(matches!(res, Err(ErrorCode::InvalidTimestamp)));
+        assert_eq!({ amm.last_oracle_price_twap }, 100);
+        assert_eq!({ amm.last_oracle_price_twap_ts }, 1000);
+
+        let mut amm = market().amm;
+        amm.last_mark_price_twap = MARK_PRICE_PRECISION;
+        amm.last_mark_price_twap_ts = 1000;
+        let res = update_mark_twap(&mut amm, 999, None);
+        assert!(matches!(res, Err(ErrorCode::InvalidTimestamp)));
+        assert_eq!({ amm.last_mark_price_twap_ts }, 1000);
+
+        // the same timestamp is still accepted
+        assert!(update_oracle_twap(&mut amm_with_oracle_twap(100, 1000), 110, 1000).is_ok());
+    }
+
+    /// A market priced at 1, with the reserves the ts tests initialize markets with
+    fn market() -> Market {
+        let reserve = 5 * 10_u128.pow(18);
+        Market {
+            initialized: true,
+            amm: AMM {
+                base_asset_reserve: reserve,
+                quote_asset_reserve: reserve,
+                sqrt_k: reserve,
+                peg_multiplier: PEG_PRECISION,
+                funding_period: 3600,
+                ..AMM::default()
+            },
+            ..Market::default()
+        }
+    }
+
+    /// market after users went long for quote_asset_amount against the amm
+    fn net_long_market(quote_asset_amount: u128) -> Market {
+        let mut market = market();
+        let base_asset_amount = crate::controller::amm::swap_quote_asset(
+            &mut market.amm,
+            quote_asset_amount,
+            SwapDirection::Add,
+            0,
+            None,
+            None,
+        )
+        .unwrap();
+        market.base_asset_amount = base_asset_amount;
+        market.base_asset_amount_long = base_asset_amount;
+        market.quote_asset_amount_long = quote_asset_amount;
+        market.open_interest = 1;
+        market
+    }
+
+    #[test]
+    fn protocol_is_short_net_long_user_open_interest() {
+        let market = net_long_market(10 * QUOTE_PRECISION);
+
+        let net_exposure = get_protocol_net_exposure(&market).unwrap();
+        assert!(net_exposure < 0);
+        assert_eq!(net_exposure, -{ market.base_asset_amount });
+
+        // unwinding the users' 10 USDC long pays back just under 10 USDC
+        let net_exposure_notional = get_protocol_net_exposure_notional(&market).unwrap();
+        assert!(net_exposure_notional < 0);
+        assert!(net_exposure_notional >= -((10 * QUOTE_PRECISION) as i128));
+        assert!(net_exposure_notional < -((9 * QUOTE_PRECISION) as i128));
+    }
+
+    #[test]
+    fn reserves_for_mark_prices_the_curve_at_the_target() {
+        let sqrt_k = 5 * 10_u128.pow(18);
+        for peg_multiplier in [PEG_PRECISION, 25_123] {
+            for target_mark in [
+                MARK_PRICE_PRECISION / 2,
+                MARK_PRICE_PRECISION,
+                13_700_000_000,
+                25 * MARK_PRICE_PRECISION,
+            ] {
+                let (base_asset_reserve, quote_asset_reserve) =
+                    reserves_for_mark(sqrt_k, peg_multiplier, target_mark).unwrap();
+                let amm = AMM {
+                    base_asset_reserve,
+                    quote_asset_reserve,
+                    sqrt_k,
+                    peg_multiplier,
+                    ..AMM::default()
+                };
+
+                // the reserves round, so the mark lands within a unit of the target
+                let mark_price = amm.mark_price().unwrap();
+                assert!(mark_price.abs_diff(target_mark) <= 1);
+                // and k stays where it was
+                assert!(get_k_drift_bps(&amm).unwrap().abs() <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn open_interest_notional_values_the_larger_side_at_the_mark() {
+        let mut market = market();
+        market.amm.peg_multiplier = 2 * PEG_PRECISION;
+        market.base_asset_amount_long = (30 * AMM_RESERVE_PRECISION) as i128;
+        market.base_asset_amount_short = -((12 * AMM_RESERVE_PRECISION) as i128);
+        assert_eq!(market.amm.mark_price().unwrap(), 2 * MARK_PRICE_PRECISION);
+
+        // 30 long at 2, not the 84 of summing both sides
+        assert_eq!(
+            get_open_interest_notional(&market).unwrap(),
+            60 * QUOTE_PRECISION
+        );
+
+        market.base_asset_amount_short = -((45 * AMM_RESERVE_PRECISION) as i128);
+        assert_eq!(
+            get_open_interest_notional(&market).unwrap(),
+            90 * QUOTE_PRECISION
+        );
+    }
+
+    #[test]
+    fn k_drift_stays_small_across_trades_and_flags_a_corrupted_reserve() {
+        let mut market = market();
+        assert_eq!(get_k_drift_bps(&market.amm).unwrap(), 0);
+
+        // odd sized trades in both directions, each rounding the reserves
+        for i in 1..=50_u128 {
+            let swap_direction = if i % 2 == 0 {
+                SwapDirection::Remove
+            } else {
+                SwapDirection::Add
+            };
+            crate::controller::amm::swap_quote_asset(
+                &mut market.amm,
+                i * 1_234_567,
+                swap_direction,
+                0,
+                None,
+                None,
+            )
+            .unwrap();
+        }
+        assert!(get_k_drift_bps(&market.amm).unwrap().abs() <= 1);
+
+        // a reserve 1% off moves k by 1%
+        market.amm.base_asset_reserve = market.amm.base_asset_reserve * 101 / 100;
+        let k_drift_bps = get_k_drift_bps(&market.amm).unwrap();
+        assert!((99..=101).contains(&k_drift_bps));
+        assert_eq!(
+            get_current_k(&market.amm).unwrap(),
+            market.amm.base_asset_reserve * market.amm.quote_asset_reserve
+        );
+    }
+
+    #[test]
+    fn amm_pnl_moves_opposite_to_traders() {
+        let mut net_short_market = market();
+        let base_asset_amount = crate::controller::amm::swap_quote_asset(
+            &mut net_short_market.amm,
+            10 * QUOTE_PRECISION,
+            SwapDirection::Remove,
+            0,
+            None,
+            None,
+        )
+        .unwrap();
+        net_short_market.base_asset_amount = base_asset_amount;
+        net_short_market.base_asset_amount_short = base_asset_amount;
+        net_short_market.quote_asset_amount_short = 10 * QUOTE_PRECISION;
+
+        for market in [net_long_market(10 * QUOTE_PRECISION), net_short_market] {
+            // the traders' aggregate position
+            let market_position = MarketPosition {
+                base_asset_amount: market.base_asset_amount,
+                quote_asset_amount: market.quote_asset_amount_long
+                    + market.quote_asset_amount_short,
+                ..MarketPosition::default()
+            };
+
+            // others trade the price up, or down
+            for swap_direction in [SwapDirection::Add, SwapDirection::Remove] {
+                let mut market = market;
+                crate::controller::amm::swap_quote_asset(
+                    &mut market.amm,
+                    1_000 * QUOTE_PRECISION,
+                    swap_direction,
+                    0,
+                    None,
+                    None,
+                )
+                .unwrap();
+
+                let (_base_asset_value, traders_unrealized_pnl) =
+                    market_position.notional_and_pnl(&market).unwrap();
+                let amm_unrealized_pnl = get_amm_unrealized_pnl(&market).unwrap();
+                assert_ne!(traders_unrealized_pnl, 0);
+                assert_eq!(
+                    amm_unrealized_pnl.signum(),
+                    -traders_unrealized_pnl.signum()
+                );
+                // a profitable short gives up a unit to rounding that the amm keeps
+                assert!((amm_unrealized_pnl + traders_unrealized_pnl).abs() <= 1);
+            }
+        }
+    }
+}
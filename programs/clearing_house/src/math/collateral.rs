@@ -1,7 +1,27 @@
 use crate::error::*;
+use crate::math::casting::cast_to_u128;
+use crate::math::constants::BPS_PRECISION;
 use crate::math_error;
+use crate::state::user::User;
 use solana_program::msg;
 
+/// The user's collateral as margin sees it: the primary collateral plus the secondary collateral
+/// after its haircut, secondary_collateral_weight_bps being the share of it that counts
+pub fn calculate_collateral_value(
+    user: &User,
+    secondary_collateral_weight_bps: u64,
+) -> ClearingHouseResult<u128> {
+    let secondary_collateral_value = cast_to_u128(user.secondary_collateral)?
+        .checked_mul(cast_to_u128(secondary_collateral_weight_bps)?.min(BPS_PRECISION))
+        .ok_or_else(math_error!())?
+        .checked_div(BPS_PRECISION)
+        .ok_or_else(math_error!())?;
+
+    user.collateral
+        .checked_add(secondary_collateral_value)
+        .ok_or_else(math_error!())
+}
+
 pub fn calculate_updated_collateral(collateral: u128, pnl: i128) -> ClearingHouseResult<u128> {
     Ok(if pnl.is_negative() && pnl.unsigned_abs() > collateral {
         0
@@ -15,3 +35,63 @@ pub fn calculate_updated_collateral(collateral: u128, pnl: i128) -> ClearingHous
             .ok_or_else(math_error!())?
     })
 }
+
+/// Applies several collateral changes (pnl, fees, funding) as one net change, so a loss applied
+/// before an offsetting gain can't floor the collateral at zero on the way
+pub fn apply_collateral_deltas(collateral: u128, deltas: &[i128]) -> ClearingHouseResult<u128> {
+    let mut net_delta: i128 = 0;
+    for delta in deltas {
+        net_delta = net_delta.checked_add(*delta).ok_or_else(math_error!())?;
+    }
+
+    calculate_updated_collateral(collateral, net_delta)
+}
+
+/// calculate_updated_collateral along with the bad debt: the part of a loss larger than the
+/// collateral, which the collateral can't cover
+pub fn calculate_updated_collateral_and_bad_debt(
+    collateral: u128,
+    pnl: i128,
+) -> ClearingHouseResult<(u128, u128)> {
+    let bad_debt = if pnl.is_negative() && pnl.unsigned_abs() > collateral {
+        pnl.unsigned_abs()
+            .checked_sub(collateral)
+            .ok_or_else(math_error!())?
+    } else {
+        0
+    };
+
+    Ok((calculate_updated_collateral(collateral, pnl)?, bad_debt))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::QUOTE_PRECISION;
+
+    #[test]
+    fn netted_deltas_dont_floor_collateral_on_the_way() {
+        let collateral = 5 * QUOTE_PRECISION;
+        let pnl = (10 * QUOTE_PRECISION) as i128;
+        let fee = -((QUOTE_PRECISION / 2) as i128);
+        let funding_payment = -((8 * QUOTE_PRECISION) as i128);
+
+        // applied one at a time, the funding owed floors the collateral before the gain lands
+        let mut sequential_collateral = collateral;
+        for delta in [funding_payment, fee, pnl] {
+            sequential_collateral =
+                calculate_updated_collateral(sequential_collateral, delta).unwrap();
+        }
+        assert_eq!(sequential_collateral, 10 * QUOTE_PRECISION);
+
+        assert_eq!(
+            apply_collateral_deltas(collateral, &[funding_payment, fee, pnl]).unwrap(),
+            collateral + 10 * QUOTE_PRECISION - QUOTE_PRECISION / 2 - 8 * QUOTE_PRECISION
+        );
+        // a net loss larger than the collateral still floors it at zero
+        assert_eq!(
+            apply_collateral_deltas(collateral, &[funding_payment, fee]).unwrap(),
+            0
+        );
+    }
+}
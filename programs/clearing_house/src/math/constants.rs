@@ -5,6 +5,7 @@ pub const QUOTE_PRECISION: u128 = 1_000_000; // expo = -6
 pub const FUNDING_PAYMENT_PRECISION: u128 = 10_000; // expo = -4
 pub const MARGIN_PRECISION: u128 = 10_000; // expo = -4
 pub const PEG_PRECISION: u128 = 1_000; //expo = -3
+pub const BPS_PRECISION: u128 = 10_000; // expo = -4
 
 // PRECISION CONVERSIONS
 pub const PRICE_TO_PEG_PRECISION_RATIO: u128 = MARK_PRICE_PRECISION / PEG_PRECISION; // expo: 7
@@ -25,6 +26,12 @@ pub const UPDATE_K_ALLOWED_PRICE_CHANGE: u128 = MARK_PRICE_PRECISION / 10;
 
 // TIME PERIODS
 pub const ONE_HOUR: i128 = 3600;
+pub const ONE_YEAR: i128 = 31_536_000; // 365 days
+pub const PNL_VELOCITY_WINDOW: i128 = ONE_HOUR;
+pub const KEEPER_REWARD_AUCTION_DURATION: i128 = 60; // 1 minute
+
+// KEEPER REWARDS
+pub const MAX_KEEPER_REWARD: u128 = 10 * QUOTE_PRECISION; // 10 USDC
 
 // FEES
 pub const DEFAULT_FEE_NUMERATOR: u128 = 10;
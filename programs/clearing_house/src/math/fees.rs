@@ -1,5 +1,8 @@
 use crate::error::*;
+use crate::math::casting::{cast_to_i128, cast_to_u128};
+use crate::math::constants::BPS_PRECISION;
 use crate::math_error;
+use crate::state::market::{Market, AMM};
 use crate::state::state::{DiscountTokenTier, FeeStructure};
 use crate::state::user::User;
 use anchor_lang::Account;
@@ -42,6 +45,56 @@ pub fn calculate(
     ))
 }
 
+/// The part of fee_to_market routed to the insurance fund. While the market has routed less than
+/// its insurance_fund_target, insurance_fund_fee_share_bps of fees go to the insurance fund, never
+/// overshooting the target. Once it's capitalized, nothing extra is routed.
+pub fn calculate_fee_to_insurance_fund(
+    fee_to_market: u128,
+    market: &Market,
+) -> ClearingHouseResult<u128> {
+    let insurance_fund_target = cast_to_u128(market.insurance_fund_target)?;
+    let total_fee_to_insurance_fund = cast_to_u128(market.total_fee_to_insurance_fund)?;
+    if total_fee_to_insurance_fund >= insurance_fund_target {
+        return Ok(0);
+    }
+
+    let fee_to_insurance_fund = fee_to_market
+        .checked_mul(cast_to_u128(market.insurance_fund_fee_share_bps)?)
+        .ok_or_else(math_error!())?
+        .checked_div(BPS_PRECISION)
+        .ok_or_else(math_error!())?;
+
+    Ok(fee_to_insurance_fund.min(insurance_fund_target - total_fee_to_insurance_fund))
+}
+
+/// Fee for the base asset a trade on a base-denominated market opened: the flat fee rate skimmed
+/// from the base asset acquired, so the quote spent is exact, and its value in quote at the trade's
+/// fill price so it books like any other fee. Token discounts and referral rewards don't apply to it.
+/// Returns the fee in base and its value in quote.
+pub fn calculate_base_asset_fee(
+    base_asset_amount: u128,
+    quote_asset_amount: u128,
+    fee_structure: &FeeStructure,
+) -> ClearingHouseResult<(u128, u128)> {
+    if base_asset_amount == 0 {
+        return Ok((0, 0));
+    }
+
+    let base_asset_fee = base_asset_amount
+        .checked_mul(fee_structure.fee_numerator)
+        .ok_or_else(math_error!())?
+        .checked_div(fee_structure.fee_denominator)
+        .ok_or_else(math_error!())?;
+
+    let base_asset_fee_value = base_asset_fee
+        .checked_mul(quote_asset_amount)
+        .ok_or_else(math_error!())?
+        .checked_div(base_asset_amount)
+        .ok_or_else(math_error!())?;
+
+    Ok((base_asset_fee, base_asset_fee_value))
+}
+
 fn calculate_token_discount(
     fee: u128,
     fee_structure: &FeeStructure,
@@ -124,3 +177,115 @@ fn calculate_referral_reward_and_referee_discount(
 
     Ok((referrer_reward, referee_discount))
 }
+
+/// Fees the amm has collected that were neither distributed back to the market (funding, repegs and
+/// k adjustments) nor withdrawn, so that
+/// total_fee == distributions + total_fee_withdrawn + undistributed fees.
+/// Negative means more was withdrawn than the distributions left behind.
+pub fn calculate_undistributed_fees(amm: &AMM) -> ClearingHouseResult<i128> {
+    cast_to_i128(amm.total_fee_minus_distributions)?
+        .checked_sub(cast_to_i128(amm.total_fee_withdrawn)?)
+        .ok_or_else(math_error!())
+}
+
+/// Net fees distributed back to the market. Negative when the market has paid more into the fee
+/// pool, e.g. from funding imbalances, than it has received.
+pub fn calculate_fee_distributions(amm: &AMM) -> ClearingHouseResult<i128> {
+    cast_to_i128(amm.total_fee)?
+        .checked_sub(cast_to_i128(amm.total_fee_minus_distributions)?)
+        .ok_or_else(math_error!())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, FUNDING_PAYMENT_PRECISION, MARK_PRICE_PRECISION, QUOTE_PRECISION,
+    };
+    use crate::math::funding::calculate_funding_rate_long_short;
+
+    #[test]
+    fn fee_routing_stops_once_the_insurance_fund_crosses_its_target() {
+        let mut market = Market {
+            insurance_fund_target: (12 * QUOTE_PRECISION) as u64,
+            insurance_fund_fee_share_bps: 5000,
+            ..Market::default()
+        };
+        let fee_to_market = 10 * QUOTE_PRECISION;
+
+        // half of each fee while below target, the last only topping it up to the target
+        let mut fees_to_insurance_fund = vec![];
+        for _ in 0..4 {
+            let fee_to_insurance_fund =
+                calculate_fee_to_insurance_fund(fee_to_market, &market).unwrap();
+            market.total_fee_to_insurance_fund += fee_to_insurance_fund as u64;
+            fees_to_insurance_fund.push(fee_to_insurance_fund);
+        }
+
+        assert_eq!(
+            fees_to_insurance_fund,
+            vec![
+                5 * QUOTE_PRECISION,
+                5 * QUOTE_PRECISION,
+                2 * QUOTE_PRECISION,
+                0
+            ]
+        );
+        assert_eq!({ market.total_fee_to_insurance_fund }, {
+            market.insurance_fund_target
+        });
+
+        // raising the target resumes routing
+        market.insurance_fund_target += QUOTE_PRECISION as u64;
+        assert_eq!(
+            calculate_fee_to_insurance_fund(fee_to_market, &market).unwrap(),
+            QUOTE_PRECISION
+        );
+    }
+
+    fn assert_fees_reconcile(amm: &AMM) {
+        assert_eq!(
+            cast_to_i128(amm.total_fee).unwrap(),
+            calculate_fee_distributions(amm).unwrap()
+                + cast_to_i128(amm.total_fee_withdrawn).unwrap()
+                + calculate_undistributed_fees(amm).unwrap()
+        );
+    }
+
+    #[test]
+    fn fee_accounts_reconcile_through_collection_distribution_and_withdrawal() {
+        let mut market = Market {
+            base_asset_amount: (10 * AMM_RESERVE_PRECISION) as i128,
+            base_asset_amount_long: (10 * AMM_RESERVE_PRECISION) as i128,
+            ..Market::default()
+        };
+        assert_fees_reconcile(&market.amm);
+
+        // collection, as open_position books the fee to the market
+        let fee_to_market = 10 * QUOTE_PRECISION;
+        market.amm.total_fee += fee_to_market;
+        market.amm.total_fee_minus_distributions += fee_to_market;
+        assert_fees_reconcile(&market.amm);
+        assert_eq!(calculate_fee_distributions(&market.amm).unwrap(), 0);
+
+        // distribution, as the clearing house pays the funding its net short side owes the longs
+        let funding_rate = -((MARK_PRICE_PRECISION * FUNDING_PAYMENT_PRECISION / 100) as i128);
+        calculate_funding_rate_long_short(&mut market, funding_rate).unwrap();
+        assert_fees_reconcile(&market.amm);
+        let fee_distributions = calculate_fee_distributions(&market.amm).unwrap();
+        assert_eq!(fee_distributions, (QUOTE_PRECISION / 10) as i128);
+
+        // withdrawal, as withdraw_fees books it
+        let withdrawal = 2 * QUOTE_PRECISION;
+        market.amm.total_fee_withdrawn += withdrawal;
+        assert_fees_reconcile(&market.amm);
+        assert_eq!(
+            calculate_fee_distributions(&market.amm).unwrap(),
+            fee_distributions
+        );
+        assert_eq!(
+            calculate_undistributed_fees(&market.amm).unwrap(),
+            (fee_to_market - withdrawal) as i128 - fee_distributions
+        );
+    }
+}
@@ -1,16 +1,84 @@
 use crate::error::*;
+use crate::math::amm::calculate_new_mark_twap;
 use crate::math::bn;
-use crate::math::casting::cast_to_i128;
+use crate::math::casting::{cast, cast_to_i128, cast_to_i64};
 use crate::math::constants::{
-    AMM_TO_QUOTE_PRECISION_RATIO, FUNDING_PAYMENT_PRECISION, MARK_PRICE_PRECISION,
-    QUOTE_TO_BASE_AMT_FUNDING_PRECISION, SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_DENOMINATOR,
+    AMM_TO_QUOTE_PRECISION_RATIO, AMM_TO_QUOTE_PRECISION_RATIO_I128, FUNDING_PAYMENT_PRECISION,
+    MARK_PRICE_PRECISION, ONE_HOUR, ONE_YEAR, QUOTE_TO_BASE_AMT_FUNDING_PRECISION,
+    SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_DENOMINATOR,
     SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_NUMERATOR,
 };
 use crate::math_error;
-use crate::state::market::Market;
+use crate::state::market::{Market, AMM};
 use crate::state::user::MarketPosition;
 use solana_program::msg;
-use std::cmp::max;
+use std::cmp::{max, min};
+
+/// The funding rate for one funding period. The mark/oracle twap spread is clamped to 3% and spread
+/// over a one day window, so shorter funding periods pay proportionally less each period.
+pub fn calculate_funding_rate(
+    mark_price_twap: u128,
+    oracle_price_twap: i128,
+    funding_period: i64,
+) -> ClearingHouseResult<i128> {
+    let one_hour_i64 = cast_to_i64(ONE_HOUR)?;
+    let period_adjustment = (24_i64)
+        .checked_mul(one_hour_i64)
+        .ok_or_else(math_error!())?
+        .checked_div(max(one_hour_i64, funding_period))
+        .ok_or_else(math_error!())?;
+    // funding period = 1 hour, window = 1 day
+    // low periodicity => quickly updating/settled funding rates => lower funding rate payment per interval
+    let price_spread = cast_to_i128(mark_price_twap)?
+        .checked_sub(oracle_price_twap)
+        .ok_or_else(math_error!())?;
+
+    // clamp price divergence to 3% for funding rate calculation
+    let max_price_spread = oracle_price_twap
+        .checked_div(33)
+        .ok_or_else(math_error!())?; // 3%
+    let clamped_price_spread = max(-max_price_spread, min(price_spread, max_price_spread));
+
+    let funding_rate = clamped_price_spread
+        .checked_mul(cast(FUNDING_PAYMENT_PRECISION)?)
+        .ok_or_else(math_error!())?
+        .checked_div(cast(period_adjustment)?)
+        .ok_or_else(math_error!())?;
+
+    Ok(funding_rate)
+}
+
+/// The last funding rate annualized into an implied cost of carry for a long, relative to the mark
+/// twap as of now, so it can be compared against spot borrow rates. In MARK_PRICE_PRECISION, so
+/// MARK_PRICE_PRECISION is 100% a year. Positive means longs pay shorts.
+pub fn implied_rate(market: &Market, now: i64) -> ClearingHouseResult<i128> {
+    if market.amm.funding_period <= 0 {
+        return Ok(0);
+    }
+
+    let mark_price_twap = cast_to_i128(calculate_new_mark_twap(&market.amm, now, None)?)?;
+    if mark_price_twap == 0 {
+        return Ok(0);
+    }
+
+    let funding_periods_per_year = ONE_YEAR
+        .checked_div(cast(market.amm.funding_period)?)
+        .ok_or_else(math_error!())?;
+
+    market
+        .amm
+        .last_funding_rate
+        .checked_mul(funding_periods_per_year)
+        .ok_or_else(math_error!())?
+        .checked_mul(cast(MARK_PRICE_PRECISION)?)
+        .ok_or_else(math_error!())?
+        .checked_div(
+            mark_price_twap
+                .checked_mul(cast(FUNDING_PAYMENT_PRECISION)?)
+                .ok_or_else(math_error!())?,
+        )
+        .ok_or_else(math_error!())
+}
 
 /// With a virtual AMM, there can be an imbalance between longs and shorts and thus funding can be asymmetric.
 /// To account for this, amm keeps track of the cumulative funding rate for both longs and shorts.
@@ -153,6 +221,42 @@ fn calculate_capped_funding_rate(
     Ok((capped_funding_rate, capped_funding_pnl))
 }
 
+/// Funding the position has accrued since it was last settled, in quote precision. Positive means
+/// the user is owed funding.
+pub fn calculate_unsettled_funding_payment(
+    market_position: &MarketPosition,
+    amm: &AMM,
+) -> ClearingHouseResult<i128> {
+    if market_position.base_asset_amount == 0 {
+        return Ok(0);
+    }
+
+    let amm_cumulative_funding_rate = if market_position.base_asset_amount > 0 {
+        amm.cumulative_funding_rate_long
+    } else {
+        amm.cumulative_funding_rate_short
+    };
+
+    if amm_cumulative_funding_rate == market_position.last_cumulative_funding_rate {
+        return Ok(0);
+    }
+
+    calculate_funding_payment(amm_cumulative_funding_rate, market_position)?
+        .checked_div(AMM_TO_QUOTE_PRECISION_RATIO_I128)
+        .ok_or_else(math_error!())
+}
+
+/// The funding settling the position would apply to collateral right now, without touching anything.
+/// Settling a single position charges exactly this, so a UI can show pending funding consistently.
+/// The account-wide settle rounds the sum over positions once, so it can differ from the sum of
+/// these by a unit per position. Zero for a flat position.
+pub fn calculate_unrealized_funding(
+    market: &Market,
+    market_position: &MarketPosition,
+) -> ClearingHouseResult<i128> {
+    calculate_unsettled_funding_payment(market_position, &market.amm)
+}
+
 pub fn calculate_funding_payment(
     amm_cumulative_funding_rate: i128,
     market_position: &MarketPosition,
@@ -217,6 +321,37 @@ fn calculate_funding_rate_from_pnl_limit(
         .ok_or_else(math_error!())
 }
 
+/// Funding the position would pay over hold_seconds if the last funding rate held for the whole
+/// period, in quote precision. Positive means the position pays funding, negative means it receives
+/// it, so it can be compared directly against the move the position needs to break even.
+pub fn projected_funding_cost(
+    market: &Market,
+    market_position: &MarketPosition,
+    hold_seconds: i64,
+) -> ClearingHouseResult<i128> {
+    if market_position.base_asset_amount == 0
+        || market.amm.funding_period <= 0
+        || market.amm.last_funding_rate == 0
+    {
+        return Ok(0);
+    }
+
+    let funding_rate_delta = market
+        .amm
+        .last_funding_rate
+        .checked_mul(cast(max(hold_seconds, 0))?)
+        .ok_or_else(math_error!())?
+        .checked_div(cast(market.amm.funding_period)?)
+        .ok_or_else(math_error!())?;
+
+    calculate_funding_payment_in_quote_precision(
+        funding_rate_delta,
+        market_position.base_asset_amount,
+    )?
+    .checked_neg()
+    .ok_or_else(math_error!())
+}
+
 fn calculate_funding_payment_in_quote_precision(
     funding_rate_delta: i128,
     base_asset_amount: i128,
@@ -228,3 +363,95 @@ fn calculate_funding_payment_in_quote_precision(
 
     Ok(funding_payment_collateral)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::{AMM_RESERVE_PRECISION, PEG_PRECISION, QUOTE_PRECISION};
+
+    /// A market priced at 1 with an hourly funding period, whose mark twap sits at the mark
+    fn market(last_funding_rate: i128) -> Market {
+        let reserve = 5 * 10_u128.pow(18);
+        Market {
+            initialized: true,
+            amm: AMM {
+                base_asset_reserve: reserve,
+                quote_asset_reserve: reserve,
+                sqrt_k: reserve,
+                peg_multiplier: PEG_PRECISION,
+                funding_period: 3600,
+                last_funding_rate,
+                last_mark_price_twap: MARK_PRICE_PRECISION,
+                ..AMM::default()
+            },
+            ..Market::default()
+        }
+    }
+
+    #[test]
+    fn implied_rate_annualizes_the_last_funding_rate() {
+        // longs pay 1bp of the mark an hour, 87.6% over 8760 hours
+        let one_bp_an_hour =
+            cast_to_i128(MARK_PRICE_PRECISION * FUNDING_PAYMENT_PRECISION).unwrap() / 10_000;
+        let annual_rate = cast_to_i128(MARK_PRICE_PRECISION * 876 / 1000).unwrap();
+
+        assert_eq!(
+            implied_rate(&market(one_bp_an_hour), 0).unwrap(),
+            annual_rate
+        );
+        assert_eq!(
+            implied_rate(&market(-one_bp_an_hour), 0).unwrap(),
+            -annual_rate
+        );
+        assert_eq!(implied_rate(&market(0), 0).unwrap(), 0);
+
+        // the same rate is half the carry on a market priced at 2
+        let mut market = market(one_bp_an_hour);
+        market.amm.peg_multiplier = 2 * PEG_PRECISION;
+        market.amm.last_mark_price_twap = 2 * MARK_PRICE_PRECISION;
+        assert_eq!(implied_rate(&market, 0).unwrap(), annual_rate / 2);
+
+        // without a funding period there is nothing to annualize
+        market.amm.funding_period = 0;
+        assert_eq!(implied_rate(&market, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn projected_funding_cost_over_a_day_is_24_hourly_payments() {
+        let one_bp_an_hour =
+            cast_to_i128(MARK_PRICE_PRECISION * FUNDING_PAYMENT_PRECISION).unwrap() / 10_000;
+        let market = market(one_bp_an_hour);
+        let one_day = 24 * 3600;
+
+        // 100 base at a price of 1 pays 24bp of $100 over the day
+        let long = MarketPosition {
+            base_asset_amount: 100 * cast_to_i128(AMM_RESERVE_PRECISION).unwrap(),
+            ..MarketPosition::default()
+        };
+        let cost = projected_funding_cost(&market, &long, one_day).unwrap();
+        assert_eq!(
+            cost,
+            cast_to_i128(24 * 100 * QUOTE_PRECISION / 10_000).unwrap()
+        );
+        assert_eq!(
+            cost,
+            24 * projected_funding_cost(&market, &long, 3600).unwrap()
+        );
+
+        // the short side of the same rate receives it
+        let short = MarketPosition {
+            base_asset_amount: -100 * cast_to_i128(AMM_RESERVE_PRECISION).unwrap(),
+            ..MarketPosition::default()
+        };
+        assert_eq!(
+            projected_funding_cost(&market, &short, one_day).unwrap(),
+            -cost
+        );
+
+        assert_eq!(
+            projected_funding_cost(&market, &MarketPosition::default(), one_day).unwrap(),
+            0
+        );
+        assert_eq!(projected_funding_cost(&market, &long, -one_day).unwrap(), 0);
+    }
+}
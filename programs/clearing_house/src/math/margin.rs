@@ -1,31 +1,276 @@
 use std::cell::{Ref, RefMut};
 
 use crate::error::*;
-use crate::math::collateral::calculate_updated_collateral;
-use crate::math::constants::MARGIN_PRECISION;
+use crate::math::casting::{cast_to_i128, cast_to_u128};
+use crate::math::collateral::{
+    apply_collateral_deltas, calculate_collateral_value, calculate_updated_collateral,
+};
+use crate::math::constants::{
+    AMM_TO_QUOTE_PRECISION_RATIO, BPS_PRECISION, MARGIN_PRECISION, MARK_PRICE_PRECISION,
+};
+use crate::math::funding::calculate_unsettled_funding_payment;
 use crate::math::position::calculate_base_asset_value_and_pnl;
 use crate::math_error;
-use crate::state::market::Markets;
-use crate::state::user::{User, UserPositions};
+use crate::state::market::{Market, Markets};
+use crate::state::state::State;
+use crate::state::user::{MarketPosition, User, UserPositions};
 use solana_program::msg;
 
+/// Returns (total collateral, unrealized pnl, base asset value, margin ratio). The margin ratio is
+/// total collateral * MARGIN_PRECISION (bps) / the base asset value margin is held against, with each
+/// position valued by margin_notional_and_pnl, so weighted towards the oracle where the market is
+/// configured to. Total collateral counts secondary collateral after its haircut, see
+/// calculate_collateral_value. An account without notional has a margin ratio of u128::MAX.
+/// Liquidation and the trade risk checks all use this.
 pub fn calculate_margin_ratio(
     user: &User,
     user_positions: &RefMut<UserPositions>,
     markets: &Ref<Markets>,
+    secondary_collateral_weight_bps: u64,
 ) -> ClearingHouseResult<(u128, i128, u128, u128)> {
+    let (base_asset_value, margin_base_asset_value, unrealized_pnl) =
+        calculate_margin_base_asset_value(user_positions, markets)?;
+
+    let total_collateral: u128;
+    let margin_ratio: u128;
+    if base_asset_value == 0 {
+        total_collateral = u128::MAX;
+        margin_ratio = u128::MAX;
+    } else {
+        total_collateral = calculate_updated_collateral(
+            calculate_collateral_value(user, secondary_collateral_weight_bps)?,
+            unrealized_pnl,
+        )?;
+        margin_ratio = if margin_base_asset_value == 0 {
+            u128::MAX
+        } else {
+            total_collateral
+                .checked_mul(MARGIN_PRECISION)
+                .ok_or_else(math_error!())?
+                .checked_div(margin_base_asset_value)
+                .ok_or_else(math_error!())?
+        };
+    }
+
+    Ok((
+        total_collateral,
+        unrealized_pnl,
+        base_asset_value,
+        margin_ratio,
+    ))
+}
+
+/// The (initial, maintenance) collateral requirements of the account, from one pass over its
+/// positions. Collateral below a requirement is the same as a margin ratio below that ratio.
+pub fn calculate_margin_requirements(
+    user_positions: &UserPositions,
+    markets: &Markets,
+    margin_ratio_initial: u128,
+    margin_ratio_maintenance: u128,
+) -> ClearingHouseResult<(u128, u128)> {
+    let (_base_asset_value, margin_base_asset_value, _unrealized_pnl) =
+        calculate_margin_base_asset_value(user_positions, markets)?;
+
+    Ok((
+        calculate_margin_requirement(margin_base_asset_value, margin_ratio_initial)?,
+        calculate_margin_requirement(margin_base_asset_value, margin_ratio_maintenance)?,
+    ))
+}
+
+/// calculate_margin_requirements along with the total collateral to hold against them, from the
+/// same pass over the positions
+pub fn calculate_total_collateral_and_margin_requirements(
+    user: &User,
+    user_positions: &UserPositions,
+    markets: &Markets,
+    margin_ratio_initial: u128,
+    margin_ratio_maintenance: u128,
+    secondary_collateral_weight_bps: u64,
+) -> ClearingHouseResult<(u128, u128, u128)> {
+    let (_base_asset_value, margin_base_asset_value, unrealized_pnl) =
+        calculate_margin_base_asset_value(user_positions, markets)?;
+
+    Ok((
+        calculate_updated_collateral(
+            calculate_collateral_value(user, secondary_collateral_weight_bps)?,
+            unrealized_pnl,
+        )?,
+        calculate_margin_requirement(margin_base_asset_value, margin_ratio_initial)?,
+        calculate_margin_requirement(margin_base_asset_value, margin_ratio_maintenance)?,
+    ))
+}
+
+/// The most collateral the user can withdraw and still meet the initial margin requirement. Funding
+/// accrued but not yet settled counts towards collateral. Unrealized pnl counts towards meeting the
+/// requirement, but unrealized profit is never withdrawable itself, only deposited collateral. The
+/// same goes for secondary collateral after its haircut.
+pub fn get_max_withdrawable(
+    user: &User,
+    user_positions: &UserPositions,
+    markets: &Markets,
+    margin_ratio_initial: u128,
+    secondary_collateral_weight_bps: u64,
+) -> ClearingHouseResult<u128> {
+    let (_base_asset_value, margin_base_asset_value, unrealized_pnl) =
+        calculate_margin_base_asset_value(user_positions, markets)?;
+
+    let mut unsettled_funding_payment: i128 = 0;
+    for market_position in user_positions.positions.iter() {
+        if !market_position.is_open_position() {
+            continue;
+        }
+
+        let amm = &markets.markets[Markets::index_from_u64(market_position.market_index)].amm;
+        unsettled_funding_payment = unsettled_funding_payment
+            .checked_add(calculate_unsettled_funding_payment(market_position, amm)?)
+            .ok_or_else(math_error!())?;
+    }
+
+    let collateral = calculate_updated_collateral(user.collateral, unsettled_funding_payment)?;
+    // netted in one step so unsettled funding owed can't floor collateral before pnl offsets it
+    let total_collateral = apply_collateral_deltas(
+        calculate_collateral_value(user, secondary_collateral_weight_bps)?,
+        &[unsettled_funding_payment, unrealized_pnl],
+    )?;
+    let initial_margin_requirement =
+        calculate_margin_requirement(margin_base_asset_value, margin_ratio_initial)?;
+
+    if total_collateral <= initial_margin_requirement {
+        return Ok(0);
+    }
+
+    let free_collateral = total_collateral
+        .checked_sub(initial_margin_requirement)
+        .ok_or_else(math_error!())?;
+
+    Ok(collateral.min(free_collateral))
+}
+
+/// The value of all of the user's positions in one pass, as the sum of each position's base asset
+/// value and unrealized pnl. markets is indexed by market index, like Markets.markets, and a position
+/// in a market that isn't in it (or isn't initialized) is an error rather than valued at zero.
+pub fn calculate_total_position_value(
+    user_positions: &UserPositions,
+    markets: &[Market],
+) -> ClearingHouseResult<i128> {
+    let mut total_position_value: i128 = 0;
+
+    for market_position in user_positions.positions.iter() {
+        if market_position.is_available() {
+            continue;
+        }
+
+        let market = markets
+            .get(Markets::index_from_u64(market_position.market_index))
+            .filter(|market| market.initialized)
+            .ok_or(ErrorCode::UserHasNoPositionInMarket)?;
+        let (base_asset_value, unrealized_pnl) = market_position.notional_and_pnl(market)?;
+
+        total_position_value = total_position_value
+            .checked_add(cast_to_i128(base_asset_value)?)
+            .ok_or_else(math_error!())?
+            .checked_add(unrealized_pnl)
+            .ok_or_else(math_error!())?;
+    }
+
+    Ok(total_position_value)
+}
+
+// rounds up so that collateral < requirement exactly when the margin ratio is below margin_ratio
+fn calculate_margin_requirement(
+    margin_base_asset_value: u128,
+    margin_ratio: u128,
+) -> ClearingHouseResult<u128> {
+    margin_base_asset_value
+        .checked_mul(margin_ratio)
+        .ok_or_else(math_error!())?
+        .checked_add(MARGIN_PRECISION - 1)
+        .ok_or_else(math_error!())?
+        .checked_div(MARGIN_PRECISION)
+        .ok_or_else(math_error!())
+}
+
+/// Rejects a trade whose notional is too small to carry at least one quantum of margin at
+/// margin_ratio, since the rounded-up requirement would otherwise be made up entirely of rounding
+pub fn validate_margin_requirement_nonzero(
+    quote_asset_amount: u128,
+    margin_ratio: u128,
+) -> ClearingHouseResult {
+    let margin_requirement = quote_asset_amount
+        .checked_mul(margin_ratio)
+        .ok_or_else(math_error!())?
+        .checked_div(MARGIN_PRECISION)
+        .ok_or_else(math_error!())?;
+
+    if margin_requirement == 0 {
+        msg!(
+            "quote asset amount {} has no margin requirement at margin ratio {}",
+            quote_asset_amount,
+            margin_ratio
+        );
+        return Err(ErrorCode::TradeSizeTooSmall);
+    }
+
+    Ok(())
+}
+
+/// Rejects a risk increasing trade that leaves the account below the initial margin requirement.
+/// One that leaves it liquidatable is a mistake or an attack, so it gets its own error rather than
+/// the generic initial margin one
+pub fn validate_margin_after_trade(
+    total_collateral: u128,
+    initial_margin_requirement: u128,
+    maintenance_margin_requirement: u128,
+    potentially_risk_increasing: bool,
+) -> ClearingHouseResult {
+    if !potentially_risk_increasing {
+        return Ok(());
+    }
+
+    if total_collateral <= maintenance_margin_requirement && maintenance_margin_requirement != 0 {
+        return Err(ErrorCode::PositionWouldBeLiquidatable);
+    }
+
+    if total_collateral < initial_margin_requirement {
+        return Err(ErrorCode::InsufficientCollateral);
+    }
+
+    Ok(())
+}
+
+/// Returns the gross base asset value, the base asset value margin is held against after the
+/// correlation offset, and the unrealized pnl of the positions
+fn calculate_margin_base_asset_value(
+    user_positions: &UserPositions,
+    markets: &Markets,
+) -> ClearingHouseResult<(u128, u128, i128)> {
     let mut base_asset_value: u128 = 0;
     let mut unrealized_pnl: i128 = 0;
+    let mut asset_group_exposures = [AssetGroupExposure::default(); 5];
 
-    // loop 1 to calculate unrealized_pnl
     for market_position in user_positions.positions.iter() {
+        // pnl held back from collateral still counts towards it, even once the position is flat
+        unrealized_pnl = unrealized_pnl
+            .checked_add(market_position.settled_pnl)
+            .ok_or_else(math_error!())?;
+
         if market_position.base_asset_amount == 0 {
             continue;
         }
 
-        let amm = &markets.markets[Markets::index_from_u64(market_position.market_index)].amm;
+        let market = &markets.markets[Markets::index_from_u64(market_position.market_index)];
         let (position_base_asset_value, position_unrealized_pnl) =
-            calculate_base_asset_value_and_pnl(market_position, amm)?;
+            market_position.margin_notional_and_pnl(market)?;
+
+        if market.asset_group != 0 {
+            add_asset_group_exposure(
+                &mut asset_group_exposures,
+                market.asset_group,
+                market.correlation_bps,
+                market_position.base_asset_amount > 0,
+                position_base_asset_value,
+            )?;
+        }
 
         base_asset_value = base_asset_value
             .checked_add(position_base_asset_value)
@@ -35,24 +280,744 @@ pub fn calculate_margin_ratio(
             .ok_or_else(math_error!())?;
     }
 
-    let total_collateral: u128;
-    let margin_ratio: u128;
+    let (correlation_margin_offset, hedged_base_asset_value) =
+        calculate_correlation_margin_offset(&asset_group_exposures)?;
+    // never hold less margin than the net exposure would need unhedged
+    let net_base_asset_value = base_asset_value
+        .checked_sub(
+            hedged_base_asset_value
+                .checked_mul(2)
+                .ok_or_else(math_error!())?,
+        )
+        .ok_or_else(math_error!())?;
+    let margin_base_asset_value = base_asset_value
+        .checked_sub(correlation_margin_offset)
+        .ok_or_else(math_error!())?
+        .max(net_base_asset_value);
+
+    Ok((base_asset_value, margin_base_asset_value, unrealized_pnl))
+}
+
+/// Each open position's share of the account's margin requirement at margin_ratio, as (market index,
+/// margin used, unrealized pnl). The requirement, net of any correlation offset, is split in
+/// proportion to base asset value, with rounding left on the last position so the shares sum to it.
+pub fn per_position_margin_contribution(
+    user_positions: &UserPositions,
+    markets: &Markets,
+    margin_ratio: u128,
+) -> ClearingHouseResult<Vec<(u64, u128, i128)>> {
+    let (base_asset_value, margin_base_asset_value, _unrealized_pnl) =
+        calculate_margin_base_asset_value(user_positions, markets)?;
+    let margin_requirement = calculate_margin_requirement(margin_base_asset_value, margin_ratio)?;
+
+    let mut contributions = Vec::with_capacity(user_positions.positions.len());
+    let mut margin_attributed: u128 = 0;
+    for market_position in user_positions.positions.iter() {
+        if !market_position.is_open_position() {
+            continue;
+        }
+
+        let market = &markets.markets[Markets::index_from_u64(market_position.market_index)];
+        let (position_base_asset_value, position_unrealized_pnl) =
+            market_position.margin_notional_and_pnl(market)?;
+
+        let margin_used = if base_asset_value == 0 {
+            0
+        } else {
+            margin_requirement
+                .checked_mul(position_base_asset_value)
+                .ok_or_else(math_error!())?
+                .checked_div(base_asset_value)
+                .ok_or_else(math_error!())?
+        };
+        margin_attributed = margin_attributed
+            .checked_add(margin_used)
+            .ok_or_else(math_error!())?;
+
+        contributions.push((
+            market_position.market_index,
+            margin_used,
+            position_unrealized_pnl,
+        ));
+    }
+
+    if let Some(last_contribution) = contributions.last_mut() {
+        last_contribution.1 = last_contribution
+            .1
+            .checked_add(margin_requirement)
+            .ok_or_else(math_error!())?
+            .checked_sub(margin_attributed)
+            .ok_or_else(math_error!())?;
+    }
+
+    Ok(contributions)
+}
+
+/// Collateral plus unrealized pnl plus funding accrued but not yet settled
+pub fn get_account_value(
+    user: &User,
+    user_positions: &UserPositions,
+    markets: &Markets,
+) -> ClearingHouseResult<i128> {
+    let mut account_value = cast_to_i128(user.collateral)?;
+    for market_position in user_positions.positions.iter() {
+        if !market_position.is_open_position() {
+            continue;
+        }
+
+        let amm = &markets.markets[Markets::index_from_u64(market_position.market_index)].amm;
+        let (_base_asset_value, unrealized_pnl) =
+            calculate_base_asset_value_and_pnl(market_position, amm)?;
+        let unsettled_funding_payment = calculate_unsettled_funding_payment(market_position, amm)?;
+
+        account_value = account_value
+            .checked_add(unrealized_pnl)
+            .ok_or_else(math_error!())?
+            .checked_add(unsettled_funding_payment)
+            .ok_or_else(math_error!())?;
+    }
+
+    Ok(account_value)
+}
+
+/// How far the account value is above (positive) or below (negative) the user's high water mark
+pub fn calculate_account_value_since_high_water_mark(
+    user: &User,
+    user_positions: &UserPositions,
+    markets: &Markets,
+) -> ClearingHouseResult<i128> {
+    get_account_value(user, user_positions, markets)?
+        .checked_sub(user.high_water_mark)
+        .ok_or_else(math_error!())
+}
+
+/// Collateral to add (positive) or that could be withdrawn (negative) for the account's leverage,
+/// base asset value over total collateral, to equal target_leverage_bps
+pub fn collateral_delta_for_target_leverage(
+    user: &User,
+    user_positions: &RefMut<UserPositions>,
+    markets: &Ref<Markets>,
+    target_leverage_bps: u128,
+    secondary_collateral_weight_bps: u64,
+) -> ClearingHouseResult<i128> {
+    let (total_collateral, _unrealized_pnl, base_asset_value, _margin_ratio) =
+        calculate_margin_ratio(
+            user,
+            user_positions,
+            markets,
+            secondary_collateral_weight_bps,
+        )?;
+
     if base_asset_value == 0 {
-        total_collateral = u128::MAX;
-        margin_ratio = u128::MAX;
+        return cast_to_i128(user.collateral)?
+            .checked_neg()
+            .ok_or_else(math_error!());
+    }
+
+    let target_total_collateral = base_asset_value
+        .checked_mul(BPS_PRECISION)
+        .ok_or_else(math_error!())?
+        .checked_div(target_leverage_bps)
+        .ok_or_else(math_error!())?;
+
+    let collateral_delta = cast_to_i128(target_total_collateral)?
+        .checked_sub(cast_to_i128(total_collateral)?)
+        .ok_or_else(math_error!())?;
+
+    // unrealized pnl can't be withdrawn, only deposited collateral
+    Ok(collateral_delta.max(-cast_to_i128(user.collateral)?))
+}
+
+/// How much a position contributes to an account's insolvency: its notional plus any unrealized
+/// loss it is dragging on collateral.
+pub fn calculate_adverse_notional(
+    market_position: &MarketPosition,
+    markets: &Markets,
+) -> ClearingHouseResult<u128> {
+    let amm = &markets.markets[Markets::index_from_u64(market_position.market_index)].amm;
+    let (base_asset_value, unrealized_pnl) =
+        calculate_base_asset_value_and_pnl(market_position, amm)?;
+
+    let unrealized_loss = if unrealized_pnl < 0 {
+        unrealized_pnl.unsigned_abs()
     } else {
-        total_collateral = calculate_updated_collateral(user.collateral, unrealized_pnl)?;
-        margin_ratio = total_collateral
-            .checked_mul(MARGIN_PRECISION)
+        0
+    };
+
+    base_asset_value
+        .checked_add(unrealized_loss)
+        .ok_or_else(math_error!())
+}
+
+/// How much base asset value to take off an account for its margin ratio to reach
+/// target_margin_ratio, assuming the reduction realizes its pnl at no cost. Measured against the gross
+/// base asset value, which ignores any correlation offset and so errs towards reducing more.
+pub fn calculate_base_asset_value_to_deleverage(
+    total_collateral: u128,
+    base_asset_value: u128,
+    target_margin_ratio: u128,
+) -> ClearingHouseResult<u128> {
+    let target_base_asset_value = total_collateral
+        .checked_mul(MARGIN_PRECISION)
+        .ok_or_else(math_error!())?
+        .checked_div(target_margin_ratio)
+        .ok_or_else(math_error!())?;
+
+    Ok(base_asset_value.saturating_sub(target_base_asset_value))
+}
+
+/// Open positions ordered from most to least adverse notional, as (position index, adverse notional)
+pub fn rank_positions_by_adverse_notional(
+    user_positions: &UserPositions,
+    markets: &Markets,
+) -> ClearingHouseResult<Vec<(usize, u128)>> {
+    let mut ranked_positions = Vec::with_capacity(user_positions.positions.len());
+    for (position_index, market_position) in user_positions.positions.iter().enumerate() {
+        if !market_position.is_open_position() {
+            continue;
+        }
+
+        let adverse_notional = calculate_adverse_notional(market_position, markets)?;
+        ranked_positions.push((position_index, adverse_notional));
+    }
+
+    ranked_positions.sort_by_key(|&(_, adverse_notional)| std::cmp::Reverse(adverse_notional));
+
+    Ok(ranked_positions)
+}
+
+/// The open position with the most adverse notional, if there is one
+pub fn get_worst_position(
+    user_positions: &UserPositions,
+    markets: &Markets,
+) -> ClearingHouseResult<Option<(usize, u128)>> {
+    let ranked_positions = rank_positions_by_adverse_notional(user_positions, markets)?;
+    Ok(ranked_positions.first().copied())
+}
+
+/// What fully liquidating the user's position in market_index would pay out, simulated against the
+/// current reserves without touching them: the quote the forced close would swap for and the
+/// liquidator's fee net of the price impact of that close, measured against the mark.
+pub fn estimate_liquidation_proceeds(
+    user: &User,
+    user_positions: &UserPositions,
+    markets: &Markets,
+    market_index: u64,
+    state: &State,
+) -> ClearingHouseResult<(u128, u128)> {
+    let market_position = user_positions
+        .positions
+        .iter()
+        .find(|market_position| market_position.is_active_for(market_index))
+        .ok_or(ErrorCode::UserHasNoPositionInMarket)?;
+    let market = &markets.markets[Markets::index_from_u64(market_index)];
+
+    let (proceeds, pnl) = market_position.notional_and_pnl(market)?;
+
+    let value_at_mark = market_position
+        .base_asset_amount
+        .unsigned_abs()
+        .checked_mul(market.amm.mark_price()?)
+        .ok_or_else(math_error!())?
+        .checked_div(MARK_PRICE_PRECISION * AMM_TO_QUOTE_PRECISION_RATIO)
+        .ok_or_else(math_error!())?;
+    let price_impact = value_at_mark.abs_diff(proceeds);
+
+    let collateral_after_close = calculate_updated_collateral(user.collateral, pnl)?;
+    let nominal_fee = collateral_after_close
+        .checked_mul(state.full_liquidation_penalty_percentage_numerator)
+        .ok_or_else(math_error!())?
+        .checked_div(state.full_liquidation_penalty_percentage_denominator)
+        .ok_or_else(math_error!())?
+        .checked_div(cast_to_u128(
+            state.full_liquidation_liquidator_share_denominator,
+        )?)
+        .ok_or_else(math_error!())?;
+
+    Ok((proceeds, nominal_fee.saturating_sub(price_impact)))
+}
+
+#[derive(Clone, Copy, Default)]
+struct AssetGroupExposure {
+    asset_group: u64,
+    correlation_bps: u64,
+    long_base_asset_value: u128,
+    short_base_asset_value: u128,
+}
+
+fn add_asset_group_exposure(
+    exposures: &mut [AssetGroupExposure],
+    asset_group: u64,
+    correlation_bps: u64,
+    is_long: bool,
+    base_asset_value: u128,
+) -> ClearingHouseResult {
+    let exposure = exposures
+        .iter_mut()
+        .find(|exposure| exposure.asset_group == asset_group || exposure.asset_group == 0)
+        .ok_or_else(math_error!())?;
+
+    if exposure.asset_group == 0 {
+        exposure.asset_group = asset_group;
+        exposure.correlation_bps = correlation_bps;
+    } else {
+        // use the weakest correlation in the group so the credit is never overstated
+        exposure.correlation_bps = exposure.correlation_bps.min(correlation_bps);
+    }
+
+    if is_long {
+        exposure.long_base_asset_value = exposure
+            .long_base_asset_value
+            .checked_add(base_asset_value)
+            .ok_or_else(math_error!())?;
+    } else {
+        exposure.short_base_asset_value = exposure
+            .short_base_asset_value
+            .checked_add(base_asset_value)
+            .ok_or_else(math_error!())?;
+    }
+
+    Ok(())
+}
+
+/// The notional of offsetting long/short positions within an asset group, scaled by the group's
+/// correlation, along with the notional of the offsetting positions themselves. The offset is
+/// removed from the base asset value used for the margin ratio. Only the smaller leg of a hedge is
+/// credited, and only once, since the larger leg's excess is exposure like any other.
+fn calculate_correlation_margin_offset(
+    exposures: &[AssetGroupExposure],
+) -> ClearingHouseResult<(u128, u128)> {
+    let mut offset: u128 = 0;
+    let mut hedged_base_asset_value: u128 = 0;
+    for exposure in exposures.iter() {
+        if exposure.asset_group == 0 || exposure.correlation_bps == 0 {
+            continue;
+        }
+
+        let group_hedged_base_asset_value = exposure
+            .long_base_asset_value
+            .min(exposure.short_base_asset_value);
+
+        let group_offset = group_hedged_base_asset_value
+            .checked_mul(cast_to_u128(exposure.correlation_bps)?)
             .ok_or_else(math_error!())?
-            .checked_div(base_asset_value)
+            .checked_div(BPS_PRECISION)
+            .ok_or_else(math_error!())?;
+
+        offset = offset.checked_add(group_offset).ok_or_else(math_error!())?;
+        hedged_base_asset_value = hedged_base_asset_value
+            .checked_add(group_hedged_base_asset_value)
             .ok_or_else(math_error!())?;
     }
 
-    Ok((
-        total_collateral,
-        unrealized_pnl,
-        base_asset_value,
-        margin_ratio,
-    ))
+    Ok((offset, hedged_base_asset_value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::{AMM_RESERVE_PRECISION, PEG_PRECISION, QUOTE_PRECISION};
+    use crate::state::market::AMM;
+    use std::cell::RefCell;
+
+    /// Markets 0 to 4 initialized, each priced at 1 with the reserves the ts tests use
+    fn markets() -> Markets {
+        let reserve = 5 * 10_u128.pow(18);
+        let mut markets = Markets::default();
+        for market in markets.markets.iter_mut().take(5) {
+            *market = Market {
+                initialized: true,
+                amm: AMM {
+                    base_asset_reserve: reserve,
+                    quote_asset_reserve: reserve,
+                    sqrt_k: reserve,
+                    peg_multiplier: PEG_PRECISION,
+                    funding_period: 3600,
+                    ..AMM::default()
+                },
+                ..Market::default()
+            };
+        }
+        markets
+    }
+
+    /// A long of base_asset_units in market_index, entered for quote_asset_units of quote
+    fn long_position(
+        market_index: u64,
+        base_asset_units: u128,
+        quote_asset_units: u128,
+    ) -> MarketPosition {
+        MarketPosition {
+            market_index,
+            base_asset_amount: (base_asset_units * AMM_RESERVE_PRECISION) as i128,
+            quote_asset_amount: quote_asset_units * QUOTE_PRECISION,
+            ..MarketPosition::default()
+        }
+    }
+
+    #[test]
+    fn worst_position_is_the_most_adverse() {
+        let markets = markets();
+        let mut user_positions = UserPositions::default();
+        user_positions.positions[0] = long_position(0, 10, 10);
+        // smaller than the third position, but dragging a 10 USDC loss on collateral
+        user_positions.positions[1] = long_position(1, 10, 20);
+        user_positions.positions[2] = long_position(2, 15, 15);
+
+        let (position_index, adverse_notional) = get_worst_position(&user_positions, &markets)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(position_index, 1);
+        assert_eq!(
+            adverse_notional,
+            calculate_adverse_notional(&user_positions.positions[1], &markets).unwrap()
+        );
+        assert!(adverse_notional > 19 * QUOTE_PRECISION);
+    }
+
+    #[test]
+    fn flat_account_has_no_worst_position() {
+        let markets = markets();
+        let user_positions = UserPositions::default();
+
+        assert!(get_worst_position(&user_positions, &markets)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn collateral_delta_delevers_from_ten_to_three_times() {
+        let user_positions = RefCell::new(UserPositions::default());
+        user_positions.borrow_mut().positions[0] = long_position(0, 10, 10);
+        let markets = RefCell::new(markets());
+        let mut user = User {
+            collateral: QUOTE_PRECISION,
+            ..User::default()
+        };
+
+        // 10 USDC of notional against 1 USDC of collateral, less the unrealized loss to rounding
+        let (_total_collateral, _unrealized_pnl, base_asset_value, _margin_ratio) =
+            calculate_margin_ratio(&user, &user_positions.borrow_mut(), &markets.borrow(), 0)
+                .unwrap();
+        let collateral_delta = collateral_delta_for_target_leverage(
+            &user,
+            &user_positions.borrow_mut(),
+            &markets.borrow(),
+            3 * BPS_PRECISION,
+            0,
+        )
+        .unwrap();
+        assert!(collateral_delta > (2 * QUOTE_PRECISION) as i128);
+        assert!(collateral_delta < (3 * QUOTE_PRECISION) as i128);
+
+        user.collateral =
+            cast_to_u128(cast_to_i128(user.collateral).unwrap() + collateral_delta).unwrap();
+        let (total_collateral, _unrealized_pnl, _base_asset_value, _margin_ratio) =
+            calculate_margin_ratio(&user, &user_positions.borrow_mut(), &markets.borrow(), 0)
+                .unwrap();
+        assert_eq!(total_collateral, base_asset_value / 3);
+        assert_eq!(
+            collateral_delta_for_target_leverage(
+                &user,
+                &user_positions.borrow_mut(),
+                &markets.borrow(),
+                3 * BPS_PRECISION,
+                0,
+            )
+            .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn profitable_period_raises_account_value_above_high_water_mark() {
+        let mut markets = markets();
+        let mut user_positions = UserPositions::default();
+        user_positions.positions[0] = long_position(0, 10, 10);
+        let mut user = User::default();
+        crate::controller::collateral::deposit(&mut user, (10 * QUOTE_PRECISION) as u64).unwrap();
+        // opening at the mark leaves the account at its high water mark, less rounding
+        assert!(
+            calculate_account_value_since_high_water_mark(&user, &user_positions, &markets)
+                .unwrap()
+                <= 0
+        );
+
+        // the mark rises as others buy, which the long gains from
+        crate::controller::amm::swap_quote_asset(
+            &mut markets.markets[0].amm,
+            1_000_000 * QUOTE_PRECISION,
+            crate::controller::amm::SwapDirection::Add,
+            0,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let (_base_asset_value, unrealized_pnl) = calculate_base_asset_value_and_pnl(
+            &user_positions.positions[0],
+            &markets.markets[0].amm,
+        )
+        .unwrap();
+        assert!(unrealized_pnl > 0);
+        assert_eq!(
+            get_account_value(&user, &user_positions, &markets).unwrap(),
+            (10 * QUOTE_PRECISION) as i128 + unrealized_pnl
+        );
+        assert_eq!(
+            calculate_account_value_since_high_water_mark(&user, &user_positions, &markets)
+                .unwrap(),
+            unrealized_pnl
+        );
+    }
+
+    #[test]
+    fn trade_leaving_the_account_liquidatable_is_rejected() {
+        let markets = markets();
+        let mut user_positions = UserPositions::default();
+        user_positions.positions[0] = long_position(0, 10, 10);
+        let mut user = User {
+            collateral: 10 * QUOTE_PRECISION,
+            ..User::default()
+        };
+        let requirements_after = |user: &User| {
+            calculate_total_collateral_and_margin_requirements(
+                user,
+                &user_positions,
+                &markets,
+                2000,
+                500,
+                0,
+            )
+            .unwrap()
+        };
+
+        // comfortably above initial margin
+        let (total_collateral, initial_margin_requirement, maintenance_margin_requirement) =
+            requirements_after(&user);
+        assert!(total_collateral > initial_margin_requirement);
+        assert!(validate_margin_after_trade(
+            total_collateral,
+            initial_margin_requirement,
+            maintenance_margin_requirement,
+            true
+        )
+        .is_ok());
+
+        // exactly at maintenance margin
+        user.collateral -= total_collateral - maintenance_margin_requirement;
+        let (total_collateral, initial_margin_requirement, maintenance_margin_requirement) =
+            requirements_after(&user);
+        assert_eq!(total_collateral, maintenance_margin_requirement);
+        assert!(matches!(
+            validate_margin_after_trade(
+                total_collateral,
+                initial_margin_requirement,
+                maintenance_margin_requirement,
+                true
+            ),
+            Err(ErrorCode::PositionWouldBeLiquidatable)
+        ));
+        // a risk reducing trade can still leave the account there
+        assert!(validate_margin_after_trade(
+            total_collateral,
+            initial_margin_requirement,
+            maintenance_margin_requirement,
+            false
+        )
+        .is_ok());
+
+        // between the two, only the initial margin check fails
+        user.collateral += QUOTE_PRECISION;
+        let (total_collateral, initial_margin_requirement, maintenance_margin_requirement) =
+            requirements_after(&user);
+        assert!(matches!(
+            validate_margin_after_trade(
+                total_collateral,
+                initial_margin_requirement,
+                maintenance_margin_requirement,
+                true
+            ),
+            Err(ErrorCode::InsufficientCollateral)
+        ));
+    }
+
+    #[test]
+    fn single_pass_requirements_match_separate_computations() {
+        let markets = RefCell::new(markets());
+        let user_positions = RefCell::new(UserPositions::default());
+        user_positions.borrow_mut().positions[0] = long_position(0, 10, 10);
+        user_positions.borrow_mut().positions[1] = long_position(1, 25, 20);
+        user_positions.borrow_mut().positions[2] = long_position(2, 7, 9);
+        let user = User {
+            collateral: 4 * QUOTE_PRECISION,
+            ..User::default()
+        };
+
+        let (initial_margin_requirement, maintenance_margin_requirement) =
+            calculate_margin_requirements(&user_positions.borrow(), &markets.borrow(), 2000, 500)
+                .unwrap();
+
+        // each requirement on its own, rounded up, off the base asset value the margin ratio uses
+        let (total_collateral, _unrealized_pnl, base_asset_value, margin_ratio) =
+            calculate_margin_ratio(&user, &user_positions.borrow_mut(), &markets.borrow(), 0)
+                .unwrap();
+        let requirement =
+            |margin_ratio: u128| (base_asset_value * margin_ratio).div_ceil(MARGIN_PRECISION);
+        assert_eq!(initial_margin_requirement, requirement(2000));
+        assert_eq!(maintenance_margin_requirement, requirement(500));
+
+        // and the checks agree with the margin ratio they replace
+        assert_eq!(
+            total_collateral < initial_margin_requirement,
+            margin_ratio < 2000
+        );
+        assert_eq!(
+            total_collateral < maintenance_margin_requirement,
+            margin_ratio < 500
+        );
+        assert_eq!(
+            calculate_total_collateral_and_margin_requirements(
+                &user,
+                &user_positions.borrow(),
+                &markets.borrow(),
+                2000,
+                500,
+                0,
+            )
+            .unwrap(),
+            (
+                total_collateral,
+                initial_margin_requirement,
+                maintenance_margin_requirement
+            )
+        );
+    }
+
+    #[test]
+    fn max_withdrawable_leaves_the_initial_margin_requirement() {
+        let markets = markets();
+        let mut user_positions = UserPositions::default();
+        let mut user = User {
+            collateral: 10 * QUOTE_PRECISION,
+            ..User::default()
+        };
+
+        // a flat account can withdraw everything
+        assert_eq!(
+            get_max_withdrawable(&user, &user_positions, &markets, 2000, 0).unwrap(),
+            10 * QUOTE_PRECISION
+        );
+
+        // 20 USDC of notional holds 4 USDC of the collateral back at 5x
+        user_positions.positions[0] = long_position(0, 20, 20);
+        let max_withdrawable =
+            get_max_withdrawable(&user, &user_positions, &markets, 2000, 0).unwrap();
+        assert!(max_withdrawable < user.collateral);
+        assert!(max_withdrawable > 5 * QUOTE_PRECISION);
+
+        user.collateral -= max_withdrawable;
+        let (total_collateral, initial_margin_requirement, _maintenance_margin_requirement) =
+            calculate_total_collateral_and_margin_requirements(
+                &user,
+                &user_positions,
+                &markets,
+                2000,
+                500,
+                0,
+            )
+            .unwrap();
+        assert_eq!(total_collateral, initial_margin_requirement);
+        assert_eq!(
+            get_max_withdrawable(&user, &user_positions, &markets, 2000, 0).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn per_position_margin_sums_to_the_account_requirement() {
+        let mut markets = markets();
+        let mut user_positions = UserPositions::default();
+        // sizes that don't split the requirement evenly
+        user_positions.positions[0] = long_position(0, 7, 7);
+        user_positions.positions[2] = long_position(1, 13, 12);
+        user_positions.positions[4] = long_position(2, 3, 4);
+        crate::controller::amm::swap_quote_asset(
+            &mut markets.markets[1].amm,
+            1_000 * QUOTE_PRECISION,
+            crate::controller::amm::SwapDirection::Add,
+            0,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let contributions =
+            per_position_margin_contribution(&user_positions, &markets, 2000).unwrap();
+        let (initial_margin_requirement, _maintenance_margin_requirement) =
+            calculate_margin_requirements(&user_positions, &markets, 2000, 500).unwrap();
+
+        assert_eq!(
+            contributions
+                .iter()
+                .map(|(market_index, _, _)| *market_index)
+                .collect::<Vec<u64>>(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            contributions
+                .iter()
+                .map(|(_, margin_used, _)| margin_used)
+                .sum::<u128>(),
+            initial_margin_requirement
+        );
+        // the bigger position in the repriced market uses the most margin
+        assert!(contributions[1].1 > contributions[0].1);
+        assert!(contributions[0].1 > contributions[2].1);
+        assert!(contributions[1].2 > 0);
+    }
+
+    #[test]
+    fn self_impact_of_a_large_liquidation_eats_into_the_fee() {
+        let markets = markets();
+        // the liquidator takes 1/20 of what's left after a full liquidation, as initialize sets it
+        let state = State {
+            full_liquidation_penalty_percentage_numerator: 1,
+            full_liquidation_penalty_percentage_denominator: 1,
+            full_liquidation_liquidator_share_denominator: 20,
+            ..State::default()
+        };
+        let user = User {
+            collateral: 20_000 * QUOTE_PRECISION,
+            ..User::default()
+        };
+
+        // (nominal fee, price impact, net fee) of liquidating a long of base_asset_units
+        let liquidate = |base_asset_units: u128| {
+            let mut user_positions = UserPositions::default();
+            user_positions.positions[0] = long_position(0, base_asset_units, base_asset_units);
+
+            let (proceeds, fee) =
+                estimate_liquidation_proceeds(&user, &user_positions, &markets, 0, &state).unwrap();
+
+            let (notional, pnl) = user_positions.positions[0]
+                .notional_and_pnl(&markets.markets[0])
+                .unwrap();
+            assert_eq!(proceeds, notional);
+            let nominal_fee = calculate_updated_collateral(user.collateral, pnl).unwrap() / 20;
+            // at a price of 1 the position is worth its base units at the mark
+            let price_impact = base_asset_units * QUOTE_PRECISION - proceeds;
+            (nominal_fee, price_impact, fee)
+        };
+
+        let (nominal_fee, price_impact, fee) = liquidate(10);
+        assert!(price_impact < QUOTE_PRECISION / 100);
+        assert_eq!(fee, nominal_fee - price_impact);
+
+        let (nominal_fee, price_impact, fee) = liquidate(20_000);
+        assert!(price_impact > 100 * QUOTE_PRECISION);
+        assert_eq!(fee, nominal_fee - price_impact);
+        assert!(fee < nominal_fee / 2);
+    }
 }
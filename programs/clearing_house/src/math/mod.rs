@@ -7,6 +7,7 @@ pub mod fees;
 pub mod funding;
 pub mod margin;
 pub mod oracle;
+pub mod orders;
 pub mod pnl;
 pub mod position;
 pub mod quote_asset;
@@ -1,9 +1,161 @@
-use crate::error::ClearingHouseResult;
+use crate::error::*;
 use crate::math::amm;
-use crate::state::market::AMM;
+use crate::math::casting::{cast, cast_to_i128, cast_to_i64, cast_to_u128};
+use crate::math::constants::MARK_PRICE_PRECISION;
+use crate::math_error;
+use crate::state::market::{OracleSource, AMM};
 use crate::state::state::OracleGuardRails;
 use anchor_lang::prelude::AccountInfo;
+use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::clock::Slot;
+use solana_program::msg;
+
+/// An oracle's reading normalized to MARK_PRICE_PRECISION, whichever provider it came from
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct OraclePriceData {
+    pub price: i128,
+    pub twap: i128,
+    pub confidence: u128,
+    pub twap_confidence: u128,
+    pub delay: i64, // slots since the provider last updated the price
+}
+
+pub fn get_oracle_price(
+    oracle_source: &OracleSource,
+    price_oracle: &AccountInfo,
+    clock_slot: u64,
+) -> ClearingHouseResult<OraclePriceData> {
+    match oracle_source {
+        OracleSource::Pyth => get_pyth_price(price_oracle, clock_slot),
+        OracleSource::Switchboard => get_switchboard_price(price_oracle, clock_slot),
+    }
+}
+
+pub fn get_pyth_price(
+    price_oracle: &AccountInfo,
+    clock_slot: u64,
+) -> ClearingHouseResult<OraclePriceData> {
+    let pyth_price_data = price_oracle
+        .try_borrow_data()
+        .or(Err(ErrorCode::UnableToLoadOracle))?;
+    let price_data = pyth_client::cast::<pyth_client::Price>(&pyth_price_data);
+
+    Ok(OraclePriceData {
+        price: normalize_oracle_price(cast(price_data.agg.price)?, price_data.expo)?,
+        twap: normalize_oracle_price(cast(price_data.twap.val)?, price_data.expo)?,
+        confidence: cast_to_u128(normalize_oracle_price(
+            cast(price_data.agg.conf)?,
+            price_data.expo,
+        )?)?,
+        twap_confidence: cast_to_u128(normalize_oracle_price(
+            cast(price_data.twac.val)?,
+            price_data.expo,
+        )?)?,
+        delay: cast_to_i64(clock_slot)?
+            .checked_sub(cast(price_data.valid_slot)?)
+            .ok_or_else(math_error!())?,
+    })
+}
+
+/// Account type tag of a Switchboard v1 aggregator's parse-optimized result account
+const SWITCHBOARD_AGGREGATOR_RESULT_ACCOUNT_TYPE: u8 = 5;
+
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+struct SwitchboardDecimal {
+    mantissa: i128,
+    scale: u32,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+struct SwitchboardRoundResult {
+    num_success: i32,
+    num_error: i32,
+    result: f64,
+    round_open_slot: u64,
+    round_open_timestamp: i64,
+    min_response: f64,
+    max_response: f64,
+    decimal: SwitchboardDecimal,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+struct SwitchboardResultAccountData {
+    parent: [u8; 32],
+    result: SwitchboardRoundResult,
+}
+
+/// Reads a Switchboard v1 aggregator result account. Switchboard publishes no twap, so the latest
+/// round stands in for it, and the confidence is half the spread of the round's responses.
+pub fn get_switchboard_price(
+    price_oracle: &AccountInfo,
+    clock_slot: u64,
+) -> ClearingHouseResult<OraclePriceData> {
+    let switchboard_data = price_oracle
+        .try_borrow_data()
+        .or(Err(ErrorCode::UnableToLoadOracle))?;
+    if switchboard_data.first() != Some(&SWITCHBOARD_AGGREGATOR_RESULT_ACCOUNT_TYPE) {
+        return Err(ErrorCode::UnableToLoadOracle);
+    }
+    let SwitchboardResultAccountData { result, .. } =
+        SwitchboardResultAccountData::deserialize(&mut &switchboard_data[1..])
+            .or(Err(ErrorCode::UnableToLoadOracle))?;
+
+    if result.num_success == 0 {
+        msg!("Switchboard round has no successful responses");
+        return Err(ErrorCode::InvalidOracle);
+    }
+
+    let oracle_exponent = -cast::<u32, i32>(result.decimal.scale)?;
+    let price = normalize_oracle_price(result.decimal.mantissa, oracle_exponent)?;
+    let confidence = ((result.max_response - result.min_response).max(0.0) / 2.0
+        * MARK_PRICE_PRECISION as f64) as u128;
+
+    Ok(OraclePriceData {
+        price,
+        twap: price,
+        confidence,
+        twap_confidence: confidence,
+        delay: cast_to_i64(clock_slot)?
+            .checked_sub(cast(result.round_open_slot)?)
+            .ok_or_else(math_error!())?,
+    })
+}
+
+/// Rescales a raw oracle value, denominated in 10^oracle_exponent, to MARK_PRICE_PRECISION. Every
+/// mark/oracle comparison has to happen on normalized prices. A value that doesn't fit in the
+/// precision is a math error rather than a wrapped price.
+pub fn normalize_oracle_price(raw_price: i128, oracle_exponent: i32) -> ClearingHouseResult<i128> {
+    let mark_price_precision = cast_to_i128(MARK_PRICE_PRECISION)?;
+    let oracle_scale = 10_i128
+        .checked_pow(oracle_exponent.unsigned_abs())
+        .ok_or_else(math_error!())?;
+
+    if oracle_exponent >= 0 {
+        return raw_price
+            .checked_mul(oracle_scale)
+            .ok_or_else(math_error!())?
+            .checked_mul(mark_price_precision)
+            .ok_or_else(math_error!());
+    }
+
+    if oracle_scale > mark_price_precision {
+        raw_price
+            .checked_div(
+                oracle_scale
+                    .checked_div(mark_price_precision)
+                    .ok_or_else(math_error!())?,
+            )
+            .ok_or_else(math_error!())
+    } else {
+        raw_price
+            .checked_mul(
+                mark_price_precision
+                    .checked_div(oracle_scale)
+                    .ok_or_else(math_error!())?,
+            )
+            .ok_or_else(math_error!())
+    }
+}
 
 pub fn block_operation(
     amm: &AMM,
@@ -27,3 +179,183 @@ pub fn block_operation(
     let block = !oracle_is_valid || is_oracle_mark_too_divergent;
     Ok((block, oracle_price))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::prelude::Pubkey;
+
+    fn oracle_account_info<'a>(
+        key: &'a Pubkey,
+        lamports: &'a mut u64,
+        data: &'a mut [u8],
+    ) -> AccountInfo<'a> {
+        AccountInfo::new(key, false, false, lamports, data, key, false, 0)
+    }
+
+    /// A pyth price account at 2.00 +/- 0.25, published with an exponent of -8 at slot 90
+    fn mock_pyth_price_account() -> Vec<u64> {
+        // backed by u64s so the account data is aligned for pyth_client::cast
+        let mut storage = vec![0_u64; std::mem::size_of::<pyth_client::Price>() / 8 + 1];
+        let price = unsafe { &mut *(storage.as_mut_ptr() as *mut pyth_client::Price) };
+        price.expo = -8;
+        price.agg.price = 200_000_000;
+        price.agg.conf = 25_000_000;
+        price.twap.val = 200_000_000;
+        price.twac.val = 25_000_000;
+        price.valid_slot = 90;
+        storage
+    }
+
+    /// A switchboard result account at 2.00 +/- 0.25, with a round opened at slot 90
+    fn mock_switchboard_result_account() -> Vec<u8> {
+        let mut data = vec![SWITCHBOARD_AGGREGATOR_RESULT_ACCOUNT_TYPE];
+        SwitchboardResultAccountData {
+            result: SwitchboardRoundResult {
+                num_success: 3,
+                result: 2.0,
+                round_open_slot: 90,
+                min_response: 1.75,
+                max_response: 2.25,
+                decimal: SwitchboardDecimal {
+                    mantissa: 2000,
+                    scale: 3,
+                },
+                ..SwitchboardRoundResult::default()
+            },
+            ..SwitchboardResultAccountData::default()
+        }
+        .serialize(&mut data)
+        .unwrap();
+        data
+    }
+
+    #[test]
+    fn pyth_and_switchboard_normalize_to_the_same_price_data() {
+        let key = Pubkey::default();
+        let expected = OraclePriceData {
+            price: 2 * MARK_PRICE_PRECISION as i128,
+            twap: 2 * MARK_PRICE_PRECISION as i128,
+            confidence: MARK_PRICE_PRECISION / 4,
+            twap_confidence: MARK_PRICE_PRECISION / 4,
+            delay: 10,
+        };
+
+        let mut pyth_storage = mock_pyth_price_account();
+        let mut pyth_lamports = 0;
+        let pyth_account = oracle_account_info(
+            &key,
+            &mut pyth_lamports,
+            bytemuck::cast_slice_mut(&mut pyth_storage),
+        );
+        let pyth_price_data = get_oracle_price(&OracleSource::Pyth, &pyth_account, 100).unwrap();
+        assert_eq!(pyth_price_data, expected);
+
+        let mut switchboard_data = mock_switchboard_result_account();
+        let mut switchboard_lamports = 0;
+        let switchboard_account =
+            oracle_account_info(&key, &mut switchboard_lamports, &mut switchboard_data);
+        let switchboard_price_data =
+            get_oracle_price(&OracleSource::Switchboard, &switchboard_account, 100).unwrap();
+        assert_eq!(switchboard_price_data, expected);
+    }
+
+    #[test]
+    fn normalize_oracle_price_with_negative_exponents() {
+        let mark_price_precision = MARK_PRICE_PRECISION as i128;
+
+        // coarser than MARK_PRICE_PRECISION, scaled up
+        assert_eq!(
+            normalize_oracle_price(200_000_000, -8).unwrap(),
+            2 * mark_price_precision
+        );
+        // the same precision, unchanged
+        assert_eq!(
+            normalize_oracle_price(2 * mark_price_precision, -10).unwrap(),
+            2 * mark_price_precision
+        );
+        // finer than MARK_PRICE_PRECISION, scaled down and rounded towards zero
+        assert_eq!(
+            normalize_oracle_price(2_000_000_000_009, -12).unwrap(),
+            2 * mark_price_precision
+        );
+        assert_eq!(
+            normalize_oracle_price(-2_000_000_000_009, -12).unwrap(),
+            -2 * mark_price_precision
+        );
+    }
+
+    #[test]
+    fn normalize_oracle_price_with_positive_exponents() {
+        let mark_price_precision = MARK_PRICE_PRECISION as i128;
+
+        assert_eq!(
+            normalize_oracle_price(2, 0).unwrap(),
+            2 * mark_price_precision
+        );
+        assert_eq!(
+            normalize_oracle_price(2, 3).unwrap(),
+            2_000 * mark_price_precision
+        );
+    }
+
+    #[test]
+    fn normalize_oracle_price_errors_instead_of_overflowing() {
+        // a positive exponent scales by 10^exponent and the full MARK_PRICE_PRECISION, so a large
+        // raw price or exponent overflows i128 and has to fail rather than wrap
+        assert!(matches!(
+            normalize_oracle_price(i64::MAX as i128, 20),
+            Err(ErrorCode::MathError)
+        ));
+        assert!(matches!(
+            normalize_oracle_price(i128::MAX / 10, 0),
+            Err(ErrorCode::MathError)
+        ));
+        // 10^39 doesn't fit in an i128 either way
+        assert!(matches!(
+            normalize_oracle_price(1, 39),
+            Err(ErrorCode::MathError)
+        ));
+        assert!(matches!(
+            normalize_oracle_price(1, -39),
+            Err(ErrorCode::MathError)
+        ));
+    }
+
+    #[test]
+    fn switchboard_rejects_other_accounts() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+
+        let mut data = mock_switchboard_result_account();
+        data[0] = 1;
+        let account = oracle_account_info(&key, &mut lamports, &mut data);
+        assert!(matches!(
+            get_switchboard_price(&account, 100),
+            Err(ErrorCode::UnableToLoadOracle)
+        ));
+
+        let mut data = mock_switchboard_result_account();
+        data.truncate(20);
+        let account = oracle_account_info(&key, &mut lamports, &mut data);
+        assert!(matches!(
+            get_switchboard_price(&account, 100),
+            Err(ErrorCode::UnableToLoadOracle)
+        ));
+    }
+
+    #[test]
+    fn switchboard_rejects_a_round_without_responses() {
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = vec![SWITCHBOARD_AGGREGATOR_RESULT_ACCOUNT_TYPE];
+        SwitchboardResultAccountData::default()
+            .serialize(&mut data)
+            .unwrap();
+        let account = oracle_account_info(&key, &mut lamports, &mut data);
+        assert!(matches!(
+            get_switchboard_price(&account, 100),
+            Err(ErrorCode::InvalidOracle)
+        ));
+    }
+}
@@ -0,0 +1,496 @@
+use crate::controller::position::PositionDirection;
+use crate::error::*;
+use crate::math::amm::calculate_base_asset_amount_to_trade_to_price;
+use crate::math::casting::{cast_to_i128, cast_to_u128};
+use crate::math::constants::{KEEPER_REWARD_AUCTION_DURATION, MAX_KEEPER_REWARD};
+use crate::math_error;
+use crate::state::market::{Market, AMM};
+use crate::state::user::UserPositions;
+use crate::state::user_orders::{Order, OrderTriggerCondition, OrderType, UserOrders};
+use anchor_lang::prelude::Pubkey;
+use solana_program::msg;
+use std::cmp::{min, Reverse};
+
+/// The base asset amount of the order that can be filled now. Zero means the order's fill condition
+/// isn't met.
+pub fn calculate_base_asset_amount_to_fill(
+    order: &Order,
+    amm: &AMM,
+    oracle_price: i128,
+    position_base_asset_amount: i128,
+) -> ClearingHouseResult<u128> {
+    let base_asset_amount = match order.order_type {
+        OrderType::Market => {
+            if order.price == 0 {
+                order.get_base_asset_amount_unfilled()
+            } else {
+                calculate_base_asset_amount_to_limit_price(order, amm, order.price)?
+            }
+        }
+        OrderType::Limit => calculate_base_asset_amount_to_limit_price(order, amm, order.price)?,
+        OrderType::TriggerMarket => {
+            if is_order_triggered(order, oracle_price)? {
+                order.get_base_asset_amount_unfilled()
+            } else {
+                0
+            }
+        }
+        OrderType::TriggerLimit => {
+            if is_order_triggered(order, oracle_price)? {
+                calculate_base_asset_amount_to_limit_price(order, amm, order.price)?
+            } else {
+                0
+            }
+        }
+        OrderType::Oracle => {
+            let limit_price = calculate_oracle_order_price(order, oracle_price)?;
+            if limit_price == 0 {
+                0
+            } else {
+                calculate_base_asset_amount_to_limit_price(order, amm, limit_price)?
+            }
+        }
+    };
+
+    if order.reduce_only {
+        return calculate_reduce_only_base_asset_amount(
+            order,
+            base_asset_amount,
+            position_base_asset_amount,
+        );
+    }
+
+    Ok(base_asset_amount)
+}
+
+/// A fillable order found by find_fillable_orders, with the fill fill_order would make for it now
+pub struct OrderRef {
+    pub user: Pubkey,
+    pub order_index: usize,
+    pub order_id: u64,
+    pub direction: PositionDirection,
+    pub base_asset_amount: u128,
+    pub ts: i64,
+    // how far the order's limit price is through the mark in its favor, i128::MAX without a limit
+    pub price_priority: i128,
+}
+
+/// The keeper's work queue for a market: every open order that fill_order would fill now, given
+/// each user's orders and positions. Orders are sorted by price then time, so orders without a
+/// limit come first, then the limits furthest through the mark, with ties going to the oldest.
+pub fn find_fillable_orders<'a>(
+    all_orders_and_positions: impl Iterator<Item = (&'a UserOrders, &'a UserPositions)>,
+    market_index: u64,
+    market: &Market,
+    oracle_price: i128,
+) -> ClearingHouseResult<Vec<OrderRef>> {
+    let mark_price = cast_to_i128(market.amm.mark_price()?)?;
+
+    let mut fillable_orders = vec![];
+    for (user_orders, user_positions) in all_orders_and_positions {
+        let position_base_asset_amount = user_positions
+            .positions
+            .iter()
+            .find(|market_position| market_position.is_for(market_index))
+            .map_or(0, |market_position| market_position.base_asset_amount);
+
+        for (order_index, order) in user_orders.orders.iter().enumerate() {
+            if !order.is_open() || order.market_index != market_index {
+                continue;
+            }
+
+            let base_asset_amount = calculate_base_asset_amount_to_fill(
+                order,
+                &market.amm,
+                oracle_price,
+                position_base_asset_amount,
+            )?;
+            if base_asset_amount == 0 || validate_fill_size(order, base_asset_amount).is_err() {
+                continue;
+            }
+
+            fillable_orders.push(OrderRef {
+                user: user_orders.user,
+                order_index,
+                order_id: order.order_id,
+                direction: order.direction,
+                base_asset_amount,
+                ts: order.ts,
+                price_priority: calculate_price_priority(order, mark_price, oracle_price)?,
+            });
+        }
+    }
+
+    fillable_orders.sort_by_key(|order_ref| {
+        (
+            Reverse(order_ref.price_priority),
+            order_ref.ts,
+            order_ref.order_id,
+        )
+    });
+
+    Ok(fillable_orders)
+}
+
+/// How far the order's limit price is through the mark in the order's favor
+fn calculate_price_priority(
+    order: &Order,
+    mark_price: i128,
+    oracle_price: i128,
+) -> ClearingHouseResult<i128> {
+    let limit_price = match order.order_type {
+        OrderType::Market if order.price == 0 => return Ok(i128::MAX),
+        OrderType::TriggerMarket => return Ok(i128::MAX),
+        OrderType::Oracle => cast_to_i128(calculate_oracle_order_price(order, oracle_price)?)?,
+        OrderType::Market | OrderType::Limit | OrderType::TriggerLimit => {
+            cast_to_i128(order.price)?
+        }
+    };
+
+    match order.direction {
+        PositionDirection::Long => limit_price.checked_sub(mark_price),
+        PositionDirection::Short => mark_price.checked_sub(limit_price),
+    }
+    .ok_or_else(math_error!())
+}
+
+/// How much of the order can trade before the mark moves through limit_price
+fn calculate_base_asset_amount_to_limit_price(
+    order: &Order,
+    amm: &AMM,
+    limit_price: u128,
+) -> ClearingHouseResult<u128> {
+    let (base_asset_amount_to_price, direction_to_price) =
+        calculate_base_asset_amount_to_trade_to_price(amm, limit_price)?;
+
+    // the mark is already through the limit price
+    if direction_to_price != order.direction {
+        return Ok(0);
+    }
+
+    Ok(min(
+        order.get_base_asset_amount_unfilled(),
+        base_asset_amount_to_price,
+    ))
+}
+
+/// Rejects a partial fill smaller than the order's minimum, so a resting order can't be nibbled
+/// away by dust fills. Filling the whole remainder is always allowed.
+pub fn validate_fill_size(order: &Order, base_asset_amount: u128) -> ClearingHouseResult {
+    let min_fill_base_asset_amount = order.min_fill_base_asset_amount;
+    if base_asset_amount < min_fill_base_asset_amount
+        && base_asset_amount < order.get_base_asset_amount_unfilled()
+    {
+        msg!(
+            "Fill of {} is below the order's min fill of {}",
+            base_asset_amount,
+            min_fill_base_asset_amount
+        );
+        return Err(ErrorCode::FillTooSmall);
+    }
+
+    Ok(())
+}
+
+/// Keeper reward for a fill of base_asset_amount of a trigger order. The reward ramps linearly from
+/// zero at placement to the order's max_keeper_reward over KEEPER_REWARD_AUCTION_DURATION, so the
+/// first keeper for whom the reward covers the fill takes it rather than waiting to snipe it. Each
+/// fill is paid its share of the order, so all fills together are bounded by max_keeper_reward.
+pub fn calculate_keeper_reward(
+    order: &Order,
+    base_asset_amount: u128,
+    now: i64,
+) -> ClearingHouseResult<u128> {
+    if order.max_keeper_reward == 0 || order.base_asset_amount == 0 {
+        return Ok(0);
+    }
+
+    let since_placed = cast_to_i128(now)?
+        .checked_sub(cast_to_i128(order.ts)?)
+        .ok_or_else(math_error!())?
+        .clamp(0, KEEPER_REWARD_AUCTION_DURATION);
+
+    let max_keeper_reward = min(order.max_keeper_reward, MAX_KEEPER_REWARD);
+    max_keeper_reward
+        .checked_mul(cast_to_u128(since_placed)?)
+        .ok_or_else(math_error!())?
+        .checked_mul(base_asset_amount)
+        .ok_or_else(math_error!())?
+        .checked_div(cast_to_u128(KEEPER_REWARD_AUCTION_DURATION)?)
+        .ok_or_else(math_error!())?
+        .checked_div(order.base_asset_amount)
+        .ok_or_else(math_error!())
+}
+
+pub fn is_order_triggered(order: &Order, oracle_price: i128) -> ClearingHouseResult<bool> {
+    let trigger_price = cast_to_i128(order.trigger_price)?;
+    Ok(match order.trigger_condition {
+        OrderTriggerCondition::Above => oracle_price > trigger_price,
+        OrderTriggerCondition::Below => oracle_price < trigger_price,
+    })
+}
+
+/// Limit price of an oracle order. Zero if the offset would price it at or below zero.
+pub fn calculate_oracle_order_price(
+    order: &Order,
+    oracle_price: i128,
+) -> ClearingHouseResult<u128> {
+    let limit_price = oracle_price
+        .checked_add(order.oracle_price_offset)
+        .ok_or_else(math_error!())?;
+
+    if limit_price <= 0 {
+        return Ok(0);
+    }
+
+    cast_to_u128(limit_price)
+}
+
+fn calculate_reduce_only_base_asset_amount(
+    order: &Order,
+    base_asset_amount: u128,
+    position_base_asset_amount: i128,
+) -> ClearingHouseResult<u128> {
+    let reduces_position = match order.direction {
+        PositionDirection::Long => position_base_asset_amount < 0,
+        PositionDirection::Short => position_base_asset_amount > 0,
+    };
+
+    if !reduces_position {
+        return Ok(0);
+    }
+
+    Ok(min(
+        base_asset_amount,
+        position_base_asset_amount.unsigned_abs(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, MARK_PRICE_PRECISION, PEG_PRECISION, QUOTE_PRECISION,
+    };
+    use crate::state::user_orders::OrderStatus;
+
+    /// An amm priced at 1, with the reserves the ts tests initialize markets with
+    fn amm() -> AMM {
+        let reserve = 5 * 10_u128.pow(18);
+        AMM {
+            base_asset_reserve: reserve,
+            quote_asset_reserve: reserve,
+            sqrt_k: reserve,
+            peg_multiplier: PEG_PRECISION,
+            ..AMM::default()
+        }
+    }
+
+    /// An open order for 10 base asset units
+    fn order(order_type: OrderType, direction: PositionDirection) -> Order {
+        Order {
+            status: OrderStatus::Open,
+            order_type,
+            direction,
+            base_asset_amount: 10 * AMM_RESERVE_PRECISION,
+            ..Order::default()
+        }
+    }
+
+    #[test]
+    fn oracle_order_limit_tracks_the_oracle() {
+        let amm = amm();
+        // bid 1% under the oracle
+        let order = Order {
+            oracle_price_offset: -((MARK_PRICE_PRECISION / 100) as i128),
+            ..order(OrderType::Oracle, PositionDirection::Long)
+        };
+
+        // with the oracle at the mark the bid is under it, so the amm doesn't cross it
+        let oracle_price = MARK_PRICE_PRECISION as i128;
+        assert_eq!(
+            calculate_oracle_order_price(&order, oracle_price).unwrap(),
+            MARK_PRICE_PRECISION * 99 / 100
+        );
+        assert_eq!(
+            calculate_base_asset_amount_to_fill(&order, &amm, oracle_price, 0).unwrap(),
+            0
+        );
+
+        // once the oracle is 2% up the bid is 1% over the mark and fills
+        let oracle_price = (MARK_PRICE_PRECISION * 102 / 100) as i128;
+        assert_eq!(
+            calculate_oracle_order_price(&order, oracle_price).unwrap(),
+            MARK_PRICE_PRECISION * 101 / 100
+        );
+        assert_eq!(
+            calculate_base_asset_amount_to_fill(&order, &amm, oracle_price, 0).unwrap(),
+            { order.base_asset_amount }
+        );
+    }
+
+    #[test]
+    fn oracle_order_priced_at_or_below_zero_does_not_fill() {
+        let amm = amm();
+        let order = Order {
+            oracle_price_offset: -(MARK_PRICE_PRECISION as i128),
+            ..order(OrderType::Oracle, PositionDirection::Long)
+        };
+
+        let oracle_price = MARK_PRICE_PRECISION as i128;
+        assert_eq!(
+            calculate_oracle_order_price(&order, oracle_price).unwrap(),
+            0
+        );
+        assert_eq!(
+            calculate_base_asset_amount_to_fill(&order, &amm, oracle_price, 0).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn dust_fill_against_a_min_fill_order_is_rejected() {
+        let mut order = Order {
+            min_fill_base_asset_amount: 2 * AMM_RESERVE_PRECISION,
+            ..order(OrderType::Limit, PositionDirection::Long)
+        };
+
+        assert!(matches!(
+            validate_fill_size(&order, AMM_RESERVE_PRECISION),
+            Err(ErrorCode::FillTooSmall)
+        ));
+        assert!(validate_fill_size(&order, 2 * AMM_RESERVE_PRECISION).is_ok());
+
+        // once less than the minimum is left, filling all of it is allowed
+        order.base_asset_amount_filled = 9 * AMM_RESERVE_PRECISION;
+        assert!(validate_fill_size(&order, AMM_RESERVE_PRECISION).is_ok());
+        assert!(matches!(
+            validate_fill_size(&order, AMM_RESERVE_PRECISION / 2),
+            Err(ErrorCode::FillTooSmall)
+        ));
+    }
+
+    #[test]
+    fn keeper_reward_ramps_up_to_its_bound() {
+        let order = Order {
+            ts: 100,
+            max_keeper_reward: QUOTE_PRECISION,
+            ..order(OrderType::TriggerMarket, PositionDirection::Long)
+        };
+        let base_asset_amount = order.base_asset_amount;
+
+        assert_eq!(
+            calculate_keeper_reward(&order, base_asset_amount, 100).unwrap(),
+            0
+        );
+        assert_eq!(
+            calculate_keeper_reward(&order, base_asset_amount, 130).unwrap(),
+            QUOTE_PRECISION / 2
+        );
+        assert_eq!(
+            calculate_keeper_reward(&order, base_asset_amount, 160).unwrap(),
+            QUOTE_PRECISION
+        );
+        // waiting past the auction doesn't pay more
+        assert_eq!(
+            calculate_keeper_reward(&order, base_asset_amount, 10_000).unwrap(),
+            QUOTE_PRECISION
+        );
+
+        // fills are paid their share, so all of them together stay within the reward
+        let fills = [3, 3, 4].map(|units| units * AMM_RESERVE_PRECISION);
+        let total_keeper_reward: u128 = fills
+            .iter()
+            .map(|fill| calculate_keeper_reward(&order, *fill, 160).unwrap())
+            .sum();
+        assert!(total_keeper_reward <= QUOTE_PRECISION);
+
+        // and no order can offer more than MAX_KEEPER_REWARD
+        let order = Order {
+            max_keeper_reward: 1_000 * QUOTE_PRECISION,
+            ..order
+        };
+        assert_eq!(
+            calculate_keeper_reward(&order, base_asset_amount, 160).unwrap(),
+            MAX_KEEPER_REWARD
+        );
+    }
+
+    #[test]
+    fn fillable_orders_are_sorted_by_price_then_time() {
+        let market = Market {
+            initialized: true,
+            amm: amm(),
+            ..Market::default()
+        };
+        let oracle_price = MARK_PRICE_PRECISION as i128;
+        let limit = |price: u128, ts: i64, order_id: u64| Order {
+            price,
+            ts,
+            order_id,
+            ..order(OrderType::Limit, PositionDirection::Long)
+        };
+
+        let mut first_user_orders = UserOrders {
+            user: Pubkey::new_unique(),
+            ..UserOrders::default()
+        };
+        first_user_orders.orders[0] = limit(MARK_PRICE_PRECISION * 101 / 100, 0, 1);
+        first_user_orders.orders[1] = limit(MARK_PRICE_PRECISION * 102 / 100, 1, 2);
+        // the mark is already through this bid
+        first_user_orders.orders[2] = limit(MARK_PRICE_PRECISION * 99 / 100, 0, 3);
+        // another market
+        first_user_orders.orders[3] = Order {
+            market_index: 1,
+            ..order(OrderType::Market, PositionDirection::Long)
+        };
+
+        let mut second_user_orders = UserOrders {
+            user: Pubkey::new_unique(),
+            ..UserOrders::default()
+        };
+        second_user_orders.orders[0] = Order {
+            ts: 5,
+            order_id: 1,
+            ..order(OrderType::Market, PositionDirection::Short)
+        };
+        second_user_orders.orders[1] = limit(MARK_PRICE_PRECISION * 102 / 100, 0, 2);
+        // reduce only without a position to reduce
+        second_user_orders.orders[2] = Order {
+            reduce_only: true,
+            order_id: 3,
+            ..order(OrderType::Market, PositionDirection::Long)
+        };
+
+        let user_positions = UserPositions::default();
+        let fillable_orders = find_fillable_orders(
+            vec![
+                (&first_user_orders, &user_positions),
+                (&second_user_orders, &user_positions),
+            ]
+            .into_iter(),
+            0,
+            &market,
+            oracle_price,
+        )
+        .unwrap();
+
+        // the market order first, then the bids furthest through the mark, oldest first
+        let fill_queue: Vec<(Pubkey, usize)> = fillable_orders
+            .iter()
+            .map(|order_ref| (order_ref.user, order_ref.order_index))
+            .collect();
+        assert_eq!(
+            fill_queue,
+            vec![
+                (second_user_orders.user, 0),
+                (second_user_orders.user, 1),
+                (first_user_orders.user, 1),
+                (first_user_orders.user, 0),
+            ]
+        );
+        assert!(fillable_orders
+            .iter()
+            .all(|order_ref| order_ref.base_asset_amount == 10 * AMM_RESERVE_PRECISION));
+    }
+}
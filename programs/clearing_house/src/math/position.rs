@@ -3,9 +3,13 @@ use crate::controller::position::PositionDirection;
 use crate::error::*;
 use crate::math::amm;
 use crate::math::amm::calculate_quote_asset_amount_swapped;
+use crate::math::casting::cast_to_u128;
+use crate::math::constants::{AMM_TO_QUOTE_PRECISION_RATIO, BPS_PRECISION, MARK_PRICE_PRECISION};
 use crate::math::pnl::calculate_pnl;
+use crate::math_error;
 use crate::state::market::AMM;
 use crate::state::user::MarketPosition;
+use solana_program::msg;
 
 pub fn calculate_base_asset_value_and_pnl(
     market_position: &MarketPosition,
@@ -18,6 +22,53 @@ pub fn calculate_base_asset_value_and_pnl(
     )
 }
 
+/// calculate_base_asset_value_and_pnl with the curve's exit value blended with the position's value
+/// at the amm's last oracle price, oracle_weight_bps of the way towards the latter. Pushing the curve
+/// then only moves the result by the curve's share. Without an oracle price on record yet, it's
+/// the curve's value alone.
+pub fn calculate_oracle_weighted_base_asset_value_and_pnl(
+    market_position: &MarketPosition,
+    amm: &AMM,
+    oracle_weight_bps: u64,
+) -> ClearingHouseResult<(u128, i128)> {
+    let (amm_base_asset_value, amm_pnl) = calculate_base_asset_value_and_pnl(market_position, amm)?;
+    if oracle_weight_bps == 0
+        || amm.last_oracle_price <= 0
+        || market_position.base_asset_amount == 0
+    {
+        return Ok((amm_base_asset_value, amm_pnl));
+    }
+
+    let oracle_base_asset_value = market_position
+        .base_asset_amount
+        .unsigned_abs()
+        .checked_mul(cast_to_u128(amm.last_oracle_price)?)
+        .ok_or_else(math_error!())?
+        .checked_div(MARK_PRICE_PRECISION * AMM_TO_QUOTE_PRECISION_RATIO)
+        .ok_or_else(math_error!())?;
+
+    let oracle_weight = cast_to_u128(oracle_weight_bps)?.min(BPS_PRECISION);
+    let base_asset_value = amm_base_asset_value
+        .checked_mul(BPS_PRECISION - oracle_weight)
+        .ok_or_else(math_error!())?
+        .checked_add(
+            oracle_base_asset_value
+                .checked_mul(oracle_weight)
+                .ok_or_else(math_error!())?,
+        )
+        .ok_or_else(math_error!())?
+        .checked_div(BPS_PRECISION)
+        .ok_or_else(math_error!())?;
+
+    let pnl = calculate_pnl(
+        base_asset_value,
+        market_position.quote_asset_amount,
+        swap_direction_to_close_position(market_position.base_asset_amount),
+    )?;
+
+    Ok((base_asset_value, pnl))
+}
+
 pub fn _calculate_base_asset_value_and_pnl(
     base_asset_amount: i128,
     quote_asset_amount: u128,
@@ -48,6 +99,22 @@ pub fn _calculate_base_asset_value_and_pnl(
     Ok((base_asset_value, pnl))
 }
 
+/// Average entry price of the position in MARK_PRICE_PRECISION. Zero for an empty position.
+pub fn calculate_entry_price(
+    quote_asset_amount: u128,
+    base_asset_amount: i128,
+) -> ClearingHouseResult<u128> {
+    if base_asset_amount == 0 {
+        return Ok(0);
+    }
+
+    quote_asset_amount
+        .checked_mul(MARK_PRICE_PRECISION * AMM_TO_QUOTE_PRECISION_RATIO)
+        .ok_or_else(math_error!())?
+        .checked_div(base_asset_amount.unsigned_abs())
+        .ok_or_else(math_error!())
+}
+
 pub fn direction_to_close_position(base_asset_amount: i128) -> PositionDirection {
     if base_asset_amount > 0 {
         PositionDirection::Short
@@ -63,3 +130,36 @@ pub fn swap_direction_to_close_position(base_asset_amount: i128) -> SwapDirectio
         SwapDirection::Remove
     }
 }
+
+/// Clamps the quote value of a liquidation fill to within the band around the oracle value of the
+/// base asset, so a pushed curve can't set the price a position is liquidated at
+pub fn calculate_oracle_band_quote_asset_amount(
+    base_asset_amount: u128,
+    quote_asset_amount: u128,
+    oracle_price: i128,
+    band_numerator: u64,
+    band_denominator: u64,
+) -> ClearingHouseResult<u128> {
+    if band_denominator == 0 || oracle_price <= 0 || base_asset_amount == 0 {
+        return Ok(quote_asset_amount);
+    }
+
+    let oracle_quote_asset_amount = base_asset_amount
+        .checked_mul(cast_to_u128(oracle_price)?)
+        .ok_or_else(math_error!())?
+        .checked_div(MARK_PRICE_PRECISION * AMM_TO_QUOTE_PRECISION_RATIO)
+        .ok_or_else(math_error!())?;
+
+    let band = oracle_quote_asset_amount
+        .checked_mul(band_numerator as u128)
+        .ok_or_else(math_error!())?
+        .checked_div(band_denominator as u128)
+        .ok_or_else(math_error!())?;
+
+    let lower_bound = oracle_quote_asset_amount.saturating_sub(band);
+    let upper_bound = oracle_quote_asset_amount
+        .checked_add(band)
+        .ok_or_else(math_error!())?;
+
+    Ok(quote_asset_amount.max(lower_bound).min(upper_bound))
+}
@@ -39,6 +39,24 @@ pub fn get_whitelist_token(
     Ok(Some(token_account))
 }
 
+/// A token account of keeper_mint with a balance, owned by one of the keepers, found anywhere in
+/// accounts since keeper instructions pass other remaining accounts, like oracles, too
+pub fn get_keeper_token(
+    accounts: &[AccountInfo],
+    keeper_mint: &Pubkey,
+    keepers: &[Pubkey],
+) -> Option<TokenAccount> {
+    accounts
+        .iter()
+        .filter(|account_info| account_info.owner == &spl_token::id())
+        .filter_map(|account_info| TokenAccount::unpack(&account_info.data.borrow()).ok())
+        .find(|token_account| {
+            token_account.mint.eq(keeper_mint)
+                && token_account.amount > 0
+                && keepers.contains(&token_account.owner)
+        })
+}
+
 pub fn get_discount_token_and_referrer<'a, 'b, 'c, 'd, 'e>(
     optional_accounts: ManagePositionOptionalAccounts,
     accounts: &'a [AccountInfo<'b>],
@@ -0,0 +1,186 @@
+use anchor_lang::prelude::*;
+
+use crate::error::ClearingHouseResult;
+use crate::state::market::{Market, OracleSource, AMM};
+
+// The markets account as deployed before markets outgrew their padding. migrate_markets reads an
+// exchange's existing account in this layout and copies it onto an account in the current one.
+pub const LEGACY_MARKET_SIZE: usize = 522;
+const _: [(); LEGACY_MARKET_SIZE] = [(); std::mem::size_of::<LegacyMarket>()];
+pub const LEGACY_AMM_SIZE: usize = 377;
+const _: [(); LEGACY_AMM_SIZE] = [(); std::mem::size_of::<LegacyAMM>()];
+
+#[zero_copy]
+pub(crate) struct LegacyMarkets {
+    pub markets: [LegacyMarket; 64],
+}
+
+// The legacy account is read as raw bytes rather than through an AccountLoader, so it needs the
+// impls #[account(zero_copy)] would otherwise provide
+unsafe impl bytemuck::Pod for LegacyMarkets {}
+unsafe impl bytemuck::Zeroable for LegacyMarkets {}
+
+#[zero_copy]
+#[derive(Default)]
+pub(crate) struct LegacyMarket {
+    pub initialized: bool,
+    pub base_asset_amount_long: i128,
+    pub base_asset_amount_short: i128,
+    pub base_asset_amount: i128,
+    pub open_interest: u128,
+    pub amm: LegacyAMM,
+    pub _padding0: u128,
+    pub _padding1: u128,
+    pub _padding2: u128,
+    pub _padding3: u128,
+    pub _padding4: u128,
+}
+
+#[zero_copy]
+#[derive(Default)]
+pub(crate) struct LegacyAMM {
+    pub oracle: Pubkey,
+    pub oracle_source: OracleSource,
+    pub base_asset_reserve: u128,
+    pub quote_asset_reserve: u128,
+    pub cumulative_repeg_rebate_long: u128,
+    pub cumulative_repeg_rebate_short: u128,
+    pub cumulative_funding_rate_long: i128,
+    pub cumulative_funding_rate_short: i128,
+    pub last_funding_rate: i128,
+    pub last_funding_rate_ts: i64,
+    pub funding_period: i64,
+    pub last_oracle_price_twap: i128,
+    pub last_mark_price_twap: u128,
+    pub last_mark_price_twap_ts: i64,
+    pub sqrt_k: u128,
+    pub peg_multiplier: u128,
+    pub total_fee: u128,
+    pub total_fee_minus_distributions: u128,
+    pub total_fee_withdrawn: u128,
+    pub minimum_trade_size: u128,
+    pub last_oracle_price_twap_ts: i64,
+    pub last_oracle_price: i128,
+    pub _padding1: u64,
+    pub _padding2: u128,
+    pub _padding3: u128,
+    pub _padding4: u128,
+}
+
+impl LegacyMarket {
+    /// Copies the market into the current layout. Fields added since are left at the values
+    /// initialize_market gives them, except the solvency totals of entry quote asset amount, which
+    /// start at 0 and only track positions changed after the migration.
+    pub fn migrate(&self) -> ClearingHouseResult<Market> {
+        let legacy_amm = self.amm;
+        let mut market = Market {
+            initialized: self.initialized,
+            base_asset_amount_long: self.base_asset_amount_long,
+            base_asset_amount_short: self.base_asset_amount_short,
+            base_asset_amount: self.base_asset_amount,
+            open_interest: self.open_interest,
+            amm: AMM {
+                oracle: legacy_amm.oracle,
+                oracle_source: legacy_amm.oracle_source,
+                base_asset_reserve: legacy_amm.base_asset_reserve,
+                quote_asset_reserve: legacy_amm.quote_asset_reserve,
+                cumulative_repeg_rebate_long: legacy_amm.cumulative_repeg_rebate_long,
+                cumulative_repeg_rebate_short: legacy_amm.cumulative_repeg_rebate_short,
+                cumulative_funding_rate_long: legacy_amm.cumulative_funding_rate_long,
+                cumulative_funding_rate_short: legacy_amm.cumulative_funding_rate_short,
+                last_funding_rate: legacy_amm.last_funding_rate,
+                last_funding_rate_ts: legacy_amm.last_funding_rate_ts,
+                funding_period: legacy_amm.funding_period,
+                last_oracle_price_twap: legacy_amm.last_oracle_price_twap,
+                last_mark_price_twap: legacy_amm.last_mark_price_twap,
+                last_mark_price_twap_ts: legacy_amm.last_mark_price_twap_ts,
+                sqrt_k: legacy_amm.sqrt_k,
+                peg_multiplier: legacy_amm.peg_multiplier,
+                total_fee: legacy_amm.total_fee,
+                total_fee_minus_distributions: legacy_amm.total_fee_minus_distributions,
+                total_fee_withdrawn: legacy_amm.total_fee_withdrawn,
+                minimum_trade_size: legacy_amm.minimum_trade_size,
+                last_oracle_price_twap_ts: legacy_amm.last_oracle_price_twap_ts,
+                last_oracle_price: legacy_amm.last_oracle_price,
+                ..AMM::default()
+            },
+            ..Market::default()
+        };
+
+        if market.initialized {
+            market.amm.reset_oracle_observations(
+                legacy_amm.last_oracle_price_twap,
+                legacy_amm.last_oracle_price_twap_ts,
+            )?;
+        }
+
+        Ok(market)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::market::{FeeDenomination, FundingPriceAnchor};
+
+    #[test]
+    fn migrate_copies_market_and_defaults_new_fields() {
+        let legacy_market = LegacyMarket {
+            initialized: true,
+            base_asset_amount_long: 10,
+            base_asset_amount_short: -4,
+            base_asset_amount: 6,
+            open_interest: 2,
+            amm: LegacyAMM {
+                base_asset_reserve: 1000,
+                quote_asset_reserve: 2000,
+                cumulative_funding_rate_long: 7,
+                cumulative_funding_rate_short: -7,
+                sqrt_k: 1414,
+                peg_multiplier: 1000,
+                total_fee: 55,
+                total_fee_minus_distributions: 50,
+                last_oracle_price_twap: 123,
+                last_oracle_price_twap_ts: 99,
+                last_oracle_price: 125,
+                ..LegacyAMM::default()
+            },
+            ..LegacyMarket::default()
+        };
+
+        let market = legacy_market.migrate().unwrap();
+
+        assert!(market.initialized);
+        assert_eq!({ market.base_asset_amount_long }, 10);
+        assert_eq!({ market.base_asset_amount_short }, -4);
+        assert_eq!({ market.base_asset_amount }, 6);
+        assert_eq!({ market.open_interest }, 2);
+        assert_eq!({ market.amm.base_asset_reserve }, 1000);
+        assert_eq!({ market.amm.quote_asset_reserve }, 2000);
+        assert_eq!({ market.amm.cumulative_funding_rate_long }, 7);
+        assert_eq!({ market.amm.cumulative_funding_rate_short }, -7);
+        assert_eq!({ market.amm.sqrt_k }, 1414);
+        assert_eq!({ market.amm.peg_multiplier }, 1000);
+        assert_eq!({ market.amm.total_fee }, 55);
+        assert_eq!({ market.amm.total_fee_minus_distributions }, 50);
+        assert_eq!({ market.amm.last_oracle_price }, 125);
+
+        assert_eq!({ market.max_open_interest }, 0);
+        assert_eq!({ market.expiry_ts }, 0);
+        assert!(market.fee_denomination == FeeDenomination::Quote);
+        assert!(market.funding_price_anchor == FundingPriceAnchor::MarkTwap);
+        assert_eq!({ market.amm.max_oracle_divergence_bps }, 0);
+        assert_eq!({ market.amm.oracle_observations_head }, 1);
+        assert_eq!({ market.amm.oracle_observations[0].price }, 123);
+        assert_eq!({ market.amm.oracle_observations[0].ts }, 99);
+    }
+
+    #[test]
+    fn migrate_leaves_uninitialized_market_empty() {
+        let market = LegacyMarket::default().migrate().unwrap();
+
+        assert!(!market.initialized);
+        assert_eq!({ market.amm.oracle_observations_head }, 0);
+        assert_eq!({ market.amm.oracle_observations[0].ts }, 0);
+    }
+}
@@ -1,10 +1,14 @@
 use anchor_lang::prelude::*;
 
+use crate::controller::position::PositionDirection;
 use crate::error::*;
 use crate::math::amm;
-use crate::math::casting::{cast, cast_to_i128, cast_to_i64, cast_to_u128};
+use crate::math::casting::{cast, cast_to_i128, cast_to_i64};
+use crate::math::constants::{AMM_TO_QUOTE_PRECISION_RATIO, MARK_PRICE_PRECISION};
+use crate::math::oracle;
+use crate::math::oracle::OraclePriceData;
 use crate::math_error;
-use crate::MARK_PRICE_PRECISION;
+use crate::state::user::MarketPosition;
 use solana_program::msg;
 
 #[account(zero_copy)]
@@ -20,6 +24,56 @@ impl Default for Markets {
     }
 }
 
+/// Totals across every initialized market, for a single protocol-wide read
+#[derive(Clone, Copy, Default)]
+pub struct ProtocolSummary {
+    pub open_interest: u128,          // number of positions
+    pub open_interest_notional: u128, // long and short base asset valued at each market's mark
+    pub total_fee: u128,
+    pub total_fee_minus_distributions: u128,
+    pub total_fee_to_insurance_fund: u128,
+}
+
+pub fn protocol_summary(markets: &[Market]) -> ClearingHouseResult<ProtocolSummary> {
+    let mut summary = ProtocolSummary::default();
+
+    for market in markets.iter().filter(|market| market.initialized) {
+        let base_asset_amount = market
+            .base_asset_amount_long
+            .unsigned_abs()
+            .checked_add(market.base_asset_amount_short.unsigned_abs())
+            .ok_or_else(math_error!())?;
+        let open_interest_notional = base_asset_amount
+            .checked_mul(market.amm.mark_price()?)
+            .ok_or_else(math_error!())?
+            .checked_div(MARK_PRICE_PRECISION * AMM_TO_QUOTE_PRECISION_RATIO)
+            .ok_or_else(math_error!())?;
+
+        summary.open_interest = summary
+            .open_interest
+            .checked_add(market.open_interest)
+            .ok_or_else(math_error!())?;
+        summary.open_interest_notional = summary
+            .open_interest_notional
+            .checked_add(open_interest_notional)
+            .ok_or_else(math_error!())?;
+        summary.total_fee = summary
+            .total_fee
+            .checked_add(market.amm.total_fee)
+            .ok_or_else(math_error!())?;
+        summary.total_fee_minus_distributions = summary
+            .total_fee_minus_distributions
+            .checked_add(market.amm.total_fee_minus_distributions)
+            .ok_or_else(math_error!())?;
+        summary.total_fee_to_insurance_fund = summary
+            .total_fee_to_insurance_fund
+            .checked_add(cast(market.total_fee_to_insurance_fund)?)
+            .ok_or_else(math_error!())?;
+    }
+
+    Ok(summary)
+}
+
 impl Markets {
     pub fn index_from_u64(index: u64) -> usize {
         std::convert::TryInto::try_into(index).unwrap()
@@ -36,6 +90,46 @@ pub struct Market {
     pub open_interest: u128,     // number of users in a position
     pub amm: AMM,
 
+    // portfolio margin
+    pub asset_group: u64,              // 0 means the market is not grouped
+    pub correlation_bps: u64,          // share of a hedge's smaller leg credited against margin
+    pub margin_oracle_weight_bps: u64, // share of the oracle price in the price margin values positions at
+
+    // position limits
+    pub max_quote_asset_amount: u64, // per position, 0 means no limit
+    pub max_open_interest: u128,     // number of users in a position, 0 means no limit
+    pub max_position_base_asset_amount: u128, // per position, 0 means no limit
+    pub margin_ratio_initial: u128, // applies to trades in the market if above the exchange's, 0 means none
+
+    // fees
+    pub fee_denomination: FeeDenomination,
+
+    // insurance fund capitalization
+    pub insurance_fund_target: u64, // total fees to route to the insurance fund, 0 means none
+    pub insurance_fund_fee_share_bps: u64, // share of fees routed while below target
+    pub total_fee_to_insurance_fund: u64,
+    pub total_fee_to_insurance_fund_settled: u64, // transferred to the insurance vault
+    pub total_bad_debt: u64, // losses beyond traders' collateral, owed by the insurance fund
+    pub total_bad_debt_settled: u64, // transferred from the insurance vault
+
+    // funding
+    pub funding_price_anchor: FundingPriceAnchor,
+    pub cumulative_quote_volume: u64, // QUOTE_PRECISION, since the last funding update
+    pub cumulative_base_volume: u64,  // QUOTE_PRECISION, since the last funding update
+
+    // solvency monitoring
+    pub total_trader_pnl_paid: u128, // realized profit credited to traders' collateral
+    pub total_trader_pnl_collected: u128, // realized losses debited from traders' collateral
+    pub quote_asset_amount_long: u128, // total entry quote asset amount of open long positions
+    pub quote_asset_amount_short: u128, // total entry quote asset amount of open short positions
+
+    // pnl settlement
+    pub settle_pnl_separately: bool, // realized pnl is held on the position until claimed
+
+    // expiry
+    pub expiry_ts: i64,         // 0 means the market never expires
+    pub settlement_price: u128, // MARK_PRICE_PRECISION, set by the admin once the market expires
+
     // upgrade-ability
     pub padding0: u128,
     pub padding1: u128,
@@ -44,6 +138,116 @@ pub struct Market {
     pub padding4: u128,
 }
 
+// Markets is zero copy and loaded with an exact size check, so a market that outgrows its padding
+// needs migrate_markets to move the exchange onto a new account. New fields come out of the padding
+// above; a field added without shrinking it fails to compile.
+pub const MARKET_SIZE: usize = 781;
+const _: [(); MARKET_SIZE] = [(); std::mem::size_of::<Market>()];
+
+impl Market {
+    /// Whether the market is dated and now is at or after its expiry
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expiry_ts != 0 && now >= self.expiry_ts
+    }
+
+    /// The initial margin ratio a trade in the market is held to: the market's own if it is set and
+    /// stricter than the exchange wide one, the exchange wide one otherwise
+    pub fn get_margin_ratio_initial(&self, state_margin_ratio_initial: u128) -> u128 {
+        self.margin_ratio_initial.max(state_margin_ratio_initial)
+    }
+
+    /// Books the change realized pnl made to a trader's collateral. Losses are counted only up to
+    /// the collateral they could actually take.
+    pub fn record_trader_pnl(
+        &mut self,
+        collateral_before: u128,
+        collateral_after: u128,
+    ) -> ClearingHouseResult {
+        if collateral_after > collateral_before {
+            self.total_trader_pnl_paid = self
+                .total_trader_pnl_paid
+                .checked_add(collateral_after - collateral_before)
+                .ok_or_else(math_error!())?;
+        } else {
+            self.total_trader_pnl_collected = self
+                .total_trader_pnl_collected
+                .checked_add(collateral_before - collateral_after)
+                .ok_or_else(math_error!())?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves a position's entry quote asset amount out of the side it was on and into the side it is
+    /// on after a trade. Positions opened before these totals were tracked aren't in them, so taking
+    /// one out saturates at zero.
+    pub fn update_entry_quote_asset_amount(
+        &mut self,
+        position_before: &MarketPosition,
+        position_after: &MarketPosition,
+    ) -> ClearingHouseResult {
+        match position_before.direction() {
+            Some(PositionDirection::Long) => {
+                self.quote_asset_amount_long = self
+                    .quote_asset_amount_long
+                    .saturating_sub(position_before.quote_asset_amount);
+            }
+            Some(PositionDirection::Short) => {
+                self.quote_asset_amount_short = self
+                    .quote_asset_amount_short
+                    .saturating_sub(position_before.quote_asset_amount);
+            }
+            None => {}
+        }
+
+        match position_after.direction() {
+            Some(PositionDirection::Long) => {
+                self.quote_asset_amount_long = self
+                    .quote_asset_amount_long
+                    .checked_add(position_after.quote_asset_amount)
+                    .ok_or_else(math_error!())?;
+            }
+            Some(PositionDirection::Short) => {
+                self.quote_asset_amount_short = self
+                    .quote_asset_amount_short
+                    .checked_add(position_after.quote_asset_amount)
+                    .ok_or_else(math_error!())?;
+            }
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    /// Realized pnl paid to traders net of what was collected from them. Positive means the
+    /// protocol has paid out more than it collected.
+    pub fn net_trader_pnl(&self) -> ClearingHouseResult<i128> {
+        cast_to_i128(self.total_trader_pnl_paid)?
+            .checked_sub(cast_to_i128(self.total_trader_pnl_collected)?)
+            .ok_or_else(math_error!())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Default)]
+pub enum FeeDenomination {
+    /// Fee is charged to the user's collateral
+    #[default]
+    Quote,
+    /// Fee is taken on the base asset acquired when a position is increased, valued at the fill
+    /// price and charged to the user's collateral
+    Base,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Default)]
+pub enum FundingPriceAnchor {
+    /// Funding is paid on the mark twap
+    #[default]
+    MarkTwap,
+    /// Funding is paid on the volume weighted price traded since the last funding update, falling
+    /// back to the mark twap if nothing traded
+    Vwap,
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
 pub enum OracleSource {
     Pyth,
@@ -82,15 +286,53 @@ pub struct AMM {
     pub minimum_trade_size: u128,
     pub last_oracle_price_twap_ts: i64,
     pub last_oracle_price: i128,
+    pub max_oracle_divergence_bps: u64, // 0 means swaps aren't bounded against the oracle price
+    pub oracle_observations_head: u64,
+    // ORACLE_OBSERVATIONS_SIZE, spelled out as the idl can't parse a constant array length
+    pub oracle_observations: [OracleObservation; 4],
+}
 
-    // upgrade-ability
-    pub padding1: u64,
-    pub padding2: u128,
-    pub padding3: u128,
-    pub padding4: u128,
+/// Size of the amm's oracle observation ring buffer. Observations are recorded on every trade and
+/// twap update, and older ones are already reflected in the previous twap, so four observations
+/// cover an hourly funding period whose twap is updated every fifteen minutes.
+pub const ORACLE_OBSERVATIONS_SIZE: usize = 4;
+
+#[zero_copy]
+#[derive(Default)]
+pub struct OracleObservation {
+    pub ts: i64,
+    pub price: i64,
 }
 
 impl AMM {
+    /// Records an oracle observation in the amm's ring buffer. An observation with the same
+    /// timestamp as the latest one overwrites it rather than evicting older history.
+    pub fn append_oracle_observation(&mut self, price: i128, ts: i64) -> ClearingHouseResult {
+        let observation = OracleObservation {
+            ts,
+            price: cast_to_i64(price)?,
+        };
+
+        let buffer_size = self.oracle_observations.len();
+        let head: usize = cast(self.oracle_observations_head)?;
+        let latest_index = (head + buffer_size - 1) % buffer_size;
+        if self.oracle_observations[latest_index].ts == ts {
+            self.oracle_observations[latest_index] = observation;
+            return Ok(());
+        }
+
+        self.oracle_observations[head] = observation;
+        self.oracle_observations_head = cast((head + 1) % buffer_size)?;
+
+        Ok(())
+    }
+
+    pub fn reset_oracle_observations(&mut self, price: i128, ts: i64) -> ClearingHouseResult {
+        self.oracle_observations = [OracleObservation::default(); ORACLE_OBSERVATIONS_SIZE];
+        self.oracle_observations_head = 0;
+        self.append_oracle_observation(price, ts)
+    }
+
     pub fn mark_price(&self) -> ClearingHouseResult<u128> {
         amm::calculate_price(
             self.quote_asset_reserve,
@@ -99,89 +341,60 @@ impl AMM {
         )
     }
 
-    pub fn get_pyth_price(
+    pub fn get_oracle_price(
         &self,
         price_oracle: &AccountInfo,
         clock_slot: u64,
-    ) -> ClearingHouseResult<(i128, i128, u128, u128, i64)> {
-        let pyth_price_data = price_oracle
-            .try_borrow_data()
-            .or(Err(ErrorCode::UnableToLoadOracle))?;
-        let price_data = pyth_client::cast::<pyth_client::Price>(&pyth_price_data);
-
-        let oracle_price = cast_to_i128(price_data.agg.price)?;
-        let oracle_conf = cast_to_u128(price_data.agg.conf)?;
-        let oracle_twap = cast_to_i128(price_data.twap.val)?;
-        let oracle_twac = cast_to_u128(price_data.twac.val)?;
-
-        let oracle_precision = 10_u128.pow(price_data.expo.unsigned_abs());
+    ) -> ClearingHouseResult<OraclePriceData> {
+        oracle::get_oracle_price(&self.oracle_source, price_oracle, clock_slot)
+    }
+}
 
-        let mut oracle_scale_mult = 1;
-        let mut oracle_scale_div = 1;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::{AMM_RESERVE_PRECISION, PEG_PRECISION, QUOTE_PRECISION};
 
-        if oracle_precision > MARK_PRICE_PRECISION {
-            oracle_scale_div = oracle_precision
-                .checked_div(MARK_PRICE_PRECISION)
-                .ok_or_else(math_error!())?;
-        } else {
-            oracle_scale_mult = MARK_PRICE_PRECISION
-                .checked_div(oracle_precision)
-                .ok_or_else(math_error!())?;
+    /// A market priced at peg_multiplier, with long and short base asset and fees in quote units
+    fn market(peg_multiplier: u128, long: u128, short: u128, fee: u128) -> Market {
+        let reserve = 5 * 10_u128.pow(18);
+        Market {
+            initialized: true,
+            base_asset_amount_long: cast_to_i128(long * AMM_RESERVE_PRECISION).unwrap(),
+            base_asset_amount_short: -cast_to_i128(short * AMM_RESERVE_PRECISION).unwrap(),
+            open_interest: 2,
+            total_fee_to_insurance_fund: cast(fee * QUOTE_PRECISION / 2).unwrap(),
+            amm: AMM {
+                base_asset_reserve: reserve,
+                quote_asset_reserve: reserve,
+                sqrt_k: reserve,
+                peg_multiplier,
+                total_fee: fee * QUOTE_PRECISION,
+                total_fee_minus_distributions: fee * QUOTE_PRECISION / 4,
+                ..AMM::default()
+            },
+            ..Market::default()
         }
+    }
 
-        let oracle_price_scaled = (oracle_price)
-            .checked_mul(cast(oracle_scale_mult)?)
-            .ok_or_else(math_error!())?
-            .checked_div(cast(oracle_scale_div)?)
-            .ok_or_else(math_error!())?;
-
-        let oracle_twap_scaled = (oracle_twap)
-            .checked_mul(cast(oracle_scale_mult)?)
-            .ok_or_else(math_error!())?
-            .checked_div(cast(oracle_scale_div)?)
-            .ok_or_else(math_error!())?;
-
-        let oracle_conf_scaled = (oracle_conf)
-            .checked_mul(oracle_scale_mult)
-            .ok_or_else(math_error!())?
-            .checked_div(oracle_scale_div)
-            .ok_or_else(math_error!())?;
-
-        let oracle_twac_scaled = (oracle_twac)
-            .checked_mul(oracle_scale_mult)
-            .ok_or_else(math_error!())?
-            .checked_div(oracle_scale_div)
-            .ok_or_else(math_error!())?;
+    #[test]
+    fn protocol_summary_totals_the_initialized_markets() {
+        let markets = [
+            market(PEG_PRECISION, 10, 4, 8),
+            market(3 * PEG_PRECISION, 1, 2, 20),
+            Market {
+                initialized: false,
+                ..market(PEG_PRECISION, 1000, 1000, 1000)
+            },
+        ];
 
-        let oracle_delay: i64 = cast_to_i64(clock_slot)?
-            .checked_sub(cast(price_data.valid_slot)?)
-            .ok_or_else(math_error!())?;
+        let summary = protocol_summary(&markets).unwrap();
 
-        Ok((
-            oracle_price_scaled,
-            oracle_twap_scaled,
-            oracle_conf_scaled,
-            oracle_twac_scaled,
-            oracle_delay,
-        ))
-    }
-
-    pub fn get_oracle_price(
-        &self,
-        price_oracle: &AccountInfo,
-        clock_slot: u64,
-    ) -> ClearingHouseResult<(i128, i128, u128, u128, i64)> {
-        let (oracle_px, oracle_twap, oracle_conf, oracle_twac, oracle_delay) =
-            match self.oracle_source {
-                OracleSource::Pyth => self.get_pyth_price(price_oracle, clock_slot)?,
-                OracleSource::Switchboard => (0, 0, 0, 0, 0),
-            };
-        Ok((
-            oracle_px,
-            oracle_twap,
-            oracle_conf,
-            oracle_twac,
-            oracle_delay,
-        ))
+        assert_eq!(summary.open_interest, 4);
+        // 14 base at 1 and 3 base at 3
+        assert_eq!(summary.open_interest_notional, 23 * QUOTE_PRECISION);
+        assert_eq!(summary.total_fee, 28 * QUOTE_PRECISION);
+        assert_eq!(summary.total_fee_minus_distributions, 7 * QUOTE_PRECISION);
+        assert_eq!(summary.total_fee_to_insurance_fund, 14 * QUOTE_PRECISION);
     }
 }
@@ -1,5 +1,7 @@
 pub mod history;
+pub mod legacy_market;
 pub mod market;
 #[allow(clippy::module_inception)]
 pub mod state;
 pub mod user;
+pub mod user_orders;
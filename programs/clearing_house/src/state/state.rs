@@ -38,14 +38,21 @@ pub struct State {
     pub oracle_guard_rails: OracleGuardRails,
     pub max_deposit: u128,
     pub extended_curve_history: Pubkey,
+    pub liquidation_oracle_band_numerator: u64,
+    pub liquidation_oracle_band_denominator: u64,
+    pub mark_twap_divergence_numerator: u64,
+    pub mark_twap_divergence_denominator: u64,
+    pub max_pnl_velocity: u64, // 0 means pnl velocity isn't capped
+    pub keeper_mint: Pubkey,   // default means liquidating and settling funding are permissionless
+
+    // swept to the fee pool when a user's last position is closed with less collateral than this
+    pub dust_collateral_threshold: u64, // 0 means dust collateral isn't swept
+
+    // share of a user's secondary collateral that counts towards margin, the rest is its haircut
+    pub secondary_collateral_weight_bps: u64,
 
     // upgrade-ability
-    pub padding0: u128,
-    pub padding1: u128,
-    pub padding2: u128,
-    pub padding3: u128,
-    pub padding4: u128,
-    pub padding5: u128,
+    pub padding5: u64,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
@@ -98,3 +105,16 @@ pub struct ReferralDiscount {
     pub referee_discount_numerator: u128,
     pub referee_discount_denominator: u128,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_keeps_its_deployed_size() {
+        // fields added since deployment have come out of the padding, so the state account's
+        // serialized size is unchanged
+        let state = State::default().try_to_vec().unwrap();
+        assert_eq!(state.len(), 1118);
+    }
+}
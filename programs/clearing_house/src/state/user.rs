@@ -1,4 +1,16 @@
 use anchor_lang::prelude::*;
+use solana_program::msg;
+use std::cell::{Ref, RefMut};
+
+use crate::controller::position::PositionDirection;
+use crate::error::{ClearingHouseResult, ErrorCode};
+use crate::math::casting::cast;
+use crate::math::funding::calculate_unrealized_funding;
+use crate::math::position::{
+    calculate_base_asset_value_and_pnl, calculate_oracle_weighted_base_asset_value_and_pnl,
+};
+use crate::math_error;
+use crate::state::market::Market;
 
 #[account]
 #[derive(Default)]
@@ -11,14 +23,23 @@ pub struct User {
     pub total_referral_reward: u128,
     pub total_referee_discount: u128,
     pub positions: Pubkey,
+    pub high_water_mark: i128, // account value net of deposits/withdrawals, for performance fees
+    pub pnl_velocity: i64, // realized pnl decayed over PNL_VELOCITY_WINDOW, for circuit-breaking
+    pub pnl_velocity_ts: i64,
+    pub total_realized_pnl: i128, // lifetime realized pnl, for reporting
+    pub auto_deleverage: bool,    // a keeper may reduce the worst position before liquidation
+    pub secondary_collateral: u64, // volatile collateral, valued in quote and haircut for margin
 
     // upgrade-ability
-    pub padding0: u128,
-    pub padding1: u128,
-    pub padding2: u128,
-    pub padding3: u128,
+    pub padding3: [u8; 7],
 }
 
+// User accounts are already allocated on chain, so new fields have to come out of the padding and
+// the borsh layout has to stay at 224 bytes. The fields pack without alignment gaps, so the in memory
+// size is the borsh size; a field added without shrinking the padding fails to compile.
+pub const USER_SIZE: usize = 224;
+const _: [(); USER_SIZE] = [(); std::mem::size_of::<User>()];
+
 #[account(zero_copy)]
 #[derive(Default)]
 pub struct UserPositions {
@@ -42,16 +63,400 @@ pub struct MarketPosition {
     pub transfer_to: Pubkey,
 
     // upgrade-ability
-    pub padding0: u128,
-    pub padding1: u128,
+    //
+    // padding allocation map. A feature that needs new position state claims a slot here (or
+    // splits one into narrower fields that add up to 16 bytes) and records it below, so two
+    // features never claim the same bytes.
+    //   padding0: settled_pnl
+    //   padding1: total_fee_paid, total_funding_payment
+    pub settled_pnl: i128, // realized gains held back from collateral until claimed
+    pub total_fee_paid: u64, // fees paid since the position was opened
+    pub total_funding_payment: i64, // funding received (paid if negative) since the position was opened
+}
+
+// UserPositions accounts are already allocated on chain, so the position layout can't change size.
+// Repurposing padding keeps this at 208 bytes; anything else fails to compile.
+pub const MARKET_POSITION_SIZE: usize = 208;
+const _: [(); MARKET_POSITION_SIZE] = [(); std::mem::size_of::<MarketPosition>()];
+const _: [(); 32 + 5 * MARKET_POSITION_SIZE] = [(); std::mem::size_of::<UserPositions>()];
+
+impl UserPositions {
+    pub fn has_open_position(&self) -> bool {
+        self.positions
+            .iter()
+            .any(|market_position| market_position.is_open_position())
+    }
+
+    /// Claims an available slot for a new position in market_index and returns its index. The slot is
+    /// reset entirely, so nothing a previous occupant left behind carries over to the new position.
+    pub fn add_new_position(&mut self, market_index: u64) -> ClearingHouseResult<usize> {
+        let new_position_index = self
+            .positions
+            .iter()
+            .position(|market_position| market_position.is_available())
+            .ok_or(ErrorCode::MaxNumberOfPositions)?;
+
+        self.positions[new_position_index] = MarketPosition {
+            market_index,
+            ..MarketPosition::default()
+        };
+
+        Ok(new_position_index)
+    }
+}
+
+/// Borrows a single position straight out of the account data. UserPositions is zero copy, so this
+/// only maps the slot at position_index instead of copying the whole array out.
+pub fn load_position<'a>(
+    user_positions: &'a AccountLoader<UserPositions>,
+    position_index: usize,
+) -> std::result::Result<Ref<'a, MarketPosition>, ProgramError> {
+    let user_positions = user_positions.load()?;
+    if position_index >= user_positions.positions.len() {
+        return Err(ErrorCode::InvalidPositionIndex.into());
+    }
+
+    Ok(Ref::map(user_positions, |user_positions| {
+        &user_positions.positions[position_index]
+    }))
+}
+
+/// Mutable version of load_position, for instructions that only touch one of the user's positions
+pub fn load_position_mut<'a>(
+    user_positions: &'a AccountLoader<UserPositions>,
+    position_index: usize,
+) -> std::result::Result<RefMut<'a, MarketPosition>, ProgramError> {
+    let user_positions = user_positions.load_mut()?;
+    if position_index >= user_positions.positions.len() {
+        return Err(ErrorCode::InvalidPositionIndex.into());
+    }
+
+    Ok(RefMut::map(user_positions, |user_positions| {
+        &mut user_positions.positions[position_index]
+    }))
 }
 
 impl MarketPosition {
+    /// Whether the slot is reserved for the market, even if it's flat. Increases can reuse a flat slot.
     pub fn is_for(&self, market_index: u64) -> bool {
-        self.market_index == market_index && self.is_open_position()
+        self.market_index == market_index
+    }
+
+    /// Whether the slot holds an actual position in the market, for operations like reducing or
+    /// closing that need one
+    pub fn is_active_for(&self, market_index: u64) -> bool {
+        self.is_for(market_index) && self.is_open_position()
     }
 
     pub fn is_open_position(&self) -> bool {
         self.base_asset_amount != 0
     }
+
+    /// Whether the slot is free to hold a position in any market. A flat slot still holding settled
+    /// pnl stays with its market until the pnl is claimed.
+    pub fn is_available(&self) -> bool {
+        !self.is_open_position() && self.settled_pnl == 0
+    }
+
+    pub fn is_long(&self) -> bool {
+        self.base_asset_amount > 0
+    }
+
+    pub fn is_short(&self) -> bool {
+        self.base_asset_amount < 0
+    }
+
+    /// The side of the position, or None for a flat one, so callers have to handle flat explicitly
+    /// instead of letting it fall into a short branch
+    pub fn direction(&self) -> Option<PositionDirection> {
+        if self.is_long() {
+            Some(PositionDirection::Long)
+        } else if self.is_short() {
+            Some(PositionDirection::Short)
+        } else {
+            None
+        }
+    }
+
+    /// The position's notional and unrealized pnl, from pricing it out against the curve once
+    pub fn notional_and_pnl(&self, market: &Market) -> ClearingHouseResult<(u128, i128)> {
+        calculate_base_asset_value_and_pnl(self, &market.amm)
+    }
+
+    /// The position's notional and unrealized pnl for margin, with the curve's value weighted
+    /// towards the oracle by the market's margin_oracle_weight_bps
+    pub fn margin_notional_and_pnl(&self, market: &Market) -> ClearingHouseResult<(u128, i128)> {
+        calculate_oracle_weighted_base_asset_value_and_pnl(
+            self,
+            &market.amm,
+            market.margin_oracle_weight_bps,
+        )
+    }
+
+    /// Funding the position would settle right now, see math::funding::calculate_unrealized_funding
+    pub fn unrealized_funding(&self, market: &Market) -> ClearingHouseResult<i128> {
+        calculate_unrealized_funding(market, self)
+    }
+
+    /// Starts tracking the fees and funding of a newly opened position from zero
+    pub fn reset_trading_costs(&mut self) {
+        self.total_fee_paid = 0;
+        self.total_funding_payment = 0;
+    }
+
+    pub fn add_fee_paid(&mut self, fee: u128) -> ClearingHouseResult {
+        self.total_fee_paid = self
+            .total_fee_paid
+            .checked_add(cast(fee)?)
+            .ok_or_else(math_error!())?;
+        Ok(())
+    }
+
+    pub fn add_funding_payment(&mut self, funding_payment: i128) -> ClearingHouseResult {
+        self.total_funding_payment = self
+            .total_funding_payment
+            .checked_add(cast(funding_payment)?)
+            .ok_or_else(math_error!())?;
+        Ok(())
+    }
+
+    /// Records the cumulative funding rate (and the amm's funding ts it was taken at) that the
+    /// position has paid funding up to. A flat position holds a zeroed snapshot.
+    pub fn apply_funding_snapshot(&mut self, cumulative_funding_rate: i128, funding_rate_ts: i64) {
+        self.last_cumulative_funding_rate = cumulative_funding_rate;
+        self.last_funding_rate_ts = funding_rate_ts;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::amm::{swap_base_asset, swap_quote_asset, SwapDirection};
+    use crate::math::constants::{AMM_RESERVE_PRECISION, PEG_PRECISION, QUOTE_PRECISION};
+    use crate::math::pnl::calculate_pnl;
+    use crate::math::position::swap_direction_to_close_position;
+    use crate::state::market::AMM;
+    use anchor_lang::Discriminator;
+
+    fn offset_of(market_position: &MarketPosition, field: *const u8) -> usize {
+        field as usize - market_position as *const MarketPosition as usize
+    }
+
+    #[test]
+    fn repurposed_padding_keeps_the_position_layout() {
+        // the fields that claimed padding sit exactly where padding0 and padding1 were, after
+        // transfer_to, so positions already on chain keep their size and field offsets
+        let market_position = MarketPosition::default();
+        assert_eq!(std::mem::size_of::<MarketPosition>(), 208);
+        assert_eq!(
+            offset_of(
+                &market_position,
+                std::ptr::addr_of!(market_position.transfer_to) as *const u8
+            ),
+            144
+        );
+        assert_eq!(
+            offset_of(
+                &market_position,
+                std::ptr::addr_of!(market_position.settled_pnl) as *const u8
+            ),
+            176
+        );
+        assert_eq!(
+            offset_of(
+                &market_position,
+                std::ptr::addr_of!(market_position.total_fee_paid) as *const u8
+            ),
+            192
+        );
+        assert_eq!(
+            offset_of(
+                &market_position,
+                std::ptr::addr_of!(market_position.total_funding_payment) as *const u8
+            ),
+            200
+        );
+    }
+
+    #[test]
+    fn flat_slot_is_for_its_market_but_not_active() {
+        let mut market_position = MarketPosition {
+            market_index: 3,
+            ..MarketPosition::default()
+        };
+
+        assert!(market_position.is_for(3));
+        assert!(!market_position.is_active_for(3));
+        assert!(!market_position.is_for(4));
+
+        market_position.base_asset_amount = -1;
+        assert!(market_position.is_active_for(3));
+        assert!(!market_position.is_active_for(4));
+    }
+
+    #[test]
+    fn reused_slot_is_reset_for_the_new_position() {
+        let mut user_positions = UserPositions::default();
+        // a closed position in market 1 that left its stops, transfer and totals behind
+        user_positions.positions[0] = MarketPosition {
+            market_index: 1,
+            quote_asset_amount: 5,
+            last_cumulative_funding_rate: 7,
+            last_cumulative_repeg_rebate: 8,
+            last_funding_rate_ts: 9,
+            stop_loss_price: 10,
+            stop_loss_amount: 11,
+            stop_profit_price: 12,
+            stop_profit_amount: 13,
+            transfer_to: Pubkey::new_unique(),
+            total_fee_paid: 14,
+            total_funding_payment: -15,
+            ..MarketPosition::default()
+        };
+        // an open position in market 2 isn't available
+        user_positions.positions[1] = MarketPosition {
+            market_index: 2,
+            base_asset_amount: 1,
+            ..MarketPosition::default()
+        };
+
+        let position_index = user_positions.add_new_position(3).unwrap();
+        assert_eq!(position_index, 0);
+
+        let market_position = &user_positions.positions[0];
+        assert_eq!({ market_position.market_index }, 3);
+        assert_eq!({ market_position.base_asset_amount }, 0);
+        assert_eq!({ market_position.quote_asset_amount }, 0);
+        assert_eq!({ market_position.last_cumulative_funding_rate }, 0);
+        assert_eq!({ market_position.last_cumulative_repeg_rebate }, 0);
+        assert_eq!({ market_position.last_funding_rate_ts }, 0);
+        assert_eq!({ market_position.stop_loss_price }, 0);
+        assert_eq!({ market_position.stop_loss_amount }, 0);
+        assert_eq!({ market_position.stop_profit_price }, 0);
+        assert_eq!({ market_position.stop_profit_amount }, 0);
+        assert_eq!({ market_position.transfer_to }, Pubkey::default());
+        assert_eq!({ market_position.settled_pnl }, 0);
+        assert_eq!({ market_position.total_fee_paid }, 0);
+        assert_eq!({ market_position.total_funding_payment }, 0);
+
+        assert_eq!({ user_positions.positions[1].market_index }, 2);
+        assert_eq!({ user_positions.positions[1].base_asset_amount }, 1);
+    }
+
+    #[test]
+    fn notional_and_pnl_matches_the_individual_computations() {
+        let reserve = 5 * 10_u128.pow(18);
+        let mut market = Market {
+            initialized: true,
+            amm: AMM {
+                base_asset_reserve: reserve,
+                quote_asset_reserve: reserve,
+                sqrt_k: reserve,
+                peg_multiplier: PEG_PRECISION,
+                ..AMM::default()
+            },
+            ..Market::default()
+        };
+        swap_quote_asset(
+            &mut market.amm,
+            1_000 * QUOTE_PRECISION,
+            SwapDirection::Add,
+            0,
+            None,
+            None,
+        )
+        .unwrap();
+
+        for base_asset_amount in [10_i128, -10] {
+            let market_position = MarketPosition {
+                base_asset_amount: base_asset_amount * AMM_RESERVE_PRECISION as i128,
+                quote_asset_amount: 10 * QUOTE_PRECISION,
+                ..MarketPosition::default()
+            };
+
+            let (notional, unrealized_pnl) = market_position.notional_and_pnl(&market).unwrap();
+
+            // the notional is what closing the position against the curve would swap for, and the
+            // pnl that against the entry
+            let swap_direction =
+                swap_direction_to_close_position(market_position.base_asset_amount);
+            let mut amm = market.amm;
+            let quote_asset_amount_swapped = swap_base_asset(
+                &mut amm,
+                market_position.base_asset_amount.unsigned_abs(),
+                swap_direction,
+                0,
+                None,
+            )
+            .unwrap();
+            assert_eq!(notional, quote_asset_amount_swapped);
+            assert_eq!(
+                unrealized_pnl,
+                calculate_pnl(
+                    quote_asset_amount_swapped,
+                    market_position.quote_asset_amount,
+                    swap_direction
+                )
+                .unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn load_position_mut_writes_through_to_the_full_account() {
+        let mut user_positions = UserPositions::default();
+        user_positions.positions[1] = MarketPosition {
+            market_index: 4,
+            base_asset_amount: 7,
+            ..MarketPosition::default()
+        };
+        let mut data = UserPositions::discriminator().to_vec();
+        data.extend_from_slice(bytemuck::bytes_of(&user_positions));
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let account_info = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &crate::ID,
+            false,
+            0,
+        );
+        let user_positions_loader =
+            AccountLoader::<UserPositions>::try_from(&account_info).unwrap();
+
+        {
+            let mut market_position = load_position_mut(&user_positions_loader, 1).unwrap();
+            market_position.base_asset_amount = -3;
+            market_position.quote_asset_amount = 11;
+        }
+
+        let user_positions = user_positions_loader.load().unwrap();
+        assert_eq!({ user_positions.positions[1].market_index }, 4);
+        assert_eq!({ user_positions.positions[1].base_asset_amount }, -3);
+        assert_eq!({ user_positions.positions[1].quote_asset_amount }, 11);
+        // the other slots are untouched
+        for position_index in [0, 2, 3, 4] {
+            let market_position = user_positions.positions[position_index];
+            assert_eq!({ market_position.market_index }, 0);
+            assert_eq!({ market_position.base_asset_amount }, 0);
+            assert_eq!({ market_position.quote_asset_amount }, 0);
+        }
+        drop(user_positions);
+
+        assert_eq!(
+            {
+                load_position(&user_positions_loader, 1)
+                    .unwrap()
+                    .base_asset_amount
+            },
+            -3
+        );
+        assert_eq!(
+            load_position_mut(&user_positions_loader, 5).err(),
+            Some(ErrorCode::InvalidPositionIndex.into())
+        );
+    }
 }
@@ -0,0 +1,77 @@
+use anchor_lang::prelude::*;
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use crate::controller::position::PositionDirection;
+
+#[account(zero_copy)]
+#[derive(Default)]
+pub struct UserOrders {
+    pub user: Pubkey,
+    pub next_order_id: u64,
+    pub orders: [Order; 32],
+}
+
+#[zero_copy]
+#[derive(Default)]
+pub struct Order {
+    pub status: OrderStatus,
+    pub order_type: OrderType,
+    pub ts: i64,
+    pub order_id: u64,
+    pub market_index: u64,
+    pub price: u128,
+    pub base_asset_amount: u128,
+    pub base_asset_amount_filled: u128,
+    pub quote_asset_amount_filled: u128,
+    pub fee: u128,
+    pub direction: PositionDirection,
+    pub reduce_only: bool,
+    pub trigger_price: u128,
+    pub trigger_condition: OrderTriggerCondition,
+    pub oracle_price_offset: i128,
+    pub min_fill_base_asset_amount: u128, // 0 means any partial fill is allowed
+    pub max_keeper_reward: u128,          // trigger orders only, 0 means no keeper reward
+
+    // upgrade-ability
+    pub padding2: u128,
+}
+
+impl Order {
+    pub fn is_open(&self) -> bool {
+        self.status == OrderStatus::Open
+    }
+
+    pub fn get_base_asset_amount_unfilled(&self) -> u128 {
+        self.base_asset_amount
+            .saturating_sub(self.base_asset_amount_filled)
+    }
+}
+
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Default)]
+pub enum OrderStatus {
+    #[default]
+    Init,
+    Open,
+}
+
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Default)]
+pub enum OrderType {
+    /// Trades immediately through open_position, so it can't be placed as an order
+    #[default]
+    Market,
+    /// Rests until the mark crosses price
+    Limit,
+    /// Fills like a market order once the oracle crosses trigger_price
+    TriggerMarket,
+    /// Rests like a limit order once the oracle crosses trigger_price
+    TriggerLimit,
+    /// Rests like a limit order priced at the oracle plus oracle_price_offset
+    Oracle,
+}
+
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Default)]
+pub enum OrderTriggerCondition {
+    #[default]
+    Above,
+    Below,
+}
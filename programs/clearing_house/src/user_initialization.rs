@@ -36,10 +36,13 @@ pub fn initialize(
     user.cumulative_deposits = 0;
     user.positions = *user_positions.to_account_info().key;
 
-    user.padding0 = 0;
-    user.padding1 = 0;
-    user.padding2 = 0;
-    user.padding3 = 0;
+    user.high_water_mark = 0;
+    user.pnl_velocity = 0;
+    user.pnl_velocity_ts = 0;
+    user.total_realized_pnl = 0;
+    user.auto_deleverage = false;
+    user.secondary_collateral = 0;
+    user.padding3 = [0; 7];
 
     let user_positions = &mut user_positions.load_init()?;
     user_positions.user = *user.to_account_info().key;
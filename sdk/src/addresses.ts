@@ -32,3 +32,25 @@ export async function getUserAccountPublicKey(
 ): Promise<PublicKey> {
 	return (await getUserAccountPublicKeyAndNonce(programId, authority))[0];
 }
+
+export async function getUserOrdersAccountPublicKeyAndNonce(
+	programId: PublicKey,
+	userAccount: PublicKey
+): Promise<[PublicKey, number]> {
+	return anchor.web3.PublicKey.findProgramAddress(
+		[
+			Buffer.from(anchor.utils.bytes.utf8.encode('user_orders')),
+			userAccount.toBuffer(),
+		],
+		programId
+	);
+}
+
+export async function getUserOrdersAccountPublicKey(
+	programId: PublicKey,
+	userAccount: PublicKey
+): Promise<PublicKey> {
+	return (
+		await getUserOrdersAccountPublicKeyAndNonce(programId, userAccount)
+	)[0];
+}
@@ -5,7 +5,13 @@ import {
 	SYSVAR_RENT_PUBKEY,
 	TransactionSignature,
 } from '@solana/web3.js';
-import { FeeStructure, IWallet, OracleGuardRails, OracleSource } from './types';
+import {
+	FeeDenomination,
+	FeeStructure,
+	IWallet,
+	OracleGuardRails,
+	OracleSource,
+} from './types';
 import { BN, Provider } from '@project-serum/anchor';
 import * as anchor from '@project-serum/anchor';
 import { getClearingHouseStateAccountPublicKeyAndNonce } from './addresses';
@@ -255,6 +261,24 @@ export class Admin extends ClearingHouse {
 		});
 	}
 
+	public async migrateMarkets(): Promise<TransactionSignature> {
+		const markets = anchor.web3.Keypair.generate();
+
+		const state = this.getStateAccount();
+		return await this.program.rpc.migrateMarkets({
+			accounts: {
+				state: await this.getStatePublicKey(),
+				admin: this.wallet.publicKey,
+				markets: markets.publicKey,
+				legacyMarkets: state.markets,
+			},
+			instructions: [
+				await this.program.account.markets.createInstruction(markets),
+			],
+			signers: [markets],
+		});
+	}
+
 	public async moveAmmToPrice(
 		marketIndex: BN,
 		targetPrice: BN
@@ -480,6 +504,22 @@ export class Admin extends ClearingHouse {
 		);
 	}
 
+	public async updateLiquidationOracleBand(
+		numerator: BN,
+		denominator: BN
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updateLiquidationOracleBand(
+			numerator,
+			denominator,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+				},
+			}
+		);
+	}
+
 	public async updatePartialLiquidationShareDenominator(
 		denominator: BN
 	): Promise<TransactionSignature> {
@@ -566,6 +606,156 @@ export class Admin extends ClearingHouse {
 		);
 	}
 
+	public async updateMarketSettlePnlSeparately(
+		marketIndex: BN,
+		settlePnlSeparately: boolean
+	): Promise<TransactionSignature> {
+		const state = this.getStateAccount();
+		return await this.program.rpc.updateMarketSettlePnlSeparately(
+			marketIndex,
+			settlePnlSeparately,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					markets: state.markets,
+				},
+			}
+		);
+	}
+
+	public async updateMarketFeeDenomination(
+		marketIndex: BN,
+		feeDenomination: FeeDenomination
+	): Promise<TransactionSignature> {
+		const state = this.getStateAccount();
+		return await this.program.rpc.updateMarketFeeDenomination(
+			marketIndex,
+			feeDenomination,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					markets: state.markets,
+				},
+			}
+		);
+	}
+
+	public async updateMarketAssetGroup(
+		marketIndex: BN,
+		assetGroup: BN,
+		correlationBps: BN
+	): Promise<TransactionSignature> {
+		const state = this.getStateAccount();
+		return await this.program.rpc.updateMarketAssetGroup(
+			marketIndex,
+			assetGroup,
+			correlationBps,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					markets: state.markets,
+				},
+			}
+		);
+	}
+
+	public async updateMarketMarginOracleWeight(
+		marketIndex: BN,
+		marginOracleWeightBps: BN
+	): Promise<TransactionSignature> {
+		const state = this.getStateAccount();
+		return await this.program.rpc.updateMarketMarginOracleWeight(
+			marketIndex,
+			marginOracleWeightBps,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					markets: state.markets,
+				},
+			}
+		);
+	}
+
+	public async updateMarketMarginRatioInitial(
+		marketIndex: BN,
+		marginRatioInitial: BN
+	): Promise<TransactionSignature> {
+		const state = this.getStateAccount();
+		return await this.program.rpc.updateMarketMarginRatioInitial(
+			marketIndex,
+			marginRatioInitial,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					markets: state.markets,
+				},
+			}
+		);
+	}
+
+	public async updateMarketExpiry(
+		marketIndex: BN,
+		expiryTs: BN
+	): Promise<TransactionSignature> {
+		const state = this.getStateAccount();
+		return await this.program.rpc.updateMarketExpiry(marketIndex, expiryTs, {
+			accounts: {
+				admin: this.wallet.publicKey,
+				state: await this.getStatePublicKey(),
+				markets: state.markets,
+			},
+		});
+	}
+
+	public async updateMarketSettlementPrice(
+		marketIndex: BN,
+		settlementPrice: BN
+	): Promise<TransactionSignature> {
+		const state = this.getStateAccount();
+		return await this.program.rpc.updateMarketSettlementPrice(
+			marketIndex,
+			settlementPrice,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					markets: state.markets,
+				},
+			}
+		);
+	}
+
+	public async forceClosePosition(
+		userAccountPublicKey: PublicKey,
+		marketIndex: BN,
+		settlementPrice: BN
+	): Promise<TransactionSignature> {
+		const userAccount: any = await this.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		const state = this.getStateAccount();
+		return await this.program.rpc.forceClosePosition(
+			marketIndex,
+			settlementPrice,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					user: userAccountPublicKey,
+					markets: state.markets,
+					userPositions: userAccount.positions,
+					tradeHistory: state.tradeHistory,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+				},
+			}
+		);
+	}
+
 	public async updateWhitelistMint(
 		whitelistMint?: PublicKey
 	): Promise<TransactionSignature> {
@@ -577,6 +767,17 @@ export class Admin extends ClearingHouse {
 		});
 	}
 
+	public async updateKeeperMint(
+		keeperMint: PublicKey
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updateKeeperMint(keeperMint, {
+			accounts: {
+				admin: this.wallet.publicKey,
+				state: await this.getStatePublicKey(),
+			},
+		});
+	}
+
 	public async updateDiscountMint(
 		discountMint: PublicKey
 	): Promise<TransactionSignature> {
@@ -597,6 +798,20 @@ export class Admin extends ClearingHouse {
 		});
 	}
 
+	public async updateDustCollateralThreshold(
+		dustCollateralThreshold: BN
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updateDustCollateralThreshold(
+			dustCollateralThreshold,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+				},
+			}
+		);
+	}
+
 	public async updateFundingPaused(
 		fundingPaused: boolean
 	): Promise<TransactionSignature> {
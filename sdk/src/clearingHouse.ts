@@ -12,6 +12,7 @@ import {
 	FundingRateHistoryAccount,
 	IWallet,
 	LiquidationHistoryAccount,
+	OrderParams,
 	PositionDirection,
 	TradeHistoryAccount,
 	UserAccount,
@@ -38,6 +39,8 @@ import {
 	getClearingHouseStateAccountPublicKey,
 	getUserAccountPublicKey,
 	getUserAccountPublicKeyAndNonce,
+	getUserOrdersAccountPublicKey,
+	getUserOrdersAccountPublicKeyAndNonce,
 } from './addresses';
 import {
 	ClearingHouseAccountSubscriber,
@@ -498,7 +501,8 @@ export class ClearingHouse {
 		marketIndex: BN,
 		limitPrice?: BN,
 		discountToken?: PublicKey,
-		referrer?: PublicKey
+		referrer?: PublicKey,
+		minBaseAssetAmount?: BN
 	): Promise<TransactionSignature> {
 		return await this.txSender.send(
 			wrapInTx(
@@ -508,7 +512,8 @@ export class ClearingHouse {
 					marketIndex,
 					limitPrice,
 					discountToken,
-					referrer
+					referrer,
+					minBaseAssetAmount
 				)
 			),
 			[],
@@ -522,7 +527,8 @@ export class ClearingHouse {
 		marketIndex: BN,
 		limitPrice?: BN,
 		discountToken?: PublicKey,
-		referrer?: PublicKey
+		referrer?: PublicKey,
+		minBaseAssetAmount?: BN
 	): Promise<TransactionInstruction> {
 		const userAccountPublicKey = await this.getUserAccountPublicKey();
 		const userAccount = await this.getUserAccount();
@@ -530,6 +536,9 @@ export class ClearingHouse {
 		if (limitPrice == undefined) {
 			limitPrice = new BN(0); // no limit
 		}
+		if (minBaseAssetAmount == undefined) {
+			minBaseAssetAmount = new BN(0); // no bound
+		}
 
 		const optionalAccounts = {
 			discountToken: false,
@@ -563,6 +572,7 @@ export class ClearingHouse {
 			marketIndex,
 			limitPrice,
 			optionalAccounts,
+			minBaseAssetAmount,
 			{
 				accounts: {
 					state: await this.getStatePublicKey(),
@@ -580,21 +590,156 @@ export class ClearingHouse {
 		);
 	}
 
+	/**
+	 * Deposits collateral and opens a position in one instruction. A failed open reverts the
+	 * deposit along with it.
+	 * @param depositAmount
+	 * @param collateralAccountPublicKey
+	 * @param direction
+	 * @param amount
+	 * @param marketIndex
+	 * @param limitPrice
+	 * @param discountToken
+	 * @param referrer
+	 * @param minBaseAssetAmount
+	 * @returns
+	 */
+	public async openPositionWithCollateral(
+		depositAmount: BN,
+		collateralAccountPublicKey: PublicKey,
+		direction: PositionDirection,
+		amount: BN,
+		marketIndex: BN,
+		limitPrice?: BN,
+		discountToken?: PublicKey,
+		referrer?: PublicKey,
+		minBaseAssetAmount?: BN
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(
+				await this.getOpenPositionWithCollateralIx(
+					depositAmount,
+					collateralAccountPublicKey,
+					direction,
+					amount,
+					marketIndex,
+					limitPrice,
+					discountToken,
+					referrer,
+					minBaseAssetAmount
+				)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getOpenPositionWithCollateralIx(
+		depositAmount: BN,
+		collateralAccountPublicKey: PublicKey,
+		direction: PositionDirection,
+		amount: BN,
+		marketIndex: BN,
+		limitPrice?: BN,
+		discountToken?: PublicKey,
+		referrer?: PublicKey,
+		minBaseAssetAmount?: BN
+	): Promise<TransactionInstruction> {
+		const userAccountPublicKey = await this.getUserAccountPublicKey();
+		const userAccount = await this.getUserAccount();
+
+		if (limitPrice == undefined) {
+			limitPrice = new BN(0); // no limit
+		}
+		if (minBaseAssetAmount == undefined) {
+			minBaseAssetAmount = new BN(0); // no bound
+		}
+
+		const optionalAccounts = {
+			discountToken: false,
+			referrer: false,
+		};
+		const remainingAccounts = [];
+		if (discountToken) {
+			optionalAccounts.discountToken = true;
+			remainingAccounts.push({
+				pubkey: discountToken,
+				isWritable: false,
+				isSigner: false,
+			});
+		}
+		if (referrer) {
+			optionalAccounts.referrer = true;
+			remainingAccounts.push({
+				pubkey: referrer,
+				isWritable: true,
+				isSigner: false,
+			});
+		}
+
+		const priceOracle =
+			this.getMarketsAccount().markets[marketIndex.toNumber()].amm.oracle;
+
+		const state = this.getStateAccount();
+		return await this.program.instruction.openPositionWithCollateral(
+			depositAmount,
+			direction,
+			amount,
+			marketIndex,
+			limitPrice,
+			optionalAccounts,
+			minBaseAssetAmount,
+			{
+				accounts: {
+					openPosition: {
+						state: await this.getStatePublicKey(),
+						user: userAccountPublicKey,
+						authority: this.wallet.publicKey,
+						markets: state.markets,
+						userPositions: userAccount.positions,
+						tradeHistory: state.tradeHistory,
+						fundingPaymentHistory: state.fundingPaymentHistory,
+						fundingRateHistory: state.fundingRateHistory,
+						oracle: priceOracle,
+					},
+					collateralVault: state.collateralVault,
+					userCollateralAccount: collateralAccountPublicKey,
+					tokenProgram: TOKEN_PROGRAM_ID,
+					depositHistory: state.depositHistory,
+				},
+				remainingAccounts: remainingAccounts,
+			}
+		);
+	}
+
 	/**
 	 * Close an entire position. If you want to reduce a position, use the {@link openPosition} method in the opposite direction of the current position.
 	 * @param marketIndex
 	 * @param discountToken
 	 * @param referrer
+	 * @param minQuoteAssetAmountOut revert if the close swaps for less than this, 0 for no bound
+	 * @param maxQuoteAssetAmountOut revert if the close swaps for more than this, 0 for no bound
+	 * @param maxTs revert if the close lands after this unix timestamp, 0 for no bound
 	 * @returns
 	 */
 	public async closePosition(
 		marketIndex: BN,
 		discountToken?: PublicKey,
-		referrer?: PublicKey
+		referrer?: PublicKey,
+		minQuoteAssetAmountOut?: BN,
+		maxQuoteAssetAmountOut?: BN,
+		maxTs?: BN
 	): Promise<TransactionSignature> {
 		return await this.txSender.send(
 			wrapInTx(
-				await this.getClosePositionIx(marketIndex, discountToken, referrer)
+				await this.getClosePositionIx(
+					marketIndex,
+					discountToken,
+					referrer,
+					minQuoteAssetAmountOut,
+					maxQuoteAssetAmountOut,
+					maxTs
+				)
 			),
 			[],
 			this.opts
@@ -604,8 +749,21 @@ export class ClearingHouse {
 	public async getClosePositionIx(
 		marketIndex: BN,
 		discountToken?: PublicKey,
-		referrer?: PublicKey
+		referrer?: PublicKey,
+		minQuoteAssetAmountOut?: BN,
+		maxQuoteAssetAmountOut?: BN,
+		maxTs?: BN
 	): Promise<TransactionInstruction> {
+		if (minQuoteAssetAmountOut == undefined) {
+			minQuoteAssetAmountOut = new BN(0); // no bound
+		}
+		if (maxQuoteAssetAmountOut == undefined) {
+			maxQuoteAssetAmountOut = new BN(0); // no bound
+		}
+		if (maxTs == undefined) {
+			maxTs = new BN(0); // no bound
+		}
+
 		const userAccountPublicKey = await this.getUserAccountPublicKey();
 		const userAccount = await this.getUserAccount();
 
@@ -638,6 +796,9 @@ export class ClearingHouse {
 		return await this.program.instruction.closePosition(
 			marketIndex,
 			optionalAccounts,
+			minQuoteAssetAmountOut,
+			maxQuoteAssetAmountOut,
+			maxTs,
 			{
 				accounts: {
 					state: await this.getStatePublicKey(),
@@ -713,6 +874,78 @@ export class ClearingHouse {
 		});
 	}
 
+	public async updateUserAutoDeleverage(
+		autoDeleverage: boolean
+	): Promise<TransactionSignature> {
+		return this.txSender.send(
+			wrapInTx(await this.getUpdateUserAutoDeleverageIx(autoDeleverage)),
+			[],
+			this.opts
+		);
+	}
+
+	public async getUpdateUserAutoDeleverageIx(
+		autoDeleverage: boolean
+	): Promise<TransactionInstruction> {
+		const userAccountPublicKey = await this.getUserAccountPublicKey();
+		return await this.program.instruction.updateUserAutoDeleverage(
+			autoDeleverage,
+			{
+				accounts: {
+					user: userAccountPublicKey,
+					authority: this.wallet.publicKey,
+				},
+			}
+		);
+	}
+
+	public async autoDeleverage(
+		userAccountPublicKey: PublicKey
+	): Promise<TransactionSignature> {
+		return this.txSender.send(
+			wrapInTx(await this.getAutoDeleverageIx(userAccountPublicKey)),
+			[],
+			this.opts
+		);
+	}
+
+	public async getAutoDeleverageIx(
+		userAccountPublicKey: PublicKey
+	): Promise<TransactionInstruction> {
+		const userAccount: any = await this.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		const userPositions: any = await this.program.account.userPositions.fetch(
+			userAccount.positions
+		);
+		const markets = this.getMarketsAccount();
+
+		const remainingAccounts = [];
+		for (const position of userPositions.positions) {
+			if (!position.baseAssetAmount.eq(new BN(0))) {
+				const market = markets.markets[position.marketIndex.toNumber()];
+				remainingAccounts.push({
+					pubkey: market.amm.oracle,
+					isWritable: false,
+					isSigner: false,
+				});
+			}
+		}
+
+		const state = this.getStateAccount();
+		return await this.program.instruction.autoDeleverage({
+			accounts: {
+				state: await this.getStatePublicKey(),
+				user: userAccountPublicKey,
+				markets: state.markets,
+				userPositions: userAccount.positions,
+				tradeHistory: state.tradeHistory,
+				fundingPaymentHistory: state.fundingPaymentHistory,
+			},
+			remainingAccounts: remainingAccounts,
+		});
+	}
+
 	public async updateFundingRate(
 		oracle: PublicKey,
 		marketIndex: BN
@@ -739,6 +972,203 @@ export class ClearingHouse {
 		});
 	}
 
+	public async updateTwaps(
+		oracles: PublicKey[],
+		marketIndexes: BN[]
+	): Promise<TransactionSignature> {
+		return this.txSender.send(
+			wrapInTx(await this.getUpdateTwapsIx(oracles, marketIndexes)),
+			[],
+			this.opts
+		);
+	}
+
+	public async getUpdateTwapsIx(
+		oracles: PublicKey[],
+		marketIndexes: BN[]
+	): Promise<TransactionInstruction> {
+		const state = this.getStateAccount();
+		return await this.program.instruction.updateTwaps(marketIndexes, {
+			accounts: {
+				state: await this.getStatePublicKey(),
+				markets: state.markets,
+			},
+			remainingAccounts: oracles.map((oracle) => {
+				return {
+					pubkey: oracle,
+					isWritable: false,
+					isSigner: false,
+				};
+			}),
+		});
+	}
+
+	public async claimSettledPnl(
+		marketIndex: BN
+	): Promise<TransactionSignature> {
+		return this.txSender.send(
+			wrapInTx(await this.getClaimSettledPnlIx(marketIndex)),
+			[],
+			this.opts
+		);
+	}
+
+	public async getClaimSettledPnlIx(
+		marketIndex: BN
+	): Promise<TransactionInstruction> {
+		const userAccountPublicKey = await this.getUserAccountPublicKey();
+		const userAccount = await this.getUserAccount();
+		const state = this.getStateAccount();
+		return await this.program.instruction.claimSettledPnl(marketIndex, {
+			accounts: {
+				state: await this.getStatePublicKey(),
+				user: userAccountPublicKey,
+				authority: this.wallet.publicKey,
+				markets: state.markets,
+				userPositions: userAccount.positions,
+			},
+		});
+	}
+
+	public async initializeUserOrders(): Promise<TransactionSignature> {
+		return this.txSender.send(
+			wrapInTx(await this.getInitializeUserOrdersIx()),
+			[],
+			this.opts
+		);
+	}
+
+	public async getInitializeUserOrdersIx(): Promise<TransactionInstruction> {
+		const userAccountPublicKey = await this.getUserAccountPublicKey();
+		const [userOrdersPublicKey, userOrdersNonce] =
+			await getUserOrdersAccountPublicKeyAndNonce(
+				this.program.programId,
+				userAccountPublicKey
+			);
+		return await this.program.instruction.initializeUserOrders(
+			userOrdersNonce,
+			{
+				accounts: {
+					user: userAccountPublicKey,
+					userOrders: userOrdersPublicKey,
+					authority: this.wallet.publicKey,
+					rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+					systemProgram: anchor.web3.SystemProgram.programId,
+				},
+			}
+		);
+	}
+
+	public async placeOrder(
+		orderParams: OrderParams
+	): Promise<TransactionSignature> {
+		return this.txSender.send(
+			wrapInTx(await this.getPlaceOrderIx(orderParams)),
+			[],
+			this.opts
+		);
+	}
+
+	public async getPlaceOrderIx(
+		orderParams: OrderParams
+	): Promise<TransactionInstruction> {
+		const userAccountPublicKey = await this.getUserAccountPublicKey();
+		const state = this.getStateAccount();
+		return await this.program.instruction.placeOrder(orderParams, {
+			accounts: {
+				state: await this.getStatePublicKey(),
+				user: userAccountPublicKey,
+				authority: this.wallet.publicKey,
+				markets: state.markets,
+				userOrders: await getUserOrdersAccountPublicKey(
+					this.program.programId,
+					userAccountPublicKey
+				),
+			},
+		});
+	}
+
+	public async fillOrder(
+		userAccountPublicKey: PublicKey,
+		orderId: BN
+	): Promise<TransactionSignature> {
+		return this.txSender.send(
+			wrapInTx(await this.getFillOrderIx(userAccountPublicKey, orderId)),
+			[],
+			this.opts
+		);
+	}
+
+	public async getFillOrderIx(
+		userAccountPublicKey: PublicKey,
+		orderId: BN
+	): Promise<TransactionInstruction> {
+		const fillerPublicKey = await this.getUserAccountPublicKey();
+		const userAccount: any = await this.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		const userOrdersPublicKey = await getUserOrdersAccountPublicKey(
+			this.program.programId,
+			userAccountPublicKey
+		);
+		const userOrders: any = await this.program.account.userOrders.fetch(
+			userOrdersPublicKey
+		);
+		const order = userOrders.orders.find((order) => order.orderId.eq(orderId));
+		const market =
+			this.getMarketsAccount().markets[order.marketIndex.toNumber()];
+
+		const state = this.getStateAccount();
+		return await this.program.instruction.fillOrder(orderId, {
+			accounts: {
+				state: await this.getStatePublicKey(),
+				authority: this.wallet.publicKey,
+				filler: fillerPublicKey,
+				user: userAccountPublicKey,
+				markets: state.markets,
+				userPositions: userAccount.positions,
+				userOrders: userOrdersPublicKey,
+				tradeHistory: state.tradeHistory,
+				fundingPaymentHistory: state.fundingPaymentHistory,
+				fundingRateHistory: state.fundingRateHistory,
+				oracle: market.amm.oracle,
+			},
+		});
+	}
+
+	public async settleExpiredPosition(
+		userAccountPublicKey: PublicKey,
+		marketIndex: BN
+	): Promise<TransactionSignature> {
+		return this.txSender.send(
+			wrapInTx(
+				await this.getSettleExpiredPositionIx(userAccountPublicKey, marketIndex)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getSettleExpiredPositionIx(
+		userAccountPublicKey: PublicKey,
+		marketIndex: BN
+	): Promise<TransactionInstruction> {
+		const userAccount: any = await this.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		const state = this.getStateAccount();
+		return await this.program.instruction.settleExpiredPosition(marketIndex, {
+			accounts: {
+				state: await this.getStatePublicKey(),
+				user: userAccountPublicKey,
+				markets: state.markets,
+				userPositions: userAccount.positions,
+				tradeHistory: state.tradeHistory,
+				fundingPaymentHistory: state.fundingPaymentHistory,
+			},
+		});
+	}
+
 	public async settleFundingPayment(
 		userAccount: PublicKey,
 		userPositionsAccount: PublicKey
@@ -76,7 +76,11 @@ export function calculatePositionPNL(
 	if (marketPosition.baseAssetAmount.gt(ZERO)) {
 		pnl = baseAssetValue.sub(marketPosition.quoteAssetAmount);
 	} else {
-		pnl = marketPosition.quoteAssetAmount.sub(baseAssetValue).sub(ONE);
+		pnl = marketPosition.quoteAssetAmount.sub(baseAssetValue);
+		// base asset value rounds down, so a short gives up one unit of any profit
+		if (pnl.gt(ZERO)) {
+			pnl = pnl.sub(ONE);
+		}
 	}
 
 	if (withFunding) {
@@ -11,6 +11,24 @@ export class PositionDirection {
 	static readonly SHORT = { short: {} };
 }
 
+export class OrderType {
+	static readonly MARKET = { market: {} };
+	static readonly LIMIT = { limit: {} };
+	static readonly TRIGGER_MARKET = { triggerMarket: {} };
+	static readonly TRIGGER_LIMIT = { triggerLimit: {} };
+	static readonly ORACLE = { oracle: {} };
+}
+
+export class OrderTriggerCondition {
+	static readonly ABOVE = { above: {} };
+	static readonly BELOW = { below: {} };
+}
+
+export class FeeDenomination {
+	static readonly QUOTE = { quote: {} };
+	static readonly BASE = { base: {} };
+}
+
 export class OracleSource {
 	static readonly PYTH = { pyth: {} };
 	static readonly SWITCHBOARD = { switchboard: {} };
@@ -270,6 +288,20 @@ export interface IWallet {
 	publicKey: PublicKey;
 }
 
+export type OrderParams = {
+	orderType: OrderType;
+	direction: PositionDirection;
+	baseAssetAmount: BN;
+	price: BN;
+	marketIndex: BN;
+	reduceOnly: boolean;
+	triggerPrice: BN;
+	triggerCondition: OrderTriggerCondition;
+	oraclePriceOffset: BN;
+	minFillBaseAssetAmount: BN;
+	maxKeeperReward: BN;
+};
+
 export type FeeStructure = {
 	feeNumerator: BN;
 	feeDenominator: BN;
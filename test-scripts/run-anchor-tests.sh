@@ -4,7 +4,7 @@ if [ "$1" != "--skip-build" ]
     cp target/idl/clearing_house.json sdk/src/idl/
 fi
 
-test_files=(clearingHouse.ts pyth.ts userAccount.ts admin.ts updateK.ts adminWithdraw.ts curve.ts whitelist.ts fees.ts idempotentCurve.ts maxDeposit.ts deleteUser.ts maxPositions.ts maxReserves.ts roundInFavor.ts minimumTradeSize.ts cappedSymFunding.ts)
+test_files=(clearingHouse.ts pyth.ts userAccount.ts admin.ts updateK.ts adminWithdraw.ts curve.ts whitelist.ts fees.ts idempotentCurve.ts maxDeposit.ts deleteUser.ts maxPositions.ts maxReserves.ts roundInFavor.ts minimumTradeSize.ts cappedSymFunding.ts dustCollateral.ts settledPnl.ts updateTwaps.ts pnlBreakdown.ts zeroMarginTrade.ts expiredMarket.ts minBaseAssetAmount.ts closeMaxTs.ts autoDeleverage.ts shortReduce.ts marketMarginRatio.ts marginOracleWeight.ts baseFeeDenomination.ts marginBounds.ts order.ts fundingSettlement.ts forceClosePosition.ts keeperMint.ts openPositionWithCollateral.ts)
 
 for test_file in ${test_files[@]}; do
   export ANCHOR_TEST_FILE=${test_file} && anchor test --skip-build || exit 1;
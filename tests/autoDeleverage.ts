@@ -0,0 +1,135 @@
+import * as anchor from '@project-serum/anchor';
+import { Program } from '@project-serum/anchor';
+import { BN } from '../sdk';
+import { assert } from 'chai';
+import {
+	Admin,
+	ClearingHouseUser,
+	MARK_PRICE_PRECISION,
+	PositionDirection,
+	QUOTE_PRECISION,
+} from '../sdk/src';
+import { Markets } from '../sdk/src/constants/markets';
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+
+describe('auto deleverage', () => {
+	const provider = anchor.Provider.local();
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+	let userAccount: ClearingHouseUser;
+
+	let usdcMint;
+	let userUSDCAccount;
+	let userAccountPublicKey;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const marketIndex = new BN(0);
+
+	const marginRatioInitial = new BN(4000);
+	const marginRatioPartial = new BN(3000);
+	const marginRatioMaintenance = new BN(2500);
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			Markets[0].marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		[, userAccountPublicKey] =
+			await clearingHouse.initializeUserAccountAndDepositCollateral(
+				usdcAmount,
+				userUSDCAccount.publicKey
+			);
+
+		userAccount = ClearingHouseUser.from(
+			clearingHouse,
+			provider.wallet.publicKey
+		);
+		await userAccount.subscribe();
+
+		// 4.5x is fine under the default margin ratios
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			QUOTE_PRECISION.mul(new BN(45)),
+			marketIndex
+		);
+
+		// but can be liquidated once the requirements are raised
+		await clearingHouse.updateMarginRatio(
+			marginRatioInitial,
+			marginRatioPartial,
+			marginRatioMaintenance
+		);
+	});
+
+	after(async () => {
+		await userAccount.unsubscribe();
+		await clearingHouse.unsubscribe();
+	});
+
+	it('fails for a user that has not opted in', async () => {
+		try {
+			await clearingHouse.autoDeleverage(userAccountPublicKey);
+			assert(false, 'Auto deleverage succeeded');
+		} catch (e) {
+			if (e.message == 'Auto deleverage succeeded') {
+				assert(false, e.message);
+			}
+			assert(e.msg, 'User has not opted in to auto deleveraging');
+		}
+	});
+
+	it('reduces the position back to health without a liquidator', async () => {
+		await clearingHouse.updateUserAutoDeleverage(true);
+
+		await clearingHouse.fetchAccounts();
+		await userAccount.fetchAccounts();
+		const positionBefore = userAccount.getUserPosition(marketIndex);
+		const collateralBefore = userAccount.getUserAccount().collateral;
+		assert(userAccount.getMarginRatio().lte(marginRatioPartial));
+
+		await clearingHouse.autoDeleverage(userAccountPublicKey);
+
+		await clearingHouse.fetchAccounts();
+		await userAccount.fetchAccounts();
+		const position = userAccount.getUserPosition(marketIndex);
+		assert(position.baseAssetAmount.gt(new BN(0)));
+		assert(position.baseAssetAmount.lt(positionBefore.baseAssetAmount));
+		assert(userAccount.getMarginRatio().gt(marginRatioPartial));
+
+		// no liquidation fee, only the pnl realized on the reduced part
+		const collateral = userAccount.getUserAccount().collateral;
+		assert(
+			collateral.gte(collateralBefore.sub(QUOTE_PRECISION.div(new BN(100))))
+		);
+	});
+});
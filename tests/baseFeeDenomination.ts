@@ -0,0 +1,199 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+import { BN } from '../sdk';
+
+import { Program } from '@project-serum/anchor';
+
+import { PublicKey } from '@solana/web3.js';
+
+import {
+	Admin,
+	AMM_RESERVE_PRECISION,
+	FeeDenomination,
+	getUserOrdersAccountPublicKey,
+	MARK_PRICE_PRECISION,
+	OrderTriggerCondition,
+	OrderType,
+	PositionDirection,
+} from '../sdk/src';
+
+import { Markets } from '../sdk/src/constants/markets';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+
+describe('base fee denomination', () => {
+	const provider = anchor.Provider.local();
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let userAccountPublicKey: PublicKey;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const quoteMarketIndex = new BN(0);
+	const baseMarketIndex = new BN(1);
+
+	const fetchUserAndPositions = async () => {
+		const user: any = await clearingHouse.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		const userPositions: any =
+			await clearingHouse.program.account.userPositions.fetch(user.positions);
+		return [user, userPositions];
+	};
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount.mul(new BN(3)),
+			provider
+		);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		// identical curves, so the same trade acquires the same base in each
+		await clearingHouse.initializeMarket(
+			Markets[0].marketIndex,
+			await mockOracle(1),
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+		await clearingHouse.initializeMarket(
+			Markets[1].marketIndex,
+			await mockOracle(1),
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await clearingHouse.updateMarketFeeDenomination(
+			baseMarketIndex,
+			FeeDenomination.BASE
+		);
+
+		[, userAccountPublicKey] =
+			await clearingHouse.initializeUserAccountAndDepositCollateral(
+				usdcAmount.mul(new BN(3)),
+				userUSDCAccount.publicKey
+			);
+		await clearingHouse.initializeUserOrders();
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('Skims the base fee from the position instead of collateral', async () => {
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			usdcAmount,
+			quoteMarketIndex
+		);
+		const [userAfterQuote, userPositionsAfterQuote] =
+			await fetchUserAndPositions();
+		const quoteFee = userAfterQuote.totalFeePaid;
+		const baseAssetAmountWithQuoteFee =
+			userPositionsAfterQuote.positions[0].baseAssetAmount;
+
+		// the quote fee comes out of collateral and the position gets all the base
+		assert(quoteFee.eq(new BN(10000)));
+		assert(
+			userAfterQuote.collateral.eq(usdcAmount.mul(new BN(3)).sub(quoteFee))
+		);
+
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			usdcAmount,
+			baseMarketIndex
+		);
+		const [user, userPositions] = await fetchUserAndPositions();
+		const baseAssetAmountWithBaseFee =
+			userPositions.positions[1].baseAssetAmount;
+		const baseFeeValue = user.totalFeePaid.sub(quoteFee);
+
+		// the same trade is credited 0.1% less base, and collateral is untouched
+		const baseFee = baseAssetAmountWithQuoteFee.div(new BN(1000));
+		assert(
+			baseAssetAmountWithQuoteFee.sub(baseAssetAmountWithBaseFee).eq(baseFee)
+		);
+		assert(user.collateral.eq(userAfterQuote.collateral));
+
+		// the base fee is booked at its value at the fill, which can round down
+		assert(baseFeeValue.gte(new BN(9999)));
+		assert(baseFeeValue.lte(new BN(10000)));
+		const market = clearingHouse.getMarketsAccount().markets[1];
+		assert(market.amm.totalFee.eq(baseFeeValue));
+		assert(market.amm.totalFeeMinusDistributions.eq(baseFeeValue));
+
+		// the amm gave up the whole swap, the fee stays out of the position
+		assert(market.baseAssetAmount.eq(baseAssetAmountWithBaseFee));
+		assert(
+			market.amm.baseAssetReserve
+				.add(market.baseAssetAmount)
+				.add(baseFee)
+				.eq(ammInitialBaseAssetReserve)
+		);
+	});
+
+	it('Skims the base fee from an order fill', async () => {
+		const [userBefore, userPositionsBefore] = await fetchUserAndPositions();
+		const positionBefore = userPositionsBefore.positions[1];
+
+		const userOrders: any = await chProgram.account.userOrders.fetch(
+			await getUserOrdersAccountPublicKey(
+				chProgram.programId,
+				userAccountPublicKey
+			)
+		);
+		const orderId = userOrders.nextOrderId;
+		await clearingHouse.placeOrder({
+			orderType: OrderType.LIMIT,
+			direction: PositionDirection.LONG,
+			baseAssetAmount: AMM_RESERVE_PRECISION,
+			price: MARK_PRICE_PRECISION.mul(new BN(2)),
+			marketIndex: baseMarketIndex,
+			reduceOnly: false,
+			triggerPrice: new BN(0),
+			triggerCondition: OrderTriggerCondition.ABOVE,
+			oraclePriceOffset: new BN(0),
+			minFillBaseAssetAmount: new BN(0),
+			maxKeeperReward: new BN(0),
+		});
+		await clearingHouse.fillOrder(userAccountPublicKey, orderId);
+
+		const [user, userPositions] = await fetchUserAndPositions();
+		const position = userPositions.positions[1];
+		// the fill is credited 0.1% less base, and collateral is untouched
+		assert(
+			position.baseAssetAmount
+				.sub(positionBefore.baseAssetAmount)
+				.eq(AMM_RESERVE_PRECISION.sub(AMM_RESERVE_PRECISION.div(new BN(1000))))
+		);
+		assert(user.collateral.eq(userBefore.collateral));
+		assert(user.totalFeePaid.gt(userBefore.totalFeePaid));
+	});
+});
@@ -0,0 +1,126 @@
+import * as anchor from '@project-serum/anchor';
+import { Program } from '@project-serum/anchor';
+import { BN } from '../sdk';
+import { assert } from 'chai';
+import {
+	Admin,
+	MARK_PRICE_PRECISION,
+	PositionDirection,
+	QUOTE_PRECISION,
+} from '../sdk/src';
+import { Markets } from '../sdk/src/constants/markets';
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+
+describe('close max ts', () => {
+	const provider = anchor.Provider.local();
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+	let userAccountPublicKey;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const marketIndex = new BN(0);
+
+	const fetchUserAndPosition = async () => {
+		const user: any = await clearingHouse.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		const userPositions: any =
+			await clearingHouse.program.account.userPositions.fetch(user.positions);
+		return [user, userPositions.positions[0]];
+	};
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			Markets[0].marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		[, userAccountPublicKey] =
+			await clearingHouse.initializeUserAccountAndDepositCollateral(
+				usdcAmount,
+				userUSDCAccount.publicKey
+			);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('rejects a close landing after max ts', async () => {
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			QUOTE_PRECISION.mul(new BN(5)),
+			marketIndex
+		);
+		const [userBefore, positionBefore] = await fetchUserAndPosition();
+
+		// the clock is far past a max ts of 1
+		try {
+			await clearingHouse.closePosition(
+				marketIndex,
+				undefined,
+				undefined,
+				undefined,
+				undefined,
+				new BN(1)
+			);
+			assert(false, 'Order succeeded');
+		} catch (e) {
+			if (e.message == 'Order succeeded') {
+				assert(false, 'Order succeeded');
+			}
+			assert(e.msg, 'Timestamp is outside its valid range');
+		}
+
+		const [user, position] = await fetchUserAndPosition();
+		assert(position.baseAssetAmount.eq(positionBefore.baseAssetAmount));
+		assert(user.collateral.eq(userBefore.collateral));
+	});
+
+	it('closes before max ts', async () => {
+		const now = await connection.getBlockTime(await connection.getSlot());
+		await clearingHouse.closePosition(
+			marketIndex,
+			undefined,
+			undefined,
+			undefined,
+			undefined,
+			new BN(now + 60 * 60)
+		);
+
+		const [, position] = await fetchUserAndPosition();
+		assert(position.baseAssetAmount.eq(new BN(0)));
+	});
+});
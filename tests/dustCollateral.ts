@@ -0,0 +1,122 @@
+import * as anchor from '@project-serum/anchor';
+import { Program } from '@project-serum/anchor';
+import { BN } from '../sdk';
+import { assert } from 'chai';
+import {
+	Admin,
+	MARK_PRICE_PRECISION,
+	PositionDirection,
+	QUOTE_PRECISION,
+} from '../sdk/src';
+import { Markets } from '../sdk/src/constants/markets';
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+
+describe('dust collateral', () => {
+	const provider = anchor.Provider.local();
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+	let userAccountPublicKey;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const marketIndex = new BN(0);
+
+	const fetchCollateralAndFeePool = async () => {
+		const user: any = await clearingHouse.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		const markets: any = await clearingHouse.program.account.markets.fetch(
+			clearingHouse.getStateAccount().markets
+		);
+		return [
+			user.collateral,
+			markets.markets[marketIndex.toNumber()].amm.totalFeeMinusDistributions,
+		];
+	};
+
+	const openAndClose = async () => {
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			QUOTE_PRECISION,
+			marketIndex
+		);
+		await clearingHouse.closePosition(marketIndex);
+	};
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			Markets[0].marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		[, userAccountPublicKey] =
+			await clearingHouse.initializeUserAccountAndDepositCollateral(
+				usdcAmount,
+				userUSDCAccount.publicKey
+			);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('leaves collateral with the user when sweeping is off', async () => {
+		await openAndClose();
+
+		const [collateral, feePoolBefore] = await fetchCollateralAndFeePool();
+		assert(collateral.gt(new BN(0)));
+
+		await openAndClose();
+
+		const [collateralAfter, feePoolAfter] = await fetchCollateralAndFeePool();
+		const fee = feePoolAfter.sub(feePoolBefore);
+		assert(collateralAfter.gt(new BN(0)));
+		assert(collateralAfter.add(fee).lte(collateral));
+	});
+
+	it('sweeps collateral below the threshold to the fee pool', async () => {
+		// everything the user has left counts as dust
+		await clearingHouse.updateDustCollateralThreshold(
+			usdcAmount.mul(new BN(2))
+		);
+
+		const [collateralBefore, feePoolBefore] = await fetchCollateralAndFeePool();
+
+		await openAndClose();
+
+		const [collateral, feePool] = await fetchCollateralAndFeePool();
+		assert(collateral.eq(new BN(0)));
+		assert(feePool.sub(feePoolBefore).gte(collateralBefore.div(new BN(2))));
+	});
+});
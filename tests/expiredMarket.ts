@@ -0,0 +1,148 @@
+import * as anchor from '@project-serum/anchor';
+import { Program } from '@project-serum/anchor';
+import { BN } from '../sdk';
+import { assert } from 'chai';
+import {
+	Admin,
+	AMM_TO_QUOTE_PRECISION_RATIO,
+	MARK_PRICE_PRECISION,
+	PositionDirection,
+	QUOTE_PRECISION,
+} from '../sdk/src';
+import { Markets } from '../sdk/src/constants/markets';
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+
+describe('expired market', () => {
+	const provider = anchor.Provider.local();
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+	let userAccountPublicKey;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const marketIndex = new BN(0);
+
+	const fetchUserAndPosition = async () => {
+		const user: any = await clearingHouse.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		const userPositions: any =
+			await clearingHouse.program.account.userPositions.fetch(user.positions);
+		return [user, userPositions.positions[0]];
+	};
+
+	const chainNow = async () => {
+		return new BN(await connection.getBlockTime(await connection.getSlot()));
+	};
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			Markets[0].marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		[, userAccountPublicKey] =
+			await clearingHouse.initializeUserAccountAndDepositCollateral(
+				usdcAmount,
+				userUSDCAccount.publicKey
+			);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('opens before expiry', async () => {
+		await clearingHouse.updateMarketExpiry(
+			marketIndex,
+			(await chainNow()).add(new BN(60 * 60))
+		);
+
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			QUOTE_PRECISION.mul(new BN(5)),
+			marketIndex
+		);
+
+		const [, position] = await fetchUserAndPosition();
+		assert(position.baseAssetAmount.gt(new BN(0)));
+	});
+
+	it('rejects opens after expiry', async () => {
+		await clearingHouse.updateMarketExpiry(marketIndex, await chainNow());
+
+		try {
+			await clearingHouse.openPosition(
+				PositionDirection.LONG,
+				QUOTE_PRECISION,
+				marketIndex
+			);
+			assert(false, 'Order succeeded');
+		} catch (e) {
+			if (e.message == 'Order succeeded') {
+				assert(false, 'Order succeeded');
+			}
+			assert(e.msg, 'Market has expired');
+		}
+	});
+
+	it('settles the position at the settlement price', async () => {
+		const [userBefore, positionBefore] = await fetchUserAndPosition();
+
+		// the long entered around 1, so settling at 1.2 is a profit
+		const settlementPrice = MARK_PRICE_PRECISION.mul(new BN(12)).div(
+			new BN(10)
+		);
+		await clearingHouse.updateMarketSettlementPrice(
+			marketIndex,
+			settlementPrice
+		);
+
+		await clearingHouse.settleExpiredPosition(
+			userAccountPublicKey,
+			marketIndex
+		);
+
+		const [user, position] = await fetchUserAndPosition();
+		assert(position.baseAssetAmount.eq(new BN(0)));
+		assert(position.quoteAssetAmount.eq(new BN(0)));
+
+		const expectedPnl = positionBefore.baseAssetAmount
+			.mul(settlementPrice)
+			.div(MARK_PRICE_PRECISION.mul(AMM_TO_QUOTE_PRECISION_RATIO))
+			.sub(positionBefore.quoteAssetAmount);
+		assert(expectedPnl.gt(new BN(0)));
+		assert(user.collateral.eq(userBefore.collateral.add(expectedPnl)));
+	});
+});
@@ -0,0 +1,136 @@
+import * as anchor from '@project-serum/anchor';
+import { Program } from '@project-serum/anchor';
+import { BN } from '../sdk';
+import { assert } from 'chai';
+import {
+	Admin,
+	MARK_PRICE_PRECISION,
+	PositionDirection,
+	QUOTE_PRECISION,
+} from '../sdk/src';
+import { Markets } from '../sdk/src/constants/markets';
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+
+describe('force close position', () => {
+	const provider = anchor.Provider.local();
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+	let userAccountPublicKey;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const marketIndex = new BN(0);
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			Markets[0].marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		[, userAccountPublicKey] =
+			await clearingHouse.initializeUserAccountAndDepositCollateral(
+				usdcAmount,
+				userUSDCAccount.publicKey
+			);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('closes at the settlement price and emits the close events', async () => {
+		let positionChanged;
+		let positionClosed;
+		const changedListener = clearingHouse.program.addEventListener(
+			'PositionChanged',
+			(event) => {
+				positionChanged = event;
+			}
+		);
+		const closedListener = clearingHouse.program.addEventListener(
+			'PositionClosed',
+			(event) => {
+				positionClosed = event;
+			}
+		);
+
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			QUOTE_PRECISION.mul(new BN(5)),
+			marketIndex
+		);
+
+		const userBefore: any = await clearingHouse.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		const positionsBefore: any =
+			await clearingHouse.program.account.userPositions.fetch(
+				userBefore.positions
+			);
+		const baseAssetAmountBefore = positionsBefore.positions[0].baseAssetAmount;
+
+		// settling at twice the entry price roughly doubles the long's value
+		await clearingHouse.forceClosePosition(
+			userAccountPublicKey,
+			marketIndex,
+			MARK_PRICE_PRECISION.mul(new BN(2))
+		);
+
+		await new Promise((r) => setTimeout(r, 1000)); // wait for the events
+		await clearingHouse.program.removeEventListener(changedListener);
+		await clearingHouse.program.removeEventListener(closedListener);
+
+		const user: any = await clearingHouse.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		const userPositions: any =
+			await clearingHouse.program.account.userPositions.fetch(user.positions);
+		const market = clearingHouse.getMarketsAccount().markets[0];
+
+		assert(userPositions.positions[0].baseAssetAmount.eq(new BN(0)));
+		assert(market.openInterest.eq(new BN(0)));
+
+		assert(positionChanged !== undefined);
+		assert(
+			positionChanged.baseAssetAmountDelta.eq(baseAssetAmountBefore.neg())
+		);
+		assert(positionChanged.pnl.gt(new BN(0)));
+
+		assert(positionClosed !== undefined);
+		assert(positionClosed.grossPnl.eq(positionChanged.pnl));
+		assert(
+			positionClosed.grossPnl.eq(user.collateral.sub(userBefore.collateral))
+		);
+	});
+});
@@ -0,0 +1,226 @@
+import * as anchor from '@project-serum/anchor';
+import { Program } from '@project-serum/anchor';
+import { BN } from '../sdk';
+import { assert } from 'chai';
+import {
+	Admin,
+	AMM_TO_QUOTE_PRECISION_RATIO,
+	FeeStructure,
+	FUNDING_PAYMENT_PRECISION,
+	MARK_PRICE_PRECISION,
+	PositionDirection,
+	QUOTE_PRECISION,
+} from '../sdk/src';
+import { Markets } from '../sdk/src/constants/markets';
+import {
+	mockOracle,
+	mockUSDCMint,
+	mockUserUSDCAccount,
+	setFeedPrice,
+} from './testHelpers';
+
+describe('funding settlement', () => {
+	const provider = anchor.Provider.local();
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+	let userAccountPublicKey;
+	let solUsd;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const marketIndex = new BN(0);
+
+	const fetchState = async () => {
+		const user: any = await clearingHouse.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		const userPositions: any =
+			await clearingHouse.program.account.userPositions.fetch(user.positions);
+		const markets: any = await clearingHouse.program.account.markets.fetch(
+			clearingHouse.getStateAccount().markets
+		);
+		return [user, userPositions.positions[0], markets.markets[0]];
+	};
+
+	// what settling the position's funding pays into collateral
+	const calculateFundingPayment = (market, position) => {
+		const isLong = position.baseAssetAmount.gt(new BN(0));
+		const cumulativeFundingRate = isLong
+			? market.amm.cumulativeFundingRateLong
+			: market.amm.cumulativeFundingRateShort;
+		const fundingRateDelta = cumulativeFundingRate.sub(
+			position.lastCumulativeFundingRate
+		);
+		const payment = fundingRateDelta
+			.abs()
+			.mul(position.baseAssetAmount.abs())
+			.div(MARK_PRICE_PRECISION)
+			.div(FUNDING_PAYMENT_PRECISION);
+		const longsPay = isLong === fundingRateDelta.gt(new BN(0));
+		return (longsPay ? payment.neg() : payment).div(
+			AMM_TO_QUOTE_PRECISION_RATIO
+		);
+	};
+
+	const advanceFunding = async (oraclePrice: number) => {
+		await setFeedPrice(anchor.workspace.Pyth, oraclePrice, solUsd);
+		await new Promise((r) => setTimeout(r, 2000)); // wait 2 seconds
+		await clearingHouse.updateFundingRate(solUsd, marketIndex);
+	};
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		solUsd = await mockOracle(1);
+		const periodicity = new BN(0);
+
+		await clearingHouse.initializeMarket(
+			Markets[0].marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		// no fees, so collateral only moves by pnl and funding
+		const newFeeStructure: FeeStructure = {
+			feeNumerator: new BN(0),
+			feeDenominator: new BN(1),
+			discountTokenTiers: {
+				firstTier: {
+					minimumBalance: new BN(1),
+					discountNumerator: new BN(1),
+					discountDenominator: new BN(1),
+				},
+				secondTier: {
+					minimumBalance: new BN(1),
+					discountNumerator: new BN(1),
+					discountDenominator: new BN(1),
+				},
+				thirdTier: {
+					minimumBalance: new BN(1),
+					discountNumerator: new BN(1),
+					discountDenominator: new BN(1),
+				},
+				fourthTier: {
+					minimumBalance: new BN(1),
+					discountNumerator: new BN(1),
+					discountDenominator: new BN(1),
+				},
+			},
+			referralDiscount: {
+				referrerRewardNumerator: new BN(1),
+				referrerRewardDenominator: new BN(1),
+				refereeDiscountNumerator: new BN(1),
+				refereeDiscountDenominator: new BN(1),
+			},
+		};
+		await clearingHouse.updateFee(newFeeStructure);
+
+		[, userAccountPublicKey] =
+			await clearingHouse.initializeUserAccountAndDepositCollateral(
+				usdcAmount,
+				userUSDCAccount.publicKey
+			);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('settles funding before reversing a position', async () => {
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			QUOTE_PRECISION.mul(new BN(5)),
+			marketIndex
+		);
+
+		// the mark sits above the oracle, so longs pay
+		await advanceFunding(0.96);
+
+		const [userBefore, positionBefore, marketBefore] = await fetchState();
+		const fundingPayment = calculateFundingPayment(
+			marketBefore,
+			positionBefore
+		);
+		assert(fundingPayment.lt(new BN(0)));
+
+		await clearingHouse.openPosition(
+			PositionDirection.SHORT,
+			QUOTE_PRECISION.mul(new BN(8)),
+			marketIndex
+		);
+
+		const [user, position, market] = await fetchState();
+		assert(position.baseAssetAmount.lt(new BN(0)));
+		assert(
+			position.lastCumulativeFundingRate.eq(
+				market.amm.cumulativeFundingRateShort
+			)
+		);
+
+		const realizedPnl = user.totalRealizedPnl.sub(userBefore.totalRealizedPnl);
+		assert(
+			user.collateral.eq(
+				userBefore.collateral.add(realizedPnl).add(fundingPayment)
+			)
+		);
+	});
+
+	it('settles funding before force closing a position', async () => {
+		await advanceFunding(0.98);
+
+		const [userBefore, positionBefore, marketBefore] = await fetchState();
+		const fundingPayment = calculateFundingPayment(
+			marketBefore,
+			positionBefore
+		);
+		assert(!fundingPayment.eq(new BN(0)));
+
+		const now = new BN(
+			await connection.getBlockTime(await connection.getSlot())
+		);
+		await clearingHouse.updateMarketExpiry(marketIndex, now);
+		await clearingHouse.updateMarketSettlementPrice(
+			marketIndex,
+			MARK_PRICE_PRECISION
+		);
+		await clearingHouse.settleExpiredPosition(
+			userAccountPublicKey,
+			marketIndex
+		);
+
+		const [user, position] = await fetchState();
+		assert(position.baseAssetAmount.eq(new BN(0)));
+
+		const realizedPnl = user.totalRealizedPnl.sub(userBefore.totalRealizedPnl);
+		assert(
+			user.collateral.eq(
+				userBefore.collateral.add(realizedPnl).add(fundingPayment)
+			)
+		);
+	});
+});
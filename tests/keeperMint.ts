@@ -0,0 +1,162 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+import { BN } from '../sdk';
+
+import { Program } from '@project-serum/anchor';
+
+import { PublicKey } from '@solana/web3.js';
+import { Token, TOKEN_PROGRAM_ID } from '@solana/spl-token';
+
+import { Admin, MARK_PRICE_PRECISION } from '../sdk/src';
+
+import { Markets } from '../sdk/src/constants/markets';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+
+describe('keeper mint', () => {
+	const provider = anchor.Provider.local();
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let userAccountPublicKey: PublicKey;
+	let userPositionsPublicKey: PublicKey;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	let keeperMint: Token;
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			Markets[0].marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		[, userAccountPublicKey] =
+			await clearingHouse.initializeUserAccountAndDepositCollateral(
+				usdcAmount,
+				userUSDCAccount.publicKey
+			);
+		const user: any = await clearingHouse.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		userPositionsPublicKey = user.positions;
+
+		keeperMint = await Token.createMint(
+			connection,
+			// @ts-ignore
+			provider.wallet.payer,
+			provider.wallet.publicKey,
+			provider.wallet.publicKey,
+			0,
+			TOKEN_PROGRAM_ID
+		);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('anyone settles funding without a keeper mint', async () => {
+		await clearingHouse.settleFundingPayment(
+			userAccountPublicKey,
+			userPositionsPublicKey
+		);
+	});
+
+	it('blocks a caller without the keeper mint', async () => {
+		await clearingHouse.updateKeeperMint(keeperMint.publicKey);
+		const state = clearingHouse.getStateAccount();
+		console.assert(state.keeperMint.equals(keeperMint.publicKey));
+
+		try {
+			await clearingHouse.settleFundingPayment(
+				userAccountPublicKey,
+				userPositionsPublicKey
+			);
+		} catch (e) {
+			assert(e.msg, 'Keeper token not found');
+			return;
+		}
+		assert(false);
+	});
+
+	it('lets a keeper mint holder settle funding', async () => {
+		const keeperTokenAccount =
+			await keeperMint.getOrCreateAssociatedAccountInfo(
+				provider.wallet.publicKey
+			);
+		await keeperMint.mintTo(
+			keeperTokenAccount.address,
+			// @ts-ignore
+			provider.wallet.payer,
+			[],
+			1
+		);
+
+		const state = clearingHouse.getStateAccount();
+		await clearingHouse.program.rpc.settleFundingPayment({
+			accounts: {
+				state: await clearingHouse.getStatePublicKey(),
+				markets: state.markets,
+				user: userAccountPublicKey,
+				userPositions: userPositionsPublicKey,
+				fundingPaymentHistory: state.fundingPaymentHistory,
+			},
+			remainingAccounts: [
+				{
+					pubkey: keeperTokenAccount.address,
+					isSigner: false,
+					isWritable: false,
+				},
+				{
+					pubkey: provider.wallet.publicKey,
+					isSigner: true,
+					isWritable: false,
+				},
+			],
+		});
+	});
+
+	it('anyone settles funding once the keeper mint is removed', async () => {
+		await clearingHouse.updateKeeperMint(PublicKey.default);
+		const state = clearingHouse.getStateAccount();
+		console.assert(state.keeperMint.equals(PublicKey.default));
+
+		await clearingHouse.settleFundingPayment(
+			userAccountPublicKey,
+			userPositionsPublicKey
+		);
+	});
+});
@@ -0,0 +1,130 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+import { BN } from '../sdk';
+
+import { Program } from '@project-serum/anchor';
+
+import { Admin, MARK_PRICE_PRECISION } from '../sdk/src';
+
+import { Markets } from '../sdk/src/constants/markets';
+
+import { mockOracle, mockUSDCMint } from './testHelpers';
+
+describe('margin bounds', () => {
+	const provider = anchor.Provider.local();
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const marketIndex = new BN(0);
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			Markets[0].marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('Rejects a full correlation', async () => {
+		try {
+			await clearingHouse.updateMarketAssetGroup(
+				marketIndex,
+				new BN(1),
+				new BN(10000)
+			);
+			assert(false, 'Update market asset group succeeded');
+		} catch (e) {
+			if (e.message == 'Update market asset group succeeded') {
+				assert(false, e.message);
+			}
+			assert(e.msg, 'Correlation must be below 10000 bps');
+		}
+	});
+
+	it('Accepts a correlation below 10000 bps', async () => {
+		await clearingHouse.updateMarketAssetGroup(
+			marketIndex,
+			new BN(1),
+			new BN(9999)
+		);
+
+		const markets: any = await clearingHouse.program.account.markets.fetch(
+			clearingHouse.getStateAccount().markets
+		);
+		assert(markets.markets[0].assetGroup.eq(new BN(1)));
+		assert(markets.markets[0].correlationBps.eq(new BN(9999)));
+	});
+
+	it('Rejects an empty liquidation oracle band', async () => {
+		try {
+			await clearingHouse.updateLiquidationOracleBand(new BN(0), new BN(100));
+			assert(false, 'Update liquidation oracle band succeeded');
+		} catch (e) {
+			if (e.message == 'Update liquidation oracle band succeeded') {
+				assert(false, e.message);
+			}
+			assert(
+				e.msg,
+				'Liquidation oracle band must be greater than 0 and below 100%'
+			);
+		}
+	});
+
+	it('Rejects a liquidation oracle band of 100%', async () => {
+		try {
+			await clearingHouse.updateLiquidationOracleBand(new BN(100), new BN(100));
+			assert(false, 'Update liquidation oracle band succeeded');
+		} catch (e) {
+			if (e.message == 'Update liquidation oracle band succeeded') {
+				assert(false, e.message);
+			}
+			assert(
+				e.msg,
+				'Liquidation oracle band must be greater than 0 and below 100%'
+			);
+		}
+	});
+
+	it('Accepts a liquidation oracle band below 100%', async () => {
+		await clearingHouse.updateLiquidationOracleBand(new BN(5), new BN(100));
+
+		const state: any = await clearingHouse.program.account.state.fetch(
+			await clearingHouse.getStatePublicKey()
+		);
+		assert(state.liquidationOracleBandNumerator.eq(new BN(5)));
+		assert(state.liquidationOracleBandDenominator.eq(new BN(100)));
+	});
+});
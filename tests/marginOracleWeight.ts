@@ -0,0 +1,134 @@
+import * as anchor from '@project-serum/anchor';
+import { Program } from '@project-serum/anchor';
+import { BN } from '../sdk';
+import { assert } from 'chai';
+import {
+	Admin,
+	MARK_PRICE_PRECISION,
+	PositionDirection,
+	QUOTE_PRECISION,
+} from '../sdk/src';
+import { Markets } from '../sdk/src/constants/markets';
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+
+describe('margin oracle weight', () => {
+	const provider = anchor.Provider.local();
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const marketIndex = new BN(0);
+	const withdrawAmount = QUOTE_PRECISION.mul(new BN(5));
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			Markets[0].marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		// margin values positions at the oracle price alone
+		await clearingHouse.updateMarketMarginOracleWeight(
+			marketIndex,
+			new BN(10000)
+		);
+
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		// close to the initial margin requirement
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			QUOTE_PRECISION.mul(new BN(45)),
+			marketIndex
+		);
+
+		// push the curve up 44% while the oracle stays put
+		await clearingHouse.moveAmmPrice(
+			ammInitialBaseAssetReserve.mul(new BN(100)).div(new BN(120)),
+			ammInitialQuoteAssetReserve.mul(new BN(120)).div(new BN(100)),
+			marketIndex
+		);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('rejects a weight above 10000 bps', async () => {
+		try {
+			await clearingHouse.updateMarketMarginOracleWeight(
+				marketIndex,
+				new BN(10001)
+			);
+			assert(false, 'Update succeeded');
+		} catch (e) {
+			if (e.message == 'Update succeeded') {
+				assert(false, e.message);
+			}
+			assert(e.msg, 'Oracle weight must be between 0 and 10000 bps');
+		}
+	});
+
+	it('ignores the pushed curve with full oracle weight', async () => {
+		try {
+			await clearingHouse.withdrawCollateral(
+				withdrawAmount,
+				userUSDCAccount.publicKey
+			);
+			assert(false, 'Withdraw succeeded');
+		} catch (e) {
+			if (e.message == 'Withdraw succeeded') {
+				assert(false, e.message);
+			}
+			assert(e.msg, 'Insufficient collateral');
+		}
+	});
+
+	it('credits the pushed curve without oracle weight', async () => {
+		await clearingHouse.updateMarketMarginOracleWeight(marketIndex, new BN(0));
+
+		await clearingHouse.withdrawCollateral(
+			withdrawAmount,
+			userUSDCAccount.publicKey
+		);
+
+		const user: any = await clearingHouse.program.account.user.fetch(
+			await clearingHouse.getUserAccountPublicKey()
+		);
+		assert(user.collateral.lt(usdcAmount.sub(withdrawAmount)));
+	});
+});
@@ -0,0 +1,147 @@
+import * as anchor from '@project-serum/anchor';
+import { Program } from '@project-serum/anchor';
+import { BN } from '../sdk';
+import { assert } from 'chai';
+import {
+	Admin,
+	FeeStructure,
+	MARK_PRICE_PRECISION,
+	PositionDirection,
+} from '../sdk/src';
+import { Markets } from '../sdk/src/constants/markets';
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+
+describe('market margin ratio', () => {
+	const provider = anchor.Provider.local();
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+	let userAccountPublicKey;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const marketIndex = new BN(0);
+
+	// 2x, stricter than the exchange wide 5x
+	const marketMarginRatioInitial = new BN(5000);
+	const maxQuoteAssetAmount = usdcAmount.mul(new BN(2));
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			Markets[0].marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		// no fees, so collateral is exactly the deposit when the trade is checked
+		const newFeeStructure: FeeStructure = {
+			feeNumerator: new BN(0),
+			feeDenominator: new BN(1),
+			discountTokenTiers: {
+				firstTier: {
+					minimumBalance: new BN(1),
+					discountNumerator: new BN(1),
+					discountDenominator: new BN(1),
+				},
+				secondTier: {
+					minimumBalance: new BN(1),
+					discountNumerator: new BN(1),
+					discountDenominator: new BN(1),
+				},
+				thirdTier: {
+					minimumBalance: new BN(1),
+					discountNumerator: new BN(1),
+					discountDenominator: new BN(1),
+				},
+				fourthTier: {
+					minimumBalance: new BN(1),
+					discountNumerator: new BN(1),
+					discountDenominator: new BN(1),
+				},
+			},
+			referralDiscount: {
+				referrerRewardNumerator: new BN(1),
+				referrerRewardDenominator: new BN(1),
+				refereeDiscountNumerator: new BN(1),
+				refereeDiscountDenominator: new BN(1),
+			},
+		};
+		await clearingHouse.updateFee(newFeeStructure);
+
+		await clearingHouse.updateMarketMarginRatioInitial(
+			marketIndex,
+			marketMarginRatioInitial
+		);
+
+		[, userAccountPublicKey] =
+			await clearingHouse.initializeUserAccountAndDepositCollateral(
+				usdcAmount,
+				userUSDCAccount.publicKey
+			);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('rejects a trade just over the market limit', async () => {
+		// swap rounding is a few units at most, so this is still over the limit
+		try {
+			await clearingHouse.openPosition(
+				PositionDirection.LONG,
+				maxQuoteAssetAmount.add(new BN(100)),
+				marketIndex
+			);
+			assert(false, 'Order succeeded');
+		} catch (e) {
+			if (e.message == 'Order succeeded') {
+				assert(false, e.message);
+			}
+			assert(e.msg, 'Insufficient collateral');
+		}
+	});
+
+	it('allows a trade right at the market limit', async () => {
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			maxQuoteAssetAmount,
+			marketIndex
+		);
+
+		const user: any = await clearingHouse.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		const userPositions: any =
+			await clearingHouse.program.account.userPositions.fetch(user.positions);
+		assert(userPositions.positions[0].quoteAssetAmount.eq(maxQuoteAssetAmount));
+	});
+});
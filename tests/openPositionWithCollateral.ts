@@ -0,0 +1,141 @@
+import * as anchor from '@project-serum/anchor';
+import { Program } from '@project-serum/anchor';
+import { getTokenAccount } from '@project-serum/common';
+import { BN } from '../sdk';
+import { assert } from 'chai';
+import {
+	Admin,
+	AMM_RESERVE_PRECISION,
+	MARK_PRICE_PRECISION,
+	PositionDirection,
+	QUOTE_PRECISION,
+} from '../sdk/src';
+import { Markets } from '../sdk/src/constants/markets';
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+
+describe('open position with collateral', () => {
+	const provider = anchor.Provider.local();
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+	let userAccountPublicKey;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const depositAmount = QUOTE_PRECISION.mul(new BN(5));
+	const marketIndex = new BN(0);
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount.mul(new BN(2)),
+			provider
+		);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			Markets[0].marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		[, userAccountPublicKey] =
+			await clearingHouse.initializeUserAccountAndDepositCollateral(
+				usdcAmount,
+				userUSDCAccount.publicKey
+			);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('reverts the deposit when the open fails', async () => {
+		// at a price of 1, 5 USDC buys a little less than 5 base after price impact
+		try {
+			await clearingHouse.openPositionWithCollateral(
+				depositAmount,
+				userUSDCAccount.publicKey,
+				PositionDirection.LONG,
+				QUOTE_PRECISION.mul(new BN(5)),
+				marketIndex,
+				undefined,
+				undefined,
+				undefined,
+				AMM_RESERVE_PRECISION.mul(new BN(5))
+			);
+			assert(false, 'Order succeeded');
+		} catch (e) {
+			if (e.message == 'Order succeeded') {
+				assert(false, 'Order succeeded');
+			}
+		}
+
+		const user: any = await clearingHouse.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		assert(user.collateral.eq(usdcAmount));
+		assert(user.cumulativeDeposits.eq(usdcAmount));
+		const userPositions: any =
+			await clearingHouse.program.account.userPositions.fetch(user.positions);
+		assert(userPositions.positions[0].baseAssetAmount.eq(new BN(0)));
+		const userUSDCTokenAccount = await getTokenAccount(
+			provider,
+			userUSDCAccount.publicKey
+		);
+		assert(userUSDCTokenAccount.amount.eq(usdcAmount));
+	});
+
+	it('deposits and opens together', async () => {
+		await clearingHouse.openPositionWithCollateral(
+			depositAmount,
+			userUSDCAccount.publicKey,
+			PositionDirection.LONG,
+			QUOTE_PRECISION.mul(new BN(5)),
+			marketIndex
+		);
+
+		const user: any = await clearingHouse.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		assert(user.cumulativeDeposits.eq(usdcAmount.add(depositAmount)));
+		// the deposit, less the fee on the open
+		assert(
+			user.collateral.eq(usdcAmount.add(depositAmount).sub(user.totalFeePaid))
+		);
+		const userPositions: any =
+			await clearingHouse.program.account.userPositions.fetch(user.positions);
+		assert(userPositions.positions[0].baseAssetAmount.gt(new BN(0)));
+		const userUSDCTokenAccount = await getTokenAccount(
+			provider,
+			userUSDCAccount.publicKey
+		);
+		assert(userUSDCTokenAccount.amount.eq(usdcAmount.sub(depositAmount)));
+	});
+});
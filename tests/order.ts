@@ -0,0 +1,179 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+import { BN } from '../sdk';
+
+import { Program } from '@project-serum/anchor';
+
+import { PublicKey } from '@solana/web3.js';
+
+import {
+	Admin,
+	AMM_RESERVE_PRECISION,
+	getUserOrdersAccountPublicKey,
+	MARK_PRICE_PRECISION,
+	OrderParams,
+	OrderTriggerCondition,
+	OrderType,
+	PositionDirection,
+} from '../sdk/src';
+
+import { Markets } from '../sdk/src/constants/markets';
+
+import {
+	mockOracle,
+	mockUSDCMint,
+	mockUserUSDCAccount,
+	setFeedPrice,
+} from './testHelpers';
+
+describe('orders', () => {
+	const provider = anchor.Provider.local();
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let userAccountPublicKey: PublicKey;
+
+	let usdcMint;
+	let userUSDCAccount;
+	let solUsd;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const marketIndex = new BN(0);
+
+	const orderParams = (
+		orderType: OrderType,
+		direction: PositionDirection,
+		price: BN
+	): OrderParams => {
+		return {
+			orderType,
+			direction,
+			baseAssetAmount: AMM_RESERVE_PRECISION,
+			price,
+			marketIndex,
+			reduceOnly: false,
+			triggerPrice: new BN(0),
+			triggerCondition: OrderTriggerCondition.ABOVE,
+			oraclePriceOffset: new BN(0),
+			minFillBaseAssetAmount: new BN(0),
+			maxKeeperReward: new BN(0),
+		};
+	};
+
+	const fetchNextOrderId = async () => {
+		const userOrders: any = await chProgram.account.userOrders.fetch(
+			await getUserOrdersAccountPublicKey(
+				chProgram.programId,
+				userAccountPublicKey
+			)
+		);
+		return userOrders.nextOrderId;
+	};
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			Markets[0].marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		[, userAccountPublicKey] =
+			await clearingHouse.initializeUserAccountAndDepositCollateral(
+				usdcAmount,
+				userUSDCAccount.publicKey
+			);
+		await clearingHouse.initializeUserOrders();
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('Rejects market orders', async () => {
+		try {
+			await clearingHouse.placeOrder(
+				orderParams(OrderType.MARKET, PositionDirection.LONG, new BN(0))
+			);
+			assert(false, 'Place market order succeeded');
+		} catch (e) {
+			if (e.message == 'Place market order succeeded') {
+				assert(false, e.message);
+			}
+			assert(e.msg, 'Invalid order');
+		}
+	});
+
+	it('Fills a limit order', async () => {
+		const orderId = await fetchNextOrderId();
+		await clearingHouse.placeOrder(
+			orderParams(
+				OrderType.LIMIT,
+				PositionDirection.LONG,
+				MARK_PRICE_PRECISION.mul(new BN(2))
+			)
+		);
+
+		await clearingHouse.fillOrder(userAccountPublicKey, orderId);
+
+		const user: any = await clearingHouse.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		const userPositions: any =
+			await clearingHouse.program.account.userPositions.fetch(user.positions);
+		assert(
+			userPositions.positions[0].baseAssetAmount.eq(AMM_RESERVE_PRECISION)
+		);
+	});
+
+	it('Blocks a fill that pushes the mark away from the oracle', async () => {
+		// with the oracle well below the mark, a long fill pushes the mark away
+		await setFeedPrice(anchor.workspace.Pyth, 0.5, solUsd);
+
+		const orderId = await fetchNextOrderId();
+		await clearingHouse.placeOrder(
+			orderParams(
+				OrderType.LIMIT,
+				PositionDirection.LONG,
+				MARK_PRICE_PRECISION.mul(new BN(2))
+			)
+		);
+
+		try {
+			await clearingHouse.fillOrder(userAccountPublicKey, orderId);
+			assert(false, 'Fill order succeeded');
+		} catch (e) {
+			if (e.message == 'Fill order succeeded') {
+				assert(false, e.message);
+			}
+			assert(e.msg, 'Oracle/Mark Spread Too Large');
+		}
+	});
+});
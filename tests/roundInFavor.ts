@@ -195,4 +195,52 @@ describe('round in favor', () => {
 
 		assert(user.collateral.eq(new BN(9998999)));
 	});
+
+	it('quote sized open and base sized close agree', async () => {
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		const wallet = new Wallet(keypair);
+		const userUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount,
+			provider,
+			keypair.publicKey
+		);
+		const clearingHouse = ClearingHouse.from(
+			connection,
+			wallet,
+			chProgram.programId
+		);
+		await clearingHouse.subscribe();
+
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		const marketIndex = new BN(0);
+		const tradeAmount = calculateTradeAmount(usdcAmount);
+		// opening is sized in quote (swap_quote_asset), closing in base (swap_base_asset)
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			tradeAmount,
+			marketIndex,
+			new BN(0)
+		);
+		await clearingHouse.closePosition(marketIndex);
+
+		const tradeHistory: any =
+			await primaryClearingHouse.program.account.tradeHistory.fetch(
+				primaryClearingHouse.getStateAccount().tradeHistory
+			);
+		const head = tradeHistory.head.toNumber();
+		const openRecord = tradeHistory.tradeRecords[head - 2];
+		const closeRecord = tradeHistory.tradeRecords[head - 1];
+
+		assert(openRecord.quoteAssetAmount.eq(tradeAmount));
+		assert(closeRecord.baseAssetAmount.eq(openRecord.baseAssetAmount));
+		assert(closeRecord.quoteAssetAmount.lte(openRecord.quoteAssetAmount));
+
+		await clearingHouse.unsubscribe();
+	});
 });
@@ -0,0 +1,186 @@
+import * as anchor from '@project-serum/anchor';
+import { Program } from '@project-serum/anchor';
+import { BN } from '../sdk';
+import { assert } from 'chai';
+import {
+	Admin,
+	FeeStructure,
+	MARK_PRICE_PRECISION,
+	PositionDirection,
+	QUOTE_PRECISION,
+} from '../sdk/src';
+import { Markets } from '../sdk/src/constants/markets';
+import {
+	mockOracle,
+	mockUSDCMint,
+	mockUserUSDCAccount,
+	setFeedPrice,
+} from './testHelpers';
+
+describe('settled pnl', () => {
+	const provider = anchor.Provider.local();
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+	let userAccountPublicKey;
+	let solUsd;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const marketIndex = new BN(0);
+
+	const fetchCollateralAndSettledPnl = async () => {
+		const user: any = await clearingHouse.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		const userPositions: any =
+			await clearingHouse.program.account.userPositions.fetch(user.positions);
+		return [user.collateral, userPositions.positions[0].settledPnl];
+	};
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			Markets[0].marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		const newFeeStructure: FeeStructure = {
+			feeNumerator: new BN(0),
+			feeDenominator: new BN(1),
+			discountTokenTiers: {
+				firstTier: {
+					minimumBalance: new BN(1),
+					discountNumerator: new BN(1),
+					discountDenominator: new BN(1),
+				},
+				secondTier: {
+					minimumBalance: new BN(1),
+					discountNumerator: new BN(1),
+					discountDenominator: new BN(1),
+				},
+				thirdTier: {
+					minimumBalance: new BN(1),
+					discountNumerator: new BN(1),
+					discountDenominator: new BN(1),
+				},
+				fourthTier: {
+					minimumBalance: new BN(1),
+					discountNumerator: new BN(1),
+					discountDenominator: new BN(1),
+				},
+			},
+			referralDiscount: {
+				referrerRewardNumerator: new BN(1),
+				referrerRewardDenominator: new BN(1),
+				refereeDiscountNumerator: new BN(1),
+				refereeDiscountDenominator: new BN(1),
+			},
+		};
+		await clearingHouse.updateFee(newFeeStructure);
+
+		await clearingHouse.updateMarketSettlePnlSeparately(marketIndex, true);
+
+		[, userAccountPublicKey] =
+			await clearingHouse.initializeUserAccountAndDepositCollateral(
+				usdcAmount,
+				userUSDCAccount.publicKey
+			);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('books reduce pnl to the position instead of collateral', async () => {
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			QUOTE_PRECISION.mul(new BN(5)),
+			marketIndex
+		);
+
+		// move the price up so the long is in profit
+		await setFeedPrice(anchor.workspace.Pyth, 1.1025, solUsd);
+		await clearingHouse.moveAmmPrice(
+			ammInitialBaseAssetReserve.mul(new BN(100)).div(new BN(105)),
+			ammInitialQuoteAssetReserve.mul(new BN(105)).div(new BN(100)),
+			marketIndex
+		);
+
+		const [collateralBefore, settledPnlBefore] =
+			await fetchCollateralAndSettledPnl();
+		assert(settledPnlBefore.eq(new BN(0)));
+
+		await clearingHouse.openPosition(
+			PositionDirection.SHORT,
+			QUOTE_PRECISION,
+			marketIndex
+		);
+
+		const [collateral, settledPnl] = await fetchCollateralAndSettledPnl();
+		assert(settledPnl.gt(new BN(0)));
+		assert(collateral.eq(collateralBefore));
+	});
+
+	it('moves settled pnl to collateral when claimed', async () => {
+		const [collateralBefore, settledPnlBefore] =
+			await fetchCollateralAndSettledPnl();
+
+		await clearingHouse.claimSettledPnl(marketIndex);
+
+		const [collateral, settledPnl] = await fetchCollateralAndSettledPnl();
+		assert(settledPnl.eq(new BN(0)));
+		assert(collateral.eq(collateralBefore.add(settledPnlBefore)));
+	});
+
+	it('books reduce losses to collateral straight away', async () => {
+		// move the price down so the long is at a loss
+		await setFeedPrice(anchor.workspace.Pyth, 0.907, solUsd);
+		await clearingHouse.moveAmmPrice(
+			ammInitialBaseAssetReserve.mul(new BN(105)).div(new BN(100)),
+			ammInitialQuoteAssetReserve.mul(new BN(100)).div(new BN(105)),
+			marketIndex
+		);
+
+		const [collateralBefore] = await fetchCollateralAndSettledPnl();
+
+		await clearingHouse.openPosition(
+			PositionDirection.SHORT,
+			QUOTE_PRECISION,
+			marketIndex
+		);
+
+		const [collateral, settledPnl] = await fetchCollateralAndSettledPnl();
+		assert(settledPnl.eq(new BN(0)));
+		assert(collateral.lt(collateralBefore));
+	});
+});
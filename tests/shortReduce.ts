@@ -0,0 +1,108 @@
+import * as anchor from '@project-serum/anchor';
+import { Program } from '@project-serum/anchor';
+import { BN } from '../sdk';
+import { assert } from 'chai';
+import {
+	Admin,
+	MARK_PRICE_PRECISION,
+	PositionDirection,
+	QUOTE_PRECISION,
+} from '../sdk/src';
+import { Markets } from '../sdk/src/constants/markets';
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+
+describe('short reduce', () => {
+	const provider = anchor.Provider.local();
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const marketIndex = new BN(0);
+
+	const fetchMarket = async () => {
+		const markets: any = await clearingHouse.program.account.markets.fetch(
+			clearingHouse.getStateAccount().markets
+		);
+		return markets.markets[marketIndex.toNumber()];
+	};
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			Markets[0].marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('keeps the short total non-positive on a partial reduce', async () => {
+		await clearingHouse.openPosition(
+			PositionDirection.SHORT,
+			QUOTE_PRECISION.mul(new BN(5)),
+			marketIndex
+		);
+		const marketBefore = await fetchMarket();
+		assert(marketBefore.baseAssetAmountShort.lt(new BN(0)));
+
+		// buying back part of the short
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			QUOTE_PRECISION.mul(new BN(2)),
+			marketIndex
+		);
+
+		const market = await fetchMarket();
+		assert(market.baseAssetAmountShort.lt(new BN(0)));
+		assert(market.baseAssetAmountShort.gt(marketBefore.baseAssetAmountShort));
+		assert(market.baseAssetAmountLong.eq(new BN(0)));
+		assert(market.baseAssetAmount.eq(market.baseAssetAmountShort));
+	});
+
+	it('brings the short total to exactly zero on a full close', async () => {
+		await clearingHouse.closePosition(marketIndex);
+
+		const market = await fetchMarket();
+		assert(market.baseAssetAmountShort.eq(new BN(0)));
+		assert(market.baseAssetAmountLong.eq(new BN(0)));
+		assert(market.baseAssetAmount.eq(new BN(0)));
+	});
+});
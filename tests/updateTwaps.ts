@@ -0,0 +1,126 @@
+import * as anchor from '@project-serum/anchor';
+import { Program } from '@project-serum/anchor';
+import { BN } from '../sdk';
+import { assert } from 'chai';
+import { Admin, MARK_PRICE_PRECISION } from '../sdk/src';
+import { Markets } from '../sdk/src/constants/markets';
+import { mockOracle, mockUSDCMint, setFeedPrice } from './testHelpers';
+
+describe('update twaps', () => {
+	const provider = anchor.Provider.local();
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const initialPrices = [1, 2, 3];
+	const oracles = [];
+	const marketIndexes = [];
+
+	const fetchMarkets = async () => {
+		const marketsAccount: any =
+			await clearingHouse.program.account.markets.fetch(
+				clearingHouse.getStateAccount().markets
+			);
+		return marketIndexes.map(
+			(marketIndex) => marketsAccount.markets[marketIndex.toNumber()]
+		);
+	};
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		for (let i = 0; i < initialPrices.length; i++) {
+			const oracle = await mockOracle(initialPrices[i]);
+			await clearingHouse.initializeMarket(
+				Markets[i].marketIndex,
+				oracle,
+				ammInitialBaseAssetReserve,
+				ammInitialQuoteAssetReserve,
+				periodicity,
+				new BN(initialPrices[i] * 1000)
+			);
+			oracles.push(oracle);
+			marketIndexes.push(Markets[i].marketIndex);
+		}
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('updates every market in one instruction', async () => {
+		const marketsBefore = await fetchMarkets();
+
+		// move two of the oracles in opposite directions and leave the third
+		await setFeedPrice(anchor.workspace.Pyth, 1.2, oracles[0]);
+		await setFeedPrice(anchor.workspace.Pyth, 2.7, oracles[2]);
+		await new Promise((r) => setTimeout(r, 2000)); // wait 2 seconds
+
+		await clearingHouse.updateTwaps(oracles, marketIndexes);
+
+		const marketsAfter = await fetchMarkets();
+		for (let i = 0; i < marketIndexes.length; i++) {
+			assert(
+				marketsAfter[i].amm.lastMarkPriceTwapTs.gt(
+					marketsBefore[i].amm.lastMarkPriceTwapTs
+				)
+			);
+			assert(
+				marketsAfter[i].amm.lastOraclePriceTwapTs.gt(
+					marketsBefore[i].amm.lastOraclePriceTwapTs
+				)
+			);
+		}
+
+		assert(
+			marketsAfter[0].amm.lastOraclePriceTwap.gt(
+				marketsBefore[0].amm.lastOraclePriceTwap
+			)
+		);
+		assert(
+			marketsAfter[2].amm.lastOraclePriceTwap.lt(
+				marketsBefore[2].amm.lastOraclePriceTwap
+			)
+		);
+	});
+
+	it('caps how far one observation moves the oracle twap', async () => {
+		const marketsBefore = await fetchMarkets();
+
+		// double the second oracle, which the twap should only follow by .1%
+		await setFeedPrice(anchor.workspace.Pyth, 4, oracles[1]);
+		await new Promise((r) => setTimeout(r, 2000)); // wait 2 seconds
+
+		await clearingHouse.updateTwaps(oracles, marketIndexes);
+
+		const marketsAfter = await fetchMarkets();
+		const twapBefore = marketsBefore[1].amm.lastOraclePriceTwap;
+		const twapAfter = marketsAfter[1].amm.lastOraclePriceTwap;
+		const maxTwap = MARK_PRICE_PRECISION.mul(new BN(2002)).div(new BN(1000));
+		assert(twapAfter.gt(twapBefore));
+		assert(twapAfter.lte(maxTwap));
+	});
+});